@@ -0,0 +1,439 @@
+use std::path::{Path, PathBuf};
+
+use super::*;
+
+const META_FILE: &str = "meta.json";
+const DB_DIR: &str = "db";
+/// Written to the checkpoints dir when a restore is scheduled for the next start
+const RESTORE_FILE: &str = "restore.json";
+/// Checkpoint blocks are looked up on the node for at most this long when looking for a restore point
+const LOOKUP_TIMEOUT: Duration = Duration::from_secs(60);
+
+#[serde_as]
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct CheckpointMeta {
+    pub height: u32,
+    #[serde_as(as = "DisplayFromStr")]
+    pub block_hash: BlockHash,
+    #[serde_as(as = "Option<DisplayFromStr>")]
+    pub proof_of_history: Option<sha256::Hash>,
+}
+
+pub struct Checkpoint {
+    pub path: PathBuf,
+    pub meta: CheckpointMeta,
+}
+
+#[derive(Serialize, Deserialize)]
+struct ScheduledRestore {
+    checkpoint: PathBuf,
+}
+
+fn dir() -> PathBuf {
+    PathBuf::from(&CONFIG.checkpoints.dir)
+}
+
+fn write_json(path: &Path, value: &impl Serialize) -> anyhow::Result<()> {
+    let tmp = path.with_extension("tmp");
+    std::fs::write(&tmp, serde_json::to_vec_pretty(value)?)
+        .anyhow_with(format!("Failed to write {}", tmp.display()))?;
+    std::fs::rename(&tmp, path).anyhow_with(format!("Failed to write {}", path.display()))
+}
+
+/// Complete checkpoints from the oldest
+pub fn list() -> anyhow::Result<Vec<Checkpoint>> {
+    let dir = dir();
+    if !dir.exists() {
+        return Ok(vec![]);
+    }
+
+    let mut checkpoints = vec![];
+    for entry in std::fs::read_dir(&dir).anyhow_with(format!("Failed to read {}", dir.display()))? {
+        let path = entry?.path();
+        // Interrupted checkpoints have no meta
+        let Ok(data) = std::fs::read(path.join(META_FILE)) else {
+            continue;
+        };
+        let meta = serde_json::from_slice(&data)
+            .anyhow_with(format!("Invalid checkpoint {}", path.display()))?;
+        checkpoints.push(Checkpoint { path, meta });
+    }
+
+    checkpoints.sort_by_key(|x| x.meta.height);
+    Ok(checkpoints)
+}
+
+/// Checkpoints are created every `checkpoints.interval_blocks` near the node tip `tip_height`.
+/// Older ones would be beyond the reorg cache and the kept checkpoints by the time the tip is reached
+pub fn is_due(height: u32, tip_height: u32) -> bool {
+    let interval = CONFIG.checkpoints.interval_blocks;
+    let window = (CONFIG.indexer.reorg_depth as u32)
+        .saturating_add(interval.saturating_mul(CONFIG.checkpoints.keep as u32));
+
+    interval != 0 && height.is_multiple_of(interval) && height.saturating_add(window) >= tip_height
+}
+
+/// Checkpoint of the db at the committed block `height`, tagged with its hash and proof of history.
+/// Checkpoints beyond the last `checkpoints.keep` are removed
+pub async fn create(server: &Server, height: u32) -> anyhow::Result<()> {
+    let meta = CheckpointMeta {
+        height,
+        block_hash: server
+            .db
+            .block_hashes
+            .get(height)?
+            .anyhow_with("Block hash of the checkpoint is not found")?,
+        proof_of_history: server.db.proof_of_history.get(height)?,
+    };
+    let db = server.db.clone();
+
+    tokio::task::spawn_blocking(move || {
+        let path = dir().join(format!("{height:010}"));
+        if path.exists() {
+            std::fs::remove_dir_all(&path)?;
+        }
+        std::fs::create_dir_all(&path)
+            .anyhow_with(format!("Failed to create {}", path.display()))?;
+
+        db.create_checkpoint(&path.join(DB_DIR))?;
+        write_json(&path.join(META_FILE), &meta)?;
+        info!("Created checkpoint at {} ({})", height, meta.block_hash);
+        metrics::CHECKPOINT_HEIGHT.set(height as i64);
+
+        for old in list()?.iter().rev().skip(CONFIG.checkpoints.keep) {
+            std::fs::remove_dir_all(&old.path)?;
+        }
+
+        anyhow::Ok(())
+    })
+    .await?
+}
+
+/// Newest checkpoint below `fork_height` whose block is on the chain of the node.
+/// Each block hash is retried by the client for at most `LOOKUP_TIMEOUT`
+pub async fn restore_point(
+    server: &Server,
+    fork_height: u32,
+) -> anyhow::Result<Option<Checkpoint>> {
+    for checkpoint in list()?
+        .into_iter()
+        .rev()
+        .filter(|x| x.meta.height < fork_height)
+    {
+        let height = checkpoint.meta.height;
+        let hash = tokio::time::timeout(LOOKUP_TIMEOUT, server.client.get_block_hash(height))
+            .await
+            .anyhow_with(format!("Node didn't reply with the hash of block {height}"))??;
+
+        if hash == checkpoint.meta.block_hash {
+            return Ok(Some(checkpoint));
+        }
+    }
+
+    Ok(None)
+}
+
+/// The checkpoint replaces the db on the next start, see `restore_scheduled`
+pub fn schedule_restore(checkpoint: &Checkpoint) -> anyhow::Result<()> {
+    write_json(
+        &dir().join(RESTORE_FILE),
+        &ScheduledRestore {
+            checkpoint: checkpoint.path.clone(),
+        },
+    )
+}
+
+pub fn is_restore_scheduled() -> bool {
+    dir().join(RESTORE_FILE).exists()
+}
+
+/// Hard-links the immutable SST files and copies the rest, so the checkpoint stays untouched
+fn copy_db(from: &Path, to: &Path) -> anyhow::Result<()> {
+    std::fs::create_dir_all(to).anyhow_with(format!("Failed to create {}", to.display()))?;
+
+    for entry in
+        std::fs::read_dir(from).anyhow_with(format!("Failed to read {}", from.display()))?
+    {
+        let source = entry?.path();
+        let target = to.join(source.file_name().anyhow()?);
+
+        let linked = source.extension().is_some_and(|x| x == "sst")
+            && std::fs::hard_link(&source, &target).is_ok();
+        if !linked {
+            std::fs::copy(&source, &target)
+                .anyhow_with(format!("Failed to copy {}", source.display()))?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Entries of `table` replaced by those of the same table of another db
+fn replace_table<K: db::Pebble, V: db::Pebble>(
+    batch: &mut DbBatch,
+    table: &db::DbTable<K, V>,
+    from: &db::DbTable<K, V>,
+) -> anyhow::Result<()> {
+    for entry in table.iter() {
+        batch.delete(table, entry?.0);
+    }
+    for entry in from.iter() {
+        let (k, v) = entry?;
+        batch.put(table, k, v);
+    }
+    Ok(())
+}
+
+/// Moves the state that doesn't depend on the chain from the replaced db in one batch. The event log
+/// is continued with a reorg of the blocks after the checkpoint, webhooks keep their registrations and cursors
+fn carry_over(replaced: &DB, db: &DB, meta: &CheckpointMeta) -> anyhow::Result<()> {
+    let mut batch = DbBatch::default();
+
+    let mut seq = db.last_event_seq.get(())?.unwrap_or_default();
+    for entry in replaced.event_log.range(&(seq + 1).., false) {
+        let (next, event) = entry?;
+        batch.put(&db.event_log, next, event);
+        seq = next;
+    }
+
+    let replaced_height = replaced.last_block.get(())?.unwrap_or_default();
+    if replaced_height > meta.height {
+        seq += 1;
+        batch.put(
+            &db.event_log,
+            seq,
+            ServerEvent::Reorg(replaced_height - meta.height, meta.height + 1),
+        );
+    }
+    batch.put(&db.last_event_seq, (), seq);
+
+    replace_table(&mut batch, &db.webhooks, &replaced.webhooks)?;
+    replace_table(&mut batch, &db.webhook_cursors, &replaced.webhook_cursors)?;
+    replace_table(
+        &mut batch,
+        &db.webhook_dead_letters,
+        &replaced.webhook_dead_letters,
+    )?;
+    if let Some(id) = replaced.last_webhook_id.get(())? {
+        batch.put(&db.last_webhook_id, (), id);
+    }
+
+    db.write(batch)
+}
+
+/// Replaces the db with the scheduled checkpoint, blocks after it are indexed again.
+/// Safe to run again if interrupted, the replaced db is kept until the restore is done
+pub fn restore_scheduled() -> anyhow::Result<()> {
+    let restore_file = dir().join(RESTORE_FILE);
+    if !restore_file.exists() {
+        return Ok(());
+    }
+
+    let ScheduledRestore { checkpoint } = serde_json::from_slice(&std::fs::read(&restore_file)?)
+        .anyhow_with("Invalid scheduled restore")?;
+    let meta: CheckpointMeta = serde_json::from_slice(&std::fs::read(checkpoint.join(META_FILE))?)
+        .anyhow_with(format!("Invalid checkpoint {}", checkpoint.display()))?;
+
+    warn!(
+        "Restoring checkpoint at {} ({}) from {}",
+        meta.height,
+        meta.block_hash,
+        checkpoint.display()
+    );
+
+    replace_db(&checkpoint, &meta, &CONFIG.db_path)?;
+    std::fs::remove_file(&restore_file)?;
+
+    warn!("Restored checkpoint at {}", meta.height);
+    Ok(())
+}
+
+/// Replaces the db at `db_path` with a copy of the checkpoint, the replaced db is kept until the copy
+/// carries over its state
+fn replace_db(checkpoint: &Path, meta: &CheckpointMeta, db_path: &str) -> anyhow::Result<()> {
+    let replaced_path = PathBuf::from(format!("{db_path}.replaced"));
+    let db_path = PathBuf::from(db_path);
+
+    if replaced_path.exists() {
+        // Interrupted restore, the db is a partial copy of the checkpoint
+        if db_path.exists() {
+            std::fs::remove_dir_all(&db_path)?;
+        }
+    } else {
+        std::fs::rename(&db_path, &replaced_path)
+            .anyhow_with(format!("Failed to move {}", db_path.display()))?;
+    }
+
+    copy_db(&checkpoint.join(DB_DIR), &db_path)?;
+
+    {
+        let replaced = DB::open(&replaced_path.to_string_lossy());
+        let db = DB::open(&db_path.to_string_lossy());
+        carry_over(&replaced, &db, meta)?;
+        db.flush_all()?;
+    }
+
+    std::fs::remove_dir_all(&replaced_path)?;
+    Ok(())
+}
+
+/// Replaces the db with the checkpoint at `height` at once
+pub fn restore(height: u32) -> anyhow::Result<()> {
+    let checkpoint = list()?
+        .into_iter()
+        .find(|x| x.meta.height == height)
+        .anyhow_with(format!("No checkpoint at {height}"))?;
+
+    schedule_restore(&checkpoint)?;
+    restore_scheduled()
+}
+
+/// Replaces the process with a new instance of it, which restores the scheduled checkpoint on start
+#[cfg(unix)]
+pub fn restart() -> anyhow::Result<()> {
+    use std::os::unix::process::CommandExt;
+
+    warn!("Restarting to restore the checkpoint");
+    let e = std::process::Command::new(std::env::current_exe()?)
+        .args(std::env::args_os().skip(1))
+        .exec();
+
+    Err(e).anyhow_with("Failed to restart")
+}
+
+#[cfg(not(unix))]
+pub fn restart() -> anyhow::Result<()> {
+    anyhow::bail!("Checkpoint restore is scheduled, start the indexer again to apply it")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils;
+
+    #[test]
+    fn checkpoints_are_due_near_the_tip() {
+        crate::config::Config::install_default();
+        let interval = CONFIG.checkpoints.interval_blocks;
+        let window = CONFIG.indexer.reorg_depth as u32 + interval * CONFIG.checkpoints.keep as u32;
+
+        assert!(is_due(interval * 100, interval * 100));
+        assert!(is_due(interval * 100, interval * 100 + window));
+        assert!(!is_due(interval * 100, interval * 100 + window + 1));
+        assert!(!is_due(interval * 100 + 1, interval * 100 + 1));
+    }
+
+    #[test]
+    fn carry_over_continues_event_log_and_webhooks() {
+        crate::config::Config::install_default();
+        let replaced = DB::open_memory();
+        let db = DB::open_memory();
+
+        for seq in 1..=3 {
+            replaced
+                .event_log
+                .set(seq, ServerEvent::Reorg(1, 1))
+                .unwrap();
+        }
+        replaced.last_event_seq.set((), 3).unwrap();
+        replaced.last_block.set((), 12).unwrap();
+        replaced
+            .webhooks
+            .set(
+                1,
+                Webhook {
+                    id: 1,
+                    url: "http://localhost/hook".to_string(),
+                    secret: None,
+                    addresses: HashSet::new(),
+                    tokens: HashSet::new(),
+                    events: HashSet::new(),
+                    created_seq: 1,
+                },
+            )
+            .unwrap();
+        replaced.webhook_cursors.set(1, 2).unwrap();
+        replaced.last_webhook_id.set((), 1).unwrap();
+
+        for seq in 1..=2 {
+            db.event_log.set(seq, ServerEvent::Reorg(1, 1)).unwrap();
+        }
+        db.last_event_seq.set((), 2).unwrap();
+        db.last_block.set((), 10).unwrap();
+        // Cursor of a webhook deleted after the checkpoint
+        db.webhook_cursors.set(5, 1).unwrap();
+
+        let meta = CheckpointMeta {
+            height: 10,
+            block_hash: BlockHash::all_zeros(),
+            proof_of_history: None,
+        };
+        carry_over(&replaced, &db, &meta).unwrap();
+
+        assert_eq!(db.last_event_seq.get(()).unwrap(), Some(4));
+        assert!(db.event_log.get(3).unwrap().is_some());
+        assert!(matches!(
+            db.event_log.get(4).unwrap(),
+            Some(ServerEvent::Reorg(2, 11))
+        ));
+        assert_eq!(
+            test_utils::entries(&db.webhooks),
+            test_utils::entries(&replaced.webhooks)
+        );
+        assert_eq!(
+            test_utils::entries(&db.webhook_cursors),
+            test_utils::entries(&replaced.webhook_cursors)
+        );
+        assert_eq!(db.last_webhook_id.get(()).unwrap(), Some(1));
+    }
+
+    #[test]
+    fn restore_replaces_db_and_keeps_its_state() {
+        crate::config::Config::install_default();
+        let dir = std::env::temp_dir().join(format!("bel20-checkpoint-{}", std::process::id()));
+        let db_path = dir.join("db").to_string_lossy().to_string();
+        let checkpoint = dir.join("checkpoint");
+        let meta = CheckpointMeta {
+            height: 10,
+            block_hash: BlockHash::all_zeros(),
+            proof_of_history: None,
+        };
+
+        {
+            let db = DB::open(&db_path);
+            db.block_hashes.set(10, BlockHash::all_zeros()).unwrap();
+            db.last_block.set((), 10).unwrap();
+            std::fs::create_dir_all(&checkpoint).unwrap();
+            db.create_checkpoint(&checkpoint.join(DB_DIR)).unwrap();
+
+            // Indexed after the checkpoint and reorged
+            db.block_hashes.set(11, BlockHash::all_zeros()).unwrap();
+            db.last_block.set((), 11).unwrap();
+            db.event_log.set(1, ServerEvent::Reorg(1, 1)).unwrap();
+            db.last_event_seq.set((), 1).unwrap();
+            db.webhook_cursors.set(1, 1).unwrap();
+            db.last_webhook_id.set((), 1).unwrap();
+            db.flush_all().unwrap();
+        }
+
+        replace_db(&checkpoint, &meta, &db_path).unwrap();
+
+        {
+            let db = DB::open(&db_path);
+            assert_eq!(db.last_block.get(()).unwrap(), Some(10));
+            assert!(db.block_hashes.get(10).unwrap().is_some());
+            assert!(db.block_hashes.get(11).unwrap().is_none());
+            assert_eq!(db.last_event_seq.get(()).unwrap(), Some(2));
+            assert!(matches!(
+                db.event_log.get(2).unwrap(),
+                Some(ServerEvent::Reorg(1, 11))
+            ));
+            assert_eq!(db.webhook_cursors.get(1).unwrap(), Some(1));
+            assert_eq!(db.last_webhook_id.get(()).unwrap(), Some(1));
+        }
+        assert!(!PathBuf::from(format!("{db_path}.replaced")).exists());
+        assert!(checkpoint.join(DB_DIR).exists());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
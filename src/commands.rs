@@ -0,0 +1,802 @@
+use std::{
+    io::{BufWriter, Write},
+    path::PathBuf,
+};
+
+use config::ExportData;
+use inscriptions::InitialIndexer;
+
+use super::*;
+
+/// The db opened as a secondary instance, so it can be read while the indexer is running
+struct ReadOnlyDb {
+    db: DB,
+    secondary_path: PathBuf,
+}
+
+impl ReadOnlyDb {
+    fn open() -> Self {
+        let secondary_path =
+            std::env::temp_dir().join(format!("bel20-secondary-{}", std::process::id()));
+        let db = DB::open_secondary(&CONFIG.db_path, &secondary_path.to_string_lossy());
+
+        Self { db, secondary_path }
+    }
+}
+
+impl Drop for ReadOnlyDb {
+    fn drop(&mut self) {
+        std::fs::remove_dir_all(&self.secondary_path).ok();
+    }
+}
+
+fn address(db: &DB, hash: FullHash) -> anyhow::Result<String> {
+    if hash.is_op_return_hash() {
+        Ok(OP_RETURN_ADDRESS.to_string())
+    } else {
+        Ok(db
+            .fullhash_to_address
+            .get(hash)?
+            .unwrap_or(NON_STANDARD_ADDRESS.to_string()))
+    }
+}
+
+/// Ranges of heights from `from` to `to` missing in ascending `heights`
+fn gaps(
+    heights: impl Iterator<Item = anyhow::Result<u32>>,
+    from: u32,
+    to: u32,
+) -> anyhow::Result<Vec<String>> {
+    let mut gaps = vec![];
+    let mut expected = from;
+
+    for height in heights.chain([Ok(to + 1)]) {
+        let height = height?;
+        if height > expected {
+            gaps.push(format!("{expected}..{}", height - 1));
+        }
+        expected = expected.max(height + 1);
+    }
+
+    Ok(gaps)
+}
+
+/// Checks that the db has every block, proof of history and token supply matching balances,
+/// and that its last `indexer.reorg_depth` blocks are on the chain of the node
+pub async fn verify() -> anyhow::Result<()> {
+    let ReadOnlyDb { db, .. } = &ReadOnlyDb::open();
+
+    let Some(last_block) = db.last_block.get(())? else {
+        info!("Db at {} is empty", CONFIG.db_path);
+        return Ok(());
+    };
+    info!("Db at {} is indexed up to {}", CONFIG.db_path, last_block);
+
+    let mut errors = vec![];
+
+    // Indexing starts from the block after genesis
+    let missing = gaps(
+        db.block_hashes.range(&1.., false).map_ok(|x| x.0),
+        1,
+        last_block,
+    )?;
+    if !missing.is_empty() {
+        errors.push(format!("Missing block hashes: {}", missing.join(", ")));
+    }
+
+    let start_height = PROTOCOL.start_height.max(1);
+    let missing = gaps(
+        db.proof_of_history
+            .range(&start_height.., false)
+            .map_ok(|x| x.0),
+        start_height,
+        last_block,
+    )?;
+    if PROTOCOL.is_indexed(last_block) && !missing.is_empty() {
+        errors.push(format!("Missing proofs of history: {}", missing.join(", ")));
+    }
+
+    let mut supply = HashMap::<LowerCaseTick, Fixed128>::new();
+    for entry in db.address_token_to_balance.iter() {
+        let (k, v) = entry?;
+        *supply.entry(k.token).or_default() += v.balance + v.transferable_balance;
+    }
+    for entry in db.token_to_meta.iter() {
+        let (tick, meta) = entry?;
+        let held = supply.remove(&tick).unwrap_or_default();
+        if held != meta.proto.supply {
+            errors.push(format!(
+                "Token {} has supply {} but balances sum to {}",
+                meta.proto.tick, meta.proto.supply, held
+            ));
+        }
+    }
+    for tick in supply.keys() {
+        errors.push(format!(
+            "Balances of unknown token {}",
+            String::from_utf8_lossy(tick)
+        ));
+    }
+
+    let client = AsyncClient::new(&CONFIG.rpc, WaitToken::default());
+    if let Some(hash) = db.block_hashes.get(last_block)? {
+        client.set_indexed_tip(last_block, hash);
+    }
+
+    let node_height = client.get_block_count().await? as u32;
+    info!("Node is at height {}", node_height);
+
+    if node_height < last_block {
+        errors.push(format!(
+            "Node is at height {node_height}, behind the db at {last_block}"
+        ));
+    }
+
+    let to = last_block.min(node_height);
+    let from = to.saturating_sub(CONFIG.indexer.reorg_depth as u32 - 1);
+    let node_hashes = client.get_block_hashes(from..=to).await?;
+    for (height, node) in (from..=to).zip(node_hashes) {
+        let Some(local) = db.block_hashes.get(height)? else {
+            continue;
+        };
+        if local != node {
+            errors.push(format!(
+                "Block {height} is {local} in the db and {node} on the node"
+            ));
+            break;
+        }
+    }
+
+    if !errors.is_empty() {
+        for e in &errors {
+            error!("{}", e);
+        }
+        anyhow::bail!("Verification failed with {} errors", errors.len());
+    }
+
+    info!("Db is consistent and follows the node");
+    Ok(())
+}
+
+/// Blocks and transactions the rollback reads from the node
+trait Node {
+    async fn get_block(&self, hash: &BlockHash) -> anyhow::Result<bellscoin::Block>;
+
+    async fn get_transactions(&self, txids: &[Txid]) -> anyhow::Result<Vec<Transaction>>;
+}
+
+impl Node for AsyncClient {
+    async fn get_block(&self, hash: &BlockHash) -> anyhow::Result<bellscoin::Block> {
+        AsyncClient::get_block(self, hash).await
+    }
+
+    async fn get_transactions(&self, txids: &[Txid]) -> anyhow::Result<Vec<Transaction>> {
+        AsyncClient::get_transactions(self, txids).await
+    }
+}
+
+/// Transactions of the block with `txids`, the others are fetched from the node
+async fn transactions(
+    node: &impl Node,
+    txs: &HashMap<Txid, &Transaction>,
+    txids: impl IntoIterator<Item = Txid>,
+) -> anyhow::Result<HashMap<Txid, Transaction>> {
+    let (found, missing): (Vec<_>, Vec<_>) = txids
+        .into_iter()
+        .unique()
+        .partition(|x| txs.contains_key(x));
+
+    Ok(node
+        .get_transactions(&missing)
+        .await?
+        .into_iter()
+        .chain(found.into_iter().map(|x| txs[&x].clone()))
+        .map(|x| (x.txid(), x))
+        .collect())
+}
+
+/// Changes of the rollback, collected from all reverted blocks and written at once
+#[derive(Default)]
+struct Rollback {
+    accounts: HashMap<AddressToken, TokenBalance>,
+    tokens: HashMap<LowerCaseTick, TokenMetaDB>,
+    removed_tokens: HashSet<LowerCaseTick>,
+    removed_history: Vec<(AddressTokenId, OutPoint)>,
+    /// Outputs spent by the reverted blocks
+    restored_prevouts: HashMap<OutPoint, TxOut>,
+    /// Outputs created by the reverted blocks
+    removed_prevouts: HashSet<OutPoint>,
+    restored_transfers: Vec<(AddressLocation, TransferProtoDB)>,
+}
+
+impl Rollback {
+    fn account(&mut self, db: &DB, key: AddressToken) -> anyhow::Result<&mut TokenBalance> {
+        Ok(match self.accounts.entry(key) {
+            std::collections::hash_map::Entry::Occupied(e) => e.into_mut(),
+            std::collections::hash_map::Entry::Vacant(e) => {
+                let account = db
+                    .address_token_to_balance
+                    .get(e.key())?
+                    .unwrap_or_default();
+                e.insert(account)
+            }
+        })
+    }
+
+    fn token(&mut self, db: &DB, tick: LowerCaseTick) -> anyhow::Result<&mut DeployProtoDB> {
+        let meta = match self.tokens.entry(tick) {
+            std::collections::hash_map::Entry::Occupied(e) => e.into_mut(),
+            std::collections::hash_map::Entry::Vacant(e) => {
+                let meta = db.token_to_meta.get(e.key())?.anyhow_with(format!(
+                    "Token {} not found",
+                    String::from_utf8_lossy(e.key())
+                ))?;
+                e.insert(meta)
+            }
+        };
+        Ok(&mut meta.proto)
+    }
+
+    /// Reverts the block, its history is inverted from the last event
+    async fn revert_block(
+        &mut self,
+        db: &DB,
+        node: &impl Node,
+        height: u32,
+        block: &bellscoin::Block,
+    ) -> anyhow::Result<()> {
+        let txs = block
+            .txdata
+            .iter()
+            .map(|x| (x.txid(), x))
+            .collect::<HashMap<_, _>>();
+
+        for (txid, tx) in &txs {
+            self.removed_prevouts
+                .extend((0..tx.output.len() as u32).map(|vout| OutPoint { txid: *txid, vout }));
+        }
+
+        // Spent outputs are removed only from indexed blocks with transactions
+        if PROTOCOL.is_indexed(height) && block.txdata.len() > 1 {
+            let spent = block
+                .txdata
+                .iter()
+                .skip(1)
+                .flat_map(|x| &x.input)
+                .map(|x| x.previous_output)
+                .unique()
+                .collect_vec();
+
+            let spent_txs = transactions(node, &txs, spent.iter().map(|x| x.txid)).await?;
+
+            for outpoint in spent {
+                let tx = spent_txs
+                    .get(&outpoint.txid)
+                    .anyhow_with(format!("Transaction {} not found", outpoint.txid))?;
+                let txout = tx
+                    .output
+                    .get(outpoint.vout as usize)
+                    .anyhow_with(format!("Output {outpoint} not found"))?;
+
+                if !txout.script_pubkey.is_provably_unspendable() {
+                    self.restored_prevouts.insert(outpoint, txout.clone());
+                }
+            }
+        }
+
+        for key in db
+            .block_events
+            .get(height)?
+            .unwrap_or_default()
+            .into_iter()
+            .rev()
+        {
+            let history = db
+                .address_token_to_history
+                .get(&key)?
+                .anyhow_with(format!("History {} of block {height} not found", key.id))?;
+            let tick = LowerCaseTick::from(key.token);
+            let account_key = AddressToken {
+                address: key.address,
+                token: tick.clone(),
+            };
+
+            match history.action {
+                TokenHistoryDB::Deploy { .. } => {
+                    self.removed_tokens.insert(tick);
+                }
+                TokenHistoryDB::Mint { amt, .. } => {
+                    self.account(db, account_key)?.balance -= amt;
+
+                    let token = self.token(db, tick)?;
+                    token.supply -= amt;
+                    token.mint_count -= 1;
+                    token.transactions -= 1;
+                }
+                TokenHistoryDB::DeployTransfer { amt, .. } => {
+                    let account = self.account(db, account_key)?;
+                    account.balance += amt;
+                    account.transferable_balance -= amt;
+                    account.transfers_count -= 1;
+
+                    let token = self.token(db, tick)?;
+                    token.transfer_count -= 1;
+                    token.transactions -= 1;
+                }
+                TokenHistoryDB::Send { amt, txid, .. } => {
+                    let account = self.account(db, account_key)?;
+                    account.transferable_balance += amt;
+                    account.transfers_count += 1;
+
+                    self.token(db, tick)?.transactions -= 1;
+                    self.restore_transfer(db, node, &txs, txid, &key, amt)
+                        .await?;
+                }
+                TokenHistoryDB::Receive { amt, .. } => {
+                    if !key.address.is_op_return_hash() {
+                        self.account(db, account_key)?.balance -= amt;
+                    }
+                }
+                TokenHistoryDB::SendReceive { amt, txid, .. } => {
+                    let account = self.account(db, account_key)?;
+                    account.balance -= amt;
+                    account.transferable_balance += amt;
+                    account.transfers_count += 1;
+
+                    self.token(db, tick)?.transactions -= 1;
+                    self.restore_transfer(db, node, &txs, txid, &key, amt)
+                        .await?;
+                }
+            }
+
+            self.removed_history.push((key, history.action.outpoint()));
+        }
+
+        Ok(())
+    }
+
+    /// Finds the transfer inscription spent by the transaction among its inputs
+    async fn restore_transfer(
+        &mut self,
+        db: &DB,
+        node: &impl Node,
+        txs: &HashMap<Txid, &Transaction>,
+        txid: Txid,
+        sender: &AddressTokenId,
+        amt: Fixed128,
+    ) -> anyhow::Result<()> {
+        let tx = txs
+            .get(&txid)
+            .anyhow_with(format!("Transaction {txid} not found in its block"))?;
+
+        for input in &tx.input {
+            let Some(key) = db.outpoint_to_event.get(input.previous_output)? else {
+                continue;
+            };
+            if key.address != sender.address || key.token != sender.token {
+                continue;
+            }
+
+            let Some(HistoryValue {
+                height,
+                action:
+                    TokenHistoryDB::DeployTransfer {
+                        amt: transfer_amt, ..
+                    },
+            }) = db.address_token_to_history.get(&key)?
+            else {
+                continue;
+            };
+            if transfer_amt != amt {
+                continue;
+            }
+
+            let location = self
+                .transfer_location(node, txs, input.previous_output, height, sender, amt)
+                .await?;
+            self.restored_transfers.push((
+                AddressLocation {
+                    address: sender.address,
+                    location,
+                },
+                TransferProtoDB {
+                    tick: sender.token,
+                    amt,
+                    height,
+                },
+            ));
+            return Ok(());
+        }
+
+        anyhow::bail!("Transfer inscription spent by {txid} not found")
+    }
+
+    /// Location of the transfer inscribed on the output, found by parsing the transaction
+    /// revealing it like the indexer did. The history only has the output
+    async fn transfer_location(
+        &self,
+        node: &impl Node,
+        txs: &HashMap<Txid, &Transaction>,
+        outpoint: OutPoint,
+        height: u32,
+        sender: &AddressTokenId,
+        amt: Fixed128,
+    ) -> anyhow::Result<Location> {
+        let reveal = transactions(node, txs, [outpoint.txid])
+            .await?
+            .remove(&outpoint.txid)
+            .anyhow_with(format!("Transaction {} not found", outpoint.txid))?;
+
+        let spent = reveal.input.iter().map(|x| x.previous_output).collect_vec();
+        let spent_txs = transactions(node, txs, spent.iter().map(|x| x.txid)).await?;
+        let prevouts = spent
+            .into_iter()
+            .filter_map(|x| {
+                let txout = spent_txs.get(&x.txid)?.output.get(x.vout as usize)?;
+                Some((x, txout.clone()))
+            })
+            .collect::<HashMap<_, _>>();
+
+        InitialIndexer::transfers(height, &reveal, &prevouts)?
+            .into_iter()
+            .filter(|(location, proto)| {
+                location.outpoint == outpoint && proto.tick == sender.token && proto.amt == amt
+            })
+            .map(|(location, _)| location)
+            .sorted_unstable()
+            .find(|location| {
+                !self
+                    .restored_transfers
+                    .iter()
+                    .any(|(k, _)| k.address == sender.address && k.location == *location)
+            })
+            .anyhow_with(format!("Transfer inscription on {outpoint} not found"))
+    }
+
+    /// Writes all changes in one batch, so an interrupted rollback leaves the db as it was
+    fn write(self, db: &DB, height: u32, last_block: u32) -> anyhow::Result<()> {
+        let Self {
+            accounts,
+            tokens,
+            removed_tokens,
+            removed_history,
+            restored_prevouts,
+            removed_prevouts,
+            restored_transfers,
+        } = self;
+
+        let mut batch = DbBatch::default();
+
+        batch.extend(
+            &db.prevouts,
+            restored_prevouts
+                .into_iter()
+                .filter(|(k, _)| !removed_prevouts.contains(k)),
+        );
+        batch.remove_batch(&db.prevouts, removed_prevouts);
+
+        batch.extend(
+            &db.address_token_to_balance,
+            accounts
+                .into_iter()
+                .filter(|(k, _)| !removed_tokens.contains(&k.token)),
+        );
+        batch.extend(
+            &db.token_to_meta,
+            tokens
+                .into_iter()
+                .filter(|(k, _)| !removed_tokens.contains(k)),
+        );
+        if !removed_tokens.is_empty() {
+            batch.remove_batch(&db.token_to_meta, removed_tokens.iter());
+            batch.remove_batch(
+                &db.address_token_to_balance,
+                keys_where(&db.address_token_to_balance, |k, _| {
+                    removed_tokens.contains(&k.token)
+                })?,
+            );
+        }
+
+        // Transfers inscribed after the height are removed whether they were spent or not
+        batch.extend(
+            &db.address_location_to_transfer,
+            restored_transfers
+                .into_iter()
+                .filter(|(_, v)| v.height <= height),
+        );
+        batch.remove_batch(
+            &db.address_location_to_transfer,
+            keys_where(&db.address_location_to_transfer, |_, v| v.height > height)?,
+        );
+
+        if let Some(first_id) = removed_history.iter().map(|(k, _)| k.id).min() {
+            batch.put(&db.last_history_id, (), first_id - 1);
+        }
+        batch.remove_batch(
+            &db.outpoint_to_event,
+            removed_history.iter().map(|(_, outpoint)| *outpoint),
+        );
+        batch.remove_batch(
+            &db.address_token_to_history,
+            removed_history.into_iter().map(|(k, _)| k),
+        );
+
+        let removed_rejections = db
+            .inscription_to_rejection
+            .iter()
+            .filter_ok(|(_, v)| v.height > height)
+            .map_ok(|(k, v)| (k, OutPointRejection::from(&v)))
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        batch.remove_batch(
+            &db.outpoint_to_rejection,
+            removed_rejections.iter().map(|(_, x)| x.clone()),
+        );
+        batch.remove_batch(
+            &db.inscription_to_rejection,
+            removed_rejections.into_iter().map(|(k, _)| k),
+        );
+        batch.remove_batch(
+            &db.address_to_rejection,
+            keys_where(&db.address_to_rejection, |k, _| k.height > height)?,
+        );
+
+        let reverted = height + 1..=last_block;
+        batch.remove_batch(&db.block_events, reverted.clone());
+        batch.remove_batch(&db.proof_of_history, reverted.clone());
+        batch.remove_batch(&db.reorg_blocks, reverted.clone());
+        batch.remove_batch(&db.block_hashes, reverted);
+
+        // Subscribers and sinks revert the blocks as on a reorg
+        let seq = db.last_event_seq.get(())?.unwrap_or_default() + 1;
+        batch.put(
+            &db.event_log,
+            seq,
+            ServerEvent::Reorg(last_block - height, height + 1),
+        );
+        batch.put(&db.last_event_seq, (), seq);
+
+        batch.put(&db.last_block, (), height);
+        db.write(batch)?;
+        db.flush_all()
+    }
+}
+
+/// Keys of the entries of the table matching `f`
+fn keys_where<K: db::Pebble, V: db::Pebble>(
+    table: &impl Table<K, V>,
+    f: impl Fn(&K::Inner, &V::Inner) -> bool,
+) -> anyhow::Result<Vec<K::Inner>> {
+    table
+        .iter()
+        .filter_ok(|(k, v)| f(k, v))
+        .map_ok(|(k, _)| k)
+        .try_collect()
+}
+
+/// Transactions spent by the reverted blocks are fetched by txid, which the node finds out of
+/// the mempool only with `txindex`. Checked with the coinbase of the last block before reverting
+async fn check_txindex(db: &DB, client: &AsyncClient) -> anyhow::Result<()> {
+    let last_block = db.last_block.get(())?.anyhow_with("Db is empty")?;
+    let hash = db
+        .block_hashes
+        .get(last_block)?
+        .anyhow_with(format!("Hash of block {last_block} not found"))?;
+    let coinbase = client.get_block(&hash).await?.txdata[0].txid();
+
+    client.check_txindex(coinbase).await
+}
+
+/// Reverts the db to `height` by inverting the history of the later blocks. The blocks are fetched
+/// from the node to restore the outputs they spent. Nothing is written until all blocks are reverted
+pub async fn rollback(height: u32) -> anyhow::Result<()> {
+    let db = DB::open(&CONFIG.db_path);
+    let client = AsyncClient::new(&CONFIG.rpc, WaitToken::default());
+    check_txindex(&db, &client).await?;
+
+    revert(&db, &client, height).await?;
+    info!("Db is rolled back to {}", height);
+
+    Ok(())
+}
+
+async fn revert(db: &DB, node: &impl Node, height: u32) -> anyhow::Result<()> {
+    let last_block = db.last_block.get(())?.anyhow_with("Db is empty")?;
+    anyhow::ensure!(
+        height < last_block,
+        "Db is indexed up to {last_block}, nothing to roll back"
+    );
+    warn!("Rolling back the db from {} to {}", last_block, height);
+
+    let mut rollback = Rollback::default();
+
+    let progress = crate::utils::Progress::begin("Reverting", (last_block - height) as _, 0);
+    for block_height in (height + 1..=last_block).rev() {
+        let hash = db
+            .block_hashes
+            .get(block_height)?
+            .anyhow_with(format!("Hash of block {block_height} not found"))?;
+        let block = node.get_block(&hash).await?;
+
+        rollback
+            .revert_block(db, node, block_height, &block)
+            .await?;
+        progress.inc(1);
+    }
+
+    rollback.write(db, height, last_block)
+}
+
+#[derive(Serialize)]
+struct BalanceExport {
+    address: String,
+    tick: TokenTick,
+    balance: Fixed128,
+    transferable_balance: Fixed128,
+    transfers_count: u64,
+}
+
+#[derive(Serialize)]
+struct TokenExport {
+    tick: TokenTick,
+    genesis: InscriptionId,
+    deployer: String,
+    max: Fixed128,
+    lim: Fixed128,
+    dec: u8,
+    supply: Fixed128,
+    mint_count: u64,
+    transfer_count: u64,
+    height: u32,
+    created: u32,
+    transactions: u32,
+}
+
+/// Writes balances or tokens as JSON lines
+pub fn export(data: ExportData, output: Option<PathBuf>) -> anyhow::Result<()> {
+    let ReadOnlyDb { db, .. } = &ReadOnlyDb::open();
+
+    let mut out: BufWriter<Box<dyn Write>> = BufWriter::new(match &output {
+        Some(path) => Box::new(
+            std::fs::File::create(path)
+                .anyhow_with(format!("Failed to create {}", path.display()))?,
+        ),
+        None => Box::new(std::io::stdout().lock()),
+    });
+
+    let height = db.last_block.get(())?.unwrap_or_default();
+    let mut count = 0;
+
+    match data {
+        ExportData::Balances => {
+            let mut ticks = HashMap::<LowerCaseTick, TokenTick>::new();
+
+            for entry in db.address_token_to_balance.iter() {
+                let (k, v) = entry?;
+                let tick = match ticks.get(&k.token) {
+                    Some(tick) => *tick,
+                    None => {
+                        let meta = db.token_to_meta.get(&k.token)?.anyhow_with(format!(
+                            "Token {} not found",
+                            String::from_utf8_lossy(&k.token)
+                        ))?;
+                        ticks.insert(k.token.clone(), meta.proto.tick);
+                        meta.proto.tick
+                    }
+                };
+
+                serde_json::to_writer(
+                    &mut out,
+                    &BalanceExport {
+                        address: address(db, k.address)?,
+                        tick,
+                        balance: v.balance,
+                        transferable_balance: v.transferable_balance,
+                        transfers_count: v.transfers_count,
+                    },
+                )?;
+                out.write_all(b"\n")?;
+                count += 1;
+            }
+        }
+        ExportData::Tokens => {
+            for entry in db.token_to_meta.iter() {
+                let (_, v) = entry?;
+                serde_json::to_writer(
+                    &mut out,
+                    &TokenExport {
+                        tick: v.proto.tick,
+                        genesis: v.genesis,
+                        deployer: address(db, v.proto.deployer)?,
+                        max: v.proto.max,
+                        lim: v.proto.lim,
+                        dec: v.proto.dec,
+                        supply: v.proto.supply,
+                        mint_count: v.proto.mint_count,
+                        transfer_count: v.proto.transfer_count,
+                        height: v.proto.height,
+                        created: v.proto.created,
+                        transactions: v.proto.transactions,
+                    },
+                )?;
+                out.write_all(b"\n")?;
+                count += 1;
+            }
+        }
+    }
+
+    out.flush()?;
+    info!("Exported {} records at height {}", count, height);
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::{self, TestChain};
+
+    impl Node for TestChain {
+        async fn get_block(&self, hash: &BlockHash) -> anyhow::Result<bellscoin::Block> {
+            self.blocks
+                .iter()
+                .find(|x| x.block_hash() == *hash)
+                .cloned()
+                .anyhow_with(format!("Block {hash} not found"))
+        }
+
+        async fn get_transactions(&self, txids: &[Txid]) -> anyhow::Result<Vec<Transaction>> {
+            txids
+                .iter()
+                .map(|x| {
+                    self.transaction(x)
+                        .cloned()
+                        .anyhow_with(format!("Transaction {x} not found"))
+                })
+                .collect()
+        }
+    }
+
+    #[tokio::test]
+    async fn rollback_matches_fresh_index() {
+        let alice = test_utils::address(1);
+        let bob = test_utils::address(2);
+
+        let mut chain = TestChain::new();
+        let deploy = chain.inscribe(&alice, &test_utils::deploy("abcd", "1000", "100"));
+        chain.mine(vec![deploy]);
+        let mint = chain.inscribe(&alice, &test_utils::mint("abcd", "100"));
+        chain.mine(vec![mint]);
+        // Lands after the padding input, so the transfer isn't at offset 0 of its output
+        let sent = chain.inscribe_padded(&alice, &test_utils::transfer("abcd", "40"));
+        let kept = chain.inscribe(&alice, &test_utils::transfer("abcd", "10"));
+        let sent_outpoint = OutPoint::new(sent.txid(), 0);
+        chain.mine(vec![sent, kept]);
+        let height = chain.height();
+
+        let send = chain.send(sent_outpoint, &bob);
+        let deploy = chain.inscribe(&bob, &test_utils::deploy("efgh", "1000", "100"));
+        let mint = chain.inscribe(&bob, &test_utils::mint("abcd", "5"));
+        chain.mine(vec![send, deploy, mint]);
+
+        let rolled_back = test_utils::server().await;
+        test_utils::index(&rolled_back, &chain, chain.height()).await;
+        revert(&rolled_back.db, &chain, height).await.unwrap();
+
+        let fresh = test_utils::server().await;
+        test_utils::index(&fresh, &chain, height).await;
+
+        let (a, b) = (&rolled_back.db, &fresh.db);
+        test_utils::assert_same_tokens(a, b);
+        assert_eq!(
+            test_utils::entries(&a.prevouts),
+            test_utils::entries(&b.prevouts)
+        );
+        assert_eq!(a.last_block.get(()).unwrap(), Some(height));
+        assert!(matches!(
+            a.event_log
+                .get(a.last_event_seq.get(()).unwrap().unwrap())
+                .unwrap(),
+            Some(ServerEvent::Reorg(1, h)) if h == height + 1
+        ));
+    }
+}
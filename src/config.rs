@@ -0,0 +1,716 @@
+use std::{net::ToSocketAddrs, path::PathBuf, sync::OnceLock};
+
+use clap::{Args, Parser, Subcommand, ValueEnum};
+
+use utils::{RpcAuth, RpcEndpoint};
+
+use super::*;
+
+/// Used if `--config` is not passed and the file exists
+const DEFAULT_CONFIG_PATH: &str = "config.toml";
+
+static LOADED: OnceLock<Config> = OnceLock::new();
+
+#[derive(Parser)]
+#[command(version, about = "bel-20 indexer and API server")]
+pub struct Cli {
+    /// TOML config file, `config.toml` is used if it exists
+    #[arg(long, short, global = true)]
+    pub config: Option<PathBuf>,
+    #[command(flatten)]
+    pub overrides: CliOverrides,
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+/// Flags taking precedence over the config file and env
+#[derive(Args)]
+pub struct CliOverrides {
+    #[arg(long, global = true)]
+    pub db_path: Option<String>,
+    #[arg(long, global = true)]
+    pub secondary_db_path: Option<String>,
+    #[arg(long, global = true)]
+    pub network: Option<String>,
+    #[arg(long, global = true)]
+    pub rpc_url: Option<String>,
+    #[arg(long, global = true)]
+    pub rpc_user: Option<String>,
+    #[arg(long, global = true)]
+    pub rpc_pass: Option<String>,
+    #[arg(long, global = true)]
+    pub rpc_cookie_file: Option<String>,
+    /// Can be repeated, replaces the fallbacks of the config file
+    #[arg(long, global = true)]
+    pub rpc_fallback_url: Vec<String>,
+    #[arg(long, global = true)]
+    pub bind: Option<String>,
+    #[arg(long, global = true)]
+    pub reorg_depth: Option<usize>,
+    #[arg(long, global = true)]
+    pub log_level: Option<String>,
+}
+
+#[derive(Subcommand, Clone)]
+pub enum Command {
+    /// Index blocks and serve the API, the default
+    Run,
+    /// Check the config, the db and that the db follows the chain of the node
+    Verify,
+    /// Revert the db to a height, the indexer must be stopped
+    Rollback {
+        #[arg(long)]
+        to_height: u32,
+    },
+    /// Replace the db with the checkpoint at a height, the indexer must be stopped
+    Restore {
+        #[arg(long)]
+        height: u32,
+    },
+    /// Write indexed data as JSON lines
+    Export {
+        #[arg(value_enum)]
+        data: ExportData,
+        /// Written to stdout if not set
+        #[arg(long, short)]
+        output: Option<PathBuf>,
+    },
+}
+
+#[derive(ValueEnum, Clone, Copy)]
+pub enum ExportData {
+    Balances,
+    Tokens,
+}
+
+#[serde_as]
+#[derive(Deserialize, Clone, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub db_path: String,
+    #[serde_as(as = "DisplayFromStr")]
+    pub network: Network,
+    /// JSON file overriding protocol activation rules of the network
+    pub protocol_rules: Option<String>,
+    pub rocksdb: RocksDbConfig,
+    pub rpc: RpcConfig,
+    pub server: ServerConfig,
+    pub secondary: SecondaryConfig,
+    pub indexer: IndexerConfig,
+    pub checkpoints: CheckpointsConfig,
+    pub channels: ChannelsConfig,
+    pub logging: LoggingConfig,
+    pub sinks: SinksConfig,
+}
+
+#[derive(Deserialize, Clone, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct RocksDbConfig {
+    /// Block cache shared by all tables
+    pub block_cache_mb: usize,
+}
+
+/// Primary node RPC endpoint with the request settings
+#[derive(Deserialize, Clone, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct RpcConfig {
+    pub url: String,
+    pub user: Option<String>,
+    pub pass: Option<String>,
+    /// Cookie file of the node, instead of `user` and `pass`
+    pub cookie_file: Option<String>,
+    /// Used in order when the primary endpoint fails
+    pub fallbacks: Vec<RpcEndpointConfig>,
+    pub timeout_ms: u64,
+    /// Requests sent at once in a JSON-RPC batch
+    pub batch_size: usize,
+    /// Delay before retrying when no endpoint replies, doubled up to `backoff_max_ms`
+    pub backoff_initial_ms: u64,
+    pub backoff_max_ms: u64,
+}
+
+/// Fallback endpoint without credentials uses the user and password of the primary one.
+/// The cookie file of the primary is written by its node, so it isn't used for fallbacks
+#[derive(Deserialize, Clone, Debug, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct RpcEndpointConfig {
+    pub url: String,
+    pub user: Option<String>,
+    pub pass: Option<String>,
+    pub cookie_file: Option<String>,
+}
+
+#[derive(Deserialize, Clone, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServerConfig {
+    pub bind: String,
+    /// Max blocks behind the node tip for `/health/ready`
+    pub ready_max_lag_blocks: u32,
+    /// Enables webhook management routes
+    pub webhook_admin_token: Option<String>,
+}
+
+/// Serve-only mode if `path` is set. The db at `db_path` is followed as a RocksDB secondary stored at `path`
+#[derive(Deserialize, Clone, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct SecondaryConfig {
+    pub path: Option<String>,
+    pub catch_up_interval_ms: u64,
+}
+
+#[derive(Deserialize, Clone, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct IndexerConfig {
+    /// Count of last blocks that can be rolled back on a reorg
+    pub reorg_depth: usize,
+    /// Diagnostic dumps of blocks that failed to index are written here
+    pub fault_dir: String,
+    /// Count of last events kept in the event log, all if 0
+    pub event_log_retention: u64,
+}
+
+/// RocksDB checkpoints, restored on reorgs deeper than `indexer.reorg_depth`
+#[derive(Deserialize, Clone, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct CheckpointsConfig {
+    pub dir: String,
+    /// Created at heights divisible by it, disabled if 0
+    pub interval_blocks: u32,
+    /// Count of last checkpoints kept
+    pub keep: usize,
+}
+
+#[derive(Deserialize, Clone, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct ChannelsConfig {
+    /// Capacity of the event broadcast, lagging receivers replay events from the event log
+    pub events: usize,
+    /// Capacity of a subscriber queue. Subscribers that fall behind are caught up from the event log
+    pub subscriber_queue: usize,
+}
+
+#[derive(Deserialize, Clone, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct LoggingConfig {
+    /// `tracing` filter directives, overridden by `RUST_LOG`
+    pub level: String,
+    pub format: LogFormat,
+    pub ansi: bool,
+}
+
+#[derive(Deserialize, Clone, Copy, Debug, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    #[default]
+    Pretty,
+    Compact,
+}
+
+/// Event sinks are disabled if unset
+#[derive(Deserialize, Clone, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct SinksConfig {
+    pub jsonl_dir: Option<String>,
+    pub jsonl_blocks_per_file: u32,
+    pub sqlite_path: Option<String>,
+    pub redis_url: Option<String>,
+    pub redis_topic_prefix: String,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            db_path: "rocksdb".to_string(),
+            network: Network::Bellscoin,
+            protocol_rules: None,
+            rocksdb: Default::default(),
+            rpc: Default::default(),
+            server: Default::default(),
+            secondary: Default::default(),
+            indexer: Default::default(),
+            checkpoints: Default::default(),
+            channels: Default::default(),
+            logging: Default::default(),
+            sinks: Default::default(),
+        }
+    }
+}
+
+impl Default for RocksDbConfig {
+    fn default() -> Self {
+        Self {
+            block_cache_mb: 256,
+        }
+    }
+}
+
+impl Default for RpcConfig {
+    fn default() -> Self {
+        Self {
+            url: String::new(),
+            user: None,
+            pass: None,
+            cookie_file: None,
+            fallbacks: vec![],
+            timeout_ms: 30_000,
+            batch_size: 100,
+            backoff_initial_ms: 500,
+            backoff_max_ms: 30_000,
+        }
+    }
+}
+
+impl RpcEndpointConfig {
+    fn auth(&self) -> Option<RpcAuth> {
+        match (&self.user, &self.cookie_file) {
+            (Some(user), _) => Some(RpcAuth::UserPass(user.clone(), self.pass.clone())),
+            (None, Some(path)) => Some(RpcAuth::Cookie(path.into())),
+            (None, None) => None,
+        }
+    }
+}
+
+impl RpcConfig {
+    fn primary(&self) -> RpcEndpointConfig {
+        RpcEndpointConfig {
+            url: self.url.clone(),
+            user: self.user.clone(),
+            pass: self.pass.clone(),
+            cookie_file: self.cookie_file.clone(),
+        }
+    }
+
+    /// Primary endpoint first, then fallbacks
+    pub fn endpoints(&self) -> Vec<RpcEndpoint> {
+        let primary = self.primary();
+        let shared_auth = match &self.user {
+            Some(user) => RpcAuth::UserPass(user.clone(), self.pass.clone()),
+            None => RpcAuth::None,
+        };
+
+        [RpcEndpoint {
+            url: primary.url.clone(),
+            auth: primary.auth().unwrap_or(RpcAuth::None),
+        }]
+        .into_iter()
+        .chain(self.fallbacks.iter().map(|x| RpcEndpoint {
+            url: x.url.clone(),
+            auth: x.auth().unwrap_or(shared_auth.clone()),
+        }))
+        .collect()
+    }
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        Self {
+            bind: "0.0.0.0:8000".to_string(),
+            ready_max_lag_blocks: 3,
+            webhook_admin_token: None,
+        }
+    }
+}
+
+impl Default for SecondaryConfig {
+    fn default() -> Self {
+        Self {
+            path: None,
+            catch_up_interval_ms: 1000,
+        }
+    }
+}
+
+impl Default for IndexerConfig {
+    fn default() -> Self {
+        Self {
+            reorg_depth: 30,
+            fault_dir: "faults".to_string(),
+            event_log_retention: 1_000_000,
+        }
+    }
+}
+
+impl Default for CheckpointsConfig {
+    fn default() -> Self {
+        Self {
+            dir: "checkpoints".to_string(),
+            interval_blocks: 100,
+            keep: 3,
+        }
+    }
+}
+
+impl Default for ChannelsConfig {
+    fn default() -> Self {
+        Self {
+            events: 30_000,
+            subscriber_queue: 1024,
+        }
+    }
+}
+
+impl Default for LoggingConfig {
+    fn default() -> Self {
+        Self {
+            level: "debug".to_string(),
+            format: LogFormat::Pretty,
+            ansi: true,
+        }
+    }
+}
+
+impl Default for SinksConfig {
+    fn default() -> Self {
+        Self {
+            jsonl_dir: None,
+            jsonl_blocks_per_file: 1000,
+            sqlite_path: None,
+            redis_url: None,
+            redis_topic_prefix: "bel20".to_string(),
+        }
+    }
+}
+
+/// Parsed value of the env variable, `None` if it's unset or empty
+fn env<T>(name: &str) -> anyhow::Result<Option<T>>
+where
+    T: FromStr,
+    T::Err: Display,
+{
+    match std::env::var(name) {
+        Ok(v) if !v.is_empty() => v
+            .parse()
+            .map(Some)
+            .map_err(|e| anyhow::anyhow!("Invalid {name}: {e}")),
+        _ => Ok(None),
+    }
+}
+
+/// Fallback endpoints from comma separated URLs
+fn fallbacks(urls: &str) -> Vec<RpcEndpointConfig> {
+    urls.split(',')
+        .map(str::trim)
+        .filter(|x| !x.is_empty())
+        .map(|url| RpcEndpointConfig {
+            url: url.to_string(),
+            ..Default::default()
+        })
+        .collect()
+}
+
+/// Overrides the field if the value is set, `Some` marks optional fields
+macro_rules! set_from {
+    ($field:expr, Some $value:expr) => {
+        if let Some(v) = $value {
+            $field = Some(v);
+        }
+    };
+    ($field:expr, $value:expr) => {
+        if let Some(v) = $value {
+            $field = v;
+        }
+    };
+}
+
+impl Config {
+    /// Defaults, then the config file, then env, then CLI flags
+    pub fn load(cli: &Cli) -> anyhow::Result<Self> {
+        let path = match &cli.config {
+            Some(path) => Some(path.clone()),
+            None => Some(PathBuf::from(DEFAULT_CONFIG_PATH)).filter(|x| x.exists()),
+        };
+
+        let mut config = match path {
+            Some(path) => {
+                let data = std::fs::read_to_string(&path)
+                    .anyhow_with(format!("Failed to read config from {}", path.display()))?;
+                toml::from_str(&data)
+                    .anyhow_with(format!("Invalid config file {}", path.display()))?
+            }
+            None => Self::default(),
+        };
+
+        config.apply_env()?;
+        config.apply_cli(&cli.overrides)?;
+
+        Ok(config)
+    }
+
+    fn apply_env(&mut self) -> anyhow::Result<()> {
+        set_from!(self.db_path, env("DB_PATH")?);
+        set_from!(self.network, env("NETWORK")?);
+        set_from!(self.protocol_rules, Some env("PROTOCOL_RULES")?);
+        set_from!(self.rocksdb.block_cache_mb, env("ROCKSDB_BLOCK_CACHE_MB")?);
+        set_from!(self.rpc.url, env("RPC_URL")?);
+        set_from!(self.rpc.user, Some env("RPC_USER")?);
+        set_from!(self.rpc.pass, Some env("RPC_PASS")?);
+        set_from!(self.rpc.cookie_file, Some env("RPC_COOKIE_FILE")?);
+        set_from!(
+            self.rpc.fallbacks,
+            env::<String>("RPC_FALLBACK_URLS")?.map(|x| fallbacks(&x))
+        );
+        set_from!(self.rpc.timeout_ms, env("RPC_TIMEOUT_MS")?);
+        set_from!(self.rpc.batch_size, env("RPC_BATCH_SIZE")?);
+        set_from!(self.server.bind, env("SERVER_BIND_URL")?);
+        set_from!(
+            self.server.ready_max_lag_blocks,
+            env("READY_MAX_LAG_BLOCKS")?
+        );
+        set_from!(
+            self.server.webhook_admin_token,
+            Some env("WEBHOOK_ADMIN_TOKEN")?
+        );
+        set_from!(self.secondary.path, Some env("SECONDARY_DB_PATH")?);
+        set_from!(
+            self.secondary.catch_up_interval_ms,
+            env("SECONDARY_CATCH_UP_INTERVAL_MS")?
+        );
+        set_from!(self.indexer.reorg_depth, env("REORG_DEPTH")?);
+        set_from!(self.indexer.fault_dir, env("FAULT_DIR")?);
+        set_from!(
+            self.indexer.event_log_retention,
+            env("EVENT_LOG_RETENTION")?
+        );
+        set_from!(self.checkpoints.dir, env("CHECKPOINT_DIR")?);
+        set_from!(
+            self.checkpoints.interval_blocks,
+            env("CHECKPOINT_INTERVAL_BLOCKS")?
+        );
+        set_from!(self.checkpoints.keep, env("CHECKPOINT_KEEP")?);
+        set_from!(self.channels.events, env("EVENT_CHANNEL_SIZE")?);
+        set_from!(self.channels.subscriber_queue, env("SUBSCRIBER_QUEUE_LEN")?);
+        set_from!(self.logging.level, env("LOG_LEVEL")?);
+        set_from!(self.logging.format, env("LOG_FORMAT")?);
+        set_from!(self.sinks.jsonl_dir, Some env("SINK_JSONL_DIR")?);
+        set_from!(
+            self.sinks.jsonl_blocks_per_file,
+            env("SINK_JSONL_BLOCKS_PER_FILE")?
+        );
+        set_from!(self.sinks.sqlite_path, Some env("SINK_SQLITE_PATH")?);
+        set_from!(self.sinks.redis_url, Some env("SINK_REDIS_URL")?);
+        set_from!(
+            self.sinks.redis_topic_prefix,
+            env("SINK_REDIS_TOPIC_PREFIX")?
+        );
+
+        Ok(())
+    }
+
+    fn apply_cli(&mut self, cli: &CliOverrides) -> anyhow::Result<()> {
+        let network = cli
+            .network
+            .as_deref()
+            .map(Network::from_str)
+            .transpose()
+            .anyhow_with("Invalid --network")?;
+
+        set_from!(self.db_path, cli.db_path.clone());
+        set_from!(self.secondary.path, Some cli.secondary_db_path.clone());
+        set_from!(self.network, network);
+        set_from!(self.rpc.url, cli.rpc_url.clone());
+        set_from!(self.rpc.user, Some cli.rpc_user.clone());
+        set_from!(self.rpc.pass, Some cli.rpc_pass.clone());
+        set_from!(self.rpc.cookie_file, Some cli.rpc_cookie_file.clone());
+        if !cli.rpc_fallback_url.is_empty() {
+            self.rpc.fallbacks = fallbacks(&cli.rpc_fallback_url.join(","));
+        }
+        set_from!(self.server.bind, cli.bind.clone());
+        set_from!(self.indexer.reorg_depth, cli.reorg_depth);
+        set_from!(self.logging.level, cli.log_level.clone());
+
+        Ok(())
+    }
+
+    /// All problems of the config at once. `needs_node` is unset for commands reading only the db
+    pub fn validate(&self, needs_node: bool) -> anyhow::Result<()> {
+        let mut errors = vec![];
+
+        if self.db_path.is_empty() {
+            errors.push("db_path is empty".to_string());
+        }
+
+        if self.rocksdb.block_cache_mb == 0 {
+            errors.push("rocksdb.block_cache_mb must be positive".to_string());
+        }
+
+        if needs_node {
+            if self.rpc.url.is_empty() {
+                errors.push("rpc.url is not set".to_string());
+            }
+
+            for endpoint in [self.rpc.primary()].iter().chain(&self.rpc.fallbacks) {
+                if !endpoint.url.is_empty()
+                    && !endpoint.url.starts_with("http://")
+                    && !endpoint.url.starts_with("https://")
+                {
+                    errors.push(format!("RPC URL {} is not an HTTP URL", endpoint.url));
+                }
+                if endpoint.user.is_some() && endpoint.cookie_file.is_some() {
+                    errors.push(format!(
+                        "RPC endpoint {} has both a user and a cookie file",
+                        endpoint.url
+                    ));
+                }
+            }
+
+            if self.rpc.user.is_none() && self.rpc.cookie_file.is_some() {
+                for endpoint in &self.rpc.fallbacks {
+                    if endpoint.auth().is_none() {
+                        errors.push(format!(
+                            "RPC fallback {} has no credentials, the cookie file of rpc.url is only valid for its node",
+                            endpoint.url
+                        ));
+                    }
+                }
+            }
+
+            if self.rpc.timeout_ms == 0 {
+                errors.push("rpc.timeout_ms must be positive".to_string());
+            }
+            if self.rpc.batch_size == 0 {
+                errors.push("rpc.batch_size must be positive".to_string());
+            }
+            if self.rpc.backoff_initial_ms == 0
+                || self.rpc.backoff_initial_ms > self.rpc.backoff_max_ms
+            {
+                errors.push(
+                    "rpc.backoff_initial_ms must be positive and not above rpc.backoff_max_ms"
+                        .to_string(),
+                );
+            }
+        }
+
+        if let Err(e) = protocol::ProtocolRules::load(self.network, self.protocol_rules.as_deref())
+        {
+            errors.push(format!("{e:#}"));
+        }
+
+        if let Err(e) = self.server.bind.to_socket_addrs() {
+            errors.push(format!("server.bind {}: {e}", self.server.bind));
+        }
+
+        if let Some(path) = &self.secondary.path {
+            if *path == self.db_path {
+                errors.push("secondary.path must differ from db_path".to_string());
+            }
+        }
+
+        if self.secondary.catch_up_interval_ms == 0 {
+            errors.push("secondary.catch_up_interval_ms must be positive".to_string());
+        }
+
+        if self.indexer.reorg_depth == 0 {
+            errors.push("indexer.reorg_depth must be positive".to_string());
+        }
+        if self.indexer.fault_dir.is_empty() {
+            errors.push("indexer.fault_dir must be set".to_string());
+        }
+
+        if self.checkpoints.dir.is_empty() || self.checkpoints.dir == self.db_path {
+            errors.push("checkpoints.dir must be set and differ from db_path".to_string());
+        }
+        if self.checkpoints.keep == 0 {
+            errors.push("checkpoints.keep must be positive".to_string());
+        }
+
+        if self.channels.events == 0 || self.channels.subscriber_queue == 0 {
+            errors.push("channel sizes must be positive".to_string());
+        }
+
+        if let Err(e) = tracing_subscriber::EnvFilter::try_new(&self.logging.level) {
+            errors.push(format!("logging.level {}: {e}", self.logging.level));
+        }
+
+        if self.sinks.jsonl_blocks_per_file == 0 {
+            errors.push("sinks.jsonl_blocks_per_file must be positive".to_string());
+        }
+
+        if !errors.is_empty() {
+            anyhow::bail!("{}", errors.join("; "));
+        }
+
+        Ok(())
+    }
+
+    /// Makes the config available as `CONFIG`
+    pub fn install(self) {
+        if LOADED.set(self).is_err() {
+            panic!("Config is already loaded");
+        }
+    }
+
+    pub fn get() -> &'static Self {
+        LOADED.get().expect("Config is not loaded")
+    }
+
+    /// Defaults for tests, installed by the first test that needs them
+    #[cfg(test)]
+    pub fn install_default() {
+        LOADED.get_or_init(Self::default);
+    }
+}
+
+impl FromStr for LogFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "pretty" => Ok(Self::Pretty),
+            "compact" => Ok(Self::Compact),
+            _ => Err(format!(
+                "unknown log format {s}, expected pretty or compact"
+            )),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn fallback(url: &str) -> RpcEndpointConfig {
+        RpcEndpointConfig {
+            url: url.to_string(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn fallbacks_use_user_of_primary() {
+        let rpc = RpcConfig {
+            url: "http://primary:19918".to_string(),
+            user: Some("user".to_string()),
+            pass: Some("pass".to_string()),
+            fallbacks: vec![fallback("http://fallback:19918")],
+            ..Default::default()
+        };
+
+        let endpoints = rpc.endpoints();
+        assert!(matches!(
+            &endpoints[1].auth,
+            RpcAuth::UserPass(user, Some(pass)) if user == "user" && pass == "pass"
+        ));
+    }
+
+    #[test]
+    fn fallbacks_need_credentials_with_cookie_file() {
+        let mut config = Config {
+            rpc: RpcConfig {
+                url: "http://primary:19918".to_string(),
+                cookie_file: Some("/node/.cookie".to_string()),
+                fallbacks: vec![fallback("http://fallback:19918")],
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+
+        let endpoints = config.rpc.endpoints();
+        assert!(matches!(endpoints[0].auth, RpcAuth::Cookie(_)));
+        assert!(matches!(endpoints[1].auth, RpcAuth::None));
+        let error = config.validate(true).unwrap_err().to_string();
+        assert!(error.contains("RPC fallback http://fallback:19918 has no credentials"));
+
+        config.rpc.fallbacks[0].cookie_file = Some("/fallback/.cookie".to_string());
+        config.validate(true).unwrap();
+    }
+}
@@ -3,6 +3,7 @@ use super::*;
 pub trait RocksDbTablesDef: Sized {
     const TABLES: &[&str];
     const VERSION: usize;
+    const BLOCK_CACHE_MB: usize = 256;
 
     fn table_info(&self, cf: &str) -> TableInfo;
     fn make_tables(db: RocksDB) -> Self;
@@ -10,18 +11,20 @@ pub trait RocksDbTablesDef: Sized {
     fn open(path: &str) -> anyhow::Result<Self> {
         let db = RocksDB::open_db(
             path,
+            Self::BLOCK_CACHE_MB,
             [
                 &[internal::TABLE_INFO_CF, internal::DB_INFO_CF],
                 Self::TABLES,
             ]
             .into_iter()
-            .flatten(),
+            .flatten()
+            .map(|x| (x.to_string(), TableOptions::new())),
         );
 
         let mut tables = Self::make_tables(db.clone());
 
-        let db_info = db.table::<(), UsingSerde<DbInfo>>(internal::DB_INFO_CF);
-        if let Some(db_info) = db_info.get(()) {
+        let db_info = db.table::<(), UsingSerde<DbInfo>>(internal::DB_INFO_CF, TableOptions::new());
+        if let Some(db_info) = db_info.get(())? {
             if db_info.version > Self::VERSION {
                 bail!(
                     "Version of DB '{}' is not supported: {}",
@@ -45,7 +48,7 @@ pub trait RocksDbTablesDef: Sized {
             DbInfo {
                 version: Self::VERSION,
             },
-        );
+        )?;
 
         Ok(tables)
     }
@@ -0,0 +1,174 @@
+use std::collections::BTreeMap;
+
+use super::*;
+
+/// Writes copy the map only while a snapshot or a range still holds it
+type Entries = Arc<parking_lot::RwLock<Arc<BTreeMap<Vec<u8>, Vec<u8>>>>>;
+
+/// Tables kept in BTreeMaps, ordered by the key bytes like RocksDB column families
+#[derive(Clone, Default)]
+pub struct MemoryDB {
+    tables: Arc<parking_lot::Mutex<HashMap<String, Entries>>>,
+}
+
+impl MemoryDB {
+    pub fn table<K: Pebble, V: Pebble>(&self, cf: impl ToString) -> MemoryTable<K, V> {
+        let cf = cf.to_string();
+        let entries = self.tables.lock().entry(cf.clone()).or_default().clone();
+
+        MemoryTable {
+            entries,
+            cf,
+            __marker: PhantomData,
+        }
+    }
+
+    /// Applies the batch while holding all of its tables, so snapshots see all of its changes or none
+    pub fn write(&self, batch: DbBatch) -> anyhow::Result<()> {
+        let mut tables = self.tables.lock();
+
+        let batch_tables = batch
+            .ops
+            .iter()
+            .map(|(cf, ..)| cf)
+            .unique()
+            .map(|cf| (cf.clone(), tables.entry(cf.clone()).or_default().clone()))
+            .collect::<HashMap<_, _>>();
+        let mut locked = batch_tables
+            .iter()
+            .map(|(cf, entries)| (cf.as_str(), entries.write()))
+            .collect::<HashMap<_, _>>();
+
+        for (cf, k, v) in batch.ops {
+            let entries = Arc::make_mut(locked.get_mut(cf.as_str()).anyhow()?);
+            match v {
+                Some(v) => entries.insert(k, v),
+                None => entries.remove(&k),
+            };
+        }
+
+        Ok(())
+    }
+
+    /// Shares the maps of all tables, later writes to either db are not seen by the other
+    pub fn snapshot(&self) -> Self {
+        let tables = self
+            .tables
+            .lock()
+            .iter()
+            .map(|(cf, entries)| {
+                let entries = entries.read().clone();
+                (cf.clone(), parking_lot::RwLock::new(entries).arc())
+            })
+            .collect();
+
+        Self {
+            tables: parking_lot::Mutex::new(tables).arc(),
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct MemoryTable<K: Pebble, V: Pebble> {
+    entries: Entries,
+    pub(super) cf: String,
+    __marker: PhantomData<(K, V)>,
+}
+
+impl<K: Pebble, V: Pebble> MemoryTable<K, V> {
+    /// Bytes of the keys and values
+    pub fn size(&self) -> u64 {
+        self.entries
+            .read()
+            .iter()
+            .map(|(k, v)| (k.len() + v.len()) as u64)
+            .sum()
+    }
+}
+
+impl<K: Pebble, V: Pebble> Table<K, V> for MemoryTable<K, V> {
+    fn get(&self, k: impl Borrow<K::Inner>) -> anyhow::Result<Option<V::Inner>> {
+        self.entries
+            .read()
+            .get(K::get_bytes(k.borrow()).as_ref())
+            .map(|x| decode_value::<V>(&self.cf, Cow::Borrowed(x)))
+            .transpose()
+    }
+
+    fn multi_get<'a>(
+        &'a self,
+        keys: impl IntoIterator<Item = &'a K::Inner>,
+    ) -> anyhow::Result<Vec<Option<V::Inner>>>
+    where
+        K::Inner: 'a,
+    {
+        keys.into_iter().map(|x| self.get(x)).collect()
+    }
+
+    fn iter(&self) -> impl Iterator<Item = anyhow::Result<(K::Inner, V::Inner)>> + '_ {
+        self.range(.., false)
+    }
+
+    fn range<'a>(
+        &'a self,
+        range: impl RangeBounds<&'a K::Inner>,
+        reversed: bool,
+    ) -> TableRange<'a, K, V>
+    where
+        K::Inner: 'a,
+    {
+        let bound = |x: Bound<&&K::Inner>| match x {
+            Bound::Included(x) => Bound::Included(K::get_bytes(x).into_owned()),
+            Bound::Excluded(x) => Bound::Excluded(K::get_bytes(x).into_owned()),
+            Bound::Unbounded => Bound::Unbounded,
+        };
+        let (mut start, mut end) = (bound(range.start_bound()), bound(range.end_bound()));
+
+        // BTreeMap::range panics on these, RocksDB reads nothing
+        let empty = match (&start, &end) {
+            (Bound::Excluded(s), Bound::Excluded(e)) => s >= e,
+            (Bound::Included(s) | Bound::Excluded(s), Bound::Included(e) | Bound::Excluded(e)) => {
+                s > e
+            }
+            _ => false,
+        };
+        if empty {
+            return Box::new(std::iter::empty());
+        }
+
+        // Walks the map as it was at the call, writes made meanwhile copy it
+        let entries = self.entries.read().clone();
+
+        Box::new(std::iter::from_fn(move || {
+            let mut range = entries.range::<[u8], _>((
+                start.as_ref().map(Vec::as_slice),
+                end.as_ref().map(Vec::as_slice),
+            ));
+            let (k, v) = if reversed {
+                range.next_back()?
+            } else {
+                range.next()?
+            };
+
+            let item = decode::<K, V>(&self.cf, Cow::Borrowed(k), Cow::Borrowed(v));
+            if reversed {
+                end = Bound::Excluded(k.clone());
+            } else {
+                start = Bound::Excluded(k.clone());
+            }
+            Some(item)
+        }))
+    }
+
+    fn write(&self, batch: TableBatch<K, V>) -> anyhow::Result<()> {
+        let mut entries = self.entries.write();
+        let entries = Arc::make_mut(&mut entries);
+        for (k, v) in batch.ops {
+            match v {
+                Some(v) => entries.insert(k, v),
+                None => entries.remove(&k),
+            };
+        }
+        Ok(())
+    }
+}
@@ -5,14 +5,21 @@ use super::*;
 mod definition;
 mod internal;
 mod item;
+mod memory;
+mod options;
 mod storage;
+mod table;
 mod utils;
 
 pub use item::{Pebble, UsingConsensus, UsingSerde};
+pub use memory::{MemoryDB, MemoryTable};
+pub use options::TableOptions;
 pub use storage::{RocksDB, RocksTable};
+pub use table::{DbBatch, DbTable, Storage, Table, TableBatch};
 
 use anyhow::bail;
 use rocksdb::WriteBatchWithTransaction;
+use table::{decode, decode_value, TableRange};
 use utils::RcUtils;
 
 use internal::{DbInfo, TableInfo};
@@ -0,0 +1,120 @@
+use rocksdb::{BlockBasedOptions, Cache, DBCompressionType, SliceTransform};
+
+/// RocksDB defaults of the write stall triggers, restored after bulk loading
+const L0_SLOWDOWN_WRITES_TRIGGER: u32 = 20;
+const L0_STOP_WRITES_TRIGGER: u32 = 36;
+/// Memtables are this many times larger while bulk loading
+const BULK_LOAD_WRITE_BUFFER_FACTOR: usize = 4;
+
+/// Column family settings, declared next to the table in `generate_db_code!`
+#[derive(Clone, Copy, Debug)]
+pub struct TableOptions {
+    /// Length of the key prefix shared by range reads, enables prefix bloom filters
+    pub prefix_len: Option<usize>,
+    /// Bloom filter of whole keys for point lookups
+    pub bloom: bool,
+    pub compression: DBCompressionType,
+    pub write_buffer_mb: usize,
+}
+
+impl Default for TableOptions {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl TableOptions {
+    pub const fn new() -> Self {
+        Self {
+            prefix_len: None,
+            bloom: false,
+            compression: DBCompressionType::Lz4,
+            write_buffer_mb: 64,
+        }
+    }
+
+    /// Read by whole keys
+    pub const fn point_lookups() -> Self {
+        Self {
+            bloom: true,
+            ..Self::new()
+        }
+    }
+
+    /// Read by whole keys and in ranges of keys sharing the first `len` bytes
+    pub const fn prefix(len: usize) -> Self {
+        Self {
+            prefix_len: Some(len),
+            bloom: true,
+            ..Self::new()
+        }
+    }
+
+    pub const fn compression(self, compression: DBCompressionType) -> Self {
+        Self {
+            compression,
+            ..self
+        }
+    }
+
+    pub const fn write_buffer_mb(self, write_buffer_mb: usize) -> Self {
+        Self {
+            write_buffer_mb,
+            ..self
+        }
+    }
+
+    /// Set if the keys are in one prefix, so the range can be read in the prefix seek mode
+    pub(super) fn is_prefix_range(&self, start: Option<&[u8]>, end: Option<&[u8]>) -> bool {
+        match (self.prefix_len, start, end) {
+            (Some(len), Some(start), Some(end)) => {
+                start.len() >= len && end.len() >= len && start[..len] == end[..len]
+            }
+            _ => false,
+        }
+    }
+
+    /// Blocks of all tables share `cache`
+    pub(super) fn cf_options(&self, cache: &Cache) -> rocksdb::Options {
+        let mut block = BlockBasedOptions::default();
+        block.set_block_cache(cache);
+        block.set_cache_index_and_filter_blocks(true);
+        if self.bloom {
+            block.set_bloom_filter(10.0, false);
+        }
+
+        let mut opts = rocksdb::Options::default();
+        opts.set_block_based_table_factory(&block);
+        opts.set_compression_type(self.compression);
+        opts.set_write_buffer_size(self.write_buffer_mb << 20);
+        if let Some(len) = self.prefix_len {
+            opts.set_prefix_extractor(SliceTransform::create_fixed_prefix(len));
+            opts.set_memtable_prefix_bloom_ratio(0.1);
+        }
+
+        opts
+    }
+
+    /// Larger memtables and no write stalls while many blocks are written at once
+    pub(super) fn bulk_load(&self, enabled: bool) -> [(&'static str, String); 3] {
+        let (write_buffer_mb, slowdown, stop) = if enabled {
+            (
+                self.write_buffer_mb * BULK_LOAD_WRITE_BUFFER_FACTOR,
+                L0_SLOWDOWN_WRITES_TRIGGER * 4,
+                L0_STOP_WRITES_TRIGGER * 4,
+            )
+        } else {
+            (
+                self.write_buffer_mb,
+                L0_SLOWDOWN_WRITES_TRIGGER,
+                L0_STOP_WRITES_TRIGGER,
+            )
+        };
+
+        [
+            ("write_buffer_size", (write_buffer_mb << 20).to_string()),
+            ("level0_slowdown_writes_trigger", slowdown.to_string()),
+            ("level0_stop_writes_trigger", stop.to_string()),
+        ]
+    }
+}
@@ -2,27 +2,203 @@ use std::cmp::Ordering;
 
 use super::*;
 
+pub enum RocksInstance {
+    Primary(rocksdb::OptimisticTransactionDB),
+    /// Read only instance following the primary. Catches up with `try_catch_up_with_primary`
+    Secondary(rocksdb::DB),
+}
+
+type RawEntry = (Box<[u8]>, Box<[u8]>);
+
+/// Runs the expression with the database of any instance
+macro_rules! with_instance {
+    ($instance:expr, $db:ident => $e:expr) => {
+        match &*$instance {
+            RocksInstance::Primary($db) => $e,
+            RocksInstance::Secondary($db) => $e,
+        }
+    };
+}
+
+/// Point-in-time view of an instance
+pub struct DbSnapshot {
+    // Declared before `_db` so it's dropped first
+    snapshot: SnapshotInstance,
+    _db: Arc<RocksInstance>,
+}
+
+enum SnapshotInstance {
+    Primary(rocksdb::SnapshotWithThreadMode<'static, rocksdb::OptimisticTransactionDB>),
+    Secondary(rocksdb::SnapshotWithThreadMode<'static, rocksdb::DB>),
+}
+
+impl DbSnapshot {
+    fn new(db: Arc<RocksInstance>) -> Self {
+        // SAFETY: the snapshot borrows the instance, which is kept alive by `_db` and dropped after the snapshot
+        let snapshot = unsafe {
+            match &*db {
+                RocksInstance::Primary(x) => {
+                    SnapshotInstance::Primary(std::mem::transmute::<
+                        rocksdb::SnapshotWithThreadMode<'_, rocksdb::OptimisticTransactionDB>,
+                        rocksdb::SnapshotWithThreadMode<'static, rocksdb::OptimisticTransactionDB>,
+                    >(x.snapshot()))
+                }
+                RocksInstance::Secondary(x) => {
+                    SnapshotInstance::Secondary(std::mem::transmute::<
+                        rocksdb::SnapshotWithThreadMode<'_, rocksdb::DB>,
+                        rocksdb::SnapshotWithThreadMode<'static, rocksdb::DB>,
+                    >(x.snapshot()))
+                }
+            }
+        };
+
+        Self { snapshot, _db: db }
+    }
+}
+
 #[derive(Clone)]
 pub struct RocksDB {
-    pub db: Arc<rocksdb::OptimisticTransactionDB>,
+    pub db: Arc<RocksInstance>,
+    /// Reads see the db at the moment of the snapshot if set
+    snapshot: Option<Arc<DbSnapshot>>,
+}
+
+/// Column families with their options, sharing a block cache of `block_cache_mb`
+fn descriptors(
+    tables: impl IntoIterator<Item = (String, TableOptions)>,
+    block_cache_mb: usize,
+) -> Vec<rocksdb::ColumnFamilyDescriptor> {
+    let cache = rocksdb::Cache::new_lru_cache(block_cache_mb << 20);
+
+    tables
+        .into_iter()
+        .map(|(name, options)| {
+            rocksdb::ColumnFamilyDescriptor::new(name, options.cf_options(&cache))
+        })
+        .collect()
 }
 
 impl RocksDB {
-    pub fn open_db(path: &str, tables: impl IntoIterator<Item = impl AsRef<str>>) -> Self {
+    pub fn open_db(
+        path: &str,
+        block_cache_mb: usize,
+        tables: impl IntoIterator<Item = (String, TableOptions)>,
+    ) -> Self {
         let mut opts = rocksdb::Options::default();
         opts.create_if_missing(true);
         opts.create_missing_column_families(true);
 
-        let db = rocksdb::OptimisticTransactionDB::open_cf(&opts, path, tables)
-            .unwrap()
-            .arc();
-        Self { db }
+        let db = rocksdb::OptimisticTransactionDB::open_cf_descriptors(
+            &opts,
+            path,
+            descriptors(tables, block_cache_mb),
+        )
+        .unwrap();
+        Self {
+            db: RocksInstance::Primary(db).arc(),
+            snapshot: None,
+        }
     }
 
-    pub fn table<K: Pebble, V: Pebble>(&self, cf: impl ToString) -> RocksTable<K, V> {
+    pub fn open_secondary(
+        primary_path: &str,
+        secondary_path: &str,
+        block_cache_mb: usize,
+        tables: impl IntoIterator<Item = (String, TableOptions)>,
+    ) -> Self {
+        let mut opts = rocksdb::Options::default();
+        // Required by secondary instances to see all files of the primary
+        opts.set_max_open_files(-1);
+
+        let db = rocksdb::DB::open_cf_descriptors_as_secondary(
+            &opts,
+            primary_path,
+            secondary_path,
+            descriptors(tables, block_cache_mb),
+        )
+        .unwrap();
+        Self {
+            db: RocksInstance::Secondary(db).arc(),
+            snapshot: None,
+        }
+    }
+
+    pub fn is_secondary(&self) -> bool {
+        matches!(&*self.db, RocksInstance::Secondary(_))
+    }
+
+    /// Applies changes of the primary to a secondary instance
+    pub fn try_catch_up_with_primary(&self) -> anyhow::Result<()> {
+        if let RocksInstance::Secondary(db) = &*self.db {
+            db.try_catch_up_with_primary()?;
+        }
+        Ok(())
+    }
+
+    /// Hard-linked copy of the db at `path`, which must not exist. Memtables are flushed first
+    pub fn create_checkpoint(&self, path: &std::path::Path) -> anyhow::Result<()> {
+        let RocksInstance::Primary(db) = &*self.db else {
+            anyhow::bail!("Checkpoints are created from the primary instance");
+        };
+
+        rocksdb::checkpoint::Checkpoint::new(db)?.create_checkpoint(path)?;
+        Ok(())
+    }
+
+    /// Same db with reads pinned to the current state
+    pub fn snapshot(&self) -> Self {
+        Self {
+            db: self.db.clone(),
+            snapshot: Some(DbSnapshot::new(self.db.clone()).arc()),
+        }
+    }
+
+    fn read_opts(&self) -> rocksdb::ReadOptions {
+        let mut opts = rocksdb::ReadOptions::default();
+        match self.snapshot.as_ref().map(|x| &x.snapshot) {
+            Some(SnapshotInstance::Primary(x)) => opts.set_snapshot(x),
+            Some(SnapshotInstance::Secondary(x)) => opts.set_snapshot(x),
+            None => {}
+        }
+        opts
+    }
+
+    /// Applies the batch in one write, so readers see all of its changes or none
+    pub fn write(&self, batch: DbBatch) -> anyhow::Result<()> {
+        let RocksInstance::Primary(db) = &*self.db else {
+            bail!("Failed to write the batch: read only instance");
+        };
+
+        let mut cfs = HashMap::new();
+        let mut w = WriteBatchWithTransaction::<true>::default();
+        for (cf, k, v) in batch.ops {
+            let handle = match cfs.entry(cf) {
+                std::collections::hash_map::Entry::Occupied(e) => e.into_mut(),
+                std::collections::hash_map::Entry::Vacant(e) => {
+                    let handle = db
+                        .cf_handle(e.key())
+                        .anyhow_with(format!("Missing column family '{}'", e.key()))?;
+                    e.insert(handle)
+                }
+            };
+            match v {
+                Some(v) => w.put_cf(handle, k, v),
+                None => w.delete_cf(handle, k),
+            }
+        }
+
+        db.write(w).anyhow_with("Failed to write the batch")
+    }
+
+    pub fn table<K: Pebble, V: Pebble>(
+        &self,
+        cf: impl ToString,
+        options: TableOptions,
+    ) -> RocksTable<K, V> {
         RocksTable {
             db: self.clone(),
             cf: cf.to_string(),
+            options,
             __marker: PhantomData,
         }
     }
@@ -32,20 +208,16 @@ impl RocksDB {
 pub struct RocksTable<K: Pebble, V: Pebble> {
     pub db: RocksDB,
     pub cf: String, // cf_handle() is just BTReeMap::get + RwLock::read + Arc::clone. Let's not fuck with lifetimes and pretend it's fine
+    pub options: TableOptions,
     __marker: PhantomData<(K, V)>,
 }
 
-#[track_caller]
-#[inline]
-fn _panic(ident: &str, cf: &str, e: anyhow::Error) -> ! {
-    panic!("Rocks {ident} '{cf}': {e:?}; bytes")
-}
-
 impl<K: Pebble, V: Pebble> RocksTable<K, V> {
-    pub fn new(db: RocksDB, cf: String) -> Self {
+    pub fn new(db: RocksDB, cf: String, options: TableOptions) -> Self {
         Self {
             db,
             cf,
+            options,
             __marker: PhantomData,
         }
     }
@@ -54,79 +226,122 @@ impl<K: Pebble, V: Pebble> RocksTable<K, V> {
         TableInfo::new::<K, V>()
     }
 
-    pub fn cf(&self) -> Arc<rocksdb::BoundColumnFamily> {
-        self.db.db.cf_handle(&self.cf).unwrap()
+    pub fn cf(&self) -> anyhow::Result<Arc<rocksdb::BoundColumnFamily>> {
+        with_instance!(self.db.db, db => db.cf_handle(&self.cf))
+            .anyhow_with(format!("Missing column family '{}'", self.cf))
+    }
+
+    /// Iterates in the key order across prefixes unless `prefix_seek` is set,
+    /// then the iterator stops at the end of the prefix of the first key
+    fn raw_iter<'a>(
+        &'a self,
+        mode: rocksdb::IteratorMode,
+        prefix_seek: bool,
+    ) -> Box<dyn Iterator<Item = anyhow::Result<RawEntry>> + 'a> {
+        let mut opts = self.db.read_opts();
+        if prefix_seek {
+            opts.set_prefix_same_as_start(true);
+        } else {
+            opts.set_total_order_seek(true);
+        }
+        let cf = match self.cf() {
+            Ok(cf) => cf,
+            Err(e) => return Box::new(std::iter::once(Err(e))),
+        };
+        let ident = format!("Failed to read '{}'", self.cf);
+        with_instance!(self.db.db, db => Box::new(
+            db.iterator_cf_opt(&cf, opts, mode)
+                .map(move |x| x.anyhow_with(&ident))
+        ))
+    }
+
+    /// Total size of the SST files of the column family
+    pub fn size(&self) -> u64 {
+        let Ok(cf) = self.cf() else {
+            return 0;
+        };
+        with_instance!(self.db.db, db => db.property_int_value_cf(&cf, "rocksdb.total-sst-files-size"))
+            .ok()
+            .flatten()
+            .unwrap_or_default()
+    }
+
+    /// Relaxes write stalls and enlarges memtables of the primary while many blocks are written
+    pub fn set_bulk_load(&self, enabled: bool) -> anyhow::Result<()> {
+        if let RocksInstance::Primary(db) = &*self.db.db {
+            let options = self.options.bulk_load(enabled);
+            let options = options.iter().map(|(k, v)| (*k, v.as_str())).collect_vec();
+            db.set_options_cf(&self.cf()?, &options)?;
+        }
+        Ok(())
+    }
+
+    pub fn flush(&self) -> anyhow::Result<()> {
+        // Secondary instances have nothing to flush
+        if let RocksInstance::Primary(db) = &*self.db.db {
+            db.flush_cf(&self.cf()?)
+                .anyhow_with(format!("Failed to flush '{}'", self.cf))?;
+        }
+        Ok(())
+    }
+
+    fn write_batch(&self, w: WriteBatchWithTransaction<true>) -> anyhow::Result<()> {
+        match &*self.db.db {
+            RocksInstance::Primary(db) => db
+                .write(w)
+                .anyhow_with(format!("Failed to write '{}'", self.cf)),
+            RocksInstance::Secondary(_) => {
+                bail!("Failed to write '{}': read only instance", self.cf)
+            }
+        }
     }
+}
 
-    pub fn get(&self, k: impl Borrow<K::Inner>) -> Option<V::Inner> {
-        self.db
-            .db
-            .get_cf(&self.cf(), K::get_bytes(k.borrow()))
-            .unwrap()
-            .map(|x| V::from_bytes(Cow::Owned(x)))
-            .map(|x| x.unwrap_or_else(|e| _panic("get", &self.cf, e)))
+impl<K: Pebble, V: Pebble> Table<K, V> for RocksTable<K, V> {
+    fn get(&self, k: impl Borrow<K::Inner>) -> anyhow::Result<Option<V::Inner>> {
+        let opts = self.db.read_opts();
+        let cf = self.cf()?;
+        with_instance!(self.db.db, db => db.get_cf_opt(&cf, K::get_bytes(k.borrow()), &opts))
+            .anyhow_with(format!("Failed to read '{}'", self.cf))?
+            .map(|x| decode_value::<V>(&self.cf, Cow::Owned(x)))
+            .transpose()
     }
 
-    pub fn multi_get<'a>(
+    fn multi_get<'a>(
         &'a self,
         keys: impl IntoIterator<Item = &'a K::Inner>,
-    ) -> Vec<Option<V::Inner>> {
+    ) -> anyhow::Result<Vec<Option<V::Inner>>>
+    where
+        K::Inner: 'a,
+    {
         let keys = keys.into_iter().map(|x| K::get_bytes(x)).collect_vec();
-        self.db
-            .db
-            .batched_multi_get_cf(&self.cf(), keys.iter(), false)
+        let opts = self.db.read_opts();
+        let cf = self.cf()?;
+        with_instance!(self.db.db, db => db.batched_multi_get_cf_opt(&cf, keys.iter(), false, &opts))
             .into_iter()
             .map(|x| {
-                x.unwrap().map(|x| {
-                    V::from_bytes(Cow::Owned((*x).to_vec()))
-                        .unwrap_or_else(|e| _panic("multi_get", &self.cf, e))
-                })
+                x.anyhow_with(format!("Failed to read '{}'", self.cf))?
+                    .map(|x| decode_value::<V>(&self.cf, Cow::Borrowed(&x)))
+                    .transpose()
             })
             .collect()
     }
 
-    pub fn set(&self, k: impl Borrow<K::Inner>, v: impl Borrow<V::Inner>) {
-        self.db
-            .db
-            .put_cf(
-                &self.cf(),
-                K::get_bytes(k.borrow()),
-                V::get_bytes(v.borrow()),
-            )
-            .unwrap();
-    }
-
-    pub fn remove(&self, k: impl Borrow<K::Inner>) {
-        self.db
-            .db
-            .delete_cf(&self.cf(), K::get_bytes(k.borrow()))
-            .unwrap();
+    fn iter(&self) -> impl Iterator<Item = anyhow::Result<(K::Inner, V::Inner)>> + '_ {
+        self.raw_iter(rocksdb::IteratorMode::Start, false).map(|x| {
+            let (k, v) = x?;
+            decode::<K, V>(&self.cf, Cow::Owned(k.into_vec()), Cow::Owned(v.into_vec()))
+        })
     }
 
-    pub fn iter(&self) -> impl Iterator<Item = (K::Inner, V::Inner)> + '_ {
-        self.db
-            .db
-            .iterator_cf(&self.cf(), rocksdb::IteratorMode::Start)
-            .flatten()
-            .map(|(k, v)| {
-                (
-                    K::from_bytes(Cow::Owned(k.into_vec())),
-                    V::from_bytes(Cow::Owned(v.into_vec())),
-                )
-            })
-            .map(|(k, v)| {
-                (
-                    k.unwrap_or_else(|e| _panic("iter key", &self.cf, e)),
-                    v.unwrap_or_else(|e| _panic("iter val", &self.cf, e)),
-                )
-            })
-    }
-
-    pub fn range<'a>(
+    fn range<'a>(
         &'a self,
         range: impl RangeBounds<&'a K::Inner>,
         reversed: bool,
-    ) -> Box<dyn Iterator<Item = (K::Inner, V::Inner)> + 'a> {
+    ) -> TableRange<'a, K, V>
+    where
+        K::Inner: 'a,
+    {
         enum Position {
             Start,
             End,
@@ -170,6 +385,10 @@ impl<K: Pebble, V: Pebble> RocksTable<K, V> {
         let (start_position, start_bound, start) = start;
         let (end_position, end_bound, end) = end;
 
+        let prefix_seek = self
+            .options
+            .is_prefix_range(start.as_deref(), end.as_deref());
+
         let (direction, mode) = if reversed {
             (rocksdb::Direction::Reverse, rocksdb::IteratorMode::End)
         } else {
@@ -177,25 +396,25 @@ impl<K: Pebble, V: Pebble> RocksTable<K, V> {
         };
 
         let x = self
-            .db
-            .db
-            .iterator_cf(
-                &self.cf(),
+            .raw_iter(
                 if let Some(start) = start.as_ref() {
                     rocksdb::IteratorMode::From(start, direction)
                 } else {
                     mode
                 },
+                prefix_seek,
             )
-            .flatten()
-            .skip_while(move |(k, _)| {
-                matches!(start_bound, BoundType::Excluded) && **k == **start.as_ref().unwrap()
+            .skip_while(move |x| {
+                matches!(start_bound, BoundType::Excluded)
+                    && x.as_ref()
+                        .is_ok_and(|(k, _)| start.as_deref() == Some(&**k))
             })
-            .take_while(move |(k, _)| {
-                let x = match end_bound {
-                    BoundType::Unbounded => None,
-                    _ => Some((**k).cmp(end.as_ref().unwrap())),
+            .take_while(move |x| {
+                let Ok((k, _)) = x else {
+                    return true;
                 };
+                // Set unless the end is unbounded
+                let x = end.as_deref().map(|end| (**k).cmp(end));
                 if let Some(x) = x {
                     if let Position::End = end_position {
                         if let BoundType::Included = end_bound {
@@ -212,74 +431,60 @@ impl<K: Pebble, V: Pebble> RocksTable<K, V> {
                     true
                 }
             })
-            .map(move |(k, v)| {
-                (
-                    K::from_bytes(Cow::Owned(k.into_vec())),
-                    V::from_bytes(Cow::Owned(v.into_vec())),
-                )
-            })
-            .map(|(k, v)| {
-                (
-                    k.unwrap_or_else(|e| _panic("range key", &self.cf, e)),
-                    v.unwrap_or_else(|e| _panic("range val", &self.cf, e)),
-                )
+            .map(|x| {
+                let (k, v) = x?;
+                decode::<K, V>(&self.cf, Cow::Owned(k.into_vec()), Cow::Owned(v.into_vec()))
             });
 
         Box::new(x)
     }
 
-    pub fn retain(&self, f: impl Fn(K::Inner, V::Inner) -> bool) {
+    fn retain(&self, f: impl Fn(K::Inner, V::Inner) -> bool) -> anyhow::Result<()> {
         let mut w = WriteBatchWithTransaction::<true>::default();
-        let cf = self.cf();
+        let cf = self.cf()?;
 
-        let iter = self
-            .db
-            .db
-            .iterator_cf(&self.cf(), rocksdb::IteratorMode::Start)
-            .flatten()
-            .flat_map(|(k, v)| {
-                anyhow::Ok((
-                    K::from_bytes(Cow::Borrowed(&k))?,
-                    V::from_bytes(Cow::Owned(v.into_vec()))?,
-                    k,
-                ))
-            })
-            .map(|(k, v, x)| (!(f)(k, v), x))
-            .filter(|(b, _)| *b)
-            .map(|(_, x)| x);
-        for k in iter {
-            w.delete_cf(&cf, k);
+        for entry in self.raw_iter(rocksdb::IteratorMode::Start, false) {
+            let (k, v) = entry?;
+            let (key, value) =
+                decode::<K, V>(&self.cf, Cow::Borrowed(&k), Cow::Owned(v.into_vec()))?;
+            if !f(key, value) {
+                w.delete_cf(&cf, k);
+            }
         }
 
-        self.write(w);
-    }
-
-    pub fn flush(&self) {
-        self.db.db.flush_cf(&self.cf()).unwrap();
+        self.write_batch(w)
     }
 
-    pub fn write(&self, w: WriteBatchWithTransaction<true>) {
-        self.db.db.write(w).unwrap();
+    fn write(&self, batch: TableBatch<K, V>) -> anyhow::Result<()> {
+        let mut w = WriteBatchWithTransaction::<true>::default();
+        let cf = self.cf()?;
+        for (k, v) in batch.ops {
+            match v {
+                Some(v) => w.put_cf(&cf, k, v),
+                None => w.delete_cf(&cf, k),
+            }
+        }
+        self.write_batch(w)
     }
 
-    pub fn extend(
+    fn extend(
         &self,
         kv: impl IntoIterator<Item = (impl Borrow<K::Inner>, impl Borrow<V::Inner>)>,
-    ) {
+    ) -> anyhow::Result<()> {
         let mut w = WriteBatchWithTransaction::<true>::default();
-        let cf = self.cf();
+        let cf = self.cf()?;
         for (k, v) in kv {
             w.put_cf(&cf, K::get_bytes(k.borrow()), V::get_bytes(v.borrow()));
         }
-        self.write(w);
+        self.write_batch(w)
     }
 
-    pub fn remove_batch(&self, k: impl Iterator<Item = impl Borrow<K::Inner>>) {
+    fn remove_batch(&self, k: impl Iterator<Item = impl Borrow<K::Inner>>) -> anyhow::Result<()> {
         let mut w = WriteBatchWithTransaction::<true>::default();
-        let cf = self.cf();
+        let cf = self.cf()?;
         for k in k {
             w.delete_cf(&cf, K::get_bytes(k.borrow()));
         }
-        self.write(w);
+        self.write_batch(w)
     }
 }
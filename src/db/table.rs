@@ -0,0 +1,331 @@
+use super::*;
+
+/// Decodes an entry read from the table `cf`
+pub(super) fn decode<K: Pebble, V: Pebble>(
+    cf: &str,
+    k: Cow<[u8]>,
+    v: Cow<[u8]>,
+) -> anyhow::Result<(K::Inner, V::Inner)> {
+    Ok((
+        K::from_bytes(k).anyhow_with(format!("Invalid key in '{cf}'"))?,
+        decode_value::<V>(cf, v)?,
+    ))
+}
+
+pub(super) fn decode_value<V: Pebble>(cf: &str, v: Cow<[u8]>) -> anyhow::Result<V::Inner> {
+    V::from_bytes(v).anyhow_with(format!("Invalid value in '{cf}'"))
+}
+
+/// Decoded entries of a table range
+pub(super) type TableRange<'a, K, V> =
+    Box<dyn Iterator<Item = anyhow::Result<(<K as Pebble>::Inner, <V as Pebble>::Inner)>> + 'a>;
+
+/// Puts and deletes applied at once by `Table::write`, in the order they were added
+pub struct TableBatch<K: Pebble, V: Pebble> {
+    pub(super) ops: Vec<(Vec<u8>, Option<Vec<u8>>)>,
+    __marker: PhantomData<(K, V)>,
+}
+
+impl<K: Pebble, V: Pebble> Default for TableBatch<K, V> {
+    fn default() -> Self {
+        Self {
+            ops: vec![],
+            __marker: PhantomData,
+        }
+    }
+}
+
+impl<K: Pebble, V: Pebble> TableBatch<K, V> {
+    pub fn put(&mut self, k: impl Borrow<K::Inner>, v: impl Borrow<V::Inner>) {
+        self.ops.push((
+            K::get_bytes(k.borrow()).into_owned(),
+            Some(V::get_bytes(v.borrow()).into_owned()),
+        ));
+    }
+
+    pub fn delete(&mut self, k: impl Borrow<K::Inner>) {
+        self.ops.push((K::get_bytes(k.borrow()).into_owned(), None));
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.ops.is_empty()
+    }
+}
+
+/// Puts and deletes of several tables applied atomically by `DB::write`, in the order they were added
+#[derive(Default)]
+pub struct DbBatch {
+    pub(super) ops: Vec<(String, Vec<u8>, Option<Vec<u8>>)>,
+}
+
+impl DbBatch {
+    pub fn put<K: Pebble, V: Pebble>(
+        &mut self,
+        table: &DbTable<K, V>,
+        k: impl Borrow<K::Inner>,
+        v: impl Borrow<V::Inner>,
+    ) {
+        self.ops.push((
+            table.cf().to_string(),
+            K::get_bytes(k.borrow()).into_owned(),
+            Some(V::get_bytes(v.borrow()).into_owned()),
+        ));
+    }
+
+    pub fn delete<K: Pebble, V: Pebble>(
+        &mut self,
+        table: &DbTable<K, V>,
+        k: impl Borrow<K::Inner>,
+    ) {
+        self.ops.push((
+            table.cf().to_string(),
+            K::get_bytes(k.borrow()).into_owned(),
+            None,
+        ));
+    }
+
+    pub fn extend<K: Pebble, V: Pebble>(
+        &mut self,
+        table: &DbTable<K, V>,
+        kv: impl IntoIterator<Item = (impl Borrow<K::Inner>, impl Borrow<V::Inner>)>,
+    ) {
+        for (k, v) in kv {
+            self.put(table, k, v);
+        }
+    }
+
+    pub fn remove_batch<K: Pebble, V: Pebble>(
+        &mut self,
+        table: &DbTable<K, V>,
+        k: impl IntoIterator<Item = impl Borrow<K::Inner>>,
+    ) {
+        for k in k {
+            self.delete(table, k);
+        }
+    }
+}
+
+/// Typed key-value table ordered by the key bytes
+pub trait Table<K: Pebble, V: Pebble> {
+    fn get(&self, k: impl Borrow<K::Inner>) -> anyhow::Result<Option<V::Inner>>;
+
+    fn multi_get<'a>(
+        &'a self,
+        keys: impl IntoIterator<Item = &'a K::Inner>,
+    ) -> anyhow::Result<Vec<Option<V::Inner>>>
+    where
+        K::Inner: 'a;
+
+    fn iter(&self) -> impl Iterator<Item = anyhow::Result<(K::Inner, V::Inner)>> + '_;
+
+    fn range<'a>(
+        &'a self,
+        range: impl RangeBounds<&'a K::Inner>,
+        reversed: bool,
+    ) -> TableRange<'a, K, V>
+    where
+        K::Inner: 'a;
+
+    fn write(&self, batch: TableBatch<K, V>) -> anyhow::Result<()>;
+
+    fn set(&self, k: impl Borrow<K::Inner>, v: impl Borrow<V::Inner>) -> anyhow::Result<()> {
+        let mut batch = TableBatch::default();
+        batch.put(k, v);
+        self.write(batch)
+    }
+
+    fn remove(&self, k: impl Borrow<K::Inner>) -> anyhow::Result<()> {
+        let mut batch = TableBatch::default();
+        batch.delete(k);
+        self.write(batch)
+    }
+
+    fn extend(
+        &self,
+        kv: impl IntoIterator<Item = (impl Borrow<K::Inner>, impl Borrow<V::Inner>)>,
+    ) -> anyhow::Result<()> {
+        let mut batch = TableBatch::default();
+        for (k, v) in kv {
+            batch.put(k, v);
+        }
+        self.write(batch)
+    }
+
+    fn remove_batch(&self, k: impl Iterator<Item = impl Borrow<K::Inner>>) -> anyhow::Result<()> {
+        let mut batch = TableBatch::default();
+        for k in k {
+            batch.delete(k);
+        }
+        self.write(batch)
+    }
+
+    fn retain(&self, f: impl Fn(K::Inner, V::Inner) -> bool) -> anyhow::Result<()> {
+        let mut batch = TableBatch::<K, V>::default();
+        for entry in self.iter() {
+            let (k, v) = entry?;
+            let key = K::get_bytes(&k).into_owned();
+            if !f(k, v) {
+                batch.ops.push((key, None));
+            }
+        }
+        self.write(batch)
+    }
+}
+
+/// Table of any storage backend
+#[derive(Clone)]
+pub enum DbTable<K: Pebble, V: Pebble> {
+    Rocks(RocksTable<K, V>),
+    Memory(MemoryTable<K, V>),
+}
+
+/// Runs the expression with the table of any backend
+macro_rules! with_table {
+    ($table:expr, $x:ident => $e:expr) => {
+        match $table {
+            DbTable::Rocks($x) => $e,
+            DbTable::Memory($x) => $e,
+        }
+    };
+}
+
+impl<K: Pebble, V: Pebble> Table<K, V> for DbTable<K, V> {
+    fn get(&self, k: impl Borrow<K::Inner>) -> anyhow::Result<Option<V::Inner>> {
+        with_table!(self, x => x.get(k))
+    }
+
+    fn multi_get<'a>(
+        &'a self,
+        keys: impl IntoIterator<Item = &'a K::Inner>,
+    ) -> anyhow::Result<Vec<Option<V::Inner>>>
+    where
+        K::Inner: 'a,
+    {
+        with_table!(self, x => x.multi_get(keys))
+    }
+
+    fn iter(&self) -> impl Iterator<Item = anyhow::Result<(K::Inner, V::Inner)>> + '_ {
+        with_table!(self, x => Box::new(x.iter()) as Box<dyn Iterator<Item = _>>)
+    }
+
+    fn range<'a>(
+        &'a self,
+        range: impl RangeBounds<&'a K::Inner>,
+        reversed: bool,
+    ) -> TableRange<'a, K, V>
+    where
+        K::Inner: 'a,
+    {
+        with_table!(self, x => x.range(range, reversed))
+    }
+
+    fn write(&self, batch: TableBatch<K, V>) -> anyhow::Result<()> {
+        with_table!(self, x => x.write(batch))
+    }
+
+    fn set(&self, k: impl Borrow<K::Inner>, v: impl Borrow<V::Inner>) -> anyhow::Result<()> {
+        with_table!(self, x => x.set(k, v))
+    }
+
+    fn remove(&self, k: impl Borrow<K::Inner>) -> anyhow::Result<()> {
+        with_table!(self, x => x.remove(k))
+    }
+
+    fn extend(
+        &self,
+        kv: impl IntoIterator<Item = (impl Borrow<K::Inner>, impl Borrow<V::Inner>)>,
+    ) -> anyhow::Result<()> {
+        with_table!(self, x => x.extend(kv))
+    }
+
+    fn remove_batch(&self, k: impl Iterator<Item = impl Borrow<K::Inner>>) -> anyhow::Result<()> {
+        with_table!(self, x => x.remove_batch(k))
+    }
+
+    fn retain(&self, f: impl Fn(K::Inner, V::Inner) -> bool) -> anyhow::Result<()> {
+        with_table!(self, x => x.retain(f))
+    }
+}
+
+impl<K: Pebble, V: Pebble> DbTable<K, V> {
+    /// Name of the column family of the table
+    pub fn cf(&self) -> &str {
+        with_table!(self, x => &x.cf)
+    }
+
+    /// Bytes stored by the table
+    pub fn size(&self) -> u64 {
+        with_table!(self, x => x.size())
+    }
+
+    pub fn set_bulk_load(&self, enabled: bool) -> anyhow::Result<()> {
+        match self {
+            DbTable::Rocks(x) => x.set_bulk_load(enabled),
+            DbTable::Memory(_) => Ok(()),
+        }
+    }
+
+    pub fn flush(&self) -> anyhow::Result<()> {
+        match self {
+            DbTable::Rocks(x) => x.flush(),
+            DbTable::Memory(_) => Ok(()),
+        }
+    }
+}
+
+/// Backend holding the tables of a `DB`
+#[derive(Clone)]
+pub enum Storage {
+    Rocks(RocksDB),
+    /// Nothing is written to disk, used to run tests without a db
+    Memory(MemoryDB),
+}
+
+impl Storage {
+    pub fn table<K: Pebble, V: Pebble>(
+        &self,
+        cf: impl ToString,
+        options: TableOptions,
+    ) -> DbTable<K, V> {
+        match self {
+            Storage::Rocks(x) => DbTable::Rocks(x.table(cf, options)),
+            Storage::Memory(x) => DbTable::Memory(x.table(cf)),
+        }
+    }
+
+    /// Same storage with reads pinned to the current state
+    pub fn snapshot(&self) -> Self {
+        match self {
+            Storage::Rocks(x) => Storage::Rocks(x.snapshot()),
+            Storage::Memory(x) => Storage::Memory(x.snapshot()),
+        }
+    }
+
+    pub fn write(&self, batch: DbBatch) -> anyhow::Result<()> {
+        match self {
+            Storage::Rocks(x) => x.write(batch),
+            Storage::Memory(x) => x.write(batch),
+        }
+    }
+
+    pub fn create_checkpoint(&self, path: &std::path::Path) -> anyhow::Result<()> {
+        match self {
+            Storage::Rocks(x) => x.create_checkpoint(path),
+            Storage::Memory(_) => bail!("Checkpoints are not supported by the memory storage"),
+        }
+    }
+
+    pub fn try_catch_up_with_primary(&self) -> anyhow::Result<()> {
+        match self {
+            Storage::Rocks(x) => x.try_catch_up_with_primary(),
+            Storage::Memory(_) => Ok(()),
+        }
+    }
+
+    pub fn is_secondary(&self) -> bool {
+        match self {
+            Storage::Rocks(x) => x.is_secondary(),
+            Storage::Memory(_) => false,
+        }
+    }
+}
@@ -8,37 +8,129 @@ pub trait RcUtils: Sized {
 
 impl<T: Sized> RcUtils for T {}
 
+/// Tables are declared as `name: Key => Value`, optionally followed by `= TableOptions`
 #[macro_export]
 macro_rules! generate_db_code {
-    ($($name:ident: $key_type:ty => $value_type:ty),* $(,)?) => {
+    (@options) => {
+        $crate::db::TableOptions::new()
+    };
+    (@options $options:expr) => {
+        $options
+    };
+    ($($name:ident: $key_type:ty => $value_type:ty $(= $options:expr)?),* $(,)?) => {
         pub struct DB {
             $(
-                pub $name: super::RocksTable<$key_type, $value_type>,
+                pub $name: $crate::db::DbTable<$key_type, $value_type>,
             )*
+            db: $crate::db::Storage,
         }
 
         impl DB {
+            fn tables() -> Vec<(String, $crate::db::TableOptions)> {
+                vec![
+                    $(
+                        (
+                            stringify!($name).to_uppercase(),
+                            $crate::generate_db_code!(@options $($options)?),
+                        ),
+                    )*
+                ]
+            }
+
             pub fn open(path: &str) -> Self {
                 let db = RocksDB::open_db(
                     path,
-                    [
-                        $(
-                            stringify!($name).to_uppercase().as_str(),
-                        )*
-                    ],
+                    $crate::CONFIG.rocksdb.block_cache_mb,
+                    Self::tables(),
                 );
 
+                Self::from_db($crate::db::Storage::Rocks(db))
+            }
+
+            /// Tables kept in memory, for tests that shouldn't touch the disk
+            #[cfg(test)]
+            pub fn open_memory() -> Self {
+                Self::from_db($crate::db::Storage::Memory(Default::default()))
+            }
+
+            /// Tables of the same memory db opened again, like after a restart
+            #[cfg(test)]
+            pub fn reopen_memory(&self) -> Self {
+                match &self.db {
+                    $crate::db::Storage::Memory(x) => {
+                        Self::from_db($crate::db::Storage::Memory(x.clone()))
+                    }
+                    _ => panic!("Db is not in memory"),
+                }
+            }
+
+            /// Opens a read only instance following the database at `path`
+            pub fn open_secondary(path: &str, secondary_path: &str) -> Self {
+                let db = RocksDB::open_secondary(
+                    path,
+                    secondary_path,
+                    $crate::CONFIG.rocksdb.block_cache_mb,
+                    Self::tables(),
+                );
+
+                Self::from_db($crate::db::Storage::Rocks(db))
+            }
+
+            fn from_db(db: $crate::db::Storage) -> Self {
                 Self {
                     $(
-                        $name: db.table(stringify!($name).to_uppercase().as_str()),
+                        $name: db.table(
+                            stringify!($name).to_uppercase().as_str(),
+                            $crate::generate_db_code!(@options $($options)?),
+                        ),
                     )*
+                    db,
                 }
             }
 
-            pub fn flush_all(&self) {
+            /// Same tables with reads pinned to the current state
+            pub fn snapshot(&self) -> Self {
+                Self::from_db(self.db.snapshot())
+            }
+
+            /// Writes changes of several tables at once
+            pub fn write(&self, batch: $crate::db::DbBatch) -> anyhow::Result<()> {
+                self.db.write(batch)
+            }
+
+            pub fn create_checkpoint(&self, path: &std::path::Path) -> anyhow::Result<()> {
+                self.db.create_checkpoint(path)
+            }
+
+            pub fn try_catch_up_with_primary(&self) -> anyhow::Result<()> {
+                self.db.try_catch_up_with_primary()
+            }
+
+            pub fn is_secondary(&self) -> bool {
+                self.db.is_secondary()
+            }
+
+            /// Settings for writing many blocks at once during the initial catch-up
+            pub fn set_bulk_load(&self, enabled: bool) -> anyhow::Result<()> {
+                $(
+                    self.$name.set_bulk_load(enabled)?;
+                )*
+                Ok(())
+            }
+
+            pub fn flush_all(&self) -> anyhow::Result<()> {
                 $(
-                    self.$name.flush();
+                    self.$name.flush()?;
                 )*
+                Ok(())
+            }
+
+            pub fn table_sizes(&self) -> Vec<(&'static str, u64)> {
+                vec![
+                    $(
+                        (stringify!($name), self.$name.size()),
+                    )*
+                ]
             }
         }
 
@@ -0,0 +1,129 @@
+use std::path::PathBuf;
+
+use super::*;
+
+/// Invariant of the indexed state broken by a transaction of the block
+#[derive(Debug)]
+pub struct InvariantViolation {
+    pub message: &'static str,
+    pub txid: Txid,
+    /// Involved accounts with their state in the block, if they have one
+    pub accounts: Vec<(AddressToken, Option<TokenBalance>)>,
+}
+
+impl std::fmt::Display for InvariantViolation {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} in {}", self.message, self.txid)
+    }
+}
+
+impl std::error::Error for InvariantViolation {}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct AccountDump {
+    pub script_hash: String,
+    /// Unknown if the address isn't saved yet
+    pub address: Option<String>,
+    pub tick: String,
+    /// State in the block when indexing stopped
+    pub block: Option<TokenBalance>,
+    /// State at the last indexed block
+    pub stored: Option<TokenBalance>,
+}
+
+/// Block that failed to index. Indexing stops at it until the indexer is restarted
+#[serde_as]
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Fault {
+    pub height: u32,
+    #[serde_as(as = "DisplayFromStr")]
+    pub block_hash: BlockHash,
+    #[serde_as(as = "Option<DisplayFromStr>")]
+    pub txid: Option<Txid>,
+    pub error: String,
+    pub accounts: Vec<AccountDump>,
+}
+
+impl Fault {
+    fn new(server: &Server, height: u32, block_hash: BlockHash, error: &anyhow::Error) -> Self {
+        let violation = error.downcast_ref::<InvariantViolation>();
+
+        let accounts = violation
+            .map(|x| x.accounts.as_slice())
+            .unwrap_or_default()
+            .iter()
+            .map(|(account, block)| AccountDump {
+                script_hash: hex::encode(*account.address),
+                // The dump is written even if the db can't be read
+                address: server
+                    .db
+                    .fullhash_to_address
+                    .get(account.address)
+                    .track()
+                    .ok()
+                    .flatten(),
+                tick: String::from_utf8_lossy(&account.token).to_string(),
+                block: block.clone(),
+                stored: server
+                    .db
+                    .address_token_to_balance
+                    .get(account)
+                    .track()
+                    .ok()
+                    .flatten(),
+            })
+            .collect();
+
+        Self {
+            height,
+            block_hash,
+            txid: violation.map(|x| x.txid),
+            error: format!("{error:#}"),
+            accounts,
+        }
+    }
+}
+
+/// Panics of a spawned indexing task are reported as errors of the block
+pub fn join_error(e: tokio::task::JoinError) -> anyhow::Error {
+    match e.try_into_panic() {
+        Ok(payload) => {
+            let message = payload
+                .downcast_ref::<&str>()
+                .map(|x| x.to_string())
+                .or_else(|| payload.downcast_ref::<String>().cloned())
+                .unwrap_or_default();
+            anyhow::anyhow!("Panicked: {message}")
+        }
+        Err(e) => e.into(),
+    }
+}
+
+/// Keeps the fault for `/status` and writes it to `indexer.fault_dir/<height>.json`
+pub fn record(
+    server: &Server,
+    height: u32,
+    block_hash: BlockHash,
+    error: anyhow::Error,
+) -> anyhow::Error {
+    let fault = Fault::new(server, height, block_hash, &error);
+    error!(
+        "Indexing stopped at block {} ({}): {}",
+        height, block_hash, fault.error
+    );
+    metrics::INDEXER_FAULT_HEIGHT.set(height as i64);
+
+    let dir = PathBuf::from(&CONFIG.indexer.fault_dir);
+    std::fs::create_dir_all(&dir)
+        .anyhow_with(format!("Failed to create {}", dir.display()))
+        .and_then(|_| {
+            let path = dir.join(format!("{height}.json"));
+            std::fs::write(&path, serde_json::to_vec_pretty(&fault)?)
+                .anyhow_with(format!("Failed to write {}", path.display()))
+        })
+        .track()
+        .ok();
+
+    *server.fault.lock() = Some(fault);
+    error
+}
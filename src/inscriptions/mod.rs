@@ -16,48 +16,188 @@ use structs::{Inscription, ParsedInscription};
 use tag::Tag;
 pub use utils::ScriptToAddr;
 
+pub use parser::InitialIndexer;
 pub use structs::Location;
 
 pub async fn main_loop(token: WaitToken, server: Arc<Server>) -> anyhow::Result<()> {
-    let reorg_cache = Arc::new(parking_lot::Mutex::new(reorg::ReorgCache::new()));
+    let reorg_cache = Arc::new(parking_lot::Mutex::new(reorg::ReorgCache::load(
+        &server.db,
+    )?));
 
     let tip_hash = server.client.best_block_hash().await?;
-    let tip_height = server.client.get_block_info(&tip_hash).await?.height as u32;
-
-    let last_block = server.db.last_block.get(());
-    let mut last_block = last_block.map(|x| x + 1).unwrap_or(1);
+    let tip_height = server.client.get_block_headers(&[tip_hash]).await?[0].height as u32;
+    metrics::NODE_TIP_HEIGHT.set(tip_height as i64);
+
+    let mut last_block = match server.db.last_block.get(())? {
+        // Blocks reorged while the indexer was stopped are reverted with the saved cache
+        Some(last_block) if last_block <= tip_height => {
+            revert_reorg(&server, &reorg_cache, last_block, tip_height)
+                .await?
+                // Shutting down for the scheduled restore
+                .unwrap_or(last_block + 1)
+        }
+        Some(last_block) => last_block + 1,
+        None => 1,
+    };
 
-    warn!("Blocks to sync: {}", tip_height - last_block);
+    warn!("Blocks to sync: {}", tip_height.saturating_sub(last_block));
 
     {
         let progress = crate::utils::Progress::begin("Indexing", tip_height as _, last_block as _);
 
-        while last_block < tip_height - reorg::REORG_CACHE_MAX_LEN as u32 && !token.is_cancelled() {
-            parser::InitialIndexer::handle(last_block, server.clone(), None)
+        let sync_to = tip_height.saturating_sub(CONFIG.indexer.reorg_depth as u32);
+        if last_block < sync_to {
+            // Blocks of the catch-up are not saved in the cache, so the saved ones can't be reverted after them
+            reorg_cache.lock().clear(&server.db)?;
+        }
+        // Set either way, settings of a catch-up that was killed may be left in the db
+        server.db.set_bulk_load(last_block < sync_to).track().ok();
+
+        'sync: while last_block < sync_to && !token.is_cancelled() {
+            let to = (last_block + CONFIG.rpc.batch_size as u32).min(sync_to) - 1;
+            let Some(hashes) = server
+                .client
+                .get_block_hashes(last_block..=to)
                 .await
                 .track()
-                .ok();
-            last_block += 1;
-            progress.inc(1);
+                .ok()
+            else {
+                break;
+            };
+
+            for hash in hashes {
+                if token.is_cancelled() {
+                    break;
+                }
+
+                let indexed =
+                    parser::InitialIndexer::handle(last_block, hash, server.clone(), None).await;
+                if indexed.track().is_err() {
+                    break 'sync;
+                }
+                if checkpoints::is_due(last_block, tip_height) {
+                    checkpoints::create(&server, last_block).await.track().ok();
+                }
+                last_block += 1;
+                progress.inc(1);
+            }
         }
+
+        server.db.set_bulk_load(false).track().ok();
     }
 
-    if !token.is_cancelled() {
+    if !token.is_cancelled() && server.fault.lock().is_none() {
+        server.synced.store(true, Ordering::SeqCst);
         new_fether(last_block - 1, token, server.clone(), reorg_cache.clone())
             .await
             .track()
             .ok();
     }
 
+    if let Some(fault) = server.fault.lock().as_ref() {
+        error!(
+            "Indexing is stopped at block {}, blocks up to {} are served",
+            fault.height,
+            fault.height - 1
+        );
+    }
+
     info!("Server is finished");
 
-    reorg_cache.lock().restore_all(&server).track().ok();
+    server.db.flush_all()?;
 
-    server.db.flush_all();
+    Ok(())
+}
 
+/// Height after the last indexed block that is on the chain of the node.
+/// Node hashes are compared with the indexed ones going down from `last_height` in batches.
+async fn first_unmatched(
+    server: &Server,
+    last_height: u32,
+    tip_height: u32,
+) -> anyhow::Result<u32> {
+    let batch = CONFIG.rpc.batch_size as u32;
+    let mut height = last_height;
+
+    loop {
+        let from = height.saturating_sub(batch - 1);
+        // Blocks above the node tip are not on its chain
+        let node_hashes = if from <= tip_height {
+            server
+                .client
+                .get_block_hashes(from..=height.min(tip_height))
+                .await?
+        } else {
+            vec![]
+        };
+
+        for height in (from..=height).rev() {
+            // Genesis block is never indexed and can't be reorged
+            let Some(local_hash) = server.db.block_hashes.get(height)? else {
+                return Ok(height + 1);
+            };
+
+            if node_hashes.get((height - from) as usize) == Some(&local_hash) {
+                return Ok(height + 1);
+            }
+        }
+
+        height = from
+            .checked_sub(1)
+            .anyhow_with("No indexed block is on the chain of the node")?;
+    }
+}
+
+/// Schedules a restore of a checkpoint older than the fork and shuts down.
+/// The checkpoint is restored on restart and the blocks after it are indexed again
+async fn deep_reorg(server: &Server, fork_height: u32) -> anyhow::Result<()> {
+    metrics::DEEP_REORGS.inc();
+    error!(
+        "Reorg from block {} is deeper than the reorg cache",
+        fork_height
+    );
+
+    let checkpoint = checkpoints::restore_point(server, fork_height)
+        .await?
+        .anyhow_with("No checkpoint before the fork, the db has to be indexed again")?;
+    checkpoints::schedule_restore(&checkpoint)?;
+    warn!(
+        "Scheduled restore of the checkpoint at {}",
+        checkpoint.meta.height
+    );
+
+    server.token.cancel();
     Ok(())
 }
 
+/// Reverts the indexed blocks after `last_height` that are not on the chain of the node.
+/// Returns the height to index next, none if the reorg is deeper than the cache and a restore is scheduled
+async fn revert_reorg(
+    server: &Server,
+    reorg_cache: &parking_lot::Mutex<reorg::ReorgCache>,
+    last_height: u32,
+    tip_height: u32,
+) -> anyhow::Result<Option<u32>> {
+    let current_height = first_unmatched(server, last_height, tip_height).await?;
+    let reorg_counter = last_height + 1 - current_height;
+
+    if reorg_counter > 0 {
+        warn!("Reorg detected: {} blocks", reorg_counter);
+        metrics::REORGS.inc();
+        metrics::REORG_DEPTH.observe(reorg_counter as f64);
+
+        if !reorg_cache.lock().covers(current_height) {
+            deep_reorg(server, current_height).await?;
+            return Ok(None);
+        }
+
+        reorg_cache.lock().restore(server, current_height)?;
+        server.commit(current_height - 1)?;
+    }
+
+    Ok(Some(current_height))
+}
+
 async fn new_fether(
     last_block: u32,
     token: WaitToken,
@@ -69,53 +209,40 @@ async fn new_fether(
     let mut repeater = token.repeat_until_cancel(Duration::from_millis(50));
 
     while repeater.next().await {
-        tokio::time::sleep(Duration::from_secs(1)).await;
-
         // Index new blocks
         let current_tip = server.client.best_block_hash().await?;
 
-        if current_tip != tip {
-            let last_height = server.client.get_block_info(&tip).await?.height;
-            let mut current_height = last_height as u32 + 1;
-            let mut next_hash = server.client.get_block_hash(current_height).await?;
-
-            let mut reorg_counter = 0;
-
-            loop {
-                let local_prev_hash = server.db.block_hashes.get(current_height - 1).unwrap();
-                let prev_block_hash = server
-                    .client
-                    .get_block_info(&next_hash)
-                    .await?
-                    .previousblockhash
-                    .unwrap();
-
-                if prev_block_hash != local_prev_hash {
-                    reorg_counter += 1;
-                    current_height -= 1;
-                    next_hash = server.client.get_block_hash(current_height).await?;
-                } else {
-                    break;
-                }
-            }
-
-            if reorg_counter > 0 {
-                warn!("Reorg detected: {} blocks", reorg_counter);
-                server
-                    .event_sender
-                    .send(ServerEvent::Reorg(reorg_counter, current_height))
-                    .ok();
-                reorg_cache.lock().restore(&server, current_height)?;
-            }
+        if current_tip == tip {
+            tokio::time::sleep(Duration::from_secs(1)).await;
+        } else {
+            let headers = server.client.get_block_headers(&[current_tip, tip]).await?;
+            let tip_height = headers[0].height as u32;
+            metrics::NODE_TIP_HEIGHT.set(tip_height as i64);
+
+            let last_height = headers[1].height as u32;
+            let Some(current_height) =
+                revert_reorg(&server, &reorg_cache, last_height, tip_height).await?
+            else {
+                return Ok(());
+            };
+            let next_hash = server.client.get_block_hash(current_height).await?;
 
             parser::InitialIndexer::handle(
                 current_height,
+                next_hash,
                 server.clone(),
                 Some(reorg_cache.clone()),
             )
             .await?;
 
             tip = next_hash;
+
+            if checkpoints::is_due(current_height, tip_height) {
+                checkpoints::create(&server, current_height)
+                    .await
+                    .track()
+                    .ok();
+            }
         }
     }
 
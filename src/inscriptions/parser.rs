@@ -1,35 +1,112 @@
+use rayon::prelude::*;
+
 use super::*;
 
 pub struct ParseInscription<'a> {
     tx: &'a Transaction,
+    txid: Txid,
+    height: u32,
     input_idx: usize,
     inscription_idx: &'a mut u32,
     inputs_cum: &'a [u64],
 }
 
+struct ParsedTx<'a> {
+    tx: &'a Transaction,
+    txid: Txid,
+    inputs_cum: Vec<u64>,
+    /// Inscriptions revealed by each input
+    inscriptions: Vec<Vec<InscriptionTemplate>>,
+}
+
 pub struct InitialIndexer {}
 
+/// Changes of a processed block and the events logged with them
+struct IndexedBlock {
+    batch: DbBatch,
+    events: Vec<ServerEvent>,
+}
+
 impl InitialIndexer {
+    /// Extracts inscriptions and computes input offsets of a transaction.
+    /// Doesn't depend on other transactions of the block so it's done in parallel.
+    fn parse_tx<'a>(
+        height: u32,
+        tx: &'a Transaction,
+        prevouts: &HashMap<OutPoint, TxOut>,
+    ) -> anyhow::Result<ParsedTx<'a>> {
+        let mut inscription_idx = 0;
+        let txid = tx.txid();
+
+        let inputs_cum = InscriptionSearcher::calc_offsets(tx, prevouts)
+            .anyhow_with("Failed to find all txos to calculate offsets")?;
+
+        let inscriptions = (0..tx.input.len())
+            .map(|idx| {
+                Self::parse_inscriptions(ParseInscription {
+                    tx,
+                    txid,
+                    height,
+                    input_idx: idx,
+                    inscription_idx: &mut inscription_idx,
+                    inputs_cum: &inputs_cum,
+                })
+            })
+            .collect();
+
+        Ok(ParsedTx {
+            tx,
+            txid,
+            inputs_cum,
+            inscriptions,
+        })
+    }
+
+    /// Transfer inscriptions revealed by the transaction at the locations the indexer gives them.
+    /// `prevouts` has the outputs spent by the transaction
+    pub(crate) fn transfers(
+        height: u32,
+        tx: &Transaction,
+        prevouts: &HashMap<OutPoint, TxOut>,
+    ) -> anyhow::Result<Vec<(Location, TransferProtoDB)>> {
+        let mut token_cache = TokenCache::default();
+
+        for inc in Self::parse_tx(height, tx, prevouts)?
+            .inscriptions
+            .iter()
+            .flatten()
+        {
+            if inc.genesis.index == 0 || PROTOCOL.is_multiple_input_active(height) {
+                token_cache.parse_token_action(inc, height, 0);
+            }
+        }
+
+        Ok(token_cache.all_transfers.into_iter().collect())
+    }
+
     fn parse_block(
         height: u32,
         created: u32,
         txs: &[Transaction],
         prevouts: &HashMap<OutPoint, TxOut>,
         token_cache: &mut TokenCache,
-    ) {
-        let mut transfers = vec![];
-
-        for tx in txs {
-            if tx.is_coin_base() {
-                continue;
-            }
-            let mut inscription_idx = 0;
-            let txid = tx.txid();
+    ) -> anyhow::Result<()> {
+        let parsed_txs = txs
+            .par_iter()
+            .filter(|tx| !tx.is_coin_base())
+            .map(|tx| Self::parse_tx(height, tx, prevouts))
+            .collect::<anyhow::Result<Vec<_>>>()?;
 
-            let inputs_cum = InscriptionSearcher::calc_offsets(tx, prevouts)
-                .expect("failed to find all txos to calculate offsets");
+        let mut transfers = vec![];
 
-            for (idx, txin) in tx.input.iter().enumerate() {
+        for ParsedTx {
+            tx,
+            txid,
+            inputs_cum,
+            inscriptions,
+        } in parsed_txs
+        {
+            for ((idx, txin), incs) in tx.input.iter().enumerate().zip(inscriptions) {
                 transfers.extend(
                     token_cache
                         .valid_transfers
@@ -66,24 +143,17 @@ impl InitialIndexer {
                             location,
                             prevouts
                                 .get(&txin.previous_output)
-                                .unwrap()
+                                .anyhow_with("Prevout of a transfer is not found")?
                                 .script_pubkey
                                 .compute_script_hash(),
-                            tx.txid(),
+                            txid,
                             0,
                         );
                     }
                 }
 
-                for inc in Self::parse_inscriptions(ParseInscription {
-                    tx,
-                    input_idx: idx,
-                    inscription_idx: &mut inscription_idx,
-                    inputs_cum: &inputs_cum,
-                }) {
-                    if inc.genesis.index == 0
-                        || height as usize >= *MULTIPLE_INPUT_BEL_20_ACTIVATION_HEIGHT
-                    {
+                for inc in incs {
+                    if inc.genesis.index == 0 || PROTOCOL.is_multiple_input_active(height) {
                         if let Some(proto) = token_cache.parse_token_action(&inc, height, created) {
                             transfers.push((
                                 inc.location,
@@ -94,47 +164,107 @@ impl InitialIndexer {
                 }
             }
         }
+
+        Ok(())
     }
 
+    /// Fetches the block, indexes it and commits it for readers.
+    /// If it fails, the fault is recorded and readers keep the last committed block
     pub async fn handle(
         block_height: u32,
+        block_hash: BlockHash,
+        server: Arc<Server>,
+        reorg_cache: Option<Arc<parking_lot::Mutex<crate::reorg::ReorgCache>>>,
+    ) -> anyhow::Result<()> {
+        let timer = metrics::INDEXER_STAGE_SECONDS
+            .with_label_values(&["fetch"])
+            .start_timer();
+        let block = match server.client.get_block(&block_hash).await {
+            Ok(block) => block,
+            Err(e) => return Err(Self::failed(&server, block_height, block_hash, None, e)),
+        };
+        timer.observe_duration();
+
+        Self::handle_block(block_height, block, server, reorg_cache).await
+    }
+
+    /// Indexes the fetched block and commits it for readers, like `handle`
+    pub(crate) async fn handle_block(
+        block_height: u32,
+        block: bellscoin::Block,
         server: Arc<Server>,
         reorg_cache: Option<Arc<parking_lot::Mutex<crate::reorg::ReorgCache>>>,
     ) -> anyhow::Result<()> {
-        let current_hash = server.client.get_block_hash(block_height).await?;
-        let mut last_history_id = server.db.last_history_id.get(()).unwrap_or_default();
+        let block_hash = block.block_hash();
+
+        // Spawned so that panics stop the indexing at the block too
+        let result = Self::index_block(block_height, block, server.clone(), reorg_cache.clone())
+            .spawn()
+            .await
+            .unwrap_or_else(|e| Err(fault::join_error(e)))
+            .and_then(|block| Self::write(&server, block_height, reorg_cache.as_deref(), block));
+
+        match result {
+            Ok(()) => server.prune_event_log(),
+            Err(e) => Err(Self::failed(
+                &server,
+                block_height,
+                block_hash,
+                reorg_cache.as_deref(),
+                e,
+            )),
+        }
+    }
+
+    /// Drops the staged changes of the block that failed and records the fault
+    fn failed(
+        server: &Server,
+        block_height: u32,
+        block_hash: BlockHash,
+        reorg_cache: Option<&parking_lot::Mutex<crate::reorg::ReorgCache>>,
+        e: anyhow::Error,
+    ) -> anyhow::Error {
+        // Nothing of the block is written, so its staged changes are dropped
+        server.holders.discard();
+        if let Some(cache) = reorg_cache {
+            cache.lock().discard(block_height);
+        }
+
+        if server.token.is_cancelled() {
+            // Interrupted by the shutdown
+            e
+        } else {
+            fault::record(server, block_height, block_hash, e)
+        }
+    }
+
+    /// Checks and processes the whole block, staging it with `last_block` and its events in one batch
+    async fn index_block(
+        block_height: u32,
+        block: bellscoin::Block,
+        server: Arc<Server>,
+        reorg_cache: Option<Arc<parking_lot::Mutex<crate::reorg::ReorgCache>>>,
+    ) -> anyhow::Result<IndexedBlock> {
+        let stage = |name| metrics::INDEXER_STAGE_SECONDS.with_label_values(&[name]);
+
+        let current_hash = block.block_hash();
+        let mut last_history_id = server.db.last_history_id.get(())?.unwrap_or_default();
 
         if let Some(cache) = reorg_cache.as_ref() {
             cache.lock().new_block(block_height, last_history_id);
         }
 
-        server.db.block_hashes.set(block_height, current_hash);
+        let mut batch = DbBatch::default();
+        batch.put(&server.db.block_hashes, block_height, current_hash);
 
         if reorg_cache.is_some() {
             debug!("Syncing block: {} ({})", current_hash, block_height);
         }
 
-        let block = server.client.get_block(&current_hash).await?;
         let created = block.header.time;
 
-        match server.addr_tx.send(server::threads::AddressesToLoad {
-            height: block_height,
-            addresses: block
-                .txdata
-                .iter()
-                .flat_map(|x| &x.output)
-                .map(|x| x.script_pubkey.clone())
-                .collect(),
-        }) {
-            Ok(_) => {}
-            _ => {
-                if !server.token.is_cancelled() {
-                    panic!("Failed to send addresses to load");
-                }
-            }
-        }
-
-        let prevouts = block
+        let timer = stage("prevouts").start_timer();
+        let outputs = block
             .txdata
             .iter()
             .flat_map(|x| {
@@ -149,127 +279,195 @@ impl InitialIndexer {
                     )
                 })
             })
-            .filter(|x| !x.1.script_pubkey.is_provably_unspendable());
+            .filter(|x| !x.1.script_pubkey.is_provably_unspendable())
+            .collect::<HashMap<_, _>>();
 
-        server.db.prevouts.extend(prevouts);
+        batch.extend(&server.db.prevouts, outputs.iter().map(|(k, v)| (k, *v)));
 
-        if block_height < *START_HEIGHT {
-            server.db.last_block.set((), block_height);
-            return Ok(());
+        if !PROTOCOL.is_indexed(block_height) {
+            batch.put(&server.db.last_block, (), block_height);
+            return Ok(IndexedBlock {
+                batch,
+                events: vec![],
+            });
         }
 
         if block.txdata.len() == 1 {
-            server.db.last_block.set((), block_height);
-            return server.new_hash(block_height, current_hash, &[]).await;
+            let proof = server.proof_of_history(block_height, &[], &HashMap::new())?;
+            batch.put(&server.db.proof_of_history, block_height, proof);
+            batch.put(&server.db.last_block, (), block_height);
+            return Ok(IndexedBlock {
+                batch,
+                events: vec![ServerEvent::NewBlock(block_height, proof, current_hash)],
+            });
         }
 
         let mut token_cache = TokenCache::default();
-        let prevouts = utils::load_prevouts_for_block(server.db.clone(), &block.txdata)?;
+        let prevouts = utils::load_prevouts_for_block(&server.db, &block.txdata, &outputs)?;
 
         if let Some(cache) = reorg_cache.as_ref() {
-            prevouts.iter().for_each(|(key, value)| {
-                cache.lock().removed_prevout(*key, value.clone());
-            });
+            let mut cache = cache.lock();
+            for (key, value) in &prevouts {
+                cache.removed_prevout(*key, value.clone())?;
+            }
         }
 
-        token_cache.valid_transfers.extend(
-            server.db.load_transfers(
-                prevouts
-                    .iter()
-                    .map(|(k, v)| AddressLocation {
-                        address: v.script_pubkey.compute_script_hash(),
-                        location: Location {
-                            outpoint: *k,
-                            offset: 0,
-                        },
-                    })
-                    .collect(),
-            ),
-        );
-
-        Self::parse_block(
-            block_height,
-            created,
-            &block.txdata,
-            &prevouts,
-            &mut token_cache,
+        let transfers = server.db.load_transfers(
+            prevouts
+                .iter()
+                .map(|(k, v)| AddressLocation {
+                    address: v.script_pubkey.compute_script_hash(),
+                    location: Location {
+                        outpoint: *k,
+                        offset: 0,
+                    },
+                })
+                .collect(),
+        )?;
+        // Transfers left unspent by the block are written back with the valid transfers
+        batch.remove_batch(
+            &server.db.address_location_to_transfer,
+            transfers
+                .iter()
+                .map(|(location, (address, _))| AddressLocation {
+                    address: *address,
+                    location: *location,
+                }),
         );
-
+        token_cache.valid_transfers.extend(transfers);
+        timer.observe_duration();
+
+        let timer = stage("parse").start_timer();
+        // Parsed by the rayon pool, waited for off the runtime threads
+        let (block, prevouts, mut token_cache) = tokio::task::spawn_blocking(move || {
+            Self::parse_block(
+                block_height,
+                created,
+                &block.txdata,
+                &prevouts,
+                &mut token_cache,
+            )
+            .map(|()| (block, prevouts, token_cache))
+        })
+        .await
+        .map_err(fault::join_error)??;
+        timer.observe_duration();
+
+        let timer = stage("tokens").start_timer();
         token_cache.load_tokens_data(&server.db)?;
 
-        let history = token_cache
-            .process_token_actions(reorg_cache.clone(), &server.holders)
-            .into_iter()
-            .flat_map(|action| {
+        let actions = token_cache.process_token_actions(
+            reorg_cache.clone(),
+            &server.holders,
+            block_height,
+        )?;
+
+        let mut history = vec![];
+        for action in actions {
+            last_history_id += 1;
+            let mut results: Vec<(AddressTokenId, HistoryValue)> = vec![];
+            let token = action.tick();
+            let recipient = action.recipient();
+            let key = AddressTokenId {
+                address: recipient,
+                token,
+                id: last_history_id,
+            };
+            let db_action = TokenHistoryDB::from_token_history(action.clone());
+            if let TokenHistoryDB::Send {
+                amt, txid, vout, ..
+            } = db_action
+            {
+                let sender = action.sender().anyhow_with("Send action has no sender")?;
                 last_history_id += 1;
-                let mut results: Vec<(AddressTokenId, HistoryValue)> = vec![];
-                let token = action.tick();
-                let recipient = action.recipient();
-                let key = AddressTokenId {
-                    address: recipient,
-                    token,
-                    id: last_history_id,
-                };
-                let db_action = TokenHistoryDB::from_token_history(action.clone());
-                if let TokenHistoryDB::Send {
-                    amt, txid, vout, ..
-                } = db_action
-                {
-                    let sender = action
-                        .sender()
-                        .expect("Should be in here with the Send action");
-                    last_history_id += 1;
-                    results.extend([
-                        (
-                            AddressTokenId {
-                                address: sender,
-                                token,
-                                id: last_history_id,
-                            },
-                            HistoryValue {
-                                height: block_height,
-                                action: db_action,
-                            },
-                        ),
-                        (
-                            key,
-                            HistoryValue {
-                                height: block_height,
-                                action: TokenHistoryDB::Receive {
-                                    amt,
-                                    sender,
-                                    txid,
-                                    vout,
-                                },
-                            },
-                        ),
-                    ])
-                } else {
-                    results.push((
-                        key,
+                results.extend([
+                    (
+                        AddressTokenId {
+                            address: sender,
+                            token,
+                            id: last_history_id,
+                        },
                         HistoryValue {
+                            height: block_height,
                             action: db_action,
+                        },
+                    ),
+                    (
+                        key,
+                        HistoryValue {
                             height: block_height,
+                            action: TokenHistoryDB::Receive {
+                                amt,
+                                sender,
+                                txid,
+                                vout,
+                            },
                         },
-                    ));
-                }
-                match server.raw_event_sender.send(results.clone()) {
-                    Ok(_) => {}
-                    _ => {
-                        if !server.token.is_cancelled() {
-                            panic!("Failed to send raw event");
-                        }
-                    }
-                }
-                results
+                    ),
+                ])
+            } else {
+                results.push((
+                    key,
+                    HistoryValue {
+                        action: db_action,
+                        height: block_height,
+                    },
+                ));
+            }
+            history.extend(results);
+        }
+
+        batch.remove_batch(&server.db.prevouts, prevouts.keys());
+
+        let referenced = history
+            .iter()
+            .flat_map(|(k, v)| [Some(k.address), v.action.address().copied()])
+            .flatten()
+            .collect_vec();
+        let new_addresses = Self::addresses(
+            &block.txdata,
+            &prevouts,
+            referenced
+                .iter()
+                .copied()
+                .chain(token_cache.rejected.iter().map(|x| x.owner)),
+        );
+
+        // The proof renders the history with the addresses the block is about to write
+        let mut addresses = server.load_addresses(referenced)?;
+        addresses.extend(new_addresses.iter().cloned());
+        let proof = server.proof_of_history(block_height, &history, &addresses)?;
+
+        batch.extend(&server.db.fullhash_to_address, new_addresses);
+        batch.put(&server.db.proof_of_history, block_height, proof);
+
+        // History is logged before the block, both are written with it
+        let mut events = history
+            .iter()
+            .map(|(k, v)| {
+                ServerEvent::NewHistory(
+                    server::AddressTokenIdEvent {
+                        address: addresses
+                            .get(&k.address)
+                            .cloned()
+                            .unwrap_or_else(|| NON_STANDARD_ADDRESS.to_string()),
+                        token: k.token,
+                        id: k.id,
+                    },
+                    server::HistoryValueEvent::into_event(v.clone(), &addresses),
+                )
             })
             .collect_vec();
+        events.push(ServerEvent::NewBlock(block_height, proof, current_hash));
 
         if let Some(reorg_cache) = reorg_cache.as_ref() {
             let mut cache = reorg_cache.lock();
-            history
-                .iter()
-                .for_each(|(k, _)| cache.added_history(k.clone()));
+            for (k, _) in &history {
+                cache.added_history(k.clone())?;
+            }
+            for x in &token_cache.rejected {
+                cache.added_rejection(x.into())?;
+            }
         };
 
         {
@@ -278,26 +476,73 @@ impl InitialIndexer {
                 .map(|x| x.0.clone())
                 .sorted_unstable_by_key(|x| x.id)
                 .collect_vec();
-            server.db.block_events.set(block_height, new_keys);
+            batch.put(&server.db.block_events, block_height, new_keys);
 
             let keys = history.iter().map(|x| (x.1.action.outpoint(), x.0.clone()));
-            server.db.outpoint_to_event.extend(keys)
+            batch.extend(&server.db.outpoint_to_event, keys);
         }
 
-        server
-            .new_hash(block_height, current_hash, &history)
-            .await?;
+        batch.extend(&server.db.address_token_to_history, history);
 
-        server.db.address_token_to_history.extend(history);
+        token_cache.write_token_data(&server.db, &mut batch);
+        token_cache.write_rejected(&server.db, &mut batch);
+        token_cache.write_valid_transfers(&server.db, &mut batch);
 
-        token_cache.write_token_data(server.db.clone()).await?;
-        token_cache.write_valid_transfers(&server.db)?;
+        batch.put(&server.db.last_block, (), block_height);
+        batch.put(&server.db.last_history_id, (), last_history_id);
+        timer.observe_duration();
+
+        Ok(IndexedBlock { batch, events })
+    }
 
-        server.db.last_block.set((), block_height);
-        server.db.last_history_id.set((), last_history_id);
+    /// Writes the block with its changes to the reorg cache and its events, then commits it
+    fn write(
+        server: &Server,
+        block_height: u32,
+        reorg_cache: Option<&parking_lot::Mutex<crate::reorg::ReorgCache>>,
+        IndexedBlock { mut batch, events }: IndexedBlock,
+    ) -> anyhow::Result<()> {
+        let timer = metrics::INDEXER_STAGE_SECONDS
+            .with_label_values(&["write"])
+            .start_timer();
+
+        if let Some(cache) = reorg_cache {
+            cache.lock().write(&server.db, &mut batch)?;
+        }
+        server.write_block(block_height, batch, events)?;
+        timer.observe_duration();
+
+        Self::block_indexed(block_height);
         Ok(())
     }
 
+    /// Addresses of the `keys` paid by outputs of the block or spent by it.
+    /// Others are referenced by earlier blocks, which have written them
+    fn addresses(
+        txs: &[Transaction],
+        prevouts: &HashMap<OutPoint, TxOut>,
+        keys: impl IntoIterator<Item = FullHash>,
+    ) -> Vec<(FullHash, String)> {
+        let keys = keys
+            .into_iter()
+            .filter(|x| !x.is_op_return_hash())
+            .collect::<HashSet<_>>();
+
+        txs.iter()
+            .flat_map(|x| &x.output)
+            .chain(prevouts.values())
+            .map(|x| (x.script_pubkey.compute_script_hash(), &x.script_pubkey))
+            .filter(|(hash, _)| keys.contains(hash))
+            .unique_by(|(hash, _)| *hash)
+            .filter_map(|(hash, script)| script.to_address_str(*NETWORK).map(|x| (hash, x)))
+            .collect()
+    }
+
+    fn block_indexed(height: u32) {
+        metrics::INDEXER_HEIGHT.set(height as i64);
+        metrics::INDEXED_BLOCKS.inc();
+    }
+
     fn parse_inscriptions(payload: ParseInscription) -> Vec<InscriptionTemplate> {
         let mut result = vec![];
 
@@ -308,7 +553,7 @@ impl InitialIndexer {
                 ParsedInscription::Complete(inscription) => {
                     let genesis = {
                         InscriptionId {
-                            txid: payload.tx.txid(),
+                            txid: payload.txid,
                             index: *payload.inscription_idx,
                         }
                     };
@@ -316,17 +561,21 @@ impl InitialIndexer {
                     *payload.inscription_idx += 1;
 
                     let content_type = inscription.content_type().map(|x| x.to_owned());
+                    let content_encoding = inscription.content_encoding.clone();
 
-                    let pointer = inscription.pointer();
+                    let pointer = inscription
+                        .pointer()
+                        .filter(|_| PROTOCOL.is_pointer_active(payload.height));
 
                     let mut inc = InscriptionTemplate {
                         content: inscription.into_body(),
                         content_type,
+                        content_encoding,
                         genesis,
                         location: Location {
                             offset: 0,
                             outpoint: OutPoint {
-                                txid: payload.tx.txid(),
+                                txid: payload.txid,
                                 vout: payload.input_idx as u32,
                             },
                         },
@@ -351,7 +600,7 @@ impl InitialIndexer {
 
                     let location: Location = Location {
                         outpoint: OutPoint {
-                            txid: payload.tx.txid(),
+                            txid: payload.txid,
                             vout,
                         },
                         offset,
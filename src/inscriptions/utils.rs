@@ -20,33 +20,33 @@ impl ScriptToAddr for &bellscoin::ScriptBuf {
     }
 }
 
+/// Outputs spent by the block, including the ones it creates in `outputs`, which aren't written yet
 pub fn load_prevouts_for_block(
-    db: Arc<DB>,
+    db: &DB,
     txs: &[Transaction],
+    outputs: &HashMap<OutPoint, &TxOut>,
 ) -> anyhow::Result<HashMap<OutPoint, TxOut>> {
-    let txids_keys = txs
+    let (created, stored): (Vec<_>, Vec<_>) = txs
         .iter()
         .skip(1)
         .flat_map(|x| x.input.iter().map(|x| x.previous_output))
         .unique()
-        .collect_vec();
+        .partition(|x| outputs.contains_key(x));
 
-    if txids_keys.is_empty() {
-        return Ok(HashMap::new());
-    }
-
-    let prevouts = db
+    let mut prevouts = db
         .prevouts
-        .multi_get(txids_keys.iter())
+        .multi_get(stored.iter())?
         .into_iter()
-        .zip(txids_keys.clone())
+        .zip(stored)
         .map(|(v, k)| v.map(|x| (k, x)))
         .collect::<Option<HashMap<_, _>>>()
         .anyhow_with("Some prevouts are missing")?;
 
-    std::thread::spawn(move || {
-        db.prevouts.remove_batch(txids_keys.iter());
-    });
+    prevouts.extend(
+        created
+            .into_iter()
+            .filter_map(|k| outputs.get(&k).map(|v| (k, (*v).clone()))),
+    );
 
     Ok(prevouts)
 }
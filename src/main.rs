@@ -15,30 +15,34 @@ use {
         hashes::{sha256, Hash},
         opcodes, script, BlockHash, Network, OutPoint, Transaction, TxOut, Txid,
     },
-    db::{RocksDB, RocksTable, UsingConsensus, UsingSerde},
+    clap::Parser,
+    db::{DbBatch, RocksDB, Table, UsingConsensus, UsingSerde},
     dutils::{
         async_thread::Spawn,
         error::{ApiError, ContextWrapper},
         wait_token::WaitToken,
     },
     futures::future::join_all,
-    inscriptions::{Location, ScriptToAddr},
+    inscriptions::Location,
     itertools::Itertools,
     lazy_static::lazy_static,
     num_traits::Zero,
     serde::{Deserialize, Deserializer, Serialize, Serializer},
     serde_with::{serde_as, DisplayFromStr},
-    server::{Server, ServerEvent},
+    server::{EventLogGap, SequencedEvent, Server, ServerEvent},
     std::{
         borrow::{Borrow, Cow},
-        collections::{BTreeMap, BTreeSet, HashMap, HashSet},
+        collections::{BTreeMap, BTreeSet, HashMap, HashSet, VecDeque},
         fmt::{Display, Formatter},
         future::IntoFuture,
         iter::Peekable,
         marker::PhantomData,
         ops::{Bound, RangeBounds},
         str::FromStr,
-        sync::{atomic::AtomicU64, Arc},
+        sync::{
+            atomic::{AtomicBool, AtomicU64, Ordering},
+            Arc,
+        },
         time::{Duration, Instant},
     },
     tables::DB,
@@ -46,22 +50,30 @@ use {
     tracing::info,
     tracing_indicatif::span_ext::IndicatifSpanExt,
     utils::AsyncClient,
+    webhooks::{DeadLetter, Webhook, WebhookEvent},
 };
 
+mod checkpoints;
+mod commands;
+mod config;
 mod db;
+mod fault;
 mod inscriptions;
+mod metrics;
+mod protocol;
 mod reorg;
 mod rest;
+mod server;
+mod sinks;
 mod tables;
+#[cfg(test)]
+mod test_utils;
 mod tokens;
-#[macro_use]
 mod utils;
-mod server;
+mod webhooks;
 
 pub type Fixed128 = nintypes::utils::fixed::Fixed128<18>;
 
-const MAINNET_START_HEIGHT: u32 = 26_371;
-
 const OP_RETURN_ADDRESS: &str = "BURNED";
 const NON_STANDARD_ADDRESS: &str = "non-standard";
 
@@ -80,42 +92,88 @@ impl IsOpReturnHash for FullHash {
 }
 
 lazy_static! {
-    static ref URL: String = load_env!("RPC_URL");
-    static ref USER: String = load_env!("RPC_USER");
-    static ref PASS: String = load_env!("RPC_PASS");
-    static ref NETWORK: Network = load_opt_env!("NETWORK")
-        .map(|x| Network::from_str(&x).unwrap())
-        .unwrap_or(Network::Bellscoin);
-    static ref MULTIPLE_INPUT_BEL_20_ACTIVATION_HEIGHT: usize = if let Network::Bellscoin = *NETWORK
-    {
-        133_000
-    } else {
-        0
-    };
-    static ref START_HEIGHT: u32 = match *NETWORK {
-        Network::Bellscoin => MAINNET_START_HEIGHT,
-        _ => 0,
-    };
-    static ref SERVER_URL: String =
-        load_opt_env!("SERVER_BIND_URL").unwrap_or("0.0.0.0:8000".to_string());
+    static ref CONFIG: &'static config::Config = config::Config::get();
+    static ref NETWORK: Network = CONFIG.network;
+    static ref PROTOCOL: protocol::ProtocolRules =
+        protocol::ProtocolRules::load(*NETWORK, CONFIG.protocol_rules.as_deref()).unwrap();
     static ref DEFAULT_HASH: sha256::Hash = sha256::Hash::hash("null".as_bytes());
 }
 
 #[tokio::main]
 async fn main() {
-    dotenv::dotenv().unwrap();
-    utils::init_logger();
+    if let Err(e) = dotenv::dotenv() {
+        if !e.not_found() {
+            eprintln!("Failed to load .env: {e}");
+            std::process::exit(1);
+        }
+    }
+
+    let cli = config::Cli::parse();
+    let command = cli.command.clone().unwrap_or(config::Command::Run);
+
+    let config = config::Config::load(&cli)
+        .and_then(|config| {
+            config.validate(!matches!(
+                command,
+                config::Command::Export { .. } | config::Command::Restore { .. }
+            ))?;
+            Ok(config)
+        })
+        .unwrap_or_else(|e| {
+            eprintln!("Invalid config: {e:#}");
+            std::process::exit(2);
+        });
+
+    utils::init_logger(&config.logging);
+    config.install();
+
+    let result = match command {
+        config::Command::Run => run().await.and_then(|_| {
+            if checkpoints::is_restore_scheduled() {
+                checkpoints::restart()?;
+            }
+            Ok(())
+        }),
+        config::Command::Verify => commands::verify().await,
+        config::Command::Rollback { to_height } => commands::rollback(to_height).await,
+        config::Command::Restore { height } => checkpoints::restore(height),
+        config::Command::Export { data, output } => commands::export(data, output),
+    };
+
+    if let Err(e) = result {
+        error!("{e:#}");
+        std::process::exit(1);
+    }
+}
 
-    let (addr_rx, raw_event_tx, event_tx, server) = Server::new("rocksdb").await.unwrap();
+async fn run() -> anyhow::Result<()> {
+    let db = match CONFIG.secondary.path.as_ref() {
+        Some(secondary_path) => {
+            warn!("Serve-only mode, following the db at {}", CONFIG.db_path);
+            DB::open_secondary(&CONFIG.db_path, secondary_path)
+        }
+        None => {
+            checkpoints::restore_scheduled()?;
+            DB::open(&CONFIG.db_path)
+        }
+    };
+
+    let server = Server::new(db).await?;
 
     let server = Arc::new(server);
 
     let signal_handler = {
         let token = server.token.clone();
         async move {
-            tokio::signal::ctrl_c().await.track().ok();
-            warn!("Ctrl-C received, shutting down...");
-            token.cancel();
+            tokio::select! {
+                v = tokio::signal::ctrl_c() => {
+                    v.track().ok();
+                    warn!("Ctrl-C received, shutting down...");
+                    token.cancel();
+                }
+                // Shut down by the indexer
+                _ = token.cancelled() => {}
+            }
             anyhow::Result::Ok(())
         }
         .spawn()
@@ -123,27 +181,34 @@ async fn main() {
 
     let server1 = server.clone();
 
-    let result = join_all([
-        signal_handler,
-        server1
-            .run_threads(server.token.clone(), addr_rx, raw_event_tx, event_tx)
-            .spawn(),
-        run_rest(server.token.clone(), server.clone()).spawn(),
-        inscriptions::main_loop(server.token.clone(), server.clone()).spawn(),
-    ])
-    .await;
-
-    let _: Vec<_> = result
+    let tasks = if server.db.is_secondary() {
+        vec![
+            signal_handler,
+            server1.run_serve_only_threads(server.token.clone()).spawn(),
+            run_rest(server.token.clone(), server.clone()).spawn(),
+        ]
+    } else {
+        vec![
+            signal_handler,
+            server1.run_threads(server.token.clone()).spawn(),
+            run_rest(server.token.clone(), server.clone()).spawn(),
+            inscriptions::main_loop(server.token.clone(), server.clone()).spawn(),
+        ]
+    };
+
+    let result = join_all(tasks).await;
+
+    result
         .into_iter()
-        .collect::<Result<anyhow::Result<Vec<()>>, _>>()
-        .track()
-        .unwrap()
-        .track()
-        .unwrap();
+        .collect::<Result<anyhow::Result<Vec<()>>, _>>()??;
+
+    Ok(())
 }
 
 async fn run_rest(token: WaitToken, server: Arc<Server>) -> anyhow::Result<()> {
-    let listener = tokio::net::TcpListener::bind(&*SERVER_URL).await.unwrap();
+    let listener = tokio::net::TcpListener::bind(&CONFIG.server.bind)
+        .await
+        .unwrap();
 
     let rest = axum::serve(listener, rest::get_router(server))
         .with_graceful_shutdown(token.cancelled())
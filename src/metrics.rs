@@ -0,0 +1,100 @@
+use prometheus::{
+    exponential_buckets, register_histogram, register_histogram_vec, register_int_counter,
+    register_int_counter_vec, register_int_gauge, register_int_gauge_vec, Encoder, Histogram,
+    HistogramVec, IntCounter, IntCounterVec, IntGauge, IntGaugeVec, TextEncoder,
+};
+
+use super::*;
+
+lazy_static! {
+    pub static ref INDEXER_HEIGHT: IntGauge =
+        register_int_gauge!("bel20_indexer_height", "Height of the last indexed block").unwrap();
+    pub static ref NODE_TIP_HEIGHT: IntGauge =
+        register_int_gauge!("bel20_node_tip_height", "Height of the node best block").unwrap();
+    pub static ref INDEXED_BLOCKS: IntCounter =
+        register_int_counter!("bel20_indexed_blocks_total", "Count of indexed blocks").unwrap();
+    pub static ref INDEXER_STAGE_SECONDS: HistogramVec = register_histogram_vec!(
+        "bel20_indexer_stage_seconds",
+        "Time spent in a stage of block indexing",
+        &["stage"],
+        exponential_buckets(0.0005, 2.0, 16).unwrap()
+    )
+    .unwrap();
+    pub static ref DB_TABLE_SIZE_BYTES: IntGaugeVec = register_int_gauge_vec!(
+        "bel20_db_table_size_bytes",
+        "Size of the SST files of a RocksDB column family",
+        &["table"]
+    )
+    .unwrap();
+    pub static ref REORGS: IntCounter =
+        register_int_counter!("bel20_reorgs_total", "Count of detected reorgs").unwrap();
+    pub static ref REORG_DEPTH: Histogram = register_histogram!(
+        "bel20_reorg_depth_blocks",
+        "Count of blocks rolled back by a reorg",
+        vec![1.0, 2.0, 3.0, 5.0, 8.0, 13.0, 21.0, 30.0]
+    )
+    .unwrap();
+    pub static ref DEEP_REORGS: IntCounter = register_int_counter!(
+        "bel20_deep_reorgs_total",
+        "Count of reorgs deeper than the reorg cache, handled by restoring a checkpoint"
+    )
+    .unwrap();
+    pub static ref CHECKPOINT_HEIGHT: IntGauge = register_int_gauge!(
+        "bel20_checkpoint_height",
+        "Height of the last created RocksDB checkpoint"
+    )
+    .unwrap();
+    pub static ref INDEXER_FAULT_HEIGHT: IntGauge = register_int_gauge!(
+        "bel20_indexer_fault_height",
+        "Height of the block indexing stopped at, 0 while indexing"
+    )
+    .unwrap();
+    pub static ref EVENTS_LAGGED: IntCounterVec = register_int_counter_vec!(
+        "bel20_events_lagged_total",
+        "Count of times events were dropped and replayed from the event log",
+        &["receiver"]
+    )
+    .unwrap();
+    pub static ref EVENT_SUBSCRIBERS: IntGauge = register_int_gauge!(
+        "bel20_event_subscribers",
+        "Count of connected event subscribers"
+    )
+    .unwrap();
+    pub static ref EVENT_SUBSCRIBER_DISCONNECTS: IntCounter = register_int_counter!(
+        "bel20_event_subscriber_disconnects_total",
+        "Count of event subscribers that disconnected"
+    )
+    .unwrap();
+    pub static ref RPC_RETRIES: IntCounterVec = register_int_counter_vec!(
+        "bel20_rpc_retries_total",
+        "Count of retried node RPC requests",
+        &["method"]
+    )
+    .unwrap();
+    pub static ref RPC_ENDPOINT_SWITCHES: IntCounter = register_int_counter!(
+        "bel20_rpc_endpoint_switches_total",
+        "Count of switches to another node RPC endpoint"
+    )
+    .unwrap();
+    pub static ref REST_REQUEST_SECONDS: HistogramVec = register_histogram_vec!(
+        "bel20_rest_request_seconds",
+        "Latency of REST requests",
+        &["method", "route", "status"],
+        exponential_buckets(0.0005, 2.0, 16).unwrap()
+    )
+    .unwrap();
+}
+
+/// Metrics in the Prometheus text format. Table sizes are read from RocksDB when scraped.
+pub fn gather(server: &Server) -> anyhow::Result<String> {
+    for (table, size) in server.db.table_sizes() {
+        DB_TABLE_SIZE_BYTES
+            .with_label_values(&[table])
+            .set(size as i64);
+    }
+
+    let mut buffer = vec![];
+    TextEncoder::new().encode(&prometheus::gather(), &mut buffer)?;
+
+    Ok(String::from_utf8(buffer)?)
+}
@@ -0,0 +1,96 @@
+use super::*;
+
+const MAINNET_START_HEIGHT: u32 = 26_371;
+const MAINNET_MULTIPLE_INPUT_ACTIVATION_HEIGHT: u32 = 133_000;
+
+/// Protocol activation heights and feature switches.
+/// Defaults are defined per network, any field can be overridden from a JSON file (`PROTOCOL_RULES` env).
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct ProtocolRules {
+    /// Height from which bel-20 actions are indexed
+    pub start_height: u32,
+    /// Height from which inscriptions other than the first one in a transaction are bel-20 actions
+    pub multiple_input_activation_height: u32,
+    /// Height from which the pointer tag moves inscriptions to the pointed output.
+    /// `None` if pointer is never supported
+    pub pointer_activation_height: Option<u32>,
+    /// Height from which inscriptions with the content encoding tag are not bel-20 actions.
+    /// `None` if content encoding is ignored
+    pub content_encoding_activation_height: Option<u32>,
+    /// Height from which ticks may be longer than 4 bytes (up to 32 bytes of printable UTF-8).
+    /// `None` if only 4 byte ticks are valid
+    pub long_tick_activation_height: Option<u32>,
+}
+
+impl ProtocolRules {
+    pub fn for_network(network: Network) -> Self {
+        match network {
+            Network::Bellscoin => Self {
+                start_height: MAINNET_START_HEIGHT,
+                multiple_input_activation_height: MAINNET_MULTIPLE_INPUT_ACTIVATION_HEIGHT,
+                pointer_activation_height: Some(0),
+                content_encoding_activation_height: None,
+                long_tick_activation_height: None,
+            },
+            _ => Self {
+                start_height: 0,
+                multiple_input_activation_height: 0,
+                pointer_activation_height: Some(0),
+                content_encoding_activation_height: None,
+                long_tick_activation_height: None,
+            },
+        }
+    }
+
+    /// Network defaults with the fields from `path` on top
+    pub fn load(network: Network, path: Option<&str>) -> anyhow::Result<Self> {
+        let mut rules = serde_json::to_value(Self::for_network(network))?;
+
+        if let Some(path) = path {
+            let data = std::fs::read_to_string(path)
+                .anyhow_with(format!("Failed to read protocol rules from {path}"))?;
+            let overrides: serde_json::Map<String, serde_json::Value> =
+                serde_json::from_str(&data).anyhow_with("Invalid protocol rules")?;
+
+            for (k, v) in overrides {
+                rules[k] = v;
+            }
+        }
+
+        serde_json::from_value(rules).anyhow_with("Invalid protocol rules")
+    }
+
+    pub fn is_indexed(&self, height: u32) -> bool {
+        height >= self.start_height
+    }
+
+    pub fn is_multiple_input_active(&self, height: u32) -> bool {
+        height >= self.multiple_input_activation_height
+    }
+
+    pub fn is_pointer_active(&self, height: u32) -> bool {
+        self.pointer_activation_height
+            .is_some_and(|activation| height >= activation)
+    }
+
+    pub fn is_content_encoding_active(&self, height: u32) -> bool {
+        self.content_encoding_activation_height
+            .is_some_and(|activation| height >= activation)
+    }
+
+    pub fn is_long_tick_active(&self, height: u32) -> bool {
+        self.long_tick_activation_height
+            .is_some_and(|activation| height >= activation)
+    }
+
+    pub fn is_tick_valid(&self, tick: &TokenTick, height: u32) -> bool {
+        if tick.len() == TokenTick::LEGACY_LEN {
+            return true;
+        }
+
+        self.is_long_tick_active(height)
+            && tick.len() > TokenTick::LEGACY_LEN
+            && tick.is_valid_long()
+    }
+}
@@ -1,8 +1,7 @@
 use super::*;
 
-pub const REORG_CACHE_MAX_LEN: usize = 30;
-
-enum TokenHistoryEntry {
+#[derive(Serialize, Deserialize)]
+pub enum TokenHistoryEntry {
     RemoveDeployed(TokenTick),
     /// Second arg `Fixed128` is amount of mint to remove. We need to decrease user balance + mint count + total supply of deploy
     RemoveMint(AddressToken, Fixed128),
@@ -12,10 +11,12 @@ enum TokenHistoryEntry {
     RestoreTransferred(AddressLocation, TransferProtoDB, FullHash),
     RemoveHistory(AddressTokenId),
     RestorePrevout(OutPoint, TxOut),
+    RemoveRejection(AddressRejection),
 }
 
-#[derive(Default)]
-struct ReorgHistoryBlock {
+/// Changes of a block to revert, saved with the block so the cache outlives restarts
+#[derive(Serialize, Deserialize, Default)]
+pub struct ReorgHistoryBlock {
     token_history: Vec<TokenHistoryEntry>,
     last_history_id: u64,
 }
@@ -38,52 +39,88 @@ impl ReorgCache {
     pub fn new() -> Self {
         Self {
             blocks: BTreeMap::new(),
-            len: REORG_CACHE_MAX_LEN,
+            len: CONFIG.indexer.reorg_depth,
         }
     }
 
+    /// Cache saved by the blocks written before
+    pub fn load(db: &DB) -> anyhow::Result<Self> {
+        Ok(Self {
+            blocks: db.reorg_blocks.iter().try_collect()?,
+            ..Self::new()
+        })
+    }
+
+    /// Forgets the saved blocks, when blocks are indexed without the cache
+    pub fn clear(&mut self, db: &DB) -> anyhow::Result<()> {
+        self.blocks.clear();
+        db.reorg_blocks.retain(|_, _| false)
+    }
+
     pub fn new_block(&mut self, block_height: u32, last_history_id: u64) {
-        if self.blocks.len() == self.len {
-            self.blocks.pop_first();
-        }
         self.blocks
             .insert(block_height, ReorgHistoryBlock::new(last_history_id));
     }
 
-    pub fn added_deployed_token(&mut self, tick: TokenTick) {
-        self.blocks
-            .last_entry()
-            .unwrap()
-            .get_mut()
-            .token_history
-            .push(TokenHistoryEntry::RemoveDeployed(tick));
+    /// Saves the last block with the batch writing it. Blocks beyond the reorg depth are dropped
+    pub fn write(&mut self, db: &DB, batch: &mut DbBatch) -> anyhow::Result<()> {
+        let (height, block) = self
+            .blocks
+            .last_key_value()
+            .anyhow_with("No block is started in the reorg cache")?;
+        batch.put(&db.reorg_blocks, *height, block);
+
+        while self.blocks.len() > self.len {
+            if let Some((height, _)) = self.blocks.pop_first() {
+                batch.delete(&db.reorg_blocks, height);
+            }
+        }
+
+        Ok(())
     }
 
-    pub fn added_minted_token(&mut self, token: AddressToken, amount: Fixed128) {
-        self.blocks
-            .last_entry()
-            .unwrap()
-            .get_mut()
-            .token_history
-            .push(TokenHistoryEntry::RemoveMint(token, amount));
+    /// Forgets the block if it's the last one, when it failed to be written
+    pub fn discard(&mut self, block_height: u32) {
+        if let Some(entry) = self.blocks.last_entry() {
+            if *entry.key() == block_height {
+                entry.remove();
+            }
+        }
     }
 
-    pub fn added_history(&mut self, key: AddressTokenId) {
+    /// Records the change made by the block started last with `new_block`
+    fn push(&mut self, entry: TokenHistoryEntry) -> anyhow::Result<()> {
         self.blocks
             .last_entry()
-            .unwrap()
+            .anyhow_with("No block is started in the reorg cache")?
             .get_mut()
             .token_history
-            .push(TokenHistoryEntry::RemoveHistory(key));
+            .push(entry);
+        Ok(())
     }
 
-    pub fn removed_prevout(&mut self, key: OutPoint, value: TxOut) {
-        self.blocks
-            .last_entry()
-            .unwrap()
-            .get_mut()
-            .token_history
-            .push(TokenHistoryEntry::RestorePrevout(key, value));
+    pub fn added_deployed_token(&mut self, tick: TokenTick) -> anyhow::Result<()> {
+        self.push(TokenHistoryEntry::RemoveDeployed(tick))
+    }
+
+    pub fn added_minted_token(
+        &mut self,
+        token: AddressToken,
+        amount: Fixed128,
+    ) -> anyhow::Result<()> {
+        self.push(TokenHistoryEntry::RemoveMint(token, amount))
+    }
+
+    pub fn added_history(&mut self, key: AddressTokenId) -> anyhow::Result<()> {
+        self.push(TokenHistoryEntry::RemoveHistory(key))
+    }
+
+    pub fn added_rejection(&mut self, key: AddressRejection) -> anyhow::Result<()> {
+        self.push(TokenHistoryEntry::RemoveRejection(key))
+    }
+
+    pub fn removed_prevout(&mut self, key: OutPoint, value: TxOut) -> anyhow::Result<()> {
+        self.push(TokenHistoryEntry::RestorePrevout(key, value))
     }
 
     pub fn added_transfer_token(
@@ -91,13 +128,8 @@ impl ReorgCache {
         location: Location,
         token: AddressToken,
         amount: Fixed128,
-    ) {
-        self.blocks
-            .last_entry()
-            .unwrap()
-            .get_mut()
-            .token_history
-            .push(TokenHistoryEntry::RemoveTransfer(location, token, amount));
+    ) -> anyhow::Result<()> {
+        self.push(TokenHistoryEntry::RemoveTransfer(location, token, amount))
     }
 
     pub fn removed_transfer_token(
@@ -105,22 +137,39 @@ impl ReorgCache {
         key: AddressLocation,
         value: TransferProtoDB,
         recipient: FullHash,
-    ) {
+    ) -> anyhow::Result<()> {
+        self.push(TokenHistoryEntry::RestoreTransferred(key, value, recipient))
+    }
+
+    /// Set if blocks from `block_height` can be rolled back
+    pub fn covers(&self, block_height: u32) -> bool {
         self.blocks
-            .last_entry()
-            .unwrap()
-            .get_mut()
-            .token_history
-            .push(TokenHistoryEntry::RestoreTransferred(key, value, recipient));
+            .first_key_value()
+            .is_some_and(|(first, _)| *first <= block_height)
     }
 
+    /// Reverts the blocks from `block_height`, the reorg is logged for subscribers first.
+    /// Each block is reverted in one batch, which also removes it from the saved cache
     pub fn restore(&mut self, server: &Server, block_height: u32) -> anyhow::Result<()> {
-        while !self.blocks.is_empty() && block_height <= *self.blocks.last_key_value().unwrap().0 {
-            let (height, data) = self.blocks.pop_last().anyhow()?;
+        let Some(last) = self.blocks.last_key_value().map(|x| *x.0) else {
+            return Ok(());
+        };
+        if last < block_height {
+            return Ok(());
+        }
+        server.send_event(ServerEvent::Reorg(last + 1 - block_height, block_height))?;
+
+        while let Some(entry) = self.blocks.last_entry() {
+            if *entry.key() < block_height {
+                break;
+            }
+            let (height, data) = entry.remove_entry();
 
-            server.db.last_block.set((), height - 1);
-            server.db.last_history_id.set((), data.last_history_id);
-            server.db.block_hashes.remove(height);
+            let mut batch = DbBatch::default();
+            batch.delete(&server.db.reorg_blocks, height);
+            batch.put(&server.db.last_block, (), height - 1);
+            batch.put(&server.db.last_history_id, (), data.last_history_id);
+            batch.delete(&server.db.block_hashes, height);
 
             {
                 let mut to_remove_deployed = vec![];
@@ -130,6 +179,7 @@ impl ReorgCache {
                 let mut to_restore_transferred = vec![];
                 let mut to_remove_history = vec![];
                 let mut to_restore_prevout = vec![];
+                let mut to_remove_rejection = vec![];
 
                 for entry in data.token_history.into_iter().rev() {
                     match entry {
@@ -156,24 +206,36 @@ impl ReorgCache {
                         TokenHistoryEntry::RestorePrevout(key, value) => {
                             to_restore_prevout.push((key, value));
                         }
+                        TokenHistoryEntry::RemoveRejection(key) => {
+                            to_remove_rejection.push(key);
+                        }
                     }
                 }
 
                 let keys_to_remove = server
                     .db
                     .address_token_to_history
-                    .multi_get(to_remove_history.iter())
+                    .multi_get(to_remove_history.iter())?
                     .into_iter()
                     .flatten()
                     .map(|x| x.action.outpoint());
 
-                server.db.outpoint_to_event.remove_batch(keys_to_remove);
-
-                server
+                batch.remove_batch(&server.db.outpoint_to_event, keys_to_remove);
+                batch.remove_batch(&server.db.address_token_to_history, to_remove_history);
+                batch.extend(&server.db.prevouts, to_restore_prevout);
+                let rejection_outpoints = server
                     .db
-                    .address_token_to_history
-                    .remove_batch(to_remove_history.into_iter());
-                server.db.prevouts.extend(to_restore_prevout.into_iter());
+                    .inscription_to_rejection
+                    .multi_get(to_remove_rejection.iter().map(|x| &x.genesis))?
+                    .into_iter()
+                    .flatten()
+                    .map(|x| OutPointRejection::from(&x));
+                batch.remove_batch(&server.db.outpoint_to_rejection, rejection_outpoints);
+                batch.remove_batch(
+                    &server.db.inscription_to_rejection,
+                    to_remove_rejection.iter().map(|x| x.genesis),
+                );
+                batch.remove_batch(&server.db.address_to_rejection, to_remove_rejection);
 
                 {
                     let deploy_keys = to_update_deployed
@@ -189,51 +251,62 @@ impl ReorgCache {
                     let deploys = server
                         .db
                         .token_to_meta
-                        .multi_get(deploy_keys.iter())
+                        .multi_get(deploy_keys.iter())?
                         .into_iter()
                         .zip(deploy_keys)
                         .map(|(v, k)| v.map(|x| (k, x)))
                         .collect::<Option<HashMap<_, _>>>()
                         .anyhow_with("Some of deploys is not found")?;
 
-                    let updated_values = to_update_deployed.into_iter().rev().map(|x| match x {
-                        DeployedUpdate::Mint(tick, amt) => {
-                            let mut meta = deploys.get(&tick.clone()).unwrap().clone();
-                            let DeployProtoDB {
-                                supply,
-                                mint_count,
-                                transactions,
-                                ..
-                            } = &mut meta.proto;
-                            *supply -= amt;
-                            *mint_count -= 1;
-                            *transactions -= 1;
-                            (tick, meta)
-                        }
-                        DeployedUpdate::Transfer(tick) => {
-                            let mut meta = deploys.get(&tick.clone()).unwrap().clone();
-                            let DeployProtoDB {
-                                transfer_count,
-                                transactions,
-                                ..
-                            } = &mut meta.proto;
-                            *transfer_count -= 1;
-                            *transactions -= 1;
-                            (tick, meta)
-                        }
-                        DeployedUpdate::Transferred(tick) => {
-                            let mut meta = deploys.get(&tick).unwrap().clone();
-                            let DeployProtoDB { transactions, .. } = &mut meta.proto;
-                            *transactions -= 1;
-                            (tick, meta)
-                        }
+                    let updated_values = to_update_deployed.into_iter().rev().map(|x| {
+                        anyhow::Ok(match x {
+                            DeployedUpdate::Mint(tick, amt) => {
+                                let mut meta = deploys
+                                    .get(&tick)
+                                    .anyhow_with("Deploy is not found")?
+                                    .clone();
+                                let DeployProtoDB {
+                                    supply,
+                                    mint_count,
+                                    transactions,
+                                    ..
+                                } = &mut meta.proto;
+                                *supply -= amt;
+                                *mint_count -= 1;
+                                *transactions -= 1;
+                                (tick, meta)
+                            }
+                            DeployedUpdate::Transfer(tick) => {
+                                let mut meta = deploys
+                                    .get(&tick)
+                                    .anyhow_with("Deploy is not found")?
+                                    .clone();
+                                let DeployProtoDB {
+                                    transfer_count,
+                                    transactions,
+                                    ..
+                                } = &mut meta.proto;
+                                *transfer_count -= 1;
+                                *transactions -= 1;
+                                (tick, meta)
+                            }
+                            DeployedUpdate::Transferred(tick) => {
+                                let mut meta = deploys
+                                    .get(&tick)
+                                    .anyhow_with("Deploy is not found")?
+                                    .clone();
+                                let DeployProtoDB { transactions, .. } = &mut meta.proto;
+                                *transactions -= 1;
+                                (tick, meta)
+                            }
+                        })
                     });
 
-                    server.db.token_to_meta.extend(updated_values);
-                    server
-                        .db
-                        .token_to_meta
-                        .remove_batch(to_remove_deployed.into_iter());
+                    batch.extend(
+                        &server.db.token_to_meta,
+                        updated_values.collect::<anyhow::Result<Vec<_>>>()?,
+                    );
+                    batch.remove_batch(&server.db.token_to_meta, to_remove_deployed);
                 }
 
                 let mut accounts = {
@@ -259,7 +332,7 @@ impl ReorgCache {
                     server
                         .db
                         .address_token_to_balance
-                        .multi_get(keys.iter())
+                        .multi_get(keys.iter())?
                         .into_iter()
                         .zip(keys)
                         .map(|(v, k)| v.map(|x| (k, x)))
@@ -269,27 +342,27 @@ impl ReorgCache {
 
                 {
                     for (key, amt) in to_remove_minted.into_iter().rev() {
-                        let account = accounts.get_mut(&key).unwrap();
+                        let account = accounts.get_mut(&key).anyhow_with("Account is not found")?;
                         server.holders.decrease(&key, account, amt);
                         account.balance = account.balance.checked_sub(amt).anyhow()?;
                     }
 
-                    let transfer_locations_to_remove = to_remove_transfer
-                        .into_iter()
-                        .map(|(location, address, amt)| {
-                            if let Some(x) = accounts.get_mut(&address) {
-                                x.balance += amt;
-                                x.transferable_balance =
-                                    x.transferable_balance.checked_sub(amt).expect("Overflow");
-                                x.transfers_count -= 1;
-                            };
+                    let mut transfer_locations_to_remove = HashSet::new();
+                    for (location, address, amt) in to_remove_transfer {
+                        if let Some(x) = accounts.get_mut(&address) {
+                            x.balance += amt;
+                            x.transferable_balance = x
+                                .transferable_balance
+                                .checked_sub(amt)
+                                .anyhow_with("Transferable balance underflow")?;
+                            x.transfers_count -= 1;
+                        };
 
-                            AddressLocation {
-                                address: address.address,
-                                location,
-                            }
-                        })
-                        .collect::<HashSet<_>>();
+                        transfer_locations_to_remove.insert(AddressLocation {
+                            address: address.address,
+                            location,
+                        });
+                    }
 
                     for (k, v, recipient) in &to_restore_transferred {
                         let key = AddressToken {
@@ -297,7 +370,7 @@ impl ReorgCache {
                             token: v.tick.into(),
                         };
 
-                        let account = accounts.get_mut(&key).unwrap();
+                        let account = accounts.get_mut(&key).anyhow_with("Account is not found")?;
 
                         server.holders.increase(&key, account, v.amt);
                         account.transferable_balance += v.amt;
@@ -309,42 +382,34 @@ impl ReorgCache {
                                 token: v.tick.into(),
                             };
 
-                            let account = accounts.get_mut(&key).unwrap();
+                            let account =
+                                accounts.get_mut(&key).anyhow_with("Account is not found")?;
 
                             server.holders.decrease(&key, account, v.amt);
                             account.balance = account.balance.checked_sub(v.amt).anyhow()?;
                         }
                     }
 
-                    server
-                        .db
-                        .address_token_to_balance
-                        .extend(accounts.into_iter());
-
-                    server.db.address_location_to_transfer.extend(
+                    batch.extend(&server.db.address_token_to_balance, accounts);
+                    batch.extend(
+                        &server.db.address_location_to_transfer,
                         to_restore_transferred
                             .into_iter()
                             .map(|x| (x.0, x.1))
                             .filter(|x| !transfer_locations_to_remove.contains(&x.0)),
                     );
-                    server
-                        .db
-                        .address_location_to_transfer
-                        .remove_batch(transfer_locations_to_remove.into_iter());
+                    batch.remove_batch(
+                        &server.db.address_location_to_transfer,
+                        transfer_locations_to_remove,
+                    );
                 }
             }
+
+            server.db.write(batch)?;
         }
 
         Ok(())
     }
-
-    pub fn restore_all(&mut self, server: &Server) -> anyhow::Result<()> {
-        let from = self.blocks.first_key_value().map(|x| *x.0);
-        let to = self.blocks.last_key_value().map(|x| *x.0);
-
-        warn!("Restoring savepoints from {:?} to {:?}", from, to);
-        self.restore(server, 0)
-    }
 }
 
 enum DeployedUpdate {
@@ -352,3 +417,127 @@ enum DeployedUpdate {
     Transfer(LowerCaseTick),
     Transferred(LowerCaseTick),
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::{self, TestChain};
+
+    #[tokio::test]
+    async fn restore_reverts_saved_block() {
+        crate::config::Config::install_default();
+        let server = Server::new(DB::open_memory()).await.unwrap();
+        let db = server.db.clone();
+        let tick = TokenTick::from(*b"abcd");
+        let owner = FullHash::from([1; 32]);
+        let key = AddressToken {
+            address: owner,
+            token: tick.into(),
+        };
+
+        let reorg_cache = Arc::new(parking_lot::Mutex::new(ReorgCache::new()));
+        reorg_cache.lock().new_block(1, 0);
+
+        let mut token_cache = TokenCache {
+            token_actions: vec![
+                test_utils::deploy_action(tick, owner),
+                test_utils::mint_action(tick, owner, "40"),
+            ],
+            ..Default::default()
+        };
+        token_cache
+            .process_token_actions(Some(reorg_cache.clone()), &server.holders, 1)
+            .unwrap();
+
+        let mut batch = DbBatch::default();
+        token_cache.write_token_data(&db, &mut batch);
+        batch.put(&db.last_block, (), 1);
+        reorg_cache.lock().write(&db, &mut batch).unwrap();
+        db.write(batch).unwrap();
+        server.commit(1).unwrap();
+
+        // Restored from the saved blocks, like after a restart
+        let mut reorg_cache = ReorgCache::load(&db).unwrap();
+        assert!(reorg_cache.covers(1));
+        reorg_cache.restore(&server, 1).unwrap();
+
+        assert!(!reorg_cache.covers(1));
+        assert!(db.reorg_blocks.iter().next().is_none());
+        assert!(db
+            .token_to_meta
+            .get(LowerCaseTick::from(tick))
+            .unwrap()
+            .is_none());
+        assert_eq!(
+            db.address_token_to_balance
+                .get(key)
+                .unwrap()
+                .unwrap()
+                .balance,
+            Fixed128::zero()
+        );
+        assert_eq!(db.last_block.get(()).unwrap(), Some(0));
+        assert!(matches!(
+            db.event_log.get(server.last_event_seq()).unwrap(),
+            Some(ServerEvent::Reorg(1, 1))
+        ));
+    }
+
+    #[tokio::test]
+    async fn reorg_across_restart_matches_fresh_index() {
+        let alice = test_utils::address(1);
+        let bob = test_utils::address(2);
+
+        let mut chain = TestChain::new();
+        let deploy = chain.inscribe(&alice, &test_utils::deploy("abcd", "1000", "100"));
+        chain.mine(vec![deploy]);
+        let mint = chain.inscribe(&alice, &test_utils::mint("abcd", "100"));
+        chain.mine(vec![mint]);
+        let fork_height = chain.height();
+
+        let transfer = chain.inscribe(&alice, &test_utils::transfer("abcd", "40"));
+        let transfer_outpoint = OutPoint::new(transfer.txid(), 0);
+        chain.mine(vec![transfer]);
+        let send = chain.send(transfer_outpoint, &bob);
+        let mint = chain.inscribe(&bob, &test_utils::mint("abcd", "5"));
+        chain.mine(vec![send, mint]);
+
+        let server = test_utils::server().await;
+        let reorg_cache = Arc::new(parking_lot::Mutex::new(ReorgCache::new()));
+        test_utils::index_with(&server, &chain, chain.height(), Some(&reorg_cache)).await;
+        let last_height = chain.height();
+
+        // Blocks after the fork are replaced while the indexer is stopped
+        chain.fork(fork_height);
+        let mint = chain.inscribe(&bob, &test_utils::mint("abcd", "7"));
+        chain.mine(vec![mint]);
+        let deploy = chain.inscribe(&bob, &test_utils::deploy("efgh", "1000", "100"));
+        chain.mine(vec![deploy]);
+
+        // Restarted with the cache saved by the blocks
+        let db = server.db.reopen_memory();
+        drop(server);
+        let server = Arc::new(Server::new(db).await.unwrap());
+        let reorg_cache = Arc::new(parking_lot::Mutex::new(
+            ReorgCache::load(&server.db).unwrap(),
+        ));
+        assert!(reorg_cache.lock().covers(fork_height + 1));
+        reorg_cache
+            .lock()
+            .restore(&server, fork_height + 1)
+            .unwrap();
+        server.commit(fork_height).unwrap();
+        assert!(matches!(
+            server.db.event_log.get(server.last_event_seq()).unwrap(),
+            Some(ServerEvent::Reorg(n, h)) if n == last_height - fork_height && h == fork_height + 1
+        ));
+        test_utils::index_with(&server, &chain, chain.height(), Some(&reorg_cache)).await;
+
+        let fresh = test_utils::server().await;
+        test_utils::index(&fresh, &chain, chain.height()).await;
+
+        // Outputs of the reverted blocks are left in prevouts, so they aren't compared
+        test_utils::assert_same_tokens(&server.db, &fresh.db);
+        assert_eq!(server.db.last_block.get(()).unwrap(), Some(chain.height()));
+    }
+}
@@ -11,34 +11,34 @@ use itertools::Itertools;
 use nintypes::common::inscriptions::Outpoint;
 use serde::{Deserialize, Serialize};
 
-use crate::LowerCaseTick;
+use crate::{AddressRejection, LowerCaseTick, RejectedRest};
 
 use super::{
-    utils::to_scripthash, AddressLocation, AddressToken, ApiResult, Fixed128, FullHash, Server,
-    TokenTick, TokenTransfer, INTERNAL, NETWORK,
+    utils::to_scripthash, view::View, AddressLocation, AddressToken, ApiResult, Fixed128, FullHash,
+    Server, Table, TokenTick, TokenTransfer, INTERNAL, NETWORK,
 };
 
 pub async fn address_tokens_tick(
     url: Uri,
-    State(state): State<Arc<Server>>,
+    view: View,
     Path(script_str): Path<String>,
 ) -> ApiResult<impl IntoResponse> {
     let script_type = url.path().split('/').nth(1).internal(INTERNAL)?;
     let scripthash =
         to_scripthash(script_type, &script_str, *NETWORK).bad_request("Invalid address")?;
     let (from, to) = AddressToken::search(scripthash).into_inner();
-    let data = state
+    let ticks = view
+        .db
+        .address_token_to_balance
+        .range(&from..&to, false)
+        .map_ok(|(k, _)| k.token)
+        .try_collect::<_, Vec<_>, _>()
+        .internal(INTERNAL)?;
+    let data = view
         .db
         .token_to_meta
-        .multi_get(
-            state
-                .db
-                .address_token_to_balance
-                .range(&from..&to, false)
-                .map(|(k, _)| k.token)
-                .collect_vec()
-                .iter(),
-        )
+        .multi_get(ticks.iter())
+        .internal(INTERNAL)?
         .into_iter()
         .flatten()
         .map(|x| x.proto.tick)
@@ -55,7 +55,7 @@ pub struct AddressTick {
 
 pub async fn address_token_balance(
     url: Uri,
-    State(state): State<Arc<Server>>,
+    view: View,
     Path((script_str, tick)): Path<(String, String)>,
     Query(params): Query<AddressTokenBalanceArgs>,
 ) -> ApiResult<impl IntoResponse> {
@@ -65,36 +65,39 @@ pub async fn address_token_balance(
 
     let token: LowerCaseTick = tick.into();
 
-    let deploy_proto = state
+    let deploy_proto = view
         .db
         .token_to_meta
         .get(&token)
+        .internal(INTERNAL)?
         .not_found("Token not found")?;
 
     let tick = deploy_proto.proto.tick;
 
-    let balance = state
+    let balance = view
         .db
         .address_token_to_balance
         .get(AddressToken {
             address: scripthash,
             token: tick.into(),
         })
+        .internal(INTERNAL)?
         .unwrap_or_default();
 
     let (from, to) =
         AddressLocation::search(scripthash, params.offset.map(|x| x.into())).into_inner();
 
-    let transfers = state
+    let transfers = view
         .db
         .address_location_to_transfer
         .range(&from..&to, false)
-        .filter(|(_, v)| v.tick == tick)
-        .map(|(k, v)| TokenTransfer {
+        .filter_ok(|(_, v)| v.tick == tick)
+        .map_ok(|(k, v)| TokenTransfer {
             amount: v.amt,
             outpoint: k.location.outpoint,
         })
-        .collect_vec();
+        .try_collect::<_, Vec<_>, _>()
+        .internal(INTERNAL)?;
 
     let data = TokenBalance {
         transfers,
@@ -120,3 +123,58 @@ pub struct TokenBalance {
     pub transfers: Vec<TokenTransfer>,
     pub transfers_count: u64,
 }
+
+pub async fn address_rejected(
+    State(state): State<Arc<Server>>,
+    view: View,
+    Path(script_str): Path<String>,
+    Query(params): Query<AddressRejectedArgs>,
+) -> ApiResult<impl IntoResponse> {
+    let scripthash =
+        to_scripthash("address", &script_str, *NETWORK).bad_request("Invalid address")?;
+
+    if let Some(limit) = params.limit {
+        if limit > 100 {
+            return Err("").bad_request("Limit exceeded");
+        }
+    }
+
+    let (from, mut to) = AddressRejection::search(scripthash).into_inner();
+    if let Some(offset) = params.offset {
+        to.height = offset;
+        to.genesis = from.genesis;
+    }
+
+    let keys = view
+        .db
+        .address_to_rejection
+        .range(&from..&to, true)
+        .map_ok(|(k, _)| k.genesis)
+        .take(params.limit.unwrap_or(100))
+        .try_collect::<_, Vec<_>, _>()
+        .internal(INTERNAL)?;
+
+    let rejected = view
+        .db
+        .inscription_to_rejection
+        .multi_get(keys.iter())
+        .internal(INTERNAL)?
+        .into_iter()
+        .flatten()
+        .collect_vec();
+
+    let addresses = state.load_addresses([scripthash]).internal(INTERNAL)?;
+
+    let data = rejected
+        .into_iter()
+        .map(|x| RejectedRest::new(x, &addresses))
+        .collect_vec();
+
+    Ok(Json(data))
+}
+
+#[derive(Deserialize)]
+pub struct AddressRejectedArgs {
+    pub offset: Option<u32>,
+    pub limit: Option<usize>,
+}
@@ -0,0 +1,591 @@
+use tokio::sync::{broadcast::error::RecvError, mpsc::error::TryRecvError};
+
+use super::*;
+
+/// Count of events read from the event log at once while replaying
+const EVENT_LOG_REPLAY_BATCH: usize = 1000;
+/// Replaying is retried after this long if the event log can't be read
+const EVENT_LOG_RETRY_DELAY: Duration = Duration::from_secs(1);
+
+/// Sequence number and serialized payload of an event
+pub type DispatchedEvent = (u64, Arc<str>);
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Hash, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum EventType {
+    History,
+    NewBlock,
+    Reorg,
+}
+
+impl EventType {
+    pub const ALL: [EventType; 3] = [EventType::History, EventType::NewBlock, EventType::Reorg];
+}
+
+impl From<&ServerEvent> for EventType {
+    fn from(value: &ServerEvent) -> Self {
+        match value {
+            ServerEvent::NewHistory(..) => EventType::History,
+            ServerEvent::NewBlock(..) => EventType::NewBlock,
+            ServerEvent::Reorg(..) => EventType::Reorg,
+        }
+    }
+}
+
+/// Events a subscriber is interested in. `None` matches everything, an empty set matches nothing.
+pub struct EventFilter {
+    pub addresses: Option<HashSet<String>>,
+    pub tokens: Option<HashSet<LowerCaseTick>>,
+    pub event_types: Option<HashSet<EventType>>,
+}
+
+impl EventFilter {
+    /// Matches nothing until event types are added
+    pub fn empty() -> Self {
+        Self {
+            addresses: None,
+            tokens: None,
+            event_types: Some(HashSet::new()),
+        }
+    }
+
+    pub fn event_types(&self) -> HashSet<EventType> {
+        match &self.event_types {
+            Some(x) => x.clone(),
+            None => EventType::ALL.into_iter().collect(),
+        }
+    }
+
+    pub fn matches(&self, event: &ServerEvent) -> bool {
+        fn contains<T: std::hash::Hash + Eq>(set: &Option<HashSet<T>>, x: &T) -> bool {
+            set.as_ref().is_none_or(|set| set.contains(x))
+        }
+
+        if !contains(&self.event_types, &EventType::from(event)) {
+            return false;
+        }
+
+        let ServerEvent::NewHistory(address_token, _) = event else {
+            return true;
+        };
+
+        contains(&self.addresses, &address_token.address)
+            && contains(&self.tokens, &address_token.token.into())
+    }
+}
+
+/// Serializes the event the same way for every event API
+pub fn event_to_json(event: ServerEvent) -> serde_json::Result<String> {
+    match event {
+        ServerEvent::NewHistory(address_token, action) => serde_json::to_string(&HistoryRest {
+            address_token: address_token.into(),
+            height: action.height,
+            action: action.into(),
+        }),
+        ServerEvent::Reorg(blocks_count, new_height) => serde_json::to_string(&ReorgRest {
+            event_type: "reorg".to_string(),
+            blocks_count,
+            new_height,
+        }),
+        ServerEvent::NewBlock(height, poh, blockhash) => serde_json::to_string(&NewBlockRest {
+            event_type: "new_block".to_string(),
+            height,
+            proof: poh,
+            blockhash,
+        }),
+    }
+}
+
+/// Live events in sequence order. Events dropped because the receiver lagged are read from the event log.
+struct EventStream {
+    server: Arc<Server>,
+    rx: tokio::sync::broadcast::Receiver<SequencedEvent>,
+    next_seq: u64,
+    replay: bool,
+    buffer: VecDeque<SequencedEvent>,
+}
+
+impl EventStream {
+    fn new(server: Arc<Server>) -> Self {
+        let (next_seq, rx) = server.subscribe_events();
+
+        Self {
+            server,
+            rx,
+            next_seq,
+            replay: false,
+            buffer: VecDeque::new(),
+        }
+    }
+
+    /// Returns `None` if the server is shutting down. Cancel safe.
+    async fn next(&mut self) -> Option<SequencedEvent> {
+        loop {
+            if self.buffer.is_empty() && self.replay {
+                match self
+                    .server
+                    .read_event_log(self.next_seq, EVENT_LOG_REPLAY_BATCH)
+                    .track()
+                {
+                    Ok(events) => {
+                        self.replay = events.len() == EVENT_LOG_REPLAY_BATCH;
+                        self.buffer.extend(events);
+                    }
+                    Err(e) => match e.downcast::<EventLogGap>() {
+                        // Lagged past the retention, dispatching goes on after the removed events
+                        Ok(gap) => {
+                            self.next_seq = gap.first;
+                            continue;
+                        }
+                        Err(_) => {
+                            tokio::select! {
+                                _ = tokio::time::sleep(EVENT_LOG_RETRY_DELAY) => continue,
+                                _ = self.server.token.cancelled() => return None,
+                            }
+                        }
+                    },
+                }
+            }
+
+            if let Some((seq, event)) = self.buffer.pop_front() {
+                self.next_seq = seq + 1;
+                return Some((seq, event));
+            }
+
+            let received = tokio::select! {
+                v = self.rx.recv() => v,
+                _ = self.server.token.cancelled() => return None,
+            };
+
+            match received {
+                Ok((seq, event)) => {
+                    // Already sent from the event log
+                    if seq < self.next_seq {
+                        continue;
+                    }
+                    self.next_seq = seq + 1;
+                    return Some((seq, event));
+                }
+                Err(RecvError::Lagged(count)) => {
+                    warn!("Lagged {} events. Replaying from the event log...", count);
+                    metrics::EVENTS_LAGGED
+                        .with_label_values(&["dispatcher"])
+                        .inc();
+                    self.replay = true;
+                }
+                Err(RecvError::Closed) => return None,
+            }
+        }
+    }
+}
+
+struct Subscriber {
+    filter: EventFilter,
+    tx: tokio::sync::mpsc::Sender<DispatchedEvent>,
+    /// Set when the queue was full and events were dropped
+    lagged: Arc<AtomicBool>,
+}
+
+/// Subscribers indexed by what they are interested in, so an event is only matched against
+/// subscribers that may want it. A history subscriber is indexed by its addresses,
+/// by its ticks if any address matches, or as a subscriber to all history if any address and tick match.
+#[derive(Default)]
+struct SubscriberIndex {
+    subscribers: HashMap<u64, Subscriber>,
+    by_address: HashMap<String, HashSet<u64>>,
+    by_tick: HashMap<LowerCaseTick, HashSet<u64>>,
+    all_history: HashSet<u64>,
+    by_type: HashMap<EventType, HashSet<u64>>,
+}
+
+impl SubscriberIndex {
+    fn insert(&mut self, id: u64, subscriber: Subscriber) {
+        let filter = &subscriber.filter;
+        let event_types = filter.event_types();
+
+        for event_type in &event_types {
+            if *event_type != EventType::History {
+                self.by_type.entry(*event_type).or_default().insert(id);
+            }
+        }
+
+        if event_types.contains(&EventType::History) {
+            match (&filter.addresses, &filter.tokens) {
+                (Some(addresses), _) => {
+                    for address in addresses {
+                        self.by_address
+                            .entry(address.clone())
+                            .or_default()
+                            .insert(id);
+                    }
+                }
+                (None, Some(tokens)) => {
+                    for tick in tokens {
+                        self.by_tick.entry(tick.clone()).or_default().insert(id);
+                    }
+                }
+                (None, None) => {
+                    self.all_history.insert(id);
+                }
+            }
+        }
+
+        self.subscribers.insert(id, subscriber);
+    }
+
+    fn remove(&mut self, id: u64) -> Option<Subscriber> {
+        let subscriber = self.subscribers.remove(&id)?;
+        let filter = &subscriber.filter;
+
+        fn remove_from<K: std::hash::Hash + Eq>(
+            map: &mut HashMap<K, HashSet<u64>>,
+            key: &K,
+            id: u64,
+        ) {
+            if let Some(ids) = map.get_mut(key) {
+                ids.remove(&id);
+                if ids.is_empty() {
+                    map.remove(key);
+                }
+            }
+        }
+
+        for event_type in &filter.event_types() {
+            remove_from(&mut self.by_type, event_type, id);
+        }
+        for address in filter.addresses.iter().flatten() {
+            remove_from(&mut self.by_address, address, id);
+        }
+        for tick in filter.tokens.iter().flatten() {
+            remove_from(&mut self.by_tick, tick, id);
+        }
+        self.all_history.remove(&id);
+
+        Some(subscriber)
+    }
+
+    fn recipients(&self, event: &ServerEvent) -> Vec<&Subscriber> {
+        let ids: Vec<&u64> = match event {
+            ServerEvent::NewHistory(address_token, _) => {
+                let tick = LowerCaseTick::from(address_token.token);
+
+                self.by_address
+                    .get(&address_token.address)
+                    .into_iter()
+                    .chain(self.by_tick.get(&tick))
+                    .flatten()
+                    .chain(&self.all_history)
+                    .collect()
+            }
+            _ => self
+                .by_type
+                .get(&EventType::from(event))
+                .into_iter()
+                .flatten()
+                .collect(),
+        };
+
+        ids.into_iter()
+            .filter_map(|id| self.subscribers.get(id))
+            .filter(|x| x.filter.matches(event))
+            .collect()
+    }
+}
+
+/// Routes every event to interested subscribers. An event is serialized once for all of them.
+pub struct EventDispatcher {
+    server: Arc<Server>,
+    index: parking_lot::RwLock<SubscriberIndex>,
+    next_id: AtomicU64,
+}
+
+impl EventDispatcher {
+    pub fn spawn(server: Arc<Server>) -> Arc<Self> {
+        let dispatcher = Arc::new(Self {
+            server,
+            index: Default::default(),
+            next_id: AtomicU64::new(0),
+        });
+
+        tokio::spawn(dispatcher.clone().run());
+
+        dispatcher
+    }
+
+    async fn run(self: Arc<Self>) {
+        let mut events = EventStream::new(self.server.clone());
+
+        while let Some((seq, event)) = events.next().await {
+            self.dispatch(seq, event);
+        }
+    }
+
+    fn dispatch(&self, seq: u64, event: ServerEvent) {
+        let index = self.index.read();
+
+        let recipients = index.recipients(&event);
+        if recipients.is_empty() {
+            return;
+        }
+
+        let data: Arc<str> = match event_to_json(event) {
+            Ok(data) => data.into(),
+            Err(e) => {
+                error!("Event {seq} is skipped, it can't be serialized: {e}");
+                return;
+            }
+        };
+
+        for subscriber in recipients {
+            if subscriber.lagged.load(Ordering::SeqCst) {
+                continue;
+            }
+
+            if let Err(tokio::sync::mpsc::error::TrySendError::Full(_)) =
+                subscriber.tx.try_send((seq, data.clone()))
+            {
+                subscriber.lagged.store(true, Ordering::SeqCst);
+            }
+        }
+    }
+
+    /// Starts from `from_seq` replaying the event log, or from the next dispatched event
+    pub fn subscribe(self: &Arc<Self>, filter: EventFilter, from_seq: Option<u64>) -> Subscription {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let (tx, rx) = tokio::sync::mpsc::channel(CONFIG.channels.subscriber_queue);
+        let lagged = Arc::new(AtomicBool::new(false));
+
+        self.index.write().insert(
+            id,
+            Subscriber {
+                filter,
+                tx,
+                lagged: lagged.clone(),
+            },
+        );
+        metrics::EVENT_SUBSCRIBERS.inc();
+
+        Subscription {
+            dispatcher: self.clone(),
+            id,
+            rx,
+            lagged,
+            next_seq: from_seq.unwrap_or_else(|| self.server.last_event_seq() + 1),
+            replay: from_seq.is_some(),
+            buffer: VecDeque::new(),
+        }
+    }
+}
+
+pub struct Subscription {
+    dispatcher: Arc<EventDispatcher>,
+    id: u64,
+    rx: tokio::sync::mpsc::Receiver<DispatchedEvent>,
+    lagged: Arc<AtomicBool>,
+    next_seq: u64,
+    replay: bool,
+    buffer: VecDeque<DispatchedEvent>,
+}
+
+impl Subscription {
+    pub fn update_filter<R>(&mut self, f: impl FnOnce(&mut EventFilter) -> R) -> anyhow::Result<R> {
+        let mut index = self.dispatcher.index.write();
+        let mut subscriber = index
+            .remove(self.id)
+            .anyhow_with("Subscriber is not registered")?;
+        let result = f(&mut subscriber.filter);
+        index.insert(self.id, subscriber);
+        Ok(result)
+    }
+
+    fn read_event_log(&mut self) -> anyhow::Result<()> {
+        let events = self
+            .dispatcher
+            .server
+            .read_event_log(self.next_seq, EVENT_LOG_REPLAY_BATCH)?;
+
+        self.replay = events.len() == EVENT_LOG_REPLAY_BATCH;
+
+        let index = self.dispatcher.index.read();
+        let filter = &index
+            .subscribers
+            .get(&self.id)
+            .anyhow_with("Subscriber is not registered")?
+            .filter;
+
+        for (seq, event) in events {
+            self.next_seq = seq + 1;
+            if !filter.matches(&event) {
+                continue;
+            }
+            match event_to_json(event) {
+                Ok(data) => self.buffer.push_back((seq, data.into())),
+                Err(e) => error!("Event {seq} is skipped, it can't be serialized: {e}"),
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Returns `None` if the server is shutting down. Cancel safe.
+    pub async fn next(&mut self) -> Option<DispatchedEvent> {
+        loop {
+            if self.buffer.is_empty() && self.replay {
+                match self.read_event_log().track() {
+                    Ok(()) => {}
+                    // Events can't be resumed without a gap, the client reconnects to find out
+                    Err(e) if e.is::<EventLogGap>() => return None,
+                    Err(_) => {
+                        tokio::select! {
+                            _ = tokio::time::sleep(EVENT_LOG_RETRY_DELAY) => continue,
+                            _ = self.dispatcher.server.token.cancelled() => return None,
+                        }
+                    }
+                }
+            }
+
+            if let Some(event) = self.buffer.pop_front() {
+                return Some(event);
+            }
+
+            let received = match self.rx.try_recv() {
+                Ok(event) => Some(event),
+                Err(TryRecvError::Disconnected) => return None,
+                Err(TryRecvError::Empty) => {
+                    // Events dropped by the dispatcher are in the event log already
+                    if self.lagged.swap(false, Ordering::SeqCst) {
+                        warn!("Subscriber lagged. Replaying from the event log...");
+                        metrics::EVENTS_LAGGED
+                            .with_label_values(&["subscriber"])
+                            .inc();
+                        self.replay = true;
+                        continue;
+                    }
+                    None
+                }
+            };
+
+            let (seq, data) = match received {
+                Some(event) => event,
+                None => tokio::select! {
+                    v = self.rx.recv() => v?,
+                    _ = self.dispatcher.server.token.cancelled() => return None,
+                },
+            };
+
+            // Already sent from the event log
+            if seq < self.next_seq {
+                continue;
+            }
+            self.next_seq = seq + 1;
+
+            return Some((seq, data));
+        }
+    }
+}
+
+impl Drop for Subscription {
+    fn drop(&mut self) {
+        self.dispatcher.index.write().remove(self.id);
+        metrics::EVENT_SUBSCRIBERS.dec();
+        metrics::EVENT_SUBSCRIBER_DISCONNECTS.inc();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::{self, TestChain};
+
+    const WAIT: Duration = Duration::from_secs(5);
+
+    fn all() -> EventFilter {
+        EventFilter {
+            addresses: None,
+            tokens: None,
+            event_types: None,
+        }
+    }
+
+    async fn next(subscription: &mut Subscription) -> Option<DispatchedEvent> {
+        tokio::time::timeout(WAIT, subscription.next())
+            .await
+            .unwrap()
+    }
+
+    fn chain() -> TestChain {
+        let alice = test_utils::address(1);
+        let mut chain = TestChain::new();
+        let deploy = chain.inscribe(&alice, &test_utils::deploy("abcd", "1000", "100"));
+        chain.mine(vec![deploy]);
+        let mint = chain.inscribe(&alice, &test_utils::mint("abcd", "100"));
+        chain.mine(vec![mint]);
+        chain
+    }
+
+    #[tokio::test]
+    async fn replay_continues_with_live_events() {
+        let mut chain = chain();
+        let server = test_utils::server().await;
+        test_utils::index(&server, &chain, chain.height()).await;
+        let logged = server.last_event_seq();
+        assert!(logged > 0);
+
+        let dispatcher = EventDispatcher::spawn(server.clone());
+        let mut replayed = dispatcher.subscribe(all(), Some(1));
+        let mut blocks = dispatcher.subscribe(
+            EventFilter {
+                event_types: Some([EventType::NewBlock].into_iter().collect()),
+                ..all()
+            },
+            Some(1),
+        );
+
+        for seq in 1..=logged {
+            let (next_seq, data) = next(&mut replayed).await.unwrap();
+            assert_eq!(next_seq, seq);
+            let expected = event_to_json(server.db.event_log.get(seq).unwrap().unwrap()).unwrap();
+            assert_eq!(data.as_ref(), expected);
+        }
+
+        // Only new blocks after the filter, the live one follows the replayed ones
+        let mint = chain.inscribe(&test_utils::address(2), &test_utils::mint("abcd", "5"));
+        chain.mine(vec![mint]);
+        test_utils::index(&server, &chain, chain.height()).await;
+
+        let mut heights = vec![];
+        while heights.last() != Some(&chain.height()) {
+            let (_, data) = next(&mut blocks).await.unwrap();
+            let data: serde_json::Value = serde_json::from_str(&data).unwrap();
+            assert_eq!(data["event_type"], "new_block");
+            heights.push(data["height"].as_u64().unwrap() as u32);
+        }
+        assert_eq!(
+            heights,
+            (PROTOCOL.start_height..=chain.height()).collect_vec()
+        );
+
+        let (seq, _) = next(&mut replayed).await.unwrap();
+        assert_eq!(seq, logged + 1);
+    }
+
+    #[tokio::test]
+    async fn replay_of_removed_events_is_a_gap() {
+        let chain = chain();
+        let server = test_utils::server().await;
+        test_utils::index(&server, &chain, chain.height()).await;
+        server.db.event_log.remove_batch(1..=2).unwrap();
+
+        let gap = server
+            .read_event_log(1, 10)
+            .unwrap_err()
+            .downcast::<EventLogGap>()
+            .unwrap();
+        assert_eq!((gap.from, gap.first), (1, 3));
+        assert_eq!(server.read_event_log(3, 10).unwrap()[0].0, 3);
+
+        let dispatcher = EventDispatcher::spawn(server.clone());
+        let mut subscription = dispatcher.subscribe(all(), Some(1));
+        assert!(next(&mut subscription).await.is_none());
+    }
+}
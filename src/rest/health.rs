@@ -0,0 +1,85 @@
+use super::*;
+
+/// Node RPC is considered unreachable if it doesn't reply in time
+const NODE_TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Serialize)]
+pub struct ReadyRest {
+    ready: bool,
+    synced: bool,
+    height: u32,
+    node_height: Option<u32>,
+    /// Reasons the server is not ready
+    errors: Vec<String>,
+}
+
+pub async fn live(State(server): State<Arc<Server>>) -> impl IntoResponse {
+    if server.token.is_cancelled() {
+        (StatusCode::SERVICE_UNAVAILABLE, "Shutting down")
+    } else {
+        (StatusCode::OK, "OK")
+    }
+}
+
+/// Ready when the initial catch-up is finished, the node is reachable,
+/// the indexer is at most `server.ready_max_lag_blocks` behind the node and indexing isn't stopped by a fault
+pub async fn ready(State(server): State<Arc<Server>>) -> impl IntoResponse {
+    let mut errors = vec![];
+
+    let synced = server.synced.load(Ordering::SeqCst);
+    if !synced {
+        errors.push("Initial sync is in progress".to_string());
+    }
+
+    // Height served to readers, blocks written but not committed yet are not counted
+    let height = server.read_view().height;
+
+    let node_height =
+        match tokio::time::timeout(NODE_TIMEOUT, server.client.get_block_count()).await {
+            Ok(Ok(v)) => Some(v as u32),
+            Ok(Err(e)) => {
+                errors.push(e.to_string());
+                None
+            }
+            Err(_) => {
+                errors.push("Node is not replying".to_string());
+                None
+            }
+        };
+
+    if let Some(node_height) = node_height {
+        let lag = node_height.saturating_sub(height);
+        if lag > CONFIG.server.ready_max_lag_blocks {
+            errors.push(format!("Indexer is {lag} blocks behind the node"));
+        }
+    }
+
+    if let Some(fault) = server.fault.lock().as_ref() {
+        errors.push(format!(
+            "Indexing is stopped at block {}: {}",
+            fault.height, fault.error
+        ));
+    }
+
+    if server.token.is_cancelled() {
+        errors.push("Shutting down".to_string());
+    }
+
+    let ready = errors.is_empty();
+    let status = if ready {
+        StatusCode::OK
+    } else {
+        StatusCode::SERVICE_UNAVAILABLE
+    };
+
+    (
+        status,
+        Json(ReadyRest {
+            ready,
+            synced,
+            height,
+            node_height,
+            errors,
+        }),
+    )
+}
@@ -1,10 +1,4 @@
-use std::sync::Arc;
-
-use axum::{
-    extract::{Query, State},
-    response::IntoResponse,
-    Json,
-};
+use axum::{extract::Query, response::IntoResponse, Json};
 use dutils::error::ApiError;
 use rust_decimal::Decimal;
 use serde::{Deserialize, Serialize};
@@ -14,24 +8,23 @@ use crate::tokens::LowerCaseTick;
 
 use super::{
     utils::{first_page, page_size_default, validate_tick},
-    ApiResult, Fixed128, Server, BAD_PARAMS, INTERNAL,
+    view::View,
+    ApiResult, Fixed128, Table, BAD_PARAMS, INTERNAL,
 };
 
-pub async fn holders(
-    State(server): State<Arc<Server>>,
-    Query(query): Query<Args>,
-) -> ApiResult<impl IntoResponse> {
+pub async fn holders(view: View, Query(query): Query<Args>) -> ApiResult<impl IntoResponse> {
     query.validate().bad_request(BAD_PARAMS)?;
 
     let tick: LowerCaseTick = query.tick.into();
-    let proto = server
+    let proto = view
         .db
         .token_to_meta
         .get(&tick)
+        .internal(INTERNAL)?
         .map(|x| x.proto)
         .not_found("Tick not found")?;
 
-    let result = if let Some(data) = server.holders.get_holders(&tick) {
+    let result = if let Some(data) = view.holders.get_holders(&tick) {
         let count = data.len();
         let pages = count.div_ceil(query.page_size);
         let mut holders = Vec::with_capacity(query.page_size);
@@ -49,7 +42,12 @@ pub async fn holders(
             .map(|(rank, x)| (rank + 1, x.0, x.1));
 
         for (rank, balance, hash) in keys {
-            let address = server.db.fullhash_to_address.get(hash).internal(INTERNAL)?;
+            let address = view
+                .db
+                .fullhash_to_address
+                .get(hash)
+                .internal(INTERNAL)?
+                .internal(INTERNAL)?;
             let percent =
                 balance.into_decimal() * Decimal::new(100, 0) / proto.supply.into_decimal();
 
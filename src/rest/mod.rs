@@ -1,18 +1,29 @@
 use axum::{
+    extract::{MatchedPath, Request},
+    http::HeaderMap,
+    middleware::{self, Next},
     response::{sse::Event, Sse},
-    routing::post,
+    routing::{delete, post},
+    Extension,
 };
+use events::{EventDispatcher, EventFilter};
 use futures::Stream;
-use tokio::sync::mpsc;
-use tokio_stream::wrappers::ReceiverStream;
+use std::collections::hash_map::Entry;
 use utils::to_scripthash;
+use view::View;
 
 use super::*;
 
 mod address;
+pub mod events;
+mod health;
 mod holders;
 mod tokens;
 mod utils;
+mod validate;
+mod view;
+mod webhooks;
+mod ws;
 
 type ApiResult<T> = core::result::Result<T, Response<String>>;
 const INTERNAL: &str = "Can't handle request";
@@ -21,6 +32,8 @@ const BAD_PARAMS: &str = "Can't handle request";
 const NOT_FOUND: &str = "Can't handle request";
 
 pub fn get_router(server: Arc<Server>) -> Router {
+    let dispatcher = EventDispatcher::spawn(server.clone());
+
     Router::new()
         .route("/address/{address}", get(address_tokens))
         .route("/address/{address}/tokens", get(address_tokens))
@@ -33,6 +46,10 @@ pub fn get_router(server: Arc<Server>) -> Router {
             "/address/{address}/{tick}/balance",
             get(address::address_token_balance),
         )
+        .route(
+            "/address/{address}/rejected",
+            get(address::address_rejected),
+        )
         .route("/tokens", get(tokens::tokens))
         .route("/token", get(tokens::token))
         .route(
@@ -41,19 +58,31 @@ pub fn get_router(server: Arc<Server>) -> Router {
         )
         .route("/holders", get(holders::holders))
         .route("/events", post(subscribe))
+        .route("/ws", get(ws::ws))
+        .route("/webhooks", post(webhooks::register).get(webhooks::list))
+        .route("/webhooks/{id}", delete(webhooks::remove))
+        .route("/webhooks/{id}/dead-letters", get(webhooks::dead_letters))
+        .route("/validate", post(validate::validate))
         .route("/status", get(status))
+        .route("/health/live", get(health::live))
+        .route("/health/ready", get(health::ready))
         .route("/proof-of-history", get(proof_of_history))
         .route("/events/{height}", get(events_by_height))
         .route("/all-addresses", get(all_addresses))
         .route("/txid/{txid}", get(txid_events))
+        .route("/metrics", get(metrics))
+        .route_layer(middleware::from_fn(view::report_height))
+        .route_layer(middleware::from_fn(track_latency))
+        .layer(Extension(dispatcher))
         .with_state(server)
 }
 
 async fn txid_events(
     State(server): State<Arc<Server>>,
+    view: View,
     Path(txid): Path<Txid>,
 ) -> ApiResult<impl IntoResponse> {
-    let keys = server
+    let keys = view
         .db
         .outpoint_to_event
         .range(
@@ -63,46 +92,96 @@ async fn txid_events(
             },
             false,
         )
-        .map(|(_, v)| v)
-        .collect_vec();
+        .map_ok(|(_, v)| v)
+        .try_collect::<_, Vec<_>, _>()
+        .internal(INTERNAL)?;
 
-    let mut events = join_all(
-        server
-            .db
-            .address_token_to_history
-            .multi_get(keys.iter())
-            .into_iter()
-            .zip(keys)
-            .filter_map(|(v, k)| v.map(|v| (k, v)))
-            .map(|(k, v)| HistoryRest::new(v.height, v.action, k, &server)),
-    )
-    .await
-    .into_iter()
-    .collect::<anyhow::Result<Vec<_>>>()
-    .internal("Failed to load addresses")?;
+    let mut events = view
+        .db
+        .address_token_to_history
+        .multi_get(keys.iter())
+        .internal(INTERNAL)?
+        .into_iter()
+        .zip(keys)
+        .filter_map(|(v, k)| v.map(|v| (k, v)))
+        .map(|(k, v)| HistoryRest::new(v.height, v.action, k, &server))
+        .try_collect::<_, Vec<_>, _>()
+        .internal(INTERNAL)?;
 
     events.sort_unstable_by_key(|x| x.address_token.id);
 
+    // Inscriptions revealed by the transaction and the ones rejected at its outputs
+    let (from, to) = OutPointRejection::search(txid).into_inner();
+    let genesis = view
+        .db
+        .inscription_to_rejection
+        .range(
+            &OutPoint { txid, vout: 0 }..&OutPoint {
+                txid,
+                vout: u32::MAX,
+            },
+            false,
+        )
+        .map_ok(|(k, _)| k)
+        .chain(
+            view.db
+                .outpoint_to_rejection
+                .range(&from..=&to, false)
+                .map_ok(|(k, _)| k.genesis),
+        )
+        .try_collect::<_, BTreeSet<_>, _>()
+        .internal(INTERNAL)?;
+
+    let rejected = view
+        .db
+        .inscription_to_rejection
+        .multi_get(genesis.iter())
+        .internal(INTERNAL)?
+        .into_iter()
+        .flatten()
+        .collect_vec();
+
+    let addresses = server
+        .load_addresses(rejected.iter().map(|x| x.owner))
+        .internal(INTERNAL)?;
+
+    let events = events
+        .into_iter()
+        .map(TxidEventRest::History)
+        .chain(
+            rejected
+                .into_iter()
+                .map(|x| TxidEventRest::Rejected(RejectedRest::new(x, &addresses))),
+        )
+        .collect_vec();
+
     Ok(Json(events))
 }
 
-async fn all_addresses(State(server): State<Arc<Server>>) -> ApiResult<impl IntoResponse> {
+async fn all_addresses(
+    State(server): State<Arc<Server>>,
+    view: View,
+) -> ApiResult<impl IntoResponse> {
+    // The stream outlives the handler, so only the snapshot is kept
+    let db = view.db.clone();
+    drop(view);
+
     let (tx, rx) = tokio::sync::mpsc::channel(1000);
     tokio::spawn(async move {
-        let addresses = server
-            .db
+        // The response has started, failures end the stream early
+        let Ok(addresses) = db
             .address_token_to_balance
             .iter()
-            .map(|x| x.0.address)
-            .collect::<HashSet<_>>();
+            .map_ok(|x| x.0.address)
+            .try_collect::<_, HashSet<_>, _>()
+            .track()
+        else {
+            return;
+        };
 
-        let addresses = server
-            .load_addresses(
-                addresses.iter().copied(),
-                *server.last_indexed_address_height.read().await,
-            )
-            .await
-            .unwrap();
+        let Ok(addresses) = server.load_addresses(addresses.iter().copied()).track() else {
+            return;
+        };
 
         for (_, address) in addresses {
             if tx.send(address).await.is_err() {
@@ -114,27 +193,55 @@ async fn all_addresses(State(server): State<Arc<Server>>) -> ApiResult<impl Into
     Ok(axum_streams::StreamBodyAs::json_array(stream))
 }
 
-async fn status(State(server): State<Arc<Server>>) -> ApiResult<impl IntoResponse> {
-    let last_height = server
+async fn track_latency(request: Request, next: Next) -> impl IntoResponse {
+    let method = request.method().to_string();
+    let route = request
+        .extensions()
+        .get::<MatchedPath>()
+        .map(|x| x.as_str().to_string())
+        .unwrap_or_default();
+
+    let start = Instant::now();
+    let response = next.run(request).await;
+
+    crate::metrics::REST_REQUEST_SECONDS
+        .with_label_values(&[&method, &route, response.status().as_str()])
+        .observe(start.elapsed().as_secs_f64());
+
+    response
+}
+
+async fn metrics(State(server): State<Arc<Server>>) -> ApiResult<impl IntoResponse> {
+    let data = crate::metrics::gather(&server).internal("Failed to gather metrics")?;
+
+    Ok(([("Content-Type", prometheus::TEXT_FORMAT)], data))
+}
+
+async fn status(State(server): State<Arc<Server>>, view: View) -> ApiResult<impl IntoResponse> {
+    let last_height = view
         .db
         .last_block
         .get(())
+        .internal(INTERNAL)?
         .internal("Failed to get last height")?;
-    let last_poh = server
+    let last_poh = view
         .db
         .proof_of_history
         .get(last_height)
+        .internal(INTERNAL)?
         .internal("Failed to get last proof of history")?;
-    let last_block_hash = server
+    let last_block_hash = view
         .db
         .block_hashes
         .get(last_height)
+        .internal(INTERNAL)?
         .internal("Failed to get last block hash")?;
 
     let data = StatusRest {
         height: last_height,
         proof: last_poh.to_string(),
         blockhash: last_block_hash.to_string(),
+        fault: server.fault.lock().clone(),
     };
 
     Ok(Json(data))
@@ -142,33 +249,36 @@ async fn status(State(server): State<Arc<Server>>) -> ApiResult<impl IntoRespons
 
 async fn events_by_height(
     State(server): State<Arc<Server>>,
+    view: View,
     Path(height): Path<u32>,
 ) -> ApiResult<impl IntoResponse> {
-    let keys = server.db.block_events.get(height).unwrap_or_default();
+    let keys = view
+        .db
+        .block_events
+        .get(height)
+        .internal(INTERNAL)?
+        .unwrap_or_default();
 
     let mut res = Vec::<HistoryRest>::new();
 
-    let iterator = server
+    let iterator = view
         .db
         .address_token_to_history
         .multi_get(keys.iter())
+        .internal(INTERNAL)?
         .into_iter()
         .zip(keys);
 
     for (v, k) in iterator {
         let v = v.not_found("No events found")?;
-        res.push(
-            HistoryRest::new(v.height, v.action, k, &server)
-                .await
-                .internal("Failed to load addresses")?,
-        );
+        res.push(HistoryRest::new(v.height, v.action, k, &server).internal(INTERNAL)?);
     }
 
     Ok(Json(res))
 }
 
 async fn proof_of_history(
-    State(server): State<Arc<Server>>,
+    view: View,
     Query(query): Query<ProofHistoryParams>,
 ) -> ApiResult<impl IntoResponse> {
     if let Some(limit) = query.limit {
@@ -177,121 +287,76 @@ async fn proof_of_history(
         }
     }
 
-    let res = server
+    let res = view
         .db
         .proof_of_history
         .range(..&query.offset.unwrap_or(u32::MAX), true)
-        .map(|(height, hash)| ProofOfHistoryRest {
+        .map_ok(|(height, hash)| ProofOfHistoryRest {
             hash: hash.to_string(),
             height,
         })
         .take(query.limit.unwrap_or(100))
-        .collect_vec();
+        .try_collect::<_, Vec<_>, _>()
+        .internal(INTERNAL)?;
 
     Ok(Json(res))
 }
 
 async fn subscribe(
     State(server): State<Arc<Server>>,
+    Extension(dispatcher): Extension<Arc<EventDispatcher>>,
+    Query(query): Query<SubscribeParams>,
+    headers: HeaderMap,
     Json(payload): Json<SubscribeRequest>,
 ) -> ApiResult<Sse<impl Stream<Item = Result<Event, std::convert::Infallible>>>> {
-    let (tx, rx) = mpsc::channel::<Result<Event, std::convert::Infallible>>(200_000);
+    // `Last-Event-ID` is the last event the client received, `from_seq` is the first event to send
+    let from_seq = match headers.get("Last-Event-ID") {
+        Some(last_event_id) => Some(
+            last_event_id
+                .to_str()
+                .ok()
+                .and_then(|x| x.parse::<u64>().ok())
+                .bad_request("Invalid Last-Event-ID")?
+                + 1,
+        ),
+        None => query.from_seq,
+    };
 
-    let addresses = payload.addresses.unwrap_or_default();
+    // Resuming after removed events would skip them silently
+    if let Some(from_seq) = from_seq {
+        let replay = server.read_event_log(from_seq, 1);
+        if let Err(Some(gap)) = replay.as_ref().map_err(|e| e.downcast_ref::<EventLogGap>()) {
+            return Err(Response::builder()
+                .status(StatusCode::GONE)
+                .body(serde_json::to_string(&gap.to_string()).internal(INTERNAL)?)
+                .internal(INTERNAL)?);
+        }
+        replay.internal(INTERNAL)?;
+    }
 
-    let tokens = payload
-        .tokens
-        .unwrap_or_default()
-        .into_iter()
-        .map(LowerCaseTick::from)
-        .collect::<HashSet<_>>();
+    let filter = EventFilter {
+        addresses: payload.addresses,
+        tokens: payload
+            .tokens
+            .map(|x| x.into_iter().map(LowerCaseTick::from).collect()),
+        event_types: None,
+    };
 
-    {
-        let mut rx = server.event_sender.subscribe();
-
-        tokio::spawn(async move {
-            while !server.token.is_cancelled() {
-                match rx.try_recv() {
-                    Ok(event) => {
-                        match event {
-                            ServerEvent::NewHistory(address_token, action) => {
-                                if !addresses.is_empty()
-                                    && !addresses.contains(&address_token.address)
-                                {
-                                    continue;
-                                }
-
-                                if !tokens.is_empty()
-                                    && !tokens.contains(&address_token.token.into())
-                                {
-                                    continue;
-                                }
-
-                                let data = Event::default().data(
-                                    serde_json::to_string(&HistoryRest {
-                                        address_token: address_token.into(),
-                                        height: action.height,
-                                        action: action.into(),
-                                    })
-                                    .unwrap(),
-                                );
-
-                                if tx.send(Ok(data)).await.is_err() {
-                                    break;
-                                };
-                            }
-                            ServerEvent::Reorg(blocks_count, new_height) => {
-                                let data = Event::default().data(
-                                    serde_json::to_string(&ReorgRest {
-                                        event_type: "reorg".to_string(),
-                                        blocks_count,
-                                        new_height,
-                                    })
-                                    .unwrap(),
-                                );
-
-                                if tx.send(Ok(data)).await.is_err() {
-                                    break;
-                                };
-                            }
-                            ServerEvent::NewBlock(height, poh, blockhash) => {
-                                let data = Event::default().data(
-                                    serde_json::to_string(&NewBlockRest {
-                                        event_type: "new_block".to_string(),
-                                        height,
-                                        proof: poh,
-                                        blockhash,
-                                    })
-                                    .unwrap(),
-                                );
-
-                                if tx.send(Ok(data)).await.is_err() {
-                                    break;
-                                };
-                            }
-                        };
-                    }
-                    Err(tokio::sync::broadcast::error::TryRecvError::Lagged(count)) => {
-                        error!("Lagged {} events. Disconnecting...", count);
-                        break;
-                    }
-                    Err(tokio::sync::broadcast::error::TryRecvError::Closed) => {
-                        break;
-                    }
-                    Err(tokio::sync::broadcast::error::TryRecvError::Empty) => {
-                        tokio::time::sleep(Duration::from_millis(10)).await;
-                    }
-                }
-            }
-        });
-    }
+    let subscription = dispatcher.subscribe(filter, from_seq);
+
+    // Dropping the stream on disconnect unsubscribes
+    let stream = futures::stream::unfold(subscription, |mut subscription| async move {
+        let (seq, data) = subscription.next().await?;
+        let event = Event::default().id(seq.to_string()).data(&*data);
+        Some((Ok(event), subscription))
+    });
 
-    let stream = ReceiverStream::new(rx);
     Ok(Sse::new(stream))
 }
 
 async fn address_token_history(
     State(server): State<Arc<Server>>,
+    view: View,
     Path(script_str): Path<String>,
     Query(query): Query<AddressTokenHistoryParams>,
 ) -> ApiResult<impl IntoResponse> {
@@ -305,10 +370,11 @@ async fn address_token_history(
     }
     let token: LowerCaseTick = query.tick.into();
 
-    let deploy_proto = server
+    let deploy_proto = view
         .db
         .token_to_meta
         .get(&token)
+        .internal(INTERNAL)?
         .not_found("Token not found")?;
 
     let token = deploy_proto.proto.tick;
@@ -327,63 +393,57 @@ async fn address_token_history(
 
     let mut res = Vec::<HistoryRest>::new();
 
-    for (k, v) in server
+    for (k, v) in view
         .db
         .address_token_to_history
         .range(&from..&to, true)
         .take(query.limit.unwrap_or(100))
-        .collect_vec()
+        .try_collect::<_, Vec<_>, _>()
+        .internal(INTERNAL)?
     {
-        res.push(
-            HistoryRest::new(v.height, v.action, k, &server)
-                .await
-                .internal("Failed to load addresses")?,
-        );
+        res.push(HistoryRest::new(v.height, v.action, k, &server).internal(INTERNAL)?);
     }
 
     Ok(Json(res))
 }
 
-async fn address_tokens(
-    State(server): State<Arc<Server>>,
-    Path(script_str): Path<String>,
-) -> ApiResult<Response<Body>> {
+async fn address_tokens(view: View, Path(script_str): Path<String>) -> ApiResult<Response<Body>> {
     let scripthash =
         to_scripthash("address", &script_str, *NETWORK).bad_request("Invalid address")?;
 
     let mut ticks = HashMap::<LowerCaseTick, TokenTick>::new();
 
-    let mut data = server
-        .db
-        .address_token_to_balance
-        .range(
-            &AddressToken {
-                address: scripthash,
-                token: [0; 4].into(),
-            }..=&AddressToken {
-                address: scripthash,
-                token: [u8::MAX; 4].into(),
-            },
-            false,
-        )
-        .map(|(k, v)| {
-            let tick = ticks
-                .entry(k.token.clone())
-                .or_insert_with(|| server.db.token_to_meta.get(&k.token).unwrap().proto.tick);
-
-            TokenBalanceRest {
-                tick: *tick,
-                balance: v.balance,
-                transferable_balance: v.transferable_balance,
-                transfers_count: v.transfers_count,
-                transfers: vec![],
+    let (from, to) = AddressToken::search(scripthash).into_inner();
+
+    let mut data = vec![];
+    for entry in view.db.address_token_to_balance.range(&from..=&to, false) {
+        let (k, v) = entry.internal(INTERNAL)?;
+
+        let tick = match ticks.entry(k.token.clone()) {
+            Entry::Occupied(x) => *x.get(),
+            Entry::Vacant(x) => {
+                let meta = view
+                    .db
+                    .token_to_meta
+                    .get(&k.token)
+                    .internal(INTERNAL)?
+                    .internal(INTERNAL)?;
+                *x.insert(meta.proto.tick)
             }
-        })
-        .collect_vec();
+        };
+
+        data.push(TokenBalanceRest {
+            tick,
+            balance: v.balance,
+            transferable_balance: v.transferable_balance,
+            transfers_count: v.transfers_count,
+            transfers: vec![],
+        });
+    }
 
     let mut transfers = HashMap::<TokenTick, Vec<(Location, TransferProto)>>::new();
 
-    for (key, value) in server
+    for (key, value) in view
         .db
         .address_location_to_transfer
         .range(
@@ -393,7 +453,9 @@ async fn address_tokens(
             }..,
             false,
         )
-        .take_while(|x| x.0.address == scripthash)
+        .take_while(|x| !matches!(x, Ok((k, _)) if k.address != scripthash))
+        .try_collect::<_, Vec<_>, _>()
+        .internal(INTERNAL)?
     {
         transfers
             .entry(value.tick)
@@ -435,6 +497,11 @@ struct AddressTokenHistoryParams {
     tick: String,
 }
 
+#[derive(Deserialize)]
+struct SubscribeParams {
+    from_seq: Option<u64>,
+}
+
 #[derive(Deserialize)]
 struct SubscribeRequest {
     #[serde(default)]
@@ -443,11 +510,21 @@ struct SubscribeRequest {
     tokens: Option<HashSet<String>>,
 }
 
+#[derive(Serialize)]
+#[serde(untagged)]
+enum TxidEventRest {
+    History(HistoryRest),
+    Rejected(RejectedRest),
+}
+
 #[derive(Serialize)]
 struct StatusRest {
     height: u32,
     proof: String,
     blockhash: String,
+    /// Set when indexing stopped at a block that failed to index
+    #[serde(skip_serializing_if = "Option::is_none")]
+    fault: Option<crate::fault::Fault>,
 }
 
 #[derive(Serialize)]
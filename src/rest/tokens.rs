@@ -1,5 +1,3 @@
-use std::sync::Arc;
-
 use crate::{
     tokens::{InscriptionId, LowerCaseTick, TokenTick},
     NON_STANDARD_ADDRESS,
@@ -7,10 +5,11 @@ use crate::{
 
 use super::{
     utils::{first_page, page_size_default, to_scripthash, validate_tick},
-    AddressLocation, Fixed128, TransferProtoDB, BAD_PARAMS, INTERNAL, NETWORK,
+    view::View,
+    AddressLocation, Fixed128, Table, TransferProtoDB, BAD_PARAMS, INTERNAL, NETWORK,
 };
 use axum::{
-    extract::{Path, Query, State},
+    extract::{Path, Query},
     response::IntoResponse,
     Json,
 };
@@ -20,7 +19,7 @@ use nintypes::common::inscriptions::Outpoint;
 use serde::{Deserialize, Serialize};
 use validator::Validate;
 
-use super::{ApiResult, Server, BAD_REQUEST, NOT_FOUND};
+use super::{ApiResult, BAD_REQUEST, NOT_FOUND};
 
 #[derive(Serialize, Deserialize)]
 pub struct Token {
@@ -47,37 +46,37 @@ pub struct TokenArgs {
     pub tick: String,
 }
 
-pub async fn token(
-    State(back): State<Arc<Server>>,
-    Query(args): Query<TokenArgs>,
-) -> ApiResult<impl IntoResponse> {
+pub async fn token(view: View, Query(args): Query<TokenArgs>) -> ApiResult<impl IntoResponse> {
     args.validate().bad_request(BAD_REQUEST)?;
     let tick: LowerCaseTick = args.tick.into();
     let ref_tick = &tick;
-    let token = back
+    let v = view
         .db
         .token_to_meta
         .get(ref_tick.clone())
-        .map(|v| Token {
-            height: v.proto.height,
-            created: v.proto.created,
-            deployer: back
-                .db
-                .fullhash_to_address
-                .get(v.proto.deployer)
-                .unwrap_or(NON_STANDARD_ADDRESS.to_string()),
-            transactions: v.proto.transactions,
-            holders: back.holders.holders_by_tick(ref_tick).unwrap_or(0) as u32,
-            tick: v.proto.tick,
-            genesis: v.genesis,
-            supply: v.proto.supply,
-            mint_percent: v.proto.mint_percent().to_string(),
-            completed: v.proto.is_completed(),
-            max: v.proto.max,
-            lim: v.proto.lim,
-            dec: v.proto.dec,
-        })
+        .internal(INTERNAL)?
         .not_found(NOT_FOUND)?;
+    let deployer = view
+        .db
+        .fullhash_to_address
+        .get(v.proto.deployer)
+        .internal(INTERNAL)?
+        .unwrap_or(NON_STANDARD_ADDRESS.to_string());
+    let token = Token {
+        height: v.proto.height,
+        created: v.proto.created,
+        deployer,
+        transactions: v.proto.transactions,
+        holders: view.holders.holders_by_tick(ref_tick).unwrap_or(0) as u32,
+        tick: v.proto.tick,
+        genesis: v.genesis,
+        supply: v.proto.supply,
+        mint_percent: v.proto.mint_percent().to_string(),
+        completed: v.proto.is_completed(),
+        max: v.proto.max,
+        lim: v.proto.lim,
+        dec: v.proto.dec,
+    };
 
     Ok(Json(token))
 }
@@ -124,17 +123,17 @@ pub struct TokensArgs {
     pub search: Option<String>,
 }
 
-pub async fn tokens(
-    State(server): State<Arc<Server>>,
-    Query(args): Query<TokensArgs>,
-) -> ApiResult<impl IntoResponse> {
+pub async fn tokens(view: View, Query(args): Query<TokensArgs>) -> ApiResult<impl IntoResponse> {
     args.validate().bad_request(BAD_PARAMS)?;
     let search = args.search.map(|x| x.to_lowercase().as_bytes().to_vec());
 
-    let iter = server
+    let iter = view
         .db
         .token_to_meta
         .iter()
+        .try_collect::<_, Vec<_>, _>()
+        .internal(INTERNAL)?
+        .into_iter()
         .filter(|x| match args.filter_by {
             FilterBy::All => true,
             FilterBy::Completed => x.1.is_completed(),
@@ -145,7 +144,7 @@ pub async fn tokens(
             _ => true,
         });
 
-    let stats = server.holders.stats();
+    let stats = view.holders.stats();
     let all = match args.sort_by {
         SortBy::DeployTimeAsc => iter.sorted_by_key(|(_, v)| v.proto.created).collect_vec(),
         SortBy::DeployTimeDesc => iter
@@ -172,26 +171,29 @@ pub async fn tokens(
         .iter()
         .skip((args.page - 1) * args.page_size)
         .take(args.page_size)
-        .map(|(tick, v)| Token {
-            height: v.proto.height,
-            created: v.proto.created,
-            mint_percent: v.proto.mint_percent().to_string(),
-            tick: v.proto.tick,
-            genesis: v.genesis,
-            deployer: server
-                .db
-                .fullhash_to_address
-                .get(v.proto.deployer)
-                .unwrap_or(NON_STANDARD_ADDRESS.to_string()),
-            transactions: v.proto.transactions,
-            holders: server.holders.holders_by_tick(tick).unwrap_or(0) as u32,
-            supply: v.proto.supply,
-            completed: v.proto.is_completed(),
-            max: v.proto.max,
-            lim: v.proto.lim,
-            dec: v.proto.dec,
+        .map(|(tick, v)| {
+            anyhow::Ok(Token {
+                height: v.proto.height,
+                created: v.proto.created,
+                mint_percent: v.proto.mint_percent().to_string(),
+                tick: v.proto.tick,
+                genesis: v.genesis,
+                deployer: view
+                    .db
+                    .fullhash_to_address
+                    .get(v.proto.deployer)?
+                    .unwrap_or(NON_STANDARD_ADDRESS.to_string()),
+                transactions: v.proto.transactions,
+                holders: view.holders.holders_by_tick(tick).unwrap_or(0) as u32,
+                supply: v.proto.supply,
+                completed: v.proto.is_completed(),
+                max: v.proto.max,
+                lim: v.proto.lim,
+                dec: v.proto.dec,
+            })
         })
-        .collect_vec();
+        .try_collect::<_, Vec<_>, _>()
+        .internal(INTERNAL)?;
 
     Ok(Json(TokensResult {
         count,
@@ -201,24 +203,24 @@ pub async fn tokens(
 }
 
 pub async fn token_transfer_proof(
-    State(state): State<Arc<Server>>,
+    view: View,
     Path((address, outpoint)): Path<(String, Outpoint)>,
 ) -> ApiResult<impl IntoResponse> {
     let scripthash = to_scripthash("address", &address, *NETWORK).bad_request("Invalid address")?;
 
     let (from, to) = AddressLocation::search(scripthash, Some(outpoint.into())).into_inner();
 
-    let data: Vec<_> = state
+    let data: Vec<_> = view
         .db
         .address_location_to_transfer
         .range(&from..&to, false)
-        .map(|(_, TransferProtoDB { tick, amt, height })| {
-            anyhow::Ok(TokenTransferProof {
+        .map_ok(
+            |(_, TransferProtoDB { tick, amt, height })| TokenTransferProof {
                 amt,
                 tick: tick.to_string(),
                 height,
-            })
-        })
+            },
+        )
         .try_collect()
         .track_with("")
         .internal(INTERNAL)?;
@@ -232,3 +234,71 @@ pub struct TokenTransferProof {
     pub tick: String,
     pub height: u32,
 }
+
+#[cfg(test)]
+mod tests {
+    use axum::http::StatusCode;
+    use bellscoin::{hashes::Hash, Txid};
+
+    use super::*;
+    use crate::{
+        test_utils,
+        tokens::{DeployProtoDB, FullHash, TokenMetaDB},
+    };
+
+    #[tokio::test]
+    async fn token_is_read_from_committed_view() {
+        let server = test_utils::server().await;
+        let tick = TokenTick::from(*b"abcd");
+
+        let meta = TokenMetaDB {
+            genesis: InscriptionId {
+                txid: Txid::all_zeros(),
+                index: 0,
+            },
+            proto: DeployProtoDB {
+                tick,
+                max: "1000".parse().unwrap(),
+                lim: "100".parse().unwrap(),
+                dec: 18,
+                supply: "250".parse().unwrap(),
+                transfer_count: 0,
+                mint_count: 3,
+                height: 1,
+                created: 0,
+                deployer: FullHash::from([1; 32]),
+                transactions: 4,
+            },
+        };
+        server
+            .db
+            .token_to_meta
+            .set(LowerCaseTick::from(tick), meta)
+            .unwrap();
+
+        let args = || TokenArgs {
+            tick: "ABCD".to_string(),
+        };
+
+        // Not committed yet
+        let err = token(View::of(&server), Query(args())).await.err().unwrap();
+        assert_eq!(err.status(), StatusCode::NOT_FOUND);
+
+        server.commit(1).unwrap();
+        let response = token(View::of(&server), Query(args()))
+            .await
+            .ok()
+            .unwrap()
+            .into_response();
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let token: Token = serde_json::from_slice(&body).unwrap();
+        assert_eq!(token.tick, tick);
+        assert_eq!(token.supply, "250".parse().unwrap());
+        assert_eq!(token.mint_percent, "25");
+        assert_eq!(token.deployer, NON_STANDARD_ADDRESS);
+    }
+}
@@ -20,19 +20,13 @@ pub fn to_scripthash(
 }
 
 fn address_to_scripthash(addr: &str, network: Network) -> anyhow::Result<FullHash> {
-    let addr = Address::from_str(addr)?;
+    // Testnet, Regtest and Signet all share the same version bytes,
+    // legacy addresses are detected as Testnet for all of them, so exact network match is not enough.
+    let addr = Address::from_str(addr)?
+        .require_network(network)
+        .anyhow_with("Address on invalid network")?;
 
-    let is_expected_net = {
-        // Testnet, Regtest and Signet all share the same version bytes,
-        // `addr_network` will be detected as Testnet for all of them.
-        addr.network == network
-    };
-
-    if !is_expected_net {
-        anyhow::bail!("Address on invalid network");
-    }
-
-    Ok(addr.payload.script_pubkey().compute_script_hash())
+    Ok(addr.script_pubkey().compute_script_hash())
 }
 
 fn parse_scripthash(scripthash: &str) -> anyhow::Result<FullHash> {
@@ -49,7 +43,7 @@ pub fn first_page() -> usize {
 }
 
 pub fn validate_tick(tick: &str) -> Result<(), ValidationError> {
-    if tick.len() != 4 {
+    if tick.len() < TokenTick::LEGACY_LEN || tick.len() > TokenTick::MAX_LEN {
         return Err(ValidationError::new("Wrong tick length"));
     }
 
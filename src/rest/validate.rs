@@ -0,0 +1,199 @@
+use axum::{response::IntoResponse, Json};
+use dutils::error::ApiError;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    inscriptions::Location,
+    tokens::{
+        Brc4, Brc4ActionErr, Brc4Error, Brc4ParseErr, FullHash, HistoryTokenAction, Holders,
+        InscriptionId, InscriptionTemplate, TokenCache,
+    },
+};
+
+use super::{utils::to_scripthash, view::View, ApiResult, Fixed128, Table, INTERNAL, NETWORK};
+
+#[derive(Deserialize)]
+pub struct ValidateRequest {
+    pub content_type: String,
+    pub content: String,
+    pub address: Option<String>,
+}
+
+#[derive(Serialize, Default)]
+pub struct ValidateRest {
+    pub parsed: Option<Brc4>,
+    pub parse_error: Option<Brc4ParseErr>,
+    /// `None` if the payload can't be checked against the current state (not parsed or transfer without address)
+    pub valid: Option<bool>,
+    pub amt: Option<Fixed128>,
+    pub reason: Option<Brc4ActionErr>,
+}
+
+pub async fn validate(
+    view: View,
+    Json(payload): Json<ValidateRequest>,
+) -> ApiResult<impl IntoResponse> {
+    let owner = payload
+        .address
+        .as_ref()
+        .map(|x| to_scripthash("address", x, *NETWORK))
+        .transpose()
+        .bad_request("Invalid address")?;
+
+    let content = payload.content.into_bytes();
+
+    let height = view
+        .db
+        .last_block
+        .get(())
+        .internal(INTERNAL)?
+        .unwrap_or_default()
+        + 1;
+
+    let parsed = match TokenCache::try_parse(&payload.content_type, &content, height) {
+        Ok(parsed) => parsed,
+        Err(e) => {
+            return Ok(Json(ValidateRest {
+                parse_error: Some(e),
+                ..Default::default()
+            }))
+        }
+    };
+
+    if owner.is_none() && matches!(parsed, Brc4::Transfer { .. }) {
+        return Ok(Json(ValidateRest {
+            parsed: Some(parsed),
+            ..Default::default()
+        }));
+    }
+
+    let location = Location::zero();
+
+    let inc = InscriptionTemplate {
+        genesis: InscriptionId {
+            txid: location.outpoint.txid,
+            index: 0,
+        },
+        location,
+        content_type: Some(payload.content_type),
+        content_encoding: None,
+        owner: owner.unwrap_or(FullHash::ZERO),
+        value: 0,
+        content: Some(content),
+        leaked: false,
+    };
+
+    // Same path as indexing a block, but holders are throwaway so the real ones stay untouched
+    let mut token_cache = TokenCache::default();
+    token_cache.parse_token_action(&inc, height, 0);
+    token_cache.load_tokens_data(&view.db).internal(INTERNAL)?;
+
+    let history = token_cache
+        .process_token_actions(None, &Holders::default(), height)
+        .internal(INTERNAL)?;
+
+    let amt = history.first().and_then(|x| match x {
+        HistoryTokenAction::Mint { amt, .. } | HistoryTokenAction::DeployTransfer { amt, .. } => {
+            Some(*amt)
+        }
+        _ => None,
+    });
+
+    let reason = token_cache.rejected.first().and_then(|x| match &x.reason {
+        Brc4Error::Action(e) => Some(e.clone()),
+        Brc4Error::Parse(_) => None,
+    });
+
+    Ok(Json(ValidateRest {
+        parsed: Some(parsed),
+        parse_error: None,
+        valid: Some(!history.is_empty()),
+        amt,
+        reason,
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::http::StatusCode;
+    use bellscoin::Address;
+
+    use super::*;
+    use crate::test_utils::{self, TestChain};
+
+    async fn check(view: View, content: &str, address: Option<&str>) -> serde_json::Value {
+        let request = ValidateRequest {
+            content_type: "text/plain;charset=utf-8".to_string(),
+            content: content.to_string(),
+            address: address.map(|x| x.to_string()),
+        };
+        let response = validate(view, Json(request))
+            .await
+            .ok()
+            .unwrap()
+            .into_response();
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        serde_json::from_slice(&body).unwrap()
+    }
+
+    fn amt(result: &serde_json::Value) -> Fixed128 {
+        result["amt"].as_str().unwrap().parse().unwrap()
+    }
+
+    #[tokio::test]
+    async fn validate_checks_against_committed_state() {
+        let alice = test_utils::address(1);
+        let bob = test_utils::address(2);
+
+        let mut chain = TestChain::new();
+        let deploy = chain.inscribe(&alice, &test_utils::deploy("abcd", "1000", "100"));
+        chain.mine(vec![deploy]);
+        let mint = chain.inscribe(&alice, &test_utils::mint("abcd", "100"));
+        chain.mine(vec![mint]);
+
+        let server = test_utils::server().await;
+        test_utils::index(&server, &chain, chain.height()).await;
+
+        let alice = Address::from_script(&alice, *NETWORK).unwrap().to_string();
+        let bob = Address::from_script(&bob, *NETWORK).unwrap().to_string();
+        let view = || View::of(&server);
+
+        let result = check(view(), &test_utils::mint("abcd", "50"), None).await;
+        assert_eq!(result["valid"], true);
+        assert_eq!(amt(&result), "50".parse().unwrap());
+
+        let result = check(view(), &test_utils::mint("abcd", "200"), None).await;
+        assert_eq!(result["valid"], false);
+        assert_eq!(result["reason"], "reach_lim_bound");
+
+        let result = check(view(), &test_utils::mint("efgh", "1"), None).await;
+        assert_eq!(result["reason"], "not_deployed");
+
+        let result = check(view(), &test_utils::transfer("abcd", "10"), Some(&alice)).await;
+        assert_eq!(result["valid"], true);
+        assert_eq!(amt(&result), "10".parse().unwrap());
+
+        let result = check(view(), &test_utils::transfer("abcd", "10"), Some(&bob)).await;
+        assert_eq!(result["valid"], false);
+        assert_eq!(result["reason"], "insufficient_balance");
+
+        // Transfers can't be checked without the sender
+        let result = check(view(), &test_utils::transfer("abcd", "10"), None).await;
+        assert!(result["valid"].is_null());
+        assert!(!result["parsed"].is_null());
+
+        let result = check(view(), &test_utils::mint("abcd", "1.2.3"), None).await;
+        assert!(result["parsed"].is_null());
+        assert!(!result["parse_error"].is_null());
+
+        // Real holders are left untouched by the checks
+        assert_eq!(
+            server.read_view().holders.holders_by_tick(&"abcd".into()),
+            Some(1)
+        );
+    }
+}
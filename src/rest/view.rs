@@ -0,0 +1,66 @@
+use std::sync::OnceLock;
+
+use axum::{extract::FromRequestParts, http::request::Parts};
+use server::ReadView;
+
+use super::*;
+
+/// Header with the height of the committed block a response was read at
+pub const HEIGHT_HEADER: &str = "X-Indexed-Height";
+
+/// Committed state of the db and holders when the request started.
+/// Blocks committed meanwhile don't change it, so every read of a request sees the same block.
+pub struct View(Arc<ReadView>);
+
+impl std::ops::Deref for View {
+    type Target = ReadView;
+
+    fn deref(&self) -> &Self::Target {
+        &self.0
+    }
+}
+
+#[cfg(test)]
+impl View {
+    /// View of the last committed block, as a request would get it
+    pub fn of(server: &Server) -> Self {
+        View(server.read_view())
+    }
+}
+
+/// Height of the view used by a request, reported by `report_height`
+#[derive(Clone, Default)]
+struct ViewHeight(Arc<OnceLock<u32>>);
+
+impl FromRequestParts<Arc<Server>> for View {
+    type Rejection = std::convert::Infallible;
+
+    async fn from_request_parts(
+        parts: &mut Parts,
+        server: &Arc<Server>,
+    ) -> Result<Self, Self::Rejection> {
+        let view = server.read_view();
+
+        if let Some(height) = parts.extensions.get::<ViewHeight>() {
+            height.0.set(view.height).ok();
+        }
+
+        Ok(View(view))
+    }
+}
+
+/// Adds the height header to responses of handlers reading a view
+pub async fn report_height(mut request: Request, next: Next) -> impl IntoResponse {
+    let height = ViewHeight::default();
+    request.extensions_mut().insert(height.clone());
+
+    let mut response = next.run(request).await;
+
+    if let Some(height) = height.0.get() {
+        response
+            .headers_mut()
+            .insert(HEIGHT_HEADER, (*height).into());
+    }
+
+    response
+}
@@ -0,0 +1,219 @@
+use axum::http::HeaderMap;
+
+use super::{
+    events::EventType,
+    utils::{to_scripthash, validate_tick},
+    *,
+};
+
+const READ_ONLY: &str = "Webhooks are managed by the indexer, not a serve-only replica";
+
+#[derive(Deserialize)]
+pub struct WebhookRequest {
+    url: String,
+    secret: Option<String>,
+    #[serde(default)]
+    addresses: HashSet<String>,
+    #[serde(default)]
+    tokens: HashSet<String>,
+    /// All event types if empty
+    #[serde(default)]
+    events: HashSet<EventType>,
+}
+
+#[derive(Serialize)]
+pub struct WebhookRest {
+    id: u64,
+    url: String,
+    signed: bool,
+    addresses: HashSet<String>,
+    tokens: HashSet<String>,
+    events: HashSet<EventType>,
+    created_seq: u64,
+    last_seq: Option<u64>,
+}
+
+impl WebhookRest {
+    fn new(webhook: Webhook, last_seq: Option<u64>) -> Self {
+        Self {
+            id: webhook.id,
+            url: webhook.url,
+            signed: webhook.secret.is_some(),
+            addresses: webhook.addresses,
+            tokens: webhook.tokens,
+            events: webhook.events,
+            created_seq: webhook.created_seq,
+            last_seq,
+        }
+    }
+}
+
+#[derive(Deserialize)]
+pub struct DeadLettersArgs {
+    offset: Option<u64>,
+    limit: Option<usize>,
+}
+
+/// Webhooks are managed with `Authorization: Bearer <WEBHOOK_ADMIN_TOKEN>` and disabled without the token
+fn authorize(headers: &HeaderMap) -> Result<(), StatusCode> {
+    let token = CONFIG
+        .server
+        .webhook_admin_token
+        .as_ref()
+        .ok_or(StatusCode::NOT_FOUND)?;
+
+    let authorized = headers
+        .get("Authorization")
+        .and_then(|x| x.to_str().ok())
+        .and_then(|x| x.strip_prefix("Bearer "))
+        .is_some_and(|x| x == token);
+
+    if !authorized {
+        return Err(StatusCode::UNAUTHORIZED);
+    }
+
+    Ok(())
+}
+
+fn unauthorized(status: StatusCode) -> Response<String> {
+    Response::builder()
+        .status(status)
+        .body(status.canonical_reason().unwrap_or_default().to_string())
+        .unwrap()
+}
+
+pub async fn register(
+    State(server): State<Arc<Server>>,
+    headers: HeaderMap,
+    Json(payload): Json<WebhookRequest>,
+) -> ApiResult<impl IntoResponse> {
+    authorize(&headers).map_err(unauthorized)?;
+    if server.db.is_secondary() {
+        return Err("").bad_request(READ_ONLY);
+    }
+
+    let url = reqwest::Url::parse(&payload.url).bad_request("Invalid url")?;
+    if !matches!(url.scheme(), "http" | "https") {
+        return Err("").bad_request("Invalid url");
+    }
+    for address in &payload.addresses {
+        to_scripthash("address", address, *NETWORK).bad_request("Invalid address")?;
+    }
+    for tick in &payload.tokens {
+        validate_tick(tick).bad_request("Invalid tick")?;
+    }
+
+    let id = server.next_webhook_id().internal(INTERNAL)?;
+
+    let webhook = Webhook {
+        id,
+        url: payload.url,
+        secret: payload.secret,
+        addresses: payload.addresses,
+        tokens: payload.tokens,
+        events: if payload.events.is_empty() {
+            EventType::ALL.into_iter().collect()
+        } else {
+            payload.events
+        },
+        created_seq: server.last_event_seq(),
+    };
+
+    server.db.webhooks.set(id, &webhook).internal(INTERNAL)?;
+    server.webhooks_changed.notify_one();
+
+    Ok(Json(WebhookRest::new(webhook, None)))
+}
+
+pub async fn list(
+    State(server): State<Arc<Server>>,
+    headers: HeaderMap,
+) -> ApiResult<impl IntoResponse> {
+    authorize(&headers).map_err(unauthorized)?;
+
+    let data = server
+        .db
+        .webhooks
+        .iter()
+        .map(|x| {
+            let (id, webhook) = x?;
+            anyhow::Ok(WebhookRest::new(
+                webhook,
+                server.db.webhook_cursors.get(id)?,
+            ))
+        })
+        .try_collect::<_, Vec<_>, _>()
+        .internal(INTERNAL)?;
+
+    Ok(Json(data))
+}
+
+pub async fn remove(
+    State(server): State<Arc<Server>>,
+    headers: HeaderMap,
+    Path(id): Path<u64>,
+) -> ApiResult<impl IntoResponse> {
+    authorize(&headers).map_err(unauthorized)?;
+    if server.db.is_secondary() {
+        return Err("").bad_request(READ_ONLY);
+    }
+
+    let webhook = server
+        .db
+        .webhooks
+        .get(id)
+        .internal(INTERNAL)?
+        .not_found("Webhook not found")?;
+    let last_seq = server.db.webhook_cursors.get(id).internal(INTERNAL)?;
+
+    server.db.webhooks.remove(id).internal(INTERNAL)?;
+    server.db.webhook_cursors.remove(id).internal(INTERNAL)?;
+    server.webhooks_changed.notify_one();
+
+    let (from, to) = WebhookEvent::search(id).into_inner();
+    let dead_letters = server
+        .db
+        .webhook_dead_letters
+        .range(&from..=&to, false)
+        .map_ok(|(k, _)| k)
+        .try_collect::<_, Vec<_>, _>()
+        .internal(INTERNAL)?;
+    server
+        .db
+        .webhook_dead_letters
+        .remove_batch(dead_letters.into_iter())
+        .internal(INTERNAL)?;
+
+    Ok(Json(WebhookRest::new(webhook, last_seq)))
+}
+
+pub async fn dead_letters(
+    State(server): State<Arc<Server>>,
+    headers: HeaderMap,
+    Path(id): Path<u64>,
+    Query(params): Query<DeadLettersArgs>,
+) -> ApiResult<impl IntoResponse> {
+    authorize(&headers).map_err(unauthorized)?;
+
+    if let Some(limit) = params.limit {
+        if limit > 100 {
+            return Err("").bad_request("Limit exceeded");
+        }
+    }
+
+    let (from, mut to) = WebhookEvent::search(id).into_inner();
+    if let Some(offset) = params.offset {
+        to.seq = offset;
+    }
+
+    let data = server
+        .db
+        .webhook_dead_letters
+        .range(&from..&to, true)
+        .map_ok(|(_, v)| v)
+        .take(params.limit.unwrap_or(100))
+        .try_collect::<_, Vec<_>, _>()
+        .internal(INTERNAL)?;
+
+    Ok(Json(data))
+}
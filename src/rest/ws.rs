@@ -0,0 +1,234 @@
+use axum::extract::ws::{Message, WebSocket, WebSocketUpgrade};
+
+use super::{
+    events::{EventDispatcher, EventFilter, EventType},
+    utils::{to_scripthash, validate_tick},
+    *,
+};
+
+/// How often the server pings the client
+const PING_INTERVAL: Duration = Duration::from_secs(30);
+/// Connection is closed if nothing was received from the client for this long
+const CLIENT_TIMEOUT: Duration = Duration::from_secs(90);
+
+#[derive(Deserialize)]
+#[serde(tag = "op", rename_all = "snake_case")]
+enum WsRequest {
+    Subscribe {
+        id: Option<u64>,
+        #[serde(flatten)]
+        topics: WsTopics,
+    },
+    Unsubscribe {
+        id: Option<u64>,
+        #[serde(flatten)]
+        topics: WsTopics,
+    },
+    Ping {
+        id: Option<u64>,
+    },
+}
+
+/// `None` in an ack means not filtered
+#[derive(Serialize, Deserialize, Default)]
+struct WsTopics {
+    addresses: Option<HashSet<String>>,
+    tokens: Option<HashSet<String>>,
+    events: Option<HashSet<EventType>>,
+}
+
+impl WsTopics {
+    fn validate(&self) -> Result<(), String> {
+        for address in self.addresses.iter().flatten() {
+            to_scripthash("address", address, *NETWORK)
+                .map_err(|_| format!("Invalid address: {address}"))?;
+        }
+        for tick in self.tokens.iter().flatten() {
+            validate_tick(tick).map_err(|_| format!("Invalid tick: {tick}"))?;
+        }
+        Ok(())
+    }
+
+    fn is_empty(&self) -> bool {
+        self.addresses.is_none() && self.tokens.is_none() && self.events.is_none()
+    }
+
+    fn current(filter: &EventFilter) -> Self {
+        Self {
+            addresses: filter.addresses.clone(),
+            tokens: filter.tokens.as_ref().map(|x| {
+                x.iter()
+                    .map(|x| String::from_utf8_lossy(x).into_owned())
+                    .collect()
+            }),
+            events: filter.event_types.clone(),
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct WsAckRest {
+    event_type: String,
+    id: Option<u64>,
+    op: String,
+    subscription: WsTopics,
+}
+
+#[derive(Serialize)]
+struct WsPongRest {
+    event_type: String,
+    id: Option<u64>,
+}
+
+#[derive(Serialize)]
+struct WsErrorRest {
+    event_type: String,
+    id: Option<u64>,
+    message: String,
+}
+
+pub async fn ws(
+    Extension(dispatcher): Extension<Arc<EventDispatcher>>,
+    ws: WebSocketUpgrade,
+) -> impl IntoResponse {
+    ws.on_upgrade(|socket| handle_socket(socket, dispatcher))
+}
+
+async fn handle_socket(mut socket: WebSocket, dispatcher: Arc<EventDispatcher>) {
+    let mut subscription = dispatcher.subscribe(EventFilter::empty(), None);
+
+    let mut ping = tokio::time::interval(PING_INTERVAL);
+    let mut last_seen = Instant::now();
+
+    loop {
+        let reply = tokio::select! {
+            message = socket.recv() => {
+                let Some(Ok(message)) = message else {
+                    break;
+                };
+                last_seen = Instant::now();
+
+                match message {
+                    Message::Text(text) => {
+                        match subscription
+                            .update_filter(|filter| handle_request(&text, filter))
+                            .track()
+                        {
+                            Ok(reply) => Some(reply),
+                            Err(_) => break,
+                        }
+                    }
+                    Message::Close(_) => break,
+                    // Pongs are sent by the websocket implementation
+                    _ => None,
+                }
+            }
+            event = subscription.next() => {
+                let Some((_, data)) = event else {
+                    break;
+                };
+
+                Some(data.to_string())
+            }
+            _ = ping.tick() => {
+                if last_seen.elapsed() > CLIENT_TIMEOUT {
+                    break;
+                }
+
+                if socket.send(Message::Ping(Default::default())).await.is_err() {
+                    break;
+                }
+                None
+            }
+        };
+
+        if let Some(reply) = reply {
+            if socket.send(reply.into()).await.is_err() {
+                break;
+            }
+        }
+    }
+}
+
+fn handle_request(text: &str, filter: &mut EventFilter) -> String {
+    let request = match serde_json::from_str::<WsRequest>(text) {
+        Ok(request) => request,
+        Err(e) => {
+            return serde_json::to_string(&WsErrorRest {
+                event_type: "error".to_string(),
+                id: None,
+                message: format!("Invalid request: {e}"),
+            })
+            .unwrap()
+        }
+    };
+
+    let (id, op) = match request {
+        WsRequest::Ping { id } => {
+            return serde_json::to_string(&WsPongRest {
+                event_type: "pong".to_string(),
+                id,
+            })
+            .unwrap()
+        }
+        WsRequest::Subscribe { id, topics } => {
+            if let Err(message) = topics.validate() {
+                return serde_json::to_string(&WsErrorRest {
+                    event_type: "error".to_string(),
+                    id,
+                    message,
+                })
+                .unwrap();
+            }
+
+            if let Some(addresses) = topics.addresses {
+                filter
+                    .addresses
+                    .get_or_insert_with(HashSet::new)
+                    .extend(addresses);
+            }
+            if let Some(tokens) = topics.tokens {
+                filter
+                    .tokens
+                    .get_or_insert_with(HashSet::new)
+                    .extend(tokens.into_iter().map(LowerCaseTick::from));
+            }
+            // All event types if none are given
+            match (topics.events, &mut filter.event_types) {
+                (Some(events), Some(event_types)) => event_types.extend(events),
+                (Some(_), None) => {}
+                (None, event_types) => *event_types = None,
+            }
+            (id, "subscribe")
+        }
+        WsRequest::Unsubscribe { id, topics } => {
+            if topics.is_empty() {
+                *filter = EventFilter::empty();
+            }
+            // Addresses and ticks can't be removed if they are not filtered.
+            // Removing the last one leaves an empty set that matches nothing.
+            if let (Some(addresses), Some(subscribed)) = (topics.addresses, &mut filter.addresses) {
+                subscribed.retain(|x| !addresses.contains(x));
+            }
+            if let (Some(tokens), Some(subscribed)) = (topics.tokens, &mut filter.tokens) {
+                for tick in tokens {
+                    subscribed.remove(&LowerCaseTick::from(tick));
+                }
+            }
+            if let Some(events) = topics.events {
+                let mut event_types = filter.event_types();
+                event_types.retain(|x| !events.contains(x));
+                filter.event_types = Some(event_types);
+            }
+            (id, "unsubscribe")
+        }
+    };
+
+    serde_json::to_string(&WsAckRest {
+        event_type: "ack".to_string(),
+        id,
+        op: op.to_string(),
+        subscription: WsTopics::current(filter),
+    })
+    .unwrap()
+}
@@ -3,77 +3,238 @@ use super::*;
 mod structs;
 pub mod threads;
 pub use structs::*;
-use threads::AddressesToLoad;
+
+/// State of the db at the last committed block
+pub struct ReadView {
+    pub height: u32,
+    /// Reads are pinned to the snapshot taken when the block was committed
+    pub db: Arc<DB>,
+    pub holders: Arc<HoldersData>,
+}
 
 pub struct Server {
     pub db: Arc<DB>,
-    pub event_sender: tokio::sync::broadcast::Sender<ServerEvent>,
-    pub raw_event_sender: kanal::Sender<RawServerEvent>,
+    pub event_sender: tokio::sync::broadcast::Sender<SequencedEvent>,
+    /// Sequence number of the last logged event. Locked while an event is logged and broadcasted
+    event_seq: parking_lot::Mutex<u64>,
+    /// Events up to it are removed from the event log
+    event_log_pruned: parking_lot::Mutex<u64>,
     pub token: WaitToken,
-    pub last_indexed_address_height: Arc<tokio::sync::RwLock<u32>>,
-    pub addr_tx: Arc<kanal::Sender<AddressesToLoad>>,
     pub client: Arc<AsyncClient>,
     pub holders: Arc<Holders>,
+    /// Id of the last registered webhook. Locked while an id is allocated
+    webhook_id: parking_lot::Mutex<u64>,
+    /// Notified when a webhook is registered or removed
+    pub webhooks_changed: tokio::sync::Notify,
+    /// Set when the initial catch-up is finished and new blocks are followed
+    pub synced: AtomicBool,
+    /// Set when indexing stopped at a block that failed to index
+    pub fault: parking_lot::Mutex<Option<fault::Fault>>,
+    /// Replaced when a block is committed. Readers keep the view they cloned
+    view: parking_lot::RwLock<Arc<ReadView>>,
 }
 
 impl Server {
-    pub async fn new(
-        db_path: &str,
-    ) -> anyhow::Result<(
-        kanal::Receiver<AddressesToLoad>,
-        kanal::Receiver<RawServerEvent>,
-        tokio::sync::broadcast::Sender<ServerEvent>,
-        Self,
-    )> {
-        let (raw_tx, raw_rx) = kanal::unbounded();
-        let (tx, _) = tokio::sync::broadcast::channel(30_000);
-        let (addr_tx, addr_rx) = kanal::unbounded();
+    pub async fn new(db: DB) -> anyhow::Result<Self> {
+        let (tx, _) = tokio::sync::broadcast::channel(CONFIG.channels.events);
         let token = WaitToken::default();
-        let db = Arc::new(DB::open(db_path));
+        let db = Arc::new(db);
+        let event_seq = db.last_event_seq.get(())?.unwrap_or_default();
+        let event_log_pruned = match db.event_log.iter().next().transpose()? {
+            Some((first, _)) => first - 1,
+            None => event_seq,
+        };
+        let webhook_id = db.last_webhook_id.get(())?.unwrap_or_default();
+        let height = db.last_block.get(())?.unwrap_or_default();
+        let client = AsyncClient::new(&CONFIG.rpc, token.clone());
+        if let Some(hash) = db.block_hashes.get(height)? {
+            client.set_indexed_tip(height, hash);
+        }
+
+        let holders = Holders::init(&db)?;
+        let view = ReadView {
+            height,
+            db: Arc::new(db.snapshot()),
+            holders: holders.snapshot(),
+        };
 
         let server = Self {
-            client: Arc::new(
-                AsyncClient::new(
-                    &URL,
-                    Some(USER.to_string()),
-                    Some(PASS.to_string()),
-                    token.clone(),
-                )
-                .await?,
-            ),
-            addr_tx: Arc::new(addr_tx),
-            holders: Arc::new(Holders::init(&db)),
+            client: Arc::new(client),
+            holders: Arc::new(holders),
             db,
-            raw_event_sender: raw_tx.clone(),
             token,
-            last_indexed_address_height: Arc::new(tokio::sync::RwLock::new(0)),
-            event_sender: tx.clone(),
+            event_sender: tx,
+            event_seq: parking_lot::Mutex::new(event_seq),
+            event_log_pruned: parking_lot::Mutex::new(event_log_pruned),
+            webhook_id: parking_lot::Mutex::new(webhook_id),
+            webhooks_changed: tokio::sync::Notify::new(),
+            synced: AtomicBool::new(false),
+            fault: parking_lot::Mutex::new(None),
+            view: parking_lot::RwLock::new(Arc::new(view)),
         };
 
-        Ok((addr_rx, raw_rx, tx, server))
+        Ok(server)
+    }
+
+    /// Makes the block visible to readers: pins reads to the current db state and applies staged holders
+    pub fn commit(&self, height: u32) -> anyhow::Result<()> {
+        let db = Arc::new(self.db.snapshot());
+        let holders = self.holders.commit();
+        *self.view.write() = Arc::new(ReadView {
+            height,
+            db,
+            holders,
+        });
+
+        if let Some(hash) = self.db.block_hashes.get(height)? {
+            self.client.set_indexed_tip(height, hash);
+        }
+        Ok(())
+    }
+
+    /// Replaces holders with the ones loaded from a secondary db and pins reads to its current state
+    pub fn commit_secondary(&self, height: u32, holders: HoldersData) {
+        let db = Arc::new(self.db.snapshot());
+        let holders = self.holders.replace(holders);
+        *self.view.write() = Arc::new(ReadView {
+            height,
+            db,
+            holders,
+        });
+    }
+
+    /// Committed state. Later commits replace the view without changing this one
+    pub fn read_view(&self) -> Arc<ReadView> {
+        self.view.read().clone()
     }
 
-    pub async fn load_addresses(
+    /// Appends the event to the event log and broadcasts it to subscribers
+    pub fn send_event(&self, event: ServerEvent) -> anyhow::Result<()> {
+        let mut seq = self.event_seq.lock();
+        let next = *seq + 1;
+
+        self.db.event_log.set(next, &event)?;
+        self.db.last_event_seq.set((), next)?;
+        *seq = next;
+
+        self.event_sender.send((next, event)).ok();
+        Ok(())
+    }
+
+    /// Writes the block with its events appended to the event log in the same batch,
+    /// then commits it for readers and broadcasts the events
+    pub fn write_block(
         &self,
-        keys: impl IntoIterator<Item = FullHash>,
         height: u32,
-    ) -> anyhow::Result<HashMap<FullHash, String>> {
-        let mut counter = 0;
-        while *self.last_indexed_address_height.read().await < height {
-            if counter > 100 {
-                anyhow::bail!("Something went wrong with the addresses");
-            }
+        mut batch: DbBatch,
+        events: Vec<ServerEvent>,
+    ) -> anyhow::Result<()> {
+        let mut seq = self.event_seq.lock();
+        let events = (*seq + 1..).zip(events).collect_vec();
+        let last = *seq + events.len() as u64;
+
+        for (next, event) in &events {
+            batch.put(&self.db.event_log, *next, event);
+        }
+        batch.put(&self.db.last_event_seq, (), last);
+        self.db.write(batch)?;
+
+        self.commit(height)?;
+        *seq = last;
+
+        for event in events {
+            self.event_sender.send(event).ok();
+        }
+        Ok(())
+    }
 
-            counter += 1;
-            tokio::time::sleep(Duration::from_millis(100)).await;
+    /// Allocates the id of a new webhook, ids are not reused
+    pub fn next_webhook_id(&self) -> anyhow::Result<u64> {
+        let mut id = self.webhook_id.lock();
+        let next = *id + 1;
+
+        self.db.last_webhook_id.set((), next)?;
+        *id = next;
+
+        Ok(next)
+    }
+
+    /// Broadcasts events appended to the event log by the primary of a secondary db
+    pub fn broadcast_logged_events(&self) -> anyhow::Result<()> {
+        let mut seq = self.event_seq.lock();
+
+        let from = *seq + 1;
+        for entry in self.db.event_log.range(&from.., false) {
+            let (next, event) = entry?;
+            *seq = next;
+            self.event_sender.send((next, event)).ok();
+        }
+        Ok(())
+    }
+
+    /// Events of the event log from `from`, at most `limit` of them.
+    /// Fails with `EventLogGap` if the first of them were removed by the retention
+    pub fn read_event_log(&self, from: u64, limit: usize) -> anyhow::Result<Vec<SequencedEvent>> {
+        // Sequence numbers start at 1 and have no holes
+        let from = from.max(1);
+        let events = self
+            .db
+            .event_log
+            .range(&from.., false)
+            .take(limit)
+            .try_collect::<_, Vec<_>, _>()?;
+
+        let first = match events.first() {
+            Some((seq, _)) => *seq,
+            None => self.db.last_event_seq.get(())?.unwrap_or_default() + 1,
+        };
+        if first > from {
+            return Err(EventLogGap { from, first }.into());
+        }
+
+        Ok(events)
+    }
+
+    /// Removes the events before the last `indexer.event_log_retention` ones from the event log
+    pub fn prune_event_log(&self) -> anyhow::Result<()> {
+        let retention = CONFIG.indexer.event_log_retention;
+        if retention == 0 {
+            return Ok(());
+        }
+
+        let mut pruned = self.event_log_pruned.lock();
+        let to = self.last_event_seq().saturating_sub(retention);
+        if to > *pruned {
+            self.db.event_log.remove_batch(*pruned + 1..=to)?;
+            *pruned = to;
         }
 
+        Ok(())
+    }
+
+    /// Sequence number of the last logged event
+    pub fn last_event_seq(&self) -> u64 {
+        *self.event_seq.lock()
+    }
+
+    /// Subscribes to broadcasted events.
+    /// Returns the sequence number of the first event that will be received
+    pub fn subscribe_events(&self) -> (u64, tokio::sync::broadcast::Receiver<SequencedEvent>) {
+        let seq = self.event_seq.lock();
+        (*seq + 1, self.event_sender.subscribe())
+    }
+
+    /// Addresses of the script hashes. They are written with the block that references them
+    pub fn load_addresses(
+        &self,
+        keys: impl IntoIterator<Item = FullHash>,
+    ) -> anyhow::Result<HashMap<FullHash, String>> {
         let keys = keys.into_iter().collect::<HashSet<_>>();
 
         Ok(self
             .db
             .fullhash_to_address
-            .multi_get(keys.iter())
+            .multi_get(keys.iter())?
             .into_iter()
             .zip(keys)
             .map(|(v, k)| {
@@ -86,46 +247,41 @@ impl Server {
             .collect())
     }
 
-    pub async fn new_hash(
+    /// Proof of history of the block, chained to the one of the previous block.
+    /// `addresses` has the addresses of the history, which may be written by the block itself
+    pub fn proof_of_history(
         &self,
         height: u32,
-        blockhash: BlockHash,
         history: &[(AddressTokenId, HistoryValue)],
-    ) -> anyhow::Result<()> {
+        addresses: &HashMap<FullHash, String>,
+    ) -> anyhow::Result<sha256::Hash> {
         let current_hash = if history.is_empty() {
             *DEFAULT_HASH
         } else {
             let mut res = Vec::<u8>::new();
 
             for (k, v) in history {
-                let bytes = serde_json::to_vec(
-                    &HistoryRest::new(v.height, v.action.clone(), k.clone(), self).await?,
-                )?;
+                let bytes = serde_json::to_vec(&HistoryRest::with_addresses(
+                    v.height,
+                    v.action.clone(),
+                    k.clone(),
+                    addresses,
+                ))?;
                 res.extend(bytes);
             }
 
             sha256::Hash::hash(&res)
         };
 
-        let new_hash = {
-            let prev_hash = self
-                .db
-                .proof_of_history
-                .get(height - 1)
-                .unwrap_or(*DEFAULT_HASH);
-            let mut result = vec![];
-            result.extend_from_slice(prev_hash.as_byte_array());
-            result.extend_from_slice(current_hash.as_byte_array());
-
-            sha256::Hash::hash(&result)
-        };
-
-        self.event_sender
-            .send(ServerEvent::NewBlock(height, new_hash, blockhash))
-            .ok();
-
-        self.db.proof_of_history.set(height, new_hash);
+        let prev_hash = self
+            .db
+            .proof_of_history
+            .get(height - 1)?
+            .unwrap_or(*DEFAULT_HASH);
+        let mut result = vec![];
+        result.extend_from_slice(prev_hash.as_byte_array());
+        result.extend_from_slice(current_hash.as_byte_array());
 
-        Ok(())
+        Ok(sha256::Hash::hash(&result))
     }
 }
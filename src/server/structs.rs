@@ -1,13 +1,35 @@
 use super::*;
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, Serialize, Deserialize)]
 pub enum ServerEvent {
     NewHistory(AddressTokenIdEvent, HistoryValueEvent),
     Reorg(u32, u32),
     NewBlock(u32, sha256::Hash, BlockHash),
 }
 
-pub type RawServerEvent = Vec<(AddressTokenId, HistoryValue)>;
+/// Event with its sequence number in the event log
+pub type SequencedEvent = (u64, ServerEvent);
+
+/// Events requested from the event log were removed by `indexer.event_log_retention`
+#[derive(Debug)]
+pub struct EventLogGap {
+    pub from: u64,
+    /// Sequence number of the oldest event left after `from`
+    pub first: u64,
+}
+
+impl std::fmt::Display for EventLogGap {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "Events from {} to {} were removed from the event log",
+            self.from,
+            self.first - 1
+        )
+    }
+}
+
+impl std::error::Error for EventLogGap {}
 
 #[derive(Serialize, Deserialize, Clone, Debug, Hash, Eq, PartialEq, PartialOrd, Ord)]
 pub struct AddressTokenIdEvent {
@@ -87,7 +109,10 @@ impl TokenHistoryEvent {
                 vout,
             } => Self::Send {
                 amt,
-                recipient: addresses.get(&recipient).unwrap().clone(),
+                recipient: addresses
+                    .get(&recipient)
+                    .cloned()
+                    .unwrap_or_else(|| NON_STANDARD_ADDRESS.to_string()),
                 txid,
                 vout,
             },
@@ -98,7 +123,10 @@ impl TokenHistoryEvent {
                 vout,
             } => Self::Receive {
                 amt,
-                sender: addresses.get(&sender).unwrap().clone(),
+                sender: addresses
+                    .get(&sender)
+                    .cloned()
+                    .unwrap_or_else(|| NON_STANDARD_ADDRESS.to_string()),
                 txid,
                 vout,
             },
@@ -2,44 +2,70 @@ use super::*;
 
 use dutils::async_thread::{Handler, Thread, ThreadController};
 
-mod address_hash_saver;
-mod event_sender;
-
-pub use self::address_hash_saver::AddressesToLoad;
+mod secondary_follower;
 
 impl Server {
-    pub async fn run_threads(
-        self: Arc<Self>,
-        token: WaitToken,
-        addr_rx: kanal::Receiver<AddressesToLoad>,
-        raw_event_tx: kanal::Receiver<RawServerEvent>,
-        event_tx: tokio::sync::broadcast::Sender<ServerEvent>,
-    ) -> anyhow::Result<()> {
-        let addr_loader = ThreadController::new(address_hash_saver::AddressHasher {
-            addr_rx,
+    pub async fn run_threads(self: Arc<Self>, token: WaitToken) -> anyhow::Result<()> {
+        let webhook_sender = ThreadController::new(crate::webhooks::WebhookSender {
             server: self.clone(),
             token: token.clone(),
         })
-        .with_name("AddressHasher")
+        .with_name("WebhookSender")
         .with_restart(Duration::from_secs(1))
         .with_cancellation(token.clone())
         .run();
 
-        let event_sender = ThreadController::new(event_sender::EventSender {
-            event_tx,
-            raw_event_tx,
+        let mut threads = vec![webhook_sender];
+
+        if let Some(dir) = CONFIG.sinks.jsonl_dir.as_ref() {
+            let sink = sinks::JsonlSink {
+                dir: dir.into(),
+                blocks_per_file: CONFIG.sinks.jsonl_blocks_per_file,
+            };
+            threads.push(self.run_sink("JsonlSink", sink, token.clone()));
+        }
+        if let Some(path) = CONFIG.sinks.sqlite_path.as_ref() {
+            let sink = sinks::SqliteSink::new(path.clone());
+            threads.push(self.run_sink("SqliteSink", sink, token.clone()));
+        }
+        if let Some(url) = CONFIG.sinks.redis_url.as_ref() {
+            let sink = sinks::BusSink {
+                producer: sinks::RedisStreamProducer::new(
+                    url.clone(),
+                    format!("{}.position", CONFIG.sinks.redis_topic_prefix),
+                ),
+                topic_prefix: CONFIG.sinks.redis_topic_prefix.clone(),
+            };
+            threads.push(self.run_sink("BusSink", sink, token.clone()));
+        }
+
+        join_all(threads).await.into_iter().try_collect().anyhow()
+    }
+
+    /// Threads of the serve-only mode, the db is written by the indexer of another process
+    pub async fn run_serve_only_threads(self: Arc<Self>, token: WaitToken) -> anyhow::Result<()> {
+        ThreadController::new(secondary_follower::SecondaryFollower {
             server: self.clone(),
             token: token.clone(),
         })
-        .with_name("EventSender")
+        .with_name("SecondaryFollower")
         .with_restart(Duration::from_secs(1))
         .with_cancellation(token)
-        .run();
+        .run()
+        .await
+        .anyhow()
+    }
 
-        join_all(vec![addr_loader, event_sender])
-            .await
-            .into_iter()
-            .try_collect()
-            .anyhow()
+    fn run_sink(
+        self: &Arc<Self>,
+        name: &str,
+        sink: impl sinks::EventSink,
+        token: WaitToken,
+    ) -> tokio::task::JoinHandle<()> {
+        ThreadController::new(sinks::SinkWriter::new(self.clone(), token.clone(), sink))
+            .with_name(name)
+            .with_restart(Duration::from_secs(5))
+            .with_cancellation(token)
+            .run()
     }
 }
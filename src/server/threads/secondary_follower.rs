@@ -0,0 +1,71 @@
+use super::*;
+
+/// Node RPC is skipped until the next catch-up if it doesn't reply in time
+const NODE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Applies changes of the indexer to a secondary db and publishes them in serve-only mode
+#[derive(Clone)]
+pub struct SecondaryFollower {
+    pub server: Arc<Server>,
+    pub token: WaitToken,
+}
+
+impl Handler for SecondaryFollower {
+    async fn run(&mut self) -> anyhow::Result<()> {
+        let mut last_block = None;
+        let mut repeater = self
+            .token
+            .repeat_until_cancel(Duration::from_millis(CONFIG.secondary.catch_up_interval_ms));
+
+        while repeater.next().await {
+            let db = self.server.db.clone();
+            tokio::task::spawn_blocking(move || db.try_catch_up_with_primary())
+                .await
+                .anyhow()??;
+
+            let height = self.server.db.last_block.get(())?.unwrap_or_default();
+            let hash = self.server.db.block_hashes.get(height)?;
+            if last_block != Some((height, hash)) {
+                let db = self.server.db.clone();
+                let data = self.server.read_view().holders.clone();
+                let holders = tokio::task::spawn_blocking(move || match last_block {
+                    // Blocks after the followed one are applied, otherwise blocks were reorged and holders are reloaded
+                    Some((last, last_hash))
+                        if last < height && db.block_hashes.get(last)? == last_hash =>
+                    {
+                        Holders::update(&db, &data, last + 1, height)
+                    }
+                    _ => Holders::load(&db),
+                })
+                .await
+                .anyhow()??;
+
+                self.server.commit_secondary(height, holders);
+
+                metrics::INDEXER_HEIGHT.set(height as i64);
+                last_block = Some((height, hash));
+            }
+
+            self.server.broadcast_logged_events()?;
+
+            // The indexer finished its catch-up once it's within the reorg depth of the node tip
+            if !self.server.synced.load(Ordering::SeqCst) {
+                // Bounded, so an unreachable node doesn't hold back the catch-ups
+                let tip = tokio::time::timeout(NODE_TIMEOUT, self.server.client.get_block_count())
+                    .await
+                    .anyhow_with("Node is not replying")
+                    .and_then(|x| x)
+                    .track()
+                    .ok();
+                if let Some(tip) = tip {
+                    let height = self.server.read_view().height;
+                    if height as u64 + CONFIG.indexer.reorg_depth as u64 >= tip {
+                        self.server.synced.store(true, Ordering::SeqCst);
+                    }
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
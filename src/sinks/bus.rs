@@ -0,0 +1,148 @@
+use super::*;
+
+pub struct BusMessage {
+    pub topic: String,
+    /// Partitioning key. Messages with the same key keep their order
+    pub key: String,
+    pub payload: String,
+}
+
+/// Message bus client used by [`BusSink`]
+pub trait BusProducer: Send + 'static {
+    fn name(&self) -> String;
+
+    /// Position stored on the bus, `None` if nothing was published yet
+    fn committed(&mut self) -> impl Future<Output = anyhow::Result<Option<SinkPosition>>> + Send;
+
+    /// Publishes the messages in order and stores the position
+    fn publish(
+        &mut self,
+        messages: Vec<BusMessage>,
+        position: SinkPosition,
+    ) -> impl Future<Output = anyhow::Result<()>> + Send;
+}
+
+/// Publishes records to `<topic_prefix>.<event_type>` topics, `revert` records included.
+/// History is keyed by address, other records by height.
+pub struct BusSink<P: BusProducer> {
+    pub producer: P,
+    pub topic_prefix: String,
+}
+
+impl<P: BusProducer> BusSink<P> {
+    fn message(&self, record: &SinkRecord) -> anyhow::Result<BusMessage> {
+        let (event_type, key) = match record.event() {
+            ServerEvent::NewHistory(address_token, _) => ("history", address_token.address.clone()),
+            ServerEvent::NewBlock(height, ..) => ("new_block", height.to_string()),
+            ServerEvent::Reorg(_, new_height) => ("reorg", new_height.to_string()),
+        };
+        let event_type = match record {
+            SinkRecord::Event { .. } => event_type,
+            SinkRecord::Revert { .. } => "revert",
+        };
+
+        Ok(BusMessage {
+            topic: format!("{}.{}", self.topic_prefix, event_type),
+            key,
+            payload: record.to_json()?,
+        })
+    }
+}
+
+impl<P: BusProducer> EventSink for BusSink<P> {
+    fn name(&self) -> String {
+        self.producer.name()
+    }
+
+    async fn committed(&mut self) -> anyhow::Result<Option<SinkPosition>> {
+        self.producer.committed().await
+    }
+
+    async fn write(
+        &mut self,
+        records: Vec<SinkRecord>,
+        position: SinkPosition,
+    ) -> anyhow::Result<()> {
+        let messages = records
+            .iter()
+            .map(|x| self.message(x))
+            .collect::<anyhow::Result<Vec<_>>>()?;
+
+        self.producer.publish(messages, position).await
+    }
+}
+
+/// Publishes to Redis streams named by topic. Messages and the position are written
+/// in one transaction under the `<position_key>` key.
+pub struct RedisStreamProducer {
+    pub url: String,
+    pub position_key: String,
+    conn: Option<redis::aio::MultiplexedConnection>,
+}
+
+impl RedisStreamProducer {
+    pub fn new(url: String, position_key: String) -> Self {
+        Self {
+            url,
+            position_key,
+            conn: None,
+        }
+    }
+}
+
+impl BusProducer for RedisStreamProducer {
+    fn name(&self) -> String {
+        format!("redis:{}", self.position_key)
+    }
+
+    async fn committed(&mut self) -> anyhow::Result<Option<SinkPosition>> {
+        let mut conn = redis::Client::open(self.url.as_str())?
+            .get_multiplexed_async_connection()
+            .await?;
+
+        let position: Option<String> = redis::cmd("GET")
+            .arg(&self.position_key)
+            .query_async(&mut conn)
+            .await?;
+
+        self.conn = Some(conn);
+
+        position
+            .map(|x| serde_json::from_str(&x))
+            .transpose()
+            .anyhow()
+    }
+
+    async fn publish(
+        &mut self,
+        messages: Vec<BusMessage>,
+        position: SinkPosition,
+    ) -> anyhow::Result<()> {
+        let conn = self
+            .conn
+            .as_mut()
+            .anyhow_with("Redis producer is not connected")?;
+
+        let mut pipe = redis::pipe();
+        pipe.atomic();
+
+        for message in messages {
+            pipe.cmd("XADD")
+                .arg(message.topic)
+                .arg("*")
+                .arg("key")
+                .arg(message.key)
+                .arg("payload")
+                .arg(message.payload)
+                .ignore();
+        }
+        pipe.cmd("SET")
+            .arg(&self.position_key)
+            .arg(serde_json::to_string(&position)?)
+            .ignore();
+
+        pipe.query_async::<()>(conn).await?;
+
+        Ok(())
+    }
+}
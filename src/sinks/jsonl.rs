@@ -0,0 +1,67 @@
+use std::path::PathBuf;
+
+use tokio::io::AsyncWriteExt;
+
+use super::*;
+
+const POSITION_FILE: &str = "position.json";
+
+/// Appends records to JSONL files named by the first height of their range,
+/// a new file is started every `blocks_per_file` blocks.
+/// Records written after the committed position may be written again after a restart.
+pub struct JsonlSink {
+    pub dir: PathBuf,
+    pub blocks_per_file: u32,
+}
+
+impl JsonlSink {
+    fn file_path(&self, height: u32) -> PathBuf {
+        let start = height - height % self.blocks_per_file;
+        self.dir.join(format!("events-{start:010}.jsonl"))
+    }
+}
+
+impl EventSink for JsonlSink {
+    fn name(&self) -> String {
+        format!("jsonl:{}", self.dir.display())
+    }
+
+    async fn committed(&mut self) -> anyhow::Result<Option<SinkPosition>> {
+        tokio::fs::create_dir_all(&self.dir).await?;
+
+        match tokio::fs::read(self.dir.join(POSITION_FILE)).await {
+            Ok(data) => Ok(Some(serde_json::from_slice(&data)?)),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    async fn write(
+        &mut self,
+        records: Vec<SinkRecord>,
+        position: SinkPosition,
+    ) -> anyhow::Result<()> {
+        let mut files = BTreeMap::<PathBuf, String>::new();
+        for record in records {
+            let data = files.entry(self.file_path(record.height())).or_default();
+            data.push_str(&record.to_json()?);
+            data.push('\n');
+        }
+
+        for (path, data) in files {
+            let mut file = tokio::fs::OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(&path)
+                .await?;
+            file.write_all(data.as_bytes()).await?;
+            file.sync_data().await?;
+        }
+
+        let tmp = self.dir.join(format!("{POSITION_FILE}.tmp"));
+        tokio::fs::write(&tmp, serde_json::to_vec(&position)?).await?;
+        tokio::fs::rename(&tmp, self.dir.join(POSITION_FILE)).await?;
+
+        Ok(())
+    }
+}
@@ -0,0 +1,367 @@
+use std::future::Future;
+
+use dutils::async_thread::Handler;
+use rest::events::event_to_json;
+use serde_json::value::RawValue;
+
+use super::*;
+
+mod bus;
+mod jsonl;
+mod sqlite;
+
+pub use self::{
+    bus::{BusProducer, BusSink, RedisStreamProducer},
+    jsonl::JsonlSink,
+    sqlite::SqliteSink,
+};
+
+/// Count of events read from the event log at once by a sink
+const SINK_BATCH: usize = 1000;
+
+/// Last event written by a sink. Persisted by the sink together with its records.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default)]
+pub struct SinkPosition {
+    /// Sequence number of the last written event
+    pub seq: u64,
+    /// Height of the last written block
+    pub height: u32,
+}
+
+#[derive(Clone, Debug)]
+pub enum SinkRecord {
+    Event {
+        seq: u64,
+        event: ServerEvent,
+    },
+    /// Compensates a history event of a reorged block. Written before the reorg event.
+    Revert {
+        seq: u64,
+        reverted_seq: u64,
+        event: ServerEvent,
+    },
+}
+
+#[derive(Serialize)]
+struct SinkRecordRest {
+    seq: u64,
+    record: &'static str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    reverted_seq: Option<u64>,
+    data: Box<RawValue>,
+}
+
+impl SinkRecord {
+    pub fn seq(&self) -> u64 {
+        match self {
+            SinkRecord::Event { seq, .. } | SinkRecord::Revert { seq, .. } => *seq,
+        }
+    }
+
+    pub fn event(&self) -> &ServerEvent {
+        match self {
+            SinkRecord::Event { event, .. } | SinkRecord::Revert { event, .. } => event,
+        }
+    }
+
+    /// Height of the block the record belongs to
+    pub fn height(&self) -> u32 {
+        match self.event() {
+            ServerEvent::NewHistory(_, v) => v.height,
+            ServerEvent::NewBlock(height, ..) => *height,
+            ServerEvent::Reorg(_, new_height) => *new_height,
+        }
+    }
+
+    /// Same payloads as the event APIs, wrapped with the sequence number and the record type
+    pub fn to_json(&self) -> anyhow::Result<String> {
+        let (record, reverted_seq) = match self {
+            SinkRecord::Event { .. } => ("event", None),
+            SinkRecord::Revert { reverted_seq, .. } => ("revert", Some(*reverted_seq)),
+        };
+
+        Ok(serde_json::to_string(&SinkRecordRest {
+            seq: self.seq(),
+            record,
+            reverted_seq,
+            data: RawValue::from_string(event_to_json(self.event().clone())?)?,
+        })?)
+    }
+}
+
+/// Destination of the event log. A sink writes records in order and commits its position
+/// with them, so it resumes after the last committed record on restart.
+pub trait EventSink: Send + 'static {
+    fn name(&self) -> String;
+
+    /// Position committed by the sink, `None` if nothing was written yet
+    fn committed(&mut self) -> impl Future<Output = anyhow::Result<Option<SinkPosition>>> + Send;
+
+    /// Writes the records and commits the position
+    fn write(
+        &mut self,
+        records: Vec<SinkRecord>,
+        position: SinkPosition,
+    ) -> impl Future<Output = anyhow::Result<()>> + Send;
+}
+
+/// Feeds a sink from the event log. On a reorg event, history events of the reorged blocks
+/// that were written to the sink are followed by compensating revert records.
+pub struct SinkWriter<S: EventSink> {
+    pub server: Arc<Server>,
+    pub token: WaitToken,
+    pub sink: Arc<tokio::sync::Mutex<S>>,
+}
+
+impl<S: EventSink> Clone for SinkWriter<S> {
+    fn clone(&self) -> Self {
+        Self {
+            server: self.server.clone(),
+            token: self.token.clone(),
+            sink: self.sink.clone(),
+        }
+    }
+}
+
+impl<S: EventSink> SinkWriter<S> {
+    pub fn new(server: Arc<Server>, token: WaitToken, sink: S) -> Self {
+        Self {
+            server,
+            token,
+            sink: Arc::new(tokio::sync::Mutex::new(sink)),
+        }
+    }
+
+    /// History events of blocks from `new_height` logged before the reorg and not reverted yet
+    fn reverted(&self, reorg_seq: u64, new_height: u32) -> anyhow::Result<Vec<SinkRecord>> {
+        let mut reverted = vec![];
+        // Events of blocks from this height were reverted by a later reorg already
+        let mut reverted_from = u32::MAX;
+
+        for entry in self.server.db.event_log.range(..&reorg_seq, true) {
+            let (seq, event) = entry?;
+            match &event {
+                ServerEvent::NewBlock(height, ..) if *height < new_height => break,
+                ServerEvent::Reorg(_, height) => reverted_from = reverted_from.min(*height),
+                ServerEvent::NewHistory(_, v)
+                    if v.height >= new_height && v.height < reverted_from =>
+                {
+                    reverted.push(SinkRecord::Revert {
+                        seq: reorg_seq,
+                        reverted_seq: seq,
+                        event,
+                    });
+                }
+                _ => {}
+            }
+        }
+
+        Ok(reverted)
+    }
+}
+
+impl<S: EventSink> Handler for SinkWriter<S> {
+    async fn run(&mut self) -> anyhow::Result<()> {
+        let mut sink = self.sink.lock().await;
+        let name = sink.name();
+
+        let mut position = sink.committed().await?.unwrap_or_default();
+        info!(
+            "Sink {} resumes after event {} at height {}",
+            name, position.seq, position.height
+        );
+
+        let (_, mut new_events) = self.server.subscribe_events();
+
+        loop {
+            let events = match self.server.read_event_log(position.seq + 1, SINK_BATCH) {
+                Ok(events) => events,
+                Err(e) => {
+                    let gap = e.downcast::<EventLogGap>()?;
+                    error!("Sink {} skips events: {}", name, gap);
+                    position.seq = gap.first - 1;
+                    continue;
+                }
+            };
+
+            if events.is_empty() {
+                // Events are read from the event log, lagging is fine
+                let received = tokio::select! {
+                    v = new_events.recv() => v,
+                    _ = self.token.cancelled() => break,
+                };
+                if let Err(tokio::sync::broadcast::error::RecvError::Closed) = received {
+                    break;
+                }
+                continue;
+            }
+
+            let mut records = Vec::with_capacity(events.len());
+
+            for (seq, event) in events {
+                match &event {
+                    ServerEvent::NewBlock(height, ..) => position.height = *height,
+                    ServerEvent::Reorg(_, new_height) => {
+                        records.extend(self.reverted(seq, *new_height)?);
+                        position.height = new_height.saturating_sub(1);
+                    }
+                    ServerEvent::NewHistory(..) => {}
+                }
+
+                position.seq = seq;
+                records.push(SinkRecord::Event { seq, event });
+            }
+
+            sink.write(records, position)
+                .await
+                .anyhow_with(format!("Failed to write to sink {name}"))?;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        reorg::ReorgCache,
+        test_utils::{self, TestChain},
+    };
+
+    /// Written records and the committed position
+    #[derive(Default)]
+    struct Written {
+        records: Vec<SinkRecord>,
+        position: Option<SinkPosition>,
+    }
+
+    /// Keeps the written records in memory, shared since the writer holds the sink while running
+    #[derive(Default)]
+    struct MemorySink(Arc<parking_lot::Mutex<Written>>);
+
+    impl EventSink for MemorySink {
+        fn name(&self) -> String {
+            "memory".to_string()
+        }
+
+        async fn committed(&mut self) -> anyhow::Result<Option<SinkPosition>> {
+            Ok(self.0.lock().position)
+        }
+
+        async fn write(
+            &mut self,
+            records: Vec<SinkRecord>,
+            position: SinkPosition,
+        ) -> anyhow::Result<()> {
+            let mut written = self.0.lock();
+            written.records.extend(records);
+            written.position = Some(position);
+            Ok(())
+        }
+    }
+
+    /// History events logged between the sequence numbers from the height, newest first
+    fn history(server: &Server, seqs: std::ops::Range<u64>, height: u32) -> Vec<u64> {
+        server
+            .db
+            .event_log
+            .iter()
+            .map(|x| x.unwrap())
+            .filter(|(seq, event)| {
+                seqs.contains(seq)
+                    && matches!(event, ServerEvent::NewHistory(_, v) if v.height >= height)
+            })
+            .map(|(seq, _)| seq)
+            .sorted_unstable_by(|a, b| b.cmp(a))
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn reorged_history_is_reverted_once() {
+        let alice = test_utils::address(1);
+        let bob = test_utils::address(2);
+
+        let mut chain = TestChain::new();
+        let deploy = chain.inscribe(&alice, &test_utils::deploy("abcd", "1000", "100"));
+        chain.mine(vec![deploy]);
+        let fork_height = chain.height() + 1;
+        let mint = chain.inscribe(&alice, &test_utils::mint("abcd", "100"));
+        chain.mine(vec![mint]);
+        let mint = chain.inscribe(&bob, &test_utils::mint("abcd", "5"));
+        chain.mine(vec![mint]);
+
+        let server = test_utils::server().await;
+        let reorg_cache = Arc::new(parking_lot::Mutex::new(ReorgCache::new()));
+        test_utils::index_with(&server, &chain, chain.height(), Some(&reorg_cache)).await;
+
+        // Reorged twice from the same height
+        let mut reorgs = vec![];
+        for amt in ["7", "9"] {
+            reorg_cache.lock().restore(&server, fork_height).unwrap();
+            server.commit(fork_height - 1).unwrap();
+            reorgs.push(server.last_event_seq());
+
+            chain.fork(fork_height - 1);
+            let mint = chain.inscribe(&bob, &test_utils::mint("abcd", amt));
+            chain.mine(vec![mint]);
+            test_utils::index_with(&server, &chain, chain.height(), Some(&reorg_cache)).await;
+        }
+
+        let token = WaitToken::default();
+        let sink = MemorySink::default();
+        let written = sink.0.clone();
+        let mut writer = SinkWriter::new(server.clone(), token.clone(), sink);
+        let task = tokio::spawn(async move { writer.run().await });
+
+        let last = server.last_event_seq();
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while written.lock().position.map(|x| x.seq) != Some(last) {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .unwrap();
+        token.cancel();
+        task.await.unwrap().unwrap();
+
+        let sink = written.lock();
+        assert_eq!(sink.position.unwrap().height, chain.height());
+
+        let reverted = |reorg_seq| {
+            sink.records
+                .iter()
+                .filter_map(|x| match x {
+                    SinkRecord::Revert {
+                        seq, reverted_seq, ..
+                    } if *seq == reorg_seq => Some(*reverted_seq),
+                    _ => None,
+                })
+                .collect_vec()
+        };
+        // The second reorg reverts only the history of the block indexed after the first one
+        assert_eq!(
+            reverted(reorgs[0]),
+            history(&server, 0..reorgs[0], fork_height)
+        );
+        assert_eq!(
+            reverted(reorgs[1]),
+            history(&server, reorgs[0]..reorgs[1], fork_height)
+        );
+        assert_eq!(reverted(reorgs[0]).len(), 2);
+        assert_eq!(reverted(reorgs[1]).len(), 1);
+
+        // Revert records come right before their reorg event
+        for reorg in reorgs {
+            let position = sink
+                .records
+                .iter()
+                .position(|x| matches!(x, SinkRecord::Event { seq, .. } if *seq == reorg))
+                .unwrap();
+            let count = reverted(reorg).len();
+            assert!(sink.records[position - count..position]
+                .iter()
+                .all(|x| matches!(x, SinkRecord::Revert { seq, .. } if *seq == reorg)));
+        }
+    }
+}
@@ -0,0 +1,277 @@
+use rusqlite::{params, Connection, OptionalExtension, Transaction};
+use server::{AddressTokenIdEvent, HistoryValueEvent, TokenHistoryEvent};
+
+use super::*;
+
+const SCHEMA: &str = "
+CREATE TABLE IF NOT EXISTS history (
+    seq INTEGER NOT NULL,
+    record TEXT NOT NULL,
+    reverted_seq INTEGER,
+    reverted_by INTEGER,
+    height INTEGER NOT NULL,
+    address TEXT NOT NULL,
+    tick TEXT NOT NULL,
+    id INTEGER NOT NULL,
+    action TEXT NOT NULL,
+    amt TEXT,
+    counterparty TEXT,
+    txid TEXT NOT NULL,
+    vout INTEGER NOT NULL
+);
+CREATE INDEX IF NOT EXISTS history_seq ON history (seq);
+CREATE INDEX IF NOT EXISTS history_address ON history (address, tick);
+CREATE INDEX IF NOT EXISTS history_height ON history (height);
+CREATE TABLE IF NOT EXISTS balances (
+    address TEXT NOT NULL,
+    tick TEXT NOT NULL,
+    balance TEXT NOT NULL,
+    transferable_balance TEXT NOT NULL,
+    PRIMARY KEY (address, tick)
+);
+CREATE TABLE IF NOT EXISTS blocks (
+    height INTEGER PRIMARY KEY,
+    seq INTEGER NOT NULL,
+    blockhash TEXT NOT NULL,
+    proof TEXT NOT NULL
+);
+CREATE TABLE IF NOT EXISTS reorgs (
+    seq INTEGER PRIMARY KEY,
+    blocks_count INTEGER NOT NULL,
+    new_height INTEGER NOT NULL
+);
+CREATE TABLE IF NOT EXISTS sink_position (
+    id INTEGER PRIMARY KEY CHECK (id = 0),
+    seq INTEGER NOT NULL,
+    height INTEGER NOT NULL
+);
+";
+
+/// Mirrors history, balances and blocks into SQLite for analytics.
+/// Reverted history is kept with `reverted_by` set and a compensating `revert` row,
+/// balances and blocks always reflect the indexed chain.
+pub struct SqliteSink {
+    pub path: String,
+    conn: Option<Arc<parking_lot::Mutex<Connection>>>,
+}
+
+impl SqliteSink {
+    pub fn new(path: String) -> Self {
+        Self { path, conn: None }
+    }
+
+    fn conn(&self) -> anyhow::Result<Arc<parking_lot::Mutex<Connection>>> {
+        self.conn.clone().anyhow_with("SQLite sink is not opened")
+    }
+
+    fn insert_history(
+        tx: &Transaction,
+        record: &SinkRecord,
+        address_token: &AddressTokenIdEvent,
+        value: &HistoryValueEvent,
+    ) -> anyhow::Result<()> {
+        let (action, amt, counterparty, txid, vout) = match &value.action {
+            TokenHistoryEvent::Deploy { txid, vout, .. } => ("deploy", None, None, txid, vout),
+            TokenHistoryEvent::Mint { amt, txid, vout } => ("mint", Some(amt), None, txid, vout),
+            TokenHistoryEvent::DeployTransfer { amt, txid, vout } => {
+                ("deploy_transfer", Some(amt), None, txid, vout)
+            }
+            TokenHistoryEvent::Send {
+                amt,
+                recipient,
+                txid,
+                vout,
+            } => ("send", Some(amt), Some(recipient), txid, vout),
+            TokenHistoryEvent::Receive {
+                amt,
+                sender,
+                txid,
+                vout,
+            } => ("receive", Some(amt), Some(sender), txid, vout),
+            TokenHistoryEvent::SendReceive { amt, txid, vout } => {
+                ("send_receive", Some(amt), None, txid, vout)
+            }
+        };
+
+        let (record_type, reverted_seq) = match record {
+            SinkRecord::Event { .. } => ("event", None),
+            SinkRecord::Revert { reverted_seq, .. } => ("revert", Some(*reverted_seq)),
+        };
+
+        tx.execute(
+            "INSERT INTO history (seq, record, reverted_seq, height, address, tick, id, action, amt, counterparty, txid, vout)
+            VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12)",
+            params![
+                record.seq(),
+                record_type,
+                reverted_seq,
+                value.height,
+                address_token.address,
+                address_token.token.to_string(),
+                address_token.id,
+                action,
+                amt.map(|x| x.to_string()),
+                counterparty,
+                txid.to_string(),
+                vout,
+            ],
+        )?;
+
+        if let Some(reverted_seq) = reverted_seq {
+            tx.execute(
+                "UPDATE history SET reverted_by = ?1 WHERE seq = ?2 AND record = 'event'",
+                params![record.seq(), reverted_seq],
+            )?;
+        }
+
+        Ok(())
+    }
+
+    /// Applies the balance change of a history action, or undoes it if `revert` is set
+    fn update_balance(
+        tx: &Transaction,
+        address_token: &AddressTokenIdEvent,
+        action: &TokenHistoryEvent,
+        revert: bool,
+    ) -> anyhow::Result<()> {
+        // (balance increase, balance decrease, transferable increase, transferable decrease)
+        let zero = Fixed128::zero();
+        let (mut add, mut sub, mut add_transferable, mut sub_transferable) = match *action {
+            TokenHistoryEvent::Deploy { .. } => return Ok(()),
+            TokenHistoryEvent::Mint { amt, .. } | TokenHistoryEvent::Receive { amt, .. } => {
+                (amt, zero, zero, zero)
+            }
+            TokenHistoryEvent::DeployTransfer { amt, .. } => (zero, amt, amt, zero),
+            TokenHistoryEvent::Send { amt, .. } => (zero, zero, zero, amt),
+            TokenHistoryEvent::SendReceive { amt, .. } => (amt, zero, zero, amt),
+        };
+        if revert {
+            std::mem::swap(&mut add, &mut sub);
+            std::mem::swap(&mut add_transferable, &mut sub_transferable);
+        }
+
+        let address = &address_token.address;
+        let tick = address_token.token.to_string();
+
+        let current = tx
+            .query_row(
+                "SELECT balance, transferable_balance FROM balances WHERE address = ?1 AND tick = ?2",
+                params![address, tick],
+                |row| Ok((row.get::<_, String>(0)?, row.get::<_, String>(1)?)),
+            )
+            .optional()?;
+
+        let (balance, transferable_balance) = match current {
+            Some((balance, transferable)) => (
+                Fixed128::from_str(&balance).anyhow()?,
+                Fixed128::from_str(&transferable).anyhow()?,
+            ),
+            None => (zero, zero),
+        };
+
+        // History mirrored from the middle of the event log may miss earlier balance changes
+        let balance = balance.saturating_add(add).saturating_sub(sub);
+        let transferable_balance = transferable_balance
+            .saturating_add(add_transferable)
+            .saturating_sub(sub_transferable);
+
+        tx.execute(
+            "INSERT INTO balances (address, tick, balance, transferable_balance) VALUES (?1, ?2, ?3, ?4)
+            ON CONFLICT (address, tick) DO UPDATE SET balance = ?3, transferable_balance = ?4",
+            params![
+                address,
+                tick,
+                balance.to_string(),
+                transferable_balance.to_string()
+            ],
+        )?;
+
+        Ok(())
+    }
+
+    fn write_records(
+        conn: &mut Connection,
+        records: Vec<SinkRecord>,
+        position: SinkPosition,
+    ) -> anyhow::Result<()> {
+        let tx = conn.transaction()?;
+
+        for record in &records {
+            let revert = matches!(record, SinkRecord::Revert { .. });
+
+            match record.event() {
+                ServerEvent::NewHistory(address_token, value) => {
+                    Self::insert_history(&tx, record, address_token, value)?;
+                    Self::update_balance(&tx, address_token, &value.action, revert)?;
+                }
+                ServerEvent::NewBlock(height, proof, blockhash) => {
+                    tx.execute(
+                        "INSERT OR REPLACE INTO blocks (height, seq, blockhash, proof) VALUES (?1, ?2, ?3, ?4)",
+                        params![height, record.seq(), blockhash.to_string(), proof.to_string()],
+                    )?;
+                }
+                ServerEvent::Reorg(blocks_count, new_height) => {
+                    tx.execute(
+                        "INSERT OR REPLACE INTO reorgs (seq, blocks_count, new_height) VALUES (?1, ?2, ?3)",
+                        params![record.seq(), blocks_count, new_height],
+                    )?;
+                    tx.execute("DELETE FROM blocks WHERE height >= ?1", params![new_height])?;
+                }
+            }
+        }
+
+        tx.execute(
+            "INSERT INTO sink_position (id, seq, height) VALUES (0, ?1, ?2)
+            ON CONFLICT (id) DO UPDATE SET seq = ?1, height = ?2",
+            params![position.seq, position.height],
+        )?;
+
+        tx.commit()?;
+
+        Ok(())
+    }
+}
+
+impl EventSink for SqliteSink {
+    fn name(&self) -> String {
+        format!("sqlite:{}", self.path)
+    }
+
+    async fn committed(&mut self) -> anyhow::Result<Option<SinkPosition>> {
+        let path = self.path.clone();
+
+        let (conn, position) = tokio::task::spawn_blocking(move || {
+            let conn = Connection::open(path)?;
+            conn.execute_batch(SCHEMA)?;
+
+            let position = conn
+                .query_row("SELECT seq, height FROM sink_position", [], |row| {
+                    Ok(SinkPosition {
+                        seq: row.get(0)?,
+                        height: row.get(1)?,
+                    })
+                })
+                .optional()?;
+
+            anyhow::Ok((conn, position))
+        })
+        .await??;
+
+        self.conn = Some(Arc::new(parking_lot::Mutex::new(conn)));
+
+        Ok(position)
+    }
+
+    async fn write(
+        &mut self,
+        records: Vec<SinkRecord>,
+        position: SinkPosition,
+    ) -> anyhow::Result<()> {
+        let conn = self.conn()?;
+
+        tokio::task::spawn_blocking(move || {
+            Self::write_records(&mut conn.lock(), records, position)
+        })
+        .await?
+    }
+}
@@ -1,25 +1,42 @@
 use super::*;
 
+use db::TableOptions;
+use reorg::ReorgHistoryBlock;
+
+/// Keys of tables read by address start with its 32 bytes hash, outpoints with the txid
+const HASH_PREFIX: usize = 32;
+
 generate_db_code! {
-    token_to_meta: LowerCaseTick => UsingSerde<TokenMetaDB>,
-    address_location_to_transfer: AddressLocation => UsingSerde<TransferProtoDB>,
-    address_token_to_balance: AddressToken => UsingSerde<TokenBalance>,
-    address_token_to_history: AddressTokenId => UsingSerde<HistoryValue>,
+    token_to_meta: LowerCaseTick => UsingSerde<TokenMetaDB> = TableOptions::point_lookups(),
+    address_location_to_transfer: AddressLocation => UsingSerde<TransferProtoDB> = TableOptions::prefix(HASH_PREFIX),
+    address_token_to_balance: AddressToken => UsingSerde<TokenBalance> = TableOptions::prefix(HASH_PREFIX),
+    address_token_to_history: AddressTokenId => UsingSerde<HistoryValue> = TableOptions::prefix(HASH_PREFIX),
     block_hashes: u32 => UsingConsensus<BlockHash>,
-    prevouts: UsingConsensus<OutPoint> => UsingConsensus<TxOut>,
+    prevouts: UsingConsensus<OutPoint> => UsingConsensus<TxOut> = TableOptions::point_lookups().write_buffer_mb(128),
     last_block: () => u32,
     last_history_id: () => u64,
     proof_of_history: u32 => UsingConsensus<sha256::Hash>,
     block_events: u32 => Vec<AddressTokenId>,
-    fullhash_to_address: FullHash => String,
-    outpoint_to_event: UsingConsensus<OutPoint> => AddressTokenId,
+    fullhash_to_address: FullHash => String = TableOptions::point_lookups(),
+    outpoint_to_event: UsingConsensus<OutPoint> => AddressTokenId = TableOptions::prefix(HASH_PREFIX),
+    inscription_to_rejection: UsingConsensus<OutPoint> => UsingSerde<RejectedInscription> = TableOptions::point_lookups(),
+    address_to_rejection: AddressRejection => () = TableOptions::prefix(HASH_PREFIX),
+    outpoint_to_rejection: OutPointRejection => () = TableOptions::prefix(HASH_PREFIX),
+    reorg_blocks: u32 => UsingSerde<ReorgHistoryBlock>,
+    // Written once and rarely read
+    event_log: u64 => UsingSerde<ServerEvent> = TableOptions::new().compression(rocksdb::DBCompressionType::Zstd),
+    last_event_seq: () => u64,
+    webhooks: u64 => UsingSerde<Webhook>,
+    last_webhook_id: () => u64,
+    webhook_cursors: u64 => u64,
+    webhook_dead_letters: WebhookEvent => UsingSerde<DeadLetter>,
 }
 
 impl DB {
     pub fn load_token_accounts(
         &self,
         keys: HashSet<(FullHash, LowerCaseTick)>,
-    ) -> HashMap<AddressToken, TokenBalance> {
+    ) -> anyhow::Result<HashMap<AddressToken, TokenBalance>> {
         let db_keys = keys
             .into_iter()
             .map(|x| AddressToken {
@@ -28,46 +45,34 @@ impl DB {
             })
             .collect_vec();
 
-        self.address_token_to_balance
-            .multi_get(db_keys.iter().collect_vec())
+        Ok(self
+            .address_token_to_balance
+            .multi_get(db_keys.iter().collect_vec())?
             .into_iter()
             .zip(db_keys)
             .flat_map(|(v, k)| v.map(|v| (k, v)))
-            .collect()
+            .collect())
     }
 
+    /// Transfers inscribed on the outputs of `keys`, the block spending them removes them
     pub fn load_transfers(
         &self,
         keys: BTreeSet<AddressLocation>,
-    ) -> Vec<(Location, (FullHash, TransferProtoDB))> {
-        let result = self
-            .address_location_to_transfer
-            .iter()
-            .filter_map(|(k, v)| {
-                if keys.contains(&AddressLocation {
-                    address: k.address,
-                    location: Location {
-                        offset: 0,
-                        outpoint: k.location.outpoint,
-                    },
-                }) {
-                    Some((k.location, (k.address, v)))
-                } else {
-                    None
-                }
-            })
-            .collect_vec();
-
-        self.address_location_to_transfer
-            .remove_batch(
-                result
-                    .iter()
-                    .map(|(location, (address, _))| AddressLocation {
-                        address: *address,
-                        location: *location,
-                    }),
-            );
+    ) -> anyhow::Result<Vec<(Location, (FullHash, TransferProtoDB))>> {
+        let mut result = vec![];
+        for entry in self.address_location_to_transfer.iter() {
+            let (k, v) = entry?;
+            if keys.contains(&AddressLocation {
+                address: k.address,
+                location: Location {
+                    offset: 0,
+                    outpoint: k.location.outpoint,
+                },
+            }) {
+                result.push((k.location, (k.address, v)));
+            }
+        }
 
-        result
+        Ok(result)
     }
 }
@@ -0,0 +1,386 @@
+//! Blocks with bel-20 inscriptions and a server indexing them, shared by tests
+
+use bellscoin::{
+    block::{Header, Version},
+    hash_types::TxMerkleNode,
+    hashes::hash160,
+    script::Builder,
+    CompactTarget, ScriptBuf, Sequence, TxIn, WPubkeyHash, Witness,
+};
+use inscriptions::InitialIndexer;
+use reorg::ReorgCache;
+use tokio::{
+    io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader},
+    net::TcpListener,
+};
+
+use super::*;
+
+/// Value of the coinbase outputs funding inscriptions
+pub const FUNDING: u64 = 10_000;
+
+/// Server on an empty memory db
+pub async fn server() -> Arc<Server> {
+    crate::config::Config::install_default();
+    Arc::new(Server::new(DB::open_memory()).await.unwrap())
+}
+
+/// Script of a test address
+pub fn address(n: u8) -> ScriptBuf {
+    ScriptBuf::new_v0_p2wpkh(&WPubkeyHash::from_raw_hash(hash160::Hash::hash(&[n])))
+}
+
+pub fn deploy(tick: &str, max: &str, lim: &str) -> String {
+    format!(r#"{{"p":"bel-20","op":"deploy","tick":"{tick}","max":"{max}","lim":"{lim}"}}"#)
+}
+
+pub fn mint(tick: &str, amt: &str) -> String {
+    format!(r#"{{"p":"bel-20","op":"mint","tick":"{tick}","amt":"{amt}"}}"#)
+}
+
+pub fn transfer(tick: &str, amt: &str) -> String {
+    format!(r#"{{"p":"bel-20","op":"transfer","tick":"{tick}","amt":"{amt}"}}"#)
+}
+
+/// Deploy of `tick` with max 1000 and limit 100, for tests of the token cache without blocks
+pub fn deploy_action(tick: TokenTick, deployer: FullHash) -> TokenAction {
+    TokenAction::Deploy {
+        genesis: InscriptionId {
+            txid: Txid::all_zeros(),
+            index: 0,
+        },
+        proto: DeployProtoDB {
+            tick,
+            max: "1000".parse().unwrap(),
+            lim: "100".parse().unwrap(),
+            dec: 18,
+            supply: Fixed128::zero(),
+            transfer_count: 0,
+            mint_count: 0,
+            height: 1,
+            created: 0,
+            deployer,
+            transactions: 1,
+        },
+        owner: deployer,
+    }
+}
+
+pub fn mint_action(tick: TokenTick, owner: FullHash, amt: &str) -> TokenAction {
+    TokenAction::Mint {
+        genesis: InscriptionId {
+            txid: Txid::all_zeros(),
+            index: 1,
+        },
+        owner,
+        proto: MintProto::Bel20 {
+            tick,
+            amt: amt.parse().unwrap(),
+        },
+        txid: Txid::all_zeros(),
+        vout: 0,
+    }
+}
+
+/// Chain starting at the protocol start height. Its first block funds the inscriptions
+pub struct TestChain {
+    pub blocks: Vec<bellscoin::Block>,
+    txs: HashMap<Txid, Transaction>,
+    funding: Vec<OutPoint>,
+}
+
+impl TestChain {
+    pub fn new() -> Self {
+        let mut chain = Self {
+            blocks: vec![],
+            txs: HashMap::new(),
+            funding: vec![],
+        };
+
+        let block = chain.mine_with(vec![], 100);
+        let coinbase = block.txdata[0].txid();
+        chain.funding = (0..100)
+            .rev()
+            .map(|vout| OutPoint::new(coinbase, vout))
+            .collect();
+
+        chain
+    }
+
+    /// Height of the last block
+    pub fn height(&self) -> u32 {
+        PROTOCOL.start_height + self.blocks.len() as u32 - 1
+    }
+
+    pub fn block(&self, height: u32) -> &bellscoin::Block {
+        &self.blocks[(height - PROTOCOL.start_height) as usize]
+    }
+
+    pub fn transaction(&self, txid: &Txid) -> Option<&Transaction> {
+        self.txs.get(txid)
+    }
+
+    /// Reveals the inscription to `owner`, at offset 0 of the first output
+    pub fn inscribe(&mut self, owner: &ScriptBuf, content: &str) -> Transaction {
+        self.reveal(owner, content, false)
+    }
+
+    /// Reveals the inscription to `owner` after an input without inscription,
+    /// at offset `FUNDING` of the first output
+    pub fn inscribe_padded(&mut self, owner: &ScriptBuf, content: &str) -> Transaction {
+        self.reveal(owner, content, true)
+    }
+
+    fn reveal(&mut self, owner: &ScriptBuf, content: &str, padded: bool) -> Transaction {
+        let tapscript = Builder::new()
+            .push_opcode(opcodes::OP_FALSE)
+            .push_opcode(opcodes::all::OP_IF)
+            .push_slice(inscriptions::PROTOCOL_ID)
+            .push_slice([1])
+            .push_slice(b"text/plain;charset=utf-8")
+            .push_opcode(opcodes::OP_FALSE)
+            .push_slice(script::PushBytesBuf::try_from(content.as_bytes().to_vec()).unwrap())
+            .push_opcode(opcodes::all::OP_ENDIF)
+            .into_script();
+
+        let mut input = vec![];
+        if padded {
+            input.push(self.funding_input(Witness::new()));
+        }
+        input.push(self.funding_input(Witness::from_slice(&[tapscript.as_bytes(), &[0xc0; 33]])));
+
+        Transaction {
+            version: 2,
+            lock_time: bellscoin::absolute::LockTime::ZERO,
+            output: vec![TxOut {
+                value: FUNDING * input.len() as u64,
+                script_pubkey: owner.clone(),
+            }],
+            input,
+        }
+    }
+
+    fn funding_input(&mut self, witness: Witness) -> TxIn {
+        TxIn {
+            previous_output: self.funding.pop().expect("Chain is out of funding outputs"),
+            script_sig: ScriptBuf::new(),
+            sequence: Sequence::MAX,
+            witness,
+        }
+    }
+
+    /// Sends the whole output with its inscriptions to `recipient`
+    pub fn send(&self, outpoint: OutPoint, recipient: &ScriptBuf) -> Transaction {
+        let value = self.txs[&outpoint.txid].output[outpoint.vout as usize].value;
+
+        Transaction {
+            version: 2,
+            lock_time: bellscoin::absolute::LockTime::ZERO,
+            input: vec![TxIn {
+                previous_output: outpoint,
+                script_sig: ScriptBuf::new(),
+                sequence: Sequence::MAX,
+                witness: Witness::new(),
+            }],
+            output: vec![TxOut {
+                value,
+                script_pubkey: recipient.clone(),
+            }],
+        }
+    }
+
+    /// Drops the blocks after `height`, the next ones fork from it
+    pub fn fork(&mut self, height: u32) {
+        self.blocks
+            .truncate((height + 1 - PROTOCOL.start_height) as usize);
+    }
+
+    /// Appends a block with the transactions after its coinbase
+    pub fn mine(&mut self, txs: Vec<Transaction>) -> &bellscoin::Block {
+        self.mine_with(txs, 1)
+    }
+
+    fn mine_with(&mut self, txs: Vec<Transaction>, outputs: u32) -> &bellscoin::Block {
+        let height = PROTOCOL.start_height + self.blocks.len() as u32;
+
+        let coinbase = Transaction {
+            version: 2,
+            lock_time: bellscoin::absolute::LockTime::ZERO,
+            input: vec![TxIn {
+                previous_output: OutPoint::null(),
+                script_sig: Builder::new().push_int(height as i64).into_script(),
+                sequence: Sequence::MAX,
+                witness: Witness::new(),
+            }],
+            output: (0..outputs)
+                .map(|_| TxOut {
+                    value: FUNDING,
+                    script_pubkey: address(0),
+                })
+                .collect(),
+        };
+
+        let txdata = [coinbase].into_iter().chain(txs).collect_vec();
+        self.txs
+            .extend(txdata.iter().map(|x| (x.txid(), x.clone())));
+
+        self.blocks.push(bellscoin::Block {
+            header: Header {
+                version: Version::ONE,
+                prev_blockhash: self
+                    .blocks
+                    .last()
+                    .map(|x| x.block_hash())
+                    .unwrap_or_else(BlockHash::all_zeros),
+                merkle_root: TxMerkleNode::all_zeros(),
+                time: height,
+                bits: CompactTarget::from_consensus(0x207fffff),
+                nonce: 0,
+                auxpow: None,
+            },
+            txdata,
+        });
+
+        self.blocks.last().unwrap()
+    }
+}
+
+/// Indexes the blocks of the chain after the last indexed one up to `to`
+pub async fn index(server: &Arc<Server>, chain: &TestChain, to: u32) {
+    index_with(server, chain, to, None).await
+}
+
+/// Same as `index`, saving the blocks in the reorg cache
+pub async fn index_with(
+    server: &Arc<Server>,
+    chain: &TestChain,
+    to: u32,
+    reorg_cache: Option<&Arc<parking_lot::Mutex<ReorgCache>>>,
+) {
+    let from = server
+        .db
+        .last_block
+        .get(())
+        .unwrap()
+        .map_or(PROTOCOL.start_height, |x| x + 1);
+
+    for height in from..=to {
+        InitialIndexer::handle_block(
+            height,
+            chain.block(height).clone(),
+            server.clone(),
+            reorg_cache.cloned(),
+        )
+        .await
+        .unwrap();
+    }
+}
+
+/// Keys and values of the table as they are stored
+pub fn entries<K: db::Pebble, V: db::Pebble>(table: &impl Table<K, V>) -> Vec<(Vec<u8>, Vec<u8>)> {
+    table
+        .iter()
+        .map_ok(|(k, v)| (K::get_bytes(&k).into_owned(), V::get_bytes(&v).into_owned()))
+        .try_collect()
+        .unwrap()
+}
+
+/// Asserts that both dbs hold the same tokens, accounts, transfers and history.
+/// Reverted blocks leave emptied accounts, like reorgs do, so only accounts with a balance are compared
+pub fn assert_same_tokens(a: &DB, b: &DB) {
+    let accounts = |db: &DB| -> Vec<(AddressToken, TokenBalance)> {
+        db.address_token_to_balance
+            .iter()
+            .filter_ok(|(_, v)| *v != TokenBalance::default())
+            .try_collect()
+            .unwrap()
+    };
+
+    assert_eq!(
+        entries(&a.address_location_to_transfer),
+        entries(&b.address_location_to_transfer)
+    );
+    assert_eq!(entries(&a.token_to_meta), entries(&b.token_to_meta));
+    assert_eq!(accounts(a), accounts(b));
+    assert_eq!(
+        entries(&a.address_token_to_history),
+        entries(&b.address_token_to_history)
+    );
+    assert_eq!(entries(&a.outpoint_to_event), entries(&b.outpoint_to_event));
+    assert_eq!(entries(&a.block_events), entries(&b.block_events));
+    assert_eq!(entries(&a.proof_of_history), entries(&b.proof_of_history));
+    assert_eq!(entries(&a.block_hashes), entries(&b.block_hashes));
+    assert_eq!(
+        entries(&a.inscription_to_rejection),
+        entries(&b.inscription_to_rejection)
+    );
+    assert_eq!(
+        entries(&a.address_to_rejection),
+        entries(&b.address_to_rejection)
+    );
+    assert_eq!(
+        a.last_history_id.get(()).unwrap(),
+        b.last_history_id.get(()).unwrap()
+    );
+}
+
+/// Request received by `http_server`. Header names are lowercase
+pub struct HttpRequest {
+    pub headers: HashMap<String, String>,
+    pub body: Vec<u8>,
+}
+
+/// Local HTTP server answering each request with the status and body returned by `reply`, returns its URL
+pub async fn http_server(
+    reply: impl Fn(HttpRequest) -> (u16, String) + Send + Sync + 'static,
+) -> String {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let url = format!("http://{}", listener.local_addr().unwrap());
+    let reply = Arc::new(reply);
+
+    tokio::spawn(async move {
+        while let Ok((stream, _)) = listener.accept().await {
+            let reply = reply.clone();
+            tokio::spawn(async move {
+                let mut stream = BufReader::new(stream);
+
+                // Clients keep connections alive, requests are answered until they close it
+                loop {
+                    let mut line = String::new();
+                    if stream.read_line(&mut line).await.unwrap_or_default() == 0 {
+                        break;
+                    }
+
+                    let mut headers = HashMap::new();
+                    loop {
+                        let mut line = String::new();
+                        stream.read_line(&mut line).await.unwrap();
+                        if line == "\r\n" {
+                            break;
+                        }
+                        if let Some((name, value)) = line.split_once(':') {
+                            headers.insert(name.trim().to_lowercase(), value.trim().to_string());
+                        }
+                    }
+
+                    let length = headers
+                        .get("content-length")
+                        .map_or(0, |x| x.parse().unwrap());
+                    let mut body = vec![0; length];
+                    stream.read_exact(&mut body).await.unwrap();
+
+                    let (status, body) = reply(HttpRequest { headers, body });
+                    let response = format!(
+                        "HTTP/1.1 {status} Reply\r\nContent-Length: {}\r\n\r\n{body}",
+                        body.len()
+                    );
+                    if stream.write_all(response.as_bytes()).await.is_err() {
+                        break;
+                    }
+                }
+            });
+        }
+    });
+
+    url
+}
@@ -3,9 +3,11 @@ use super::*;
 #[derive(Eq, PartialEq, Clone, Ord, PartialOrd, Serialize, Deserialize, Debug)]
 pub struct SortedByBalance(pub Fixed128, pub FullHash);
 
+/// Changes are staged while a block is written and become visible on `commit`
+#[derive(Default)]
 pub struct Holders {
-    balances: parking_lot::RwLock<HashMap<LowerCaseTick, BTreeSet<SortedByBalance>>>,
-    stats: parking_lot::RwLock<HashMap<LowerCaseTick, usize>>,
+    data: parking_lot::RwLock<Arc<HoldersData>>,
+    pending: parking_lot::Mutex<Vec<(AddressToken, Fixed128, Fixed128, Action)>>,
 }
 
 enum Action {
@@ -13,11 +15,79 @@ enum Action {
     Decrease,
 }
 
+/// Holders of every tick at a committed block. Ticks are shared between blocks until they change
+#[derive(Clone, Default)]
+pub struct HoldersData {
+    balances: HashMap<LowerCaseTick, Arc<BTreeSet<SortedByBalance>>>,
+    stats: HashMap<LowerCaseTick, usize>,
+}
+
+impl HoldersData {
+    pub fn get_holders(&self, tick: &LowerCaseTick) -> Option<&BTreeSet<SortedByBalance>> {
+        self.balances.get(tick).map(|x| x.as_ref())
+    }
+
+    pub fn holders_by_tick(&self, tick: &LowerCaseTick) -> Option<usize> {
+        self.stats.get(tick).cloned()
+    }
+
+    pub fn stats(&self) -> &HashMap<LowerCaseTick, usize> {
+        &self.stats
+    }
+
+    fn apply(&mut self, key: &AddressToken, old_balance: Fixed128, amt: Fixed128, action: Action) {
+        let v = Arc::make_mut(self.balances.entry(key.token.clone()).or_default());
+
+        let existed = v.remove(&SortedByBalance(old_balance, key.address));
+
+        match action {
+            Action::Increase => {
+                if !existed {
+                    self.stats
+                        .entry(key.token.clone())
+                        .and_modify(|x| *x += 1)
+                        .or_insert(1);
+                }
+
+                v.insert(SortedByBalance(old_balance + amt, key.address));
+            }
+            Action::Decrease => {
+                let bal = old_balance - amt;
+                if !bal.is_zero() {
+                    v.insert(SortedByBalance(bal, key.address));
+                } else {
+                    self.stats.entry(key.token.clone()).and_modify(|x| *x -= 1);
+                }
+            }
+        }
+    }
+}
+
 impl Holders {
-    pub fn init(db: &DB) -> Self {
+    pub fn init(db: &DB) -> anyhow::Result<Self> {
+        Ok(Self {
+            data: parking_lot::RwLock::new(Arc::new(Self::load(db)?)),
+            pending: Default::default(),
+        })
+    }
+
+    /// Replaces holders with the ones loaded from the db. Staged changes are dropped
+    pub fn replace(&self, data: HoldersData) -> Arc<HoldersData> {
+        self.pending.lock().clear();
+        let data = Arc::new(data);
+        *self.data.write() = data.clone();
+        data
+    }
+
+    pub fn load(db: &DB) -> anyhow::Result<HoldersData> {
+        let balances = db
+            .address_token_to_balance
+            .iter()
+            .collect::<anyhow::Result<Vec<_>>>()?;
+
         let holders = HashMap::<LowerCaseTick, _>::from_iter(
-            db.address_token_to_balance
-                .iter()
+            balances
+                .into_iter()
                 .filter(|(_, v)| !v.balance.is_zero() || !v.transferable_balance.is_zero())
                 .map(|(k, v)| {
                     (
@@ -28,19 +98,88 @@ impl Holders {
                 .sorted_unstable_by_key(|x| x.0.clone())
                 .chunk_by(|(k, _)| k.clone())
                 .into_iter()
-                .map(|(k, v)| (k, v.map(|(_, v)| v).collect::<BTreeSet<_>>())),
+                .map(|(k, v)| (k, Arc::new(v.map(|(_, v)| v).collect::<BTreeSet<_>>()))),
         );
 
         let stats = holders.iter().map(|x| (x.0.clone(), x.1.len())).collect();
 
-        Self {
-            balances: parking_lot::RwLock::new(holders),
-            stats: parking_lot::RwLock::new(stats),
+        Ok(HoldersData {
+            balances: holders,
+            stats,
+        })
+    }
+
+    /// Holders after the blocks `from..=to` are applied to `data`.
+    /// Only the accounts with history in those blocks are read, every balance change is in the history
+    pub fn update(db: &DB, data: &HoldersData, from: u32, to: u32) -> anyhow::Result<HoldersData> {
+        let keys = db
+            .block_events
+            .range(&from..=&to, false)
+            .map_ok(|(_, v)| v)
+            .flatten_ok()
+            .map_ok(|x| AddressToken {
+                address: x.address,
+                token: x.token.into(),
+            })
+            .collect::<anyhow::Result<HashSet<_>>>()?
+            .into_iter()
+            .collect_vec();
+
+        let balances = db.address_token_to_balance.multi_get(keys.iter())?;
+
+        let mut data = data.clone();
+        for (tick, accounts) in &keys
+            .into_iter()
+            .zip(balances)
+            .sorted_unstable_by(|x, y| x.0.token.cmp(&y.0.token))
+            .chunk_by(|x| x.0.token.clone())
+        {
+            let accounts = accounts.collect_vec();
+            let changed = accounts.iter().map(|x| x.0.address).collect::<HashSet<_>>();
+
+            let holders = Arc::make_mut(data.balances.entry(tick.clone()).or_default());
+            holders.retain(|x| !changed.contains(&x.1));
+            holders.extend(accounts.into_iter().filter_map(|(k, v)| {
+                let balance = v.map(|v| v.balance + v.transferable_balance)?;
+                (!balance.is_zero()).then_some(SortedByBalance(balance, k.address))
+            }));
+
+            if holders.is_empty() {
+                data.balances.remove(&tick);
+                data.stats.remove(&tick);
+            } else {
+                let len = holders.len();
+                data.stats.insert(tick, len);
+            }
         }
+
+        Ok(data)
+    }
+
+    /// Drops changes staged since the last commit
+    pub fn discard(&self) {
+        self.pending.lock().clear();
     }
 
-    pub fn get_holders(&self, tick: &LowerCaseTick) -> Option<BTreeSet<SortedByBalance>> {
-        self.balances.read().get(tick).cloned()
+    /// Holders at the last commit
+    pub fn snapshot(&self) -> Arc<HoldersData> {
+        self.data.read().clone()
+    }
+
+    /// Applies staged changes in order. Snapshots taken before keep the previous holders
+    pub fn commit(&self) -> Arc<HoldersData> {
+        let mut pending = self.pending.lock();
+        let mut data = self.data.write();
+
+        if !pending.is_empty() {
+            let mut next = HoldersData::clone(&data);
+            for (key, old_balance, amt, action) in pending.drain(..) {
+                next.apply(&key, old_balance, amt, action);
+            }
+            *data = Arc::new(next);
+        }
+
+        data.clone()
     }
 
     /// hack because i cant throw -amt cause of type
@@ -53,46 +192,59 @@ impl Holders {
         self.change(key, prev_balance, amt, Action::Increase)
     }
 
-    pub fn holders_by_tick(&self, tick: &LowerCaseTick) -> Option<usize> {
-        self.stats.read().get(tick).cloned()
-    }
-
-    pub fn stats(&self) -> HashMap<LowerCaseTick, usize> {
-        self.stats.read().clone()
-    }
-
     fn change(&self, key: &AddressToken, acc: &TokenBalance, amt: Fixed128, action: Action) {
         // used to prevent footgun with balance (not to forget to add transferable)
         let old_balance = acc.balance + acc.transferable_balance;
-        let mut balances = self.balances.write();
-
-        let v = balances.entry(key.token.clone()).or_default();
-
-        let existed = v.remove(&SortedByBalance(old_balance, key.address));
+        self.pending
+            .lock()
+            .push((key.clone(), old_balance, amt, action));
+    }
+}
 
-        match action {
-            Action::Increase => {
-                if !existed {
-                    self.stats
-                        .write()
-                        .entry(key.token.clone())
-                        .and_modify(|x| *x += 1)
-                        .or_insert(1);
-                }
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::{self, TestChain};
+
+    fn holders(data: &HoldersData) -> Vec<(LowerCaseTick, Vec<SortedByBalance>, usize)> {
+        data.balances
+            .iter()
+            .map(|(k, v)| (k.clone(), v.iter().cloned().collect(), data.stats[k]))
+            .sorted_unstable_by(|x, y| x.0.cmp(&y.0))
+            .collect()
+    }
 
-                v.insert(SortedByBalance(old_balance + amt, key.address));
-            }
-            Action::Decrease => {
-                let bal = old_balance - amt;
-                if !bal.is_zero() {
-                    v.insert(SortedByBalance(bal, key.address));
-                } else {
-                    self.stats
-                        .write()
-                        .entry(key.token.clone())
-                        .and_modify(|x| *x -= 1);
-                }
-            }
-        }
+    #[tokio::test]
+    async fn update_matches_load() {
+        let alice = test_utils::address(1);
+        let bob = test_utils::address(2);
+
+        let mut chain = TestChain::new();
+        let deploy = chain.inscribe(&alice, &test_utils::deploy("abcd", "1000", "100"));
+        chain.mine(vec![deploy]);
+        let mint = chain.inscribe(&alice, &test_utils::mint("abcd", "100"));
+        chain.mine(vec![mint]);
+        let transfer = chain.inscribe(&alice, &test_utils::transfer("abcd", "100"));
+        let transfer_outpoint = OutPoint::new(transfer.txid(), 0);
+        chain.mine(vec![transfer]);
+
+        let server = test_utils::server().await;
+        test_utils::index(&server, &chain, chain.height()).await;
+        let from = chain.height() + 1;
+        let data = Holders::load(&server.db).unwrap();
+
+        // Alice sends her whole balance, so she is no longer a holder
+        let send = chain.send(transfer_outpoint, &bob);
+        let deploy = chain.inscribe(&bob, &test_utils::deploy("efgh", "1000", "100"));
+        let mint = chain.inscribe(&bob, &test_utils::mint("efgh", "10"));
+        chain.mine(vec![send, deploy]);
+        chain.mine(vec![mint]);
+        test_utils::index(&server, &chain, chain.height()).await;
+
+        let updated = Holders::update(&server.db, &data, from, chain.height()).unwrap();
+        let loaded = Holders::load(&server.db).unwrap();
+        assert_eq!(holders(&updated), holders(&loaded));
+        assert_eq!(updated.holders_by_tick(&"abcd".into()), Some(1));
+        assert_eq!(updated.holders_by_tick(&"efgh".into()), Some(1));
     }
 }
@@ -7,7 +7,7 @@ mod proto;
 mod structs;
 
 pub use fullhash::{ComputeScriptHash, FullHash};
-pub use holders::Holders;
+pub use holders::{Holders, HoldersData};
 pub use parser::{HistoryTokenAction, TokenCache};
-pub use proto::{DeployProtoDB, MintProto, TransferProto, TransferProtoDB};
+pub use proto::{Brc4, Brc4Value, DeployProtoDB, MintProto, TransferProto, TransferProtoDB};
 pub use structs::*;
@@ -1,4 +1,4 @@
-use crate::Fixed128;
+use crate::{fault::InvariantViolation, Fixed128};
 
 use super::*;
 
@@ -86,9 +86,16 @@ pub struct TokenCache {
 
     /// All transfer actions that are valid. Used to write to the db.
     pub valid_transfers: BTreeMap<Location, (FullHash, TransferProtoDB)>,
+
+    /// All bel-20 inscriptions that failed parsing or validation. Used to write to the db.
+    pub rejected: Vec<RejectedInscription>,
 }
 impl TokenCache {
-    fn try_parse(content_type: &str, content: &[u8]) -> Result<Brc4, Brc4ParseErr> {
+    pub fn try_parse(
+        content_type: &str,
+        content: &[u8],
+        height: u32,
+    ) -> Result<Brc4, Brc4ParseErr> {
         match content_type.split(';').nth(0) {
             Some("text/plain" | "application/json") => {
                 let Ok(data) = String::from_utf8(content.to_vec()) else {
@@ -110,6 +117,7 @@ impl TokenCache {
                         }
                         "value cannot contain spaces" => return Err(Brc4ParseErr::DecimalSpaces),
                         "invalid digit found in string" => return Err(Brc4ParseErr::InvalidDigit),
+                        "invalid token tick" => return Err(Brc4ParseErr::InvalidTick),
                         _msg => {
                             // eprintln!("ERR: {msg:?}");
                             return Err(Brc4ParseErr::Unknown);
@@ -117,6 +125,10 @@ impl TokenCache {
                     },
                 };
 
+                if !PROTOCOL.is_tick_valid(&brc4.tick(), height) {
+                    return Err(Brc4ParseErr::InvalidTick);
+                }
+
                 match &brc4 {
                     Brc4::Mint {
                         proto: MintProto::Bel20 { amt, .. },
@@ -139,6 +151,14 @@ impl TokenCache {
         }
     }
 
+    /// Checks if the content claims to be bel-20 so we don't log every random text inscription as rejected.
+    fn is_bel_20(content: &[u8]) -> bool {
+        serde_json::from_slice::<serde_json::Value>(content)
+            .ok()
+            .and_then(|x| x.get("p")?.as_str().map(|p| p == "bel-20"))
+            .unwrap_or_default()
+    }
+
     /// Parse token action from InscriptionTemplace and returns bool if it is mint or not.
     pub fn parse_token_action(
         &mut self,
@@ -150,8 +170,29 @@ impl TokenCache {
             return None;
         }
 
-        let Ok(brc4) = Self::try_parse(inc.content_type.as_ref()?, inc.content.as_ref()?) else {
+        if inc.content_encoding.is_some() && PROTOCOL.is_content_encoding_active(height) {
             return None;
+        }
+
+        let content = inc.content.as_ref()?;
+
+        let brc4 = match Self::try_parse(inc.content_type.as_ref()?, content, height) {
+            Ok(brc4) => brc4,
+            Err(Brc4ParseErr::WrongContentType) => return None,
+            Err(e) => {
+                if !inc.leaked && Self::is_bel_20(content) {
+                    self.rejected.push(RejectedInscription {
+                        genesis: inc.genesis,
+                        owner: inc.owner,
+                        height,
+                        txid: inc.location.outpoint.txid,
+                        vout: inc.location.outpoint.vout,
+                        op: None,
+                        reason: e.into(),
+                    });
+                }
+                return None;
+            }
         };
 
         // skip to not add invalid token creation in token_cache
@@ -188,6 +229,7 @@ impl TokenCache {
             }
             Brc4::Mint { proto } => {
                 self.token_actions.push(TokenAction::Mint {
+                    genesis: inc.genesis,
                     owner: inc.owner,
                     proto,
                     txid: inc.location.outpoint.txid,
@@ -196,6 +238,7 @@ impl TokenCache {
             }
             Brc4::Transfer { proto } => {
                 self.token_actions.push(TokenAction::Transfer {
+                    genesis: inc.genesis,
                     location: inc.location,
                     owner: inc.owner,
                     proto: proto.clone(),
@@ -242,13 +285,13 @@ impl TokenCache {
 
         self.tokens = db
             .token_to_meta
-            .multi_get(tickers.iter())
+            .multi_get(tickers.iter())?
             .into_iter()
             .zip(tickers)
             .filter_map(|(v, k)| v.map(|x| (k, TokenMeta::from(x))))
             .collect::<HashMap<_, _>>();
 
-        self.token_accounts = db.load_token_accounts(users);
+        self.token_accounts = db.load_token_accounts(users)?;
 
         Ok(())
     }
@@ -315,7 +358,8 @@ impl TokenCache {
         &mut self,
         reorg_cache: Option<Arc<parking_lot::Mutex<crate::reorg::ReorgCache>>>,
         holders: &Holders,
-    ) -> Vec<HistoryTokenAction> {
+        height: u32,
+    ) -> anyhow::Result<Vec<HistoryTokenAction>> {
         let mut history = vec![];
 
         for action in self.token_actions.drain(..) {
@@ -348,18 +392,45 @@ impl TokenCache {
                         });
 
                         if let Some(x) = reorg_cache.as_ref() {
-                            x.lock().added_deployed_token(tick);
+                            x.lock().added_deployed_token(tick)?;
                         }
+                    } else {
+                        self.rejected.push(RejectedInscription {
+                            genesis,
+                            owner,
+                            height,
+                            txid: genesis.txid,
+                            vout: genesis.index,
+                            op: Some(Brc4Value::Deploy {
+                                tick,
+                                max,
+                                lim,
+                                dec,
+                            }),
+                            reason: Brc4ActionErr::AlreadyDeployed.into(),
+                        });
                     }
                 }
                 TokenAction::Mint {
+                    genesis,
                     owner,
                     proto,
                     txid,
                     vout,
                 } => {
                     let MintProto::Bel20 { tick, amt } = proto;
+                    let rejected = |reason: Brc4ActionErr| RejectedInscription {
+                        genesis,
+                        owner,
+                        height,
+                        txid,
+                        vout,
+                        op: Some(Brc4Value::Mint { tick, amt }),
+                        reason: reason.into(),
+                    };
+
                     let Some(token) = self.tokens.get_mut(&tick.into()) else {
+                        self.rejected.push(rejected(Brc4ActionErr::NotDeployed));
                         continue;
                     };
                     let DeployProtoDB {
@@ -373,14 +444,17 @@ impl TokenCache {
                     } = &mut token.proto;
 
                     if amt.scale() > *dec {
+                        self.rejected.push(rejected(Brc4ActionErr::ReachDecBound));
                         continue;
                     }
 
                     if Fixed128::from(*lim) < amt {
+                        self.rejected.push(rejected(Brc4ActionErr::ReachLimBound));
                         continue;
                     }
 
                     if *supply == Fixed128::from(*max) {
+                        self.rejected.push(rejected(Brc4ActionErr::SupplyMinted));
                         continue;
                     }
                     let amt = amt.min(Fixed128::from(*max) - *supply);
@@ -411,24 +485,36 @@ impl TokenCache {
                     });
 
                     if let Some(x) = reorg_cache.as_ref() {
-                        x.lock().added_minted_token(key, amt);
+                        x.lock().added_minted_token(key, amt)?;
                     }
                 }
                 TokenAction::Transfer {
+                    genesis,
                     owner,
                     location,
                     proto,
                     txid,
                     vout,
                 } => {
+                    let TransferProto::Bel20 { tick, amt } = proto;
+                    let rejected = |reason: Brc4ActionErr| RejectedInscription {
+                        genesis,
+                        owner,
+                        height,
+                        txid,
+                        vout,
+                        op: Some(Brc4Value::Transfer { tick, amt }),
+                        reason: reason.into(),
+                    };
+
                     let Some(data) = self.all_transfers.remove(&location) else {
                         // skip cause is it transfer already spent
+                        self.rejected.push(rejected(Brc4ActionErr::Transferred));
                         continue;
                     };
 
-                    let TransferProto::Bel20 { tick, amt } = proto;
-
                     let Some(token) = self.tokens.get_mut(&tick.into()) else {
+                        self.rejected.push(rejected(Brc4ActionErr::NotDeployed));
                         continue;
                     };
                     let DeployProtoDB {
@@ -440,6 +526,7 @@ impl TokenCache {
 
                     if amt.scale() > *dec {
                         // skip wrong protocol
+                        self.rejected.push(rejected(Brc4ActionErr::ReachDecBound));
                         continue;
                     }
 
@@ -448,15 +535,19 @@ impl TokenCache {
                         token: tick.into(),
                     };
                     let Some(account) = self.token_accounts.get_mut(&key) else {
+                        self.rejected
+                            .push(rejected(Brc4ActionErr::InsufficientBalance));
                         continue;
                     };
 
                     if amt > account.balance {
+                        self.rejected
+                            .push(rejected(Brc4ActionErr::InsufficientBalance));
                         continue;
                     }
 
                     if let Some(x) = reorg_cache.as_ref() {
-                        x.lock().added_transfer_token(location, key.clone(), amt);
+                        x.lock().added_transfer_token(location, key.clone(), amt)?;
                     }
 
                     account.balance -= amt;
@@ -488,18 +579,29 @@ impl TokenCache {
                         continue;
                     };
 
-                    if !self.tokens.contains_key(&tick.into()) {
-                        unreachable!();
-                    }
-
                     let old_key = AddressToken {
                         address: sender,
                         token: tick.into(),
                     };
+                    let violation = |message, account: Option<&TokenBalance>| InvariantViolation {
+                        message,
+                        txid,
+                        accounts: vec![(old_key.clone(), account.cloned())],
+                    };
 
-                    let old_account = self.token_accounts.get_mut(&old_key).unwrap();
+                    if !self.tokens.contains_key(&tick.into()) {
+                        return Err(violation("Transferred token is not deployed", None).into());
+                    }
+
+                    let Some(old_account) = self.token_accounts.get_mut(&old_key) else {
+                        return Err(violation("Transfer sender has no balance", None).into());
+                    };
                     if old_account.transfers_count == 0 || old_account.transferable_balance < amt {
-                        panic!("Invalid transfer sender balance");
+                        return Err(violation(
+                            "Invalid transfer sender balance",
+                            Some(old_account),
+                        )
+                        .into());
                     }
 
                     let Some(token) = self.tokens.get_mut(&tick.into()) else {
@@ -549,63 +651,151 @@ impl TokenCache {
                             },
                             TransferProtoDB { tick, amt, height },
                             recipient,
-                        );
+                        )?;
                     }
                 }
             }
         }
 
-        history
+        Ok(history)
     }
 
-    async fn _write_tokens_amount(
-        token_accounts: Vec<(AddressToken, TokenBalance)>,
-        db: Arc<DB>,
-    ) -> anyhow::Result<()> {
-        if !token_accounts.is_empty() {
-            db.address_token_to_balance
-                .extend(token_accounts.into_iter())
-        }
-        Ok(())
+    /// Stages balances and tokens changed by the block
+    pub fn write_token_data(&mut self, db: &DB, batch: &mut DbBatch) {
+        batch.extend(&db.address_token_to_balance, self.token_accounts.drain());
+        batch.extend(
+            &db.token_to_meta,
+            self.tokens.drain().map(|(k, v)| (k, TokenMetaDB::from(v))),
+        );
     }
 
-    async fn _write_tokens_meta(
-        tokens: Vec<(LowerCaseTick, TokenMeta)>,
-        db: Arc<DB>,
-    ) -> anyhow::Result<()> {
-        if !tokens.is_empty() {
-            db.token_to_meta
-                .extend(tokens.into_iter().map(|(k, v)| (k, TokenMetaDB::from(v))));
-        }
+    pub fn write_rejected(&mut self, db: &DB, batch: &mut DbBatch) {
+        batch.extend(
+            &db.address_to_rejection,
+            self.rejected
+                .iter()
+                .map(|x| (AddressRejection::from(x), ())),
+        );
+        batch.extend(
+            &db.outpoint_to_rejection,
+            self.rejected
+                .iter()
+                .map(|x| (OutPointRejection::from(x), ())),
+        );
+        batch.extend(
+            &db.inscription_to_rejection,
+            self.rejected
+                .drain(..)
+                .map(|x| (OutPoint::from(x.genesis), x)),
+        );
+    }
 
-        Ok(())
+    pub fn write_valid_transfers(self, db: &DB, batch: &mut DbBatch) {
+        batch.extend(
+            &db.address_location_to_transfer,
+            self.valid_transfers
+                .into_iter()
+                .map(|(location, (address, proto))| (AddressLocation { address, location }, proto)),
+        );
     }
+}
 
-    pub async fn write_token_data(&mut self, db: Arc<DB>) -> anyhow::Result<()> {
-        let (a, b) = futures::future::join(
-            Self::_write_tokens_meta(self.tokens.drain().collect_vec(), db.clone()).spawn(),
-            Self::_write_tokens_amount(self.token_accounts.drain().collect_vec(), db).spawn(),
-        )
-        .await;
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::reorg::ReorgCache;
+    use crate::test_utils::{self, TestChain};
+
+    #[test]
+    fn token_cache_round_trip() {
+        crate::config::Config::install_default();
+        let db = DB::open_memory();
+        let holders = Holders::init(&db).unwrap();
+        let tick = TokenTick::from(*b"abcd");
+        let owner = FullHash::from([1; 32]);
+
+        let mut cache = TokenCache {
+            token_actions: vec![
+                test_utils::deploy_action(tick, owner),
+                test_utils::mint_action(tick, owner, "40"),
+            ],
+            ..Default::default()
+        };
+        cache.load_tokens_data(&db).unwrap();
+        let history = cache.process_token_actions(None, &holders, 1).unwrap();
+        assert_eq!(history.len(), 2);
 
-        a.anyhow()?.anyhow()?;
-        b.anyhow()?.anyhow()?;
+        let mut batch = DbBatch::default();
+        cache.write_token_data(&db, &mut batch);
+        db.write(batch).unwrap();
 
-        Ok(())
-    }
+        let mut cache = TokenCache {
+            token_actions: vec![test_utils::mint_action(tick, owner, "100")],
+            ..Default::default()
+        };
+        cache.load_tokens_data(&db).unwrap();
 
-    pub fn write_valid_transfers(self, db: &DB) -> anyhow::Result<()> {
-        if !self.valid_transfers.is_empty() {
-            db.address_location_to_transfer
-                .extend(
-                    self.valid_transfers
-                        .into_iter()
-                        .map(|(location, (address, proto))| {
-                            (AddressLocation { address, location }, proto)
-                        }),
-                );
-        }
+        let token = cache.tokens.get(&tick.into()).unwrap();
+        assert_eq!(token.proto.supply, "40".parse().unwrap());
+        assert_eq!(token.proto.mint_count, 1);
 
-        Ok(())
+        let key = AddressToken {
+            address: owner,
+            token: tick.into(),
+        };
+        assert_eq!(
+            cache.token_accounts.get(&key).unwrap().balance,
+            "40".parse().unwrap()
+        );
+    }
+
+    #[tokio::test]
+    async fn rejections_are_recorded_and_reverted() {
+        let alice = test_utils::address(1);
+        let bob = test_utils::address(2);
+
+        let mut chain = TestChain::new();
+        let deploy = chain.inscribe(&alice, &test_utils::deploy("abcd", "1000", "100"));
+        chain.mine(vec![deploy]);
+        let height = chain.height();
+        // Bob has no balance to transfer
+        let transfer = chain.inscribe(&bob, &test_utils::transfer("abcd", "10"));
+        let txid = transfer.txid();
+        chain.mine(vec![transfer]);
+
+        let server = test_utils::server().await;
+        let reorg_cache = Arc::new(parking_lot::Mutex::new(ReorgCache::new()));
+        test_utils::index_with(&server, &chain, chain.height(), Some(&reorg_cache)).await;
+
+        let genesis = OutPoint { txid, vout: 0 };
+        let rejected = server
+            .db
+            .inscription_to_rejection
+            .get(genesis)
+            .unwrap()
+            .unwrap();
+        assert_eq!(rejected.owner, bob.compute_script_hash());
+        assert_eq!(rejected.height, chain.height());
+        assert_eq!(
+            rejected.reason,
+            Brc4Error::Action(Brc4ActionErr::InsufficientBalance)
+        );
+
+        let key = AddressRejection::from(&rejected);
+        assert!(server.db.address_to_rejection.get(key).unwrap().is_some());
+        let (from, to) = OutPointRejection::search(txid).into_inner();
+        let indexed = server
+            .db
+            .outpoint_to_rejection
+            .range(&from..=&to, false)
+            .map_ok(|(k, _)| k)
+            .collect::<anyhow::Result<Vec<_>>>()
+            .unwrap();
+        assert_eq!(indexed, vec![OutPointRejection::from(&rejected)]);
+
+        reorg_cache.lock().restore(&server, height + 1).unwrap();
+        assert!(server.db.inscription_to_rejection.iter().next().is_none());
+        assert!(server.db.address_to_rejection.iter().next().is_none());
+        assert!(server.db.outpoint_to_rejection.iter().next().is_none());
     }
 }
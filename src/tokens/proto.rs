@@ -54,13 +54,8 @@ where
     D: serde::Deserializer<'de>,
 {
     let val = <Cow<str> as serde::Deserialize>::deserialize(deserializer)?;
-    let val = val.as_bytes().to_vec();
 
-    if val.len() != 4 {
-        return Err(Error::custom("invalid token tick"));
-    }
-
-    Ok(val.try_into().unwrap())
+    TokenTick::try_from(val.as_bytes()).map_err(|_| Error::custom("invalid token tick"))
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -200,6 +195,22 @@ pub enum Brc4Value {
     },
 }
 
+impl Brc4 {
+    pub fn tick(&self) -> TokenTick {
+        match self {
+            Brc4::Mint {
+                proto: MintProto::Bel20 { tick, .. },
+            }
+            | Brc4::Deploy {
+                proto: DeployProto::Bel20 { tick, .. },
+            }
+            | Brc4::Transfer {
+                proto: TransferProto::Bel20 { tick, .. },
+            } => *tick,
+        }
+    }
+}
+
 impl From<&DeployProto> for Brc4Value {
     fn from(v: &DeployProto) -> Self {
         match v {
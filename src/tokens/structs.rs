@@ -15,11 +15,11 @@ impl AddressToken {
     pub fn search(address: FullHash) -> RangeInclusive<AddressToken> {
         let start = AddressToken {
             address,
-            token: [0; 4].into(),
+            token: LowerCaseTick(vec![]),
         };
         let end = AddressToken {
             address,
-            token: [u8::MAX; 4].into(),
+            token: LowerCaseTick(vec![u8::MAX; TokenTick::MAX_LEN]),
         };
 
         start..=end
@@ -48,7 +48,7 @@ impl db::Pebble for AddressToken {
     fn get_bytes(v: &Self::Inner) -> Cow<[u8]> {
         let mut result = Vec::with_capacity(32 + 4);
         result.extend(v.address);
-        result.extend(v.token.0.clone());
+        result.extend(v.token.iter());
         Cow::Owned(result)
     }
 }
@@ -60,24 +60,61 @@ pub struct AddressTokenId {
     pub id: u64,
 }
 
+impl AddressTokenId {
+    /// Ticks of legacy length are written as is so keys written before long ticks stay valid.
+    /// Other ticks are prefixed with `0xFF` (never a part of UTF-8) and their length,
+    /// so keys of different ticks never share a prefix and entries can be concatenated.
+    const LONG_TICK_MARKER: u8 = u8::MAX;
+
+    fn write_bytes(&self, result: &mut Vec<u8>) {
+        result.extend(self.address);
+        if self.token.len() != TokenTick::LEGACY_LEN {
+            result.push(Self::LONG_TICK_MARKER);
+            result.push(self.token.len() as u8);
+        }
+        result.extend(self.token.as_bytes());
+        result.extend(self.id.to_be_bytes());
+    }
+
+    /// Returns the decoded key and the count of bytes it took
+    fn read_bytes(v: &[u8]) -> anyhow::Result<(Self, usize)> {
+        let address: FullHash = v.get(..32).anyhow()?.try_into().anyhow()?;
+
+        let (tick_start, tick_len) = match v.get(32) {
+            Some(&Self::LONG_TICK_MARKER) => (32 + 2, *v.get(33).anyhow()? as usize),
+            _ => (32, TokenTick::LEGACY_LEN),
+        };
+        let tick_end = tick_start + tick_len;
+
+        let token = TokenTick::try_from(v.get(tick_start..tick_end).anyhow()?)?;
+        let id = u64::from_be_bytes(
+            v.get(tick_end..tick_end + 8)
+                .anyhow()?
+                .try_into()
+                .anyhow()?,
+        );
+
+        Ok((Self { address, id, token }, tick_end + 8))
+    }
+}
+
 impl db::Pebble for AddressTokenId {
     type Inner = Self;
 
     fn get_bytes(v: &Self::Inner) -> Cow<[u8]> {
-        let mut result = Vec::with_capacity(32 + 4 + 8);
-        result.extend(v.address);
-        result.extend(v.token.0);
-        result.extend(v.id.to_be_bytes());
+        let mut result = Vec::with_capacity(32 + 2 + v.token.len() + 8);
+        v.write_bytes(&mut result);
 
         Cow::Owned(result)
     }
 
     fn from_bytes(v: Cow<[u8]>) -> anyhow::Result<Self::Inner> {
-        let address: FullHash = v[..32].try_into().anyhow()?;
-        let token = TokenTick(v[32..v.len() - 8].try_into().anyhow()?);
-        let id = u64::from_be_bytes(v[v.len() - 8..].try_into().anyhow()?);
+        let (key, len) = AddressTokenId::read_bytes(&v)?;
+        if len != v.len() {
+            anyhow::bail!("Invalid key length");
+        }
 
-        Ok(Self { address, id, token })
+        Ok(key)
     }
 }
 
@@ -87,15 +124,21 @@ impl db::Pebble for Vec<AddressTokenId> {
     fn get_bytes(v: &Self::Inner) -> Cow<[u8]> {
         let mut result = Vec::new();
         for item in v {
-            result.extend(AddressTokenId::get_bytes(item).into_owned());
+            item.write_bytes(&mut result);
         }
         Cow::Owned(result)
     }
 
     fn from_bytes(v: Cow<[u8]>) -> anyhow::Result<Self::Inner> {
-        v.chunks(32 + 4 + 8)
-            .map(|x| AddressTokenId::from_bytes(Cow::Borrowed(x)))
-            .collect()
+        let mut result = Vec::new();
+        let mut rest = &v[..];
+        while !rest.is_empty() {
+            let (key, len) = AddressTokenId::read_bytes(rest)?;
+            result.push(key);
+            rest = &rest[len..];
+        }
+
+        Ok(result)
     }
 }
 
@@ -323,6 +366,7 @@ impl db::Pebble for AddressLocation {
 }
 
 #[derive(Clone, PartialEq, Eq, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
 pub enum Brc4ActionErr {
     NotDeployed,
     AlreadyDeployed,
@@ -334,6 +378,7 @@ pub enum Brc4ActionErr {
 }
 
 #[derive(Clone, PartialEq, Eq, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
 pub enum Brc4ParseErr {
     WrongContentType,
     WrongProtocol,
@@ -345,24 +390,248 @@ pub enum Brc4ParseErr {
     InvalidDigit,
     InvalidUtf8,
     Unknown,
+    InvalidTick,
 }
 
 #[derive(Clone, PartialEq, Eq, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
 pub enum Brc4Error {
     Action(Brc4ActionErr),
     Parse(Brc4ParseErr),
 }
 
-#[derive(Clone, Copy, PartialEq, PartialOrd, Ord, Eq, Hash)]
-pub struct TokenTick(pub [u8; 4]);
+impl From<Brc4ActionErr> for Brc4Error {
+    fn from(value: Brc4ActionErr) -> Self {
+        Self::Action(value)
+    }
+}
+
+impl From<Brc4ParseErr> for Brc4Error {
+    fn from(value: Brc4ParseErr) -> Self {
+        Self::Parse(value)
+    }
+}
+
+/// Bel-20 inscription that was parsed or validated unsuccessfully.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct RejectedInscription {
+    pub genesis: InscriptionId,
+    pub owner: FullHash,
+    pub height: u32,
+    pub txid: Txid,
+    pub vout: u32,
+    /// `None` if the payload failed to parse.
+    pub op: Option<Brc4Value>,
+    pub reason: Brc4Error,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, Hash, Eq, PartialEq, PartialOrd, Ord)]
+pub struct AddressRejection {
+    pub address: FullHash,
+    pub height: u32,
+    pub genesis: OutPoint,
+}
+
+impl AddressRejection {
+    pub fn search(address: FullHash) -> RangeInclusive<Self> {
+        let start = Self {
+            address,
+            height: 0,
+            genesis: OutPoint {
+                txid: Txid::all_zeros(),
+                vout: 0,
+            },
+        };
+        let end = Self {
+            address,
+            height: u32::MAX,
+            genesis: OutPoint {
+                txid: Txid::from_byte_array([u8::MAX; 32]),
+                vout: u32::MAX,
+            },
+        };
+
+        start..=end
+    }
+}
+
+impl From<&RejectedInscription> for AddressRejection {
+    fn from(value: &RejectedInscription) -> Self {
+        Self {
+            address: value.owner,
+            height: value.height,
+            genesis: value.genesis.into(),
+        }
+    }
+}
+
+impl db::Pebble for AddressRejection {
+    type Inner = Self;
+
+    fn get_bytes(v: &Self::Inner) -> Cow<[u8]> {
+        let mut result = Vec::with_capacity(32 + 4 + 36);
+
+        result.extend(v.address);
+        result.extend(v.height.to_be_bytes());
+        result.extend(consensus::serialize(&v.genesis));
+
+        Cow::Owned(result)
+    }
+
+    fn from_bytes(v: Cow<[u8]>) -> anyhow::Result<Self::Inner> {
+        let address = v[..32].try_into().anyhow()?;
+        let height = u32::from_be_bytes(v[32..32 + 4].try_into().anyhow()?);
+        let genesis: OutPoint = consensus::deserialize(&v[32 + 4..])?;
+
+        Ok(Self {
+            address,
+            height,
+            genesis,
+        })
+    }
+}
+
+/// Rejection indexed by the outpoint it was rejected at, so the transaction spending
+/// a transfer lists it even if the inscription was revealed in another one
+#[derive(Serialize, Deserialize, Clone, Debug, Hash, Eq, PartialEq, PartialOrd, Ord)]
+pub struct OutPointRejection {
+    pub outpoint: OutPoint,
+    pub genesis: OutPoint,
+}
+
+impl OutPointRejection {
+    pub fn search(txid: Txid) -> RangeInclusive<Self> {
+        let start = Self {
+            outpoint: OutPoint { txid, vout: 0 },
+            genesis: OutPoint {
+                txid: Txid::all_zeros(),
+                vout: 0,
+            },
+        };
+        let end = Self {
+            outpoint: OutPoint {
+                txid,
+                vout: u32::MAX,
+            },
+            genesis: OutPoint {
+                txid: Txid::from_byte_array([u8::MAX; 32]),
+                vout: u32::MAX,
+            },
+        };
+
+        start..=end
+    }
+}
+
+impl From<&RejectedInscription> for OutPointRejection {
+    fn from(value: &RejectedInscription) -> Self {
+        Self {
+            outpoint: OutPoint {
+                txid: value.txid,
+                vout: value.vout,
+            },
+            genesis: value.genesis.into(),
+        }
+    }
+}
+
+impl db::Pebble for OutPointRejection {
+    type Inner = Self;
+
+    fn get_bytes(v: &Self::Inner) -> Cow<[u8]> {
+        let mut result = Vec::with_capacity(36 + 36);
+
+        result.extend(v.outpoint.txid.to_byte_array());
+        result.extend(v.outpoint.vout.to_be_bytes());
+        result.extend(consensus::serialize(&v.genesis));
+
+        Cow::Owned(result)
+    }
+
+    fn from_bytes(v: Cow<[u8]>) -> anyhow::Result<Self::Inner> {
+        let txid = Txid::from_byte_array(v[..32].try_into().anyhow()?);
+        let vout = u32::from_be_bytes(v[32..32 + 4].try_into().anyhow()?);
+        let genesis: OutPoint = consensus::deserialize(&v[32 + 4..])?;
+
+        Ok(Self {
+            outpoint: OutPoint { txid, vout },
+            genesis,
+        })
+    }
+}
+
+/// Tick as it was inscribed. Kept inline to stay `Copy`, use [`TokenTick::as_bytes`] to access it.
+#[derive(Clone, Copy)]
+pub struct TokenTick {
+    len: u8,
+    bytes: [u8; TokenTick::MAX_LEN],
+}
+
+impl TokenTick {
+    /// The only length allowed before long ticks activation
+    pub const LEGACY_LEN: usize = 4;
+    /// Upper bound of tick length in bytes
+    pub const MAX_LEN: usize = 32;
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.bytes[..self.len as usize]
+    }
+
+    pub fn len(&self) -> usize {
+        self.len as usize
+    }
+
+    /// Long ticks must be printable UTF-8 without whitespaces
+    pub fn is_valid_long(&self) -> bool {
+        std::str::from_utf8(self.as_bytes())
+            .is_ok_and(|x| !x.chars().any(|c| c.is_control() || c.is_whitespace()))
+    }
+}
+
+impl PartialEq for TokenTick {
+    fn eq(&self, other: &Self) -> bool {
+        self.as_bytes() == other.as_bytes()
+    }
+}
+impl Eq for TokenTick {}
+impl PartialOrd for TokenTick {
+    fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
+        Some(self.cmp(other))
+    }
+}
+impl Ord for TokenTick {
+    fn cmp(&self, other: &Self) -> std::cmp::Ordering {
+        self.as_bytes().cmp(other.as_bytes())
+    }
+}
+impl std::hash::Hash for TokenTick {
+    fn hash<H: std::hash::Hasher>(&self, state: &mut H) {
+        self.as_bytes().hash(state)
+    }
+}
+
+impl TryFrom<&[u8]> for TokenTick {
+    type Error = anyhow::Error;
+
+    fn try_from(v: &[u8]) -> Result<Self, Self::Error> {
+        if v.is_empty() || v.len() > Self::MAX_LEN {
+            anyhow::bail!("Invalid byte length");
+        }
+
+        let mut bytes = [0; Self::MAX_LEN];
+        bytes[..v.len()].copy_from_slice(v);
+
+        Ok(Self {
+            len: v.len() as u8,
+            bytes,
+        })
+    }
+}
 impl TryFrom<Vec<u8>> for TokenTick {
     type Error = anyhow::Error;
 
     fn try_from(v: Vec<u8>) -> Result<Self, Self::Error> {
-        Ok(Self(
-            v.try_into()
-                .map_err(|_| anyhow::Error::msg("Invalid byte length"))?,
-        ))
+        Self::try_from(v.as_slice())
     }
 }
 
@@ -386,7 +655,7 @@ impl Serialize for TokenTick {
 
 impl From<[u8; 4]> for TokenTick {
     fn from(v: [u8; 4]) -> Self {
-        Self(v)
+        Self::try_from(v.as_slice()).unwrap()
     }
 }
 impl std::fmt::Debug for TokenTick {
@@ -396,24 +665,24 @@ impl std::fmt::Debug for TokenTick {
 }
 impl Display for TokenTick {
     fn fmt(&self, f: &mut Formatter) -> std::fmt::Result {
-        write!(f, "{}", String::from_utf8_lossy(&self.0))
+        write!(f, "{}", String::from_utf8_lossy(self.as_bytes()))
     }
 }
 impl FromStr for TokenTick {
     type Err = anyhow::Error;
 
     fn from_str(s: &str) -> Result<Self, Self::Err> {
-        Ok(Self(s.as_bytes().try_into().anyhow_with("Invalid tick")?))
+        Self::try_from(s.as_bytes()).anyhow_with("Invalid tick")
     }
 }
 impl From<TokenTick> for LowerCaseTick {
     fn from(value: TokenTick) -> Self {
-        LowerCaseTick::from(value.0)
+        LowerCaseTick::from(value.as_bytes())
     }
 }
 impl From<&TokenTick> for LowerCaseTick {
     fn from(value: &TokenTick) -> Self {
-        LowerCaseTick::from(&value.0)
+        LowerCaseTick::from(value.as_bytes())
     }
 }
 
@@ -475,6 +744,7 @@ pub enum TokenAction {
     },
     /// Mint new token action.
     Mint {
+        genesis: InscriptionId,
         owner: FullHash,
         proto: MintProto,
         txid: Txid,
@@ -482,6 +752,7 @@ pub enum TokenAction {
     },
     /// Transfer token action.
     Transfer {
+        genesis: InscriptionId,
         location: Location,
         owner: FullHash,
         proto: TransferProto,
@@ -643,7 +914,10 @@ impl TokenActionRest {
                 vout,
             } => TokenActionRest::Send {
                 amt,
-                recipient: addresses.get(&recipient).unwrap().clone(),
+                recipient: addresses
+                    .get(&recipient)
+                    .cloned()
+                    .unwrap_or_else(|| NON_STANDARD_ADDRESS.to_string()),
                 txid,
                 vout,
             },
@@ -654,7 +928,10 @@ impl TokenActionRest {
                 vout,
             } => TokenActionRest::Receive {
                 amt,
-                sender: addresses.get(&sender).unwrap().clone(),
+                sender: addresses
+                    .get(&sender)
+                    .cloned()
+                    .unwrap_or_else(|| NON_STANDARD_ADDRESS.to_string()),
                 txid,
                 vout,
             },
@@ -692,7 +969,7 @@ pub struct HistoryRest {
 }
 
 impl HistoryRest {
-    pub async fn new(
+    pub fn new(
         height: u32,
         action: TokenHistoryDB,
         address_token: AddressTokenId,
@@ -702,17 +979,65 @@ impl HistoryRest {
             .into_iter()
             .flatten();
 
-        let addresses = server.load_addresses(keys, height).await?;
+        let addresses = server.load_addresses(keys)?;
 
-        Ok(Self {
+        Ok(Self::with_addresses(
+            height,
+            action,
+            address_token,
+            &addresses,
+        ))
+    }
+
+    pub fn with_addresses(
+        height: u32,
+        action: TokenHistoryDB,
+        address_token: AddressTokenId,
+        addresses: &HashMap<FullHash, String>,
+    ) -> Self {
+        Self {
             height,
-            action: TokenActionRest::from_with_addresses(action, &addresses),
+            action: TokenActionRest::from_with_addresses(action, addresses),
             address_token: AddressTokenIdRest {
-                address: addresses.get(&address_token.address).unwrap().clone(),
+                address: addresses
+                    .get(&address_token.address)
+                    .cloned()
+                    .unwrap_or_else(|| NON_STANDARD_ADDRESS.to_string()),
                 id: address_token.id,
                 tick: address_token.token,
             },
-        })
+        }
+    }
+}
+
+#[derive(Serialize)]
+pub struct RejectedRest {
+    #[serde(rename = "type")]
+    pub event_type: String,
+    pub genesis: InscriptionId,
+    pub address: String,
+    pub height: u32,
+    pub txid: Txid,
+    pub vout: u32,
+    pub op: Option<Brc4Value>,
+    pub reason: Brc4Error,
+}
+
+impl RejectedRest {
+    pub fn new(value: RejectedInscription, addresses: &HashMap<FullHash, String>) -> Self {
+        Self {
+            event_type: "Rejected".to_string(),
+            genesis: value.genesis,
+            address: addresses
+                .get(&value.owner)
+                .cloned()
+                .unwrap_or_else(|| NON_STANDARD_ADDRESS.to_string()),
+            height: value.height,
+            txid: value.txid,
+            vout: value.vout,
+            op: value.op,
+            reason: value.reason,
+        }
     }
 }
 
@@ -757,6 +1082,7 @@ pub struct InscriptionTemplate {
     pub genesis: InscriptionId,
     pub location: Location,
     pub content_type: Option<String>,
+    pub content_encoding: Option<Vec<u8>>,
     pub owner: FullHash,
     pub value: u64,
     pub content: Option<Vec<u8>>,
@@ -835,6 +1161,8 @@ impl FromStr for InscriptionId {
     }
 }
 
+/// Ticks are case insensitive and compared by the Unicode lowercase mapping of their UTF-8 text.
+/// ASCII ticks keep their bytes and length, multi-byte characters may change length when lowercased.
 #[derive(Clone, Eq, PartialEq, Hash, PartialOrd, Ord, Debug, Serialize, Deserialize)]
 #[serde(transparent)]
 pub struct LowerCaseTick(pub Vec<u8>);
@@ -875,3 +1203,91 @@ impl db::Pebble for LowerCaseTick {
         Ok(Self(v.into_owned()))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use db::Pebble;
+
+    fn key(tick: &str, id: u64) -> AddressTokenId {
+        AddressTokenId {
+            address: FullHash::from([7; 32]),
+            token: TokenTick::try_from(tick.as_bytes()).unwrap(),
+            id,
+        }
+    }
+
+    #[test]
+    fn legacy_tick_keys_are_unchanged() {
+        let key = key("abcd", 5);
+        let bytes = AddressTokenId::get_bytes(&key).into_owned();
+
+        let mut expected = vec![7; 32];
+        expected.extend(b"abcd");
+        expected.extend(5u64.to_be_bytes());
+        assert_eq!(bytes, expected);
+        assert_eq!(AddressTokenId::from_bytes(bytes.into()).unwrap(), key);
+    }
+
+    #[test]
+    fn long_tick_keys_round_trip() {
+        for tick in ["abcde", "a", &"x".repeat(TokenTick::MAX_LEN)] {
+            let key = key(tick, u64::MAX);
+            let bytes = AddressTokenId::get_bytes(&key).into_owned();
+
+            let (decoded, len) = AddressTokenId::read_bytes(&bytes).unwrap();
+            assert_eq!(decoded, key);
+            assert_eq!(len, bytes.len());
+        }
+
+        // A long tick starting with a legacy one doesn't share its prefix
+        let legacy = AddressTokenId::get_bytes(&key("abcd", 0)).into_owned();
+        let long = AddressTokenId::get_bytes(&key("abcde", 0)).into_owned();
+        assert!(!long.starts_with(&legacy[..32 + 4]));
+
+        let mut bytes = AddressTokenId::get_bytes(&key("abcde", 1)).into_owned();
+        bytes.push(0);
+        assert!(AddressTokenId::from_bytes(bytes.into()).is_err());
+    }
+
+    #[test]
+    fn keys_of_mixed_ticks_are_concatenated() {
+        let keys = vec![
+            key("abcd", 1),
+            key("abcde", 2),
+            key(&"x".repeat(TokenTick::MAX_LEN), 3),
+            key("efgh", 4),
+        ];
+        let bytes = Vec::<AddressTokenId>::get_bytes(&keys).into_owned();
+        assert_eq!(
+            Vec::<AddressTokenId>::from_bytes(bytes.into()).unwrap(),
+            keys
+        );
+
+        let bytes = Vec::<AddressTokenId>::get_bytes(&keys[..2].to_vec()).into_owned();
+        assert!(
+            Vec::<AddressTokenId>::from_bytes(bytes[..bytes.len() - 1].to_vec().into()).is_err()
+        );
+    }
+
+    #[test]
+    fn outpoint_rejections_are_found_by_txid() {
+        let txid = Txid::from_byte_array([3; 32]);
+        let rejection = OutPointRejection {
+            outpoint: OutPoint { txid, vout: 2 },
+            genesis: OutPoint {
+                txid: Txid::from_byte_array([4; 32]),
+                vout: 0,
+            },
+        };
+        let bytes = OutPointRejection::get_bytes(&rejection).into_owned();
+        assert_eq!(
+            OutPointRejection::from_bytes(bytes.clone().into()).unwrap(),
+            rejection
+        );
+
+        let (from, to) = OutPointRejection::search(txid).into_inner();
+        assert!(OutPointRejection::get_bytes(&from).as_ref() <= bytes.as_slice());
+        assert!(bytes.as_slice() <= OutPointRejection::get_bytes(&to).as_ref());
+    }
+}
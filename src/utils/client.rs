@@ -1,58 +1,346 @@
-use std::time::Duration;
+use std::{
+    future::Future,
+    ops::RangeInclusive,
+    path::PathBuf,
+    sync::Arc,
+    time::{Duration, Instant},
+};
 
 use bellscoin::{
     consensus::{Decodable, ReadExt},
     hashes::hex::HexIterator,
+    BlockHash,
 };
 use dutils::{error::ContextWrapper, wait_token::WaitToken};
-use jsonrpc_async::Client;
+use jsonrpc_async::{simple_http, Client};
 use serde::de::DeserializeOwned;
 use serde_json::{value::RawValue, Value};
 
+use crate::config::RpcConfig;
+
+/// Endpoints are checked at most this long before switching
+const HEALTH_CHECK_TIMEOUT: Duration = Duration::from_secs(5);
+/// A fallback endpoint is used at least this long before endpoints preferred by the config are checked again
+const PREFERRED_RECHECK_INTERVAL: Duration = Duration::from_secs(60);
+
+#[derive(Clone, Debug)]
+pub enum RpcAuth {
+    None,
+    UserPass(String, Option<String>),
+    /// `user:pass` file written by the node, read on every connect as the node rewrites it on restart
+    Cookie(PathBuf),
+}
+
+#[derive(Clone, Debug)]
+pub struct RpcEndpoint {
+    pub url: String,
+    pub auth: RpcAuth,
+}
+
+struct ActiveEndpoint {
+    index: usize,
+    client: Arc<Client>,
+    since: Instant,
+}
+
+/// Node RPC client failing over between endpoints in the order of preference
 pub struct AsyncClient {
-    client: Client,
+    endpoints: Vec<RpcEndpoint>,
+    active: parking_lot::Mutex<Option<ActiveEndpoint>>,
+    /// Serializes endpoint switches
+    switching: tokio::sync::Mutex<()>,
+    /// Endpoint used last. Other endpoints must have the indexed tip to be switched to
+    trusted: parking_lot::Mutex<usize>,
+    /// Height and hash of the last indexed block
+    indexed_tip: parking_lot::Mutex<Option<(u32, BlockHash)>>,
+    timeout: Duration,
+    batch_size: usize,
+    backoff_initial: Duration,
+    backoff_max: Duration,
     token: WaitToken,
 }
 
+/// Error replied by the node to the request, as opposed to a transport or HTTP failure
+fn is_rpc_error(e: &anyhow::Error) -> bool {
+    matches!(
+        e.downcast_ref::<jsonrpc_async::Error>(),
+        Some(jsonrpc_async::Error::Rpc(_))
+    )
+}
+
+fn to_params(params: &[Value]) -> anyhow::Result<Vec<Box<RawValue>>> {
+    params
+        .iter()
+        .map(|x| RawValue::from_string(x.to_string()).anyhow_with("Failed to serialize params"))
+        .collect()
+}
+
 impl AsyncClient {
-    pub async fn new(
-        url: &str,
-        user: Option<String>,
-        pass: Option<String>,
-        token: WaitToken,
-    ) -> anyhow::Result<Self> {
-        let client = Client::simple_http(url, user, pass)
+    pub fn new(config: &RpcConfig, token: WaitToken) -> Self {
+        Self {
+            endpoints: config.endpoints(),
+            active: parking_lot::Mutex::new(None),
+            switching: tokio::sync::Mutex::new(()),
+            trusted: parking_lot::Mutex::new(0),
+            indexed_tip: parking_lot::Mutex::new(None),
+            timeout: Duration::from_millis(config.timeout_ms),
+            batch_size: config.batch_size,
+            backoff_initial: Duration::from_millis(config.backoff_initial_ms),
+            backoff_max: Duration::from_millis(config.backoff_max_ms),
+            token,
+        }
+    }
+
+    /// Fallback endpoints are switched to only if they have this block
+    pub fn set_indexed_tip(&self, height: u32, hash: BlockHash) {
+        *self.indexed_tip.lock() = Some((height, hash));
+    }
+
+    async fn connect(&self, endpoint: &RpcEndpoint) -> anyhow::Result<Client> {
+        let builder = simple_http::Builder::new()
+            .timeout(self.timeout)
+            .url(&endpoint.url)
             .await
             .anyhow_with("Invalid URL for RPC client")?;
 
-        Ok(Self { client, token })
+        let builder = match &endpoint.auth {
+            RpcAuth::None => builder,
+            RpcAuth::UserPass(user, pass) => builder.auth(user.as_str(), pass.as_deref()),
+            RpcAuth::Cookie(path) => {
+                let cookie = tokio::fs::read_to_string(path)
+                    .await
+                    .anyhow_with(format!("Failed to read RPC cookie {}", path.display()))?;
+                builder.cookie_auth(cookie.trim())
+            }
+        };
+
+        Ok(Client::with_transport(builder.build()))
     }
 
-    async fn request<T: DeserializeOwned>(
-        &self,
-        method: &str,
-        params: &[Value],
-    ) -> anyhow::Result<T> {
-        let params = params
-            .iter()
-            .map(|x| RawValue::from_string(x.to_string()).anyhow_with("Failed to serialize params"))
-            .collect::<anyhow::Result<Vec<_>>>()?;
+    /// Connects and checks that the node replies and, if `check_chain` is set, has the indexed tip
+    async fn probe(&self, endpoint: &RpcEndpoint, check_chain: bool) -> anyhow::Result<Client> {
+        let client = self.connect(endpoint).await?;
+        let tip = *self.indexed_tip.lock();
+
+        let check = async {
+            let height: u32 = client.call("getblockcount", &[]).await?;
+
+            if let Some((tip_height, tip_hash)) = tip.filter(|_| check_chain) {
+                anyhow::ensure!(
+                    height >= tip_height,
+                    "Node is at height {height}, behind the indexed block {tip_height}"
+                );
+
+                let hash: BlockHash = client
+                    .call("getblockhash", &to_params(&[tip_height.into()])?)
+                    .await?;
+                anyhow::ensure!(
+                    hash == tip_hash,
+                    "Node is on another chain, block {tip_height} is {hash} instead of {tip_hash}"
+                );
+            }
+
+            anyhow::Ok(())
+        };
+
+        tokio::time::timeout(HEALTH_CHECK_TIMEOUT, check)
+            .await
+            .anyhow_with("Node is not replying")??;
+
+        Ok(client)
+    }
+
+    /// Switches to the first healthy endpoint. `failed` is the endpoint the caller used,
+    /// if another request switched from it already, the new endpoint is returned
+    async fn reconnect(&self, failed: Option<usize>) -> anyhow::Result<(usize, Arc<Client>)> {
+        let _switching = self.switching.lock().await;
+
+        if let Some(active) = self.active.lock().as_ref() {
+            if Some(active.index) != failed {
+                return Ok((active.index, active.client.clone()));
+            }
+        }
+
+        let trusted = *self.trusted.lock();
+
+        for (index, endpoint) in self.endpoints.iter().enumerate() {
+            match self.probe(endpoint, index != trusted).await {
+                Ok(client) => {
+                    if index != trusted {
+                        warn!("Switched RPC to {}", endpoint.url);
+                        crate::metrics::RPC_ENDPOINT_SWITCHES.inc();
+                    }
+
+                    let client = Arc::new(client);
+                    *self.trusted.lock() = index;
+                    *self.active.lock() = Some(ActiveEndpoint {
+                        index,
+                        client: client.clone(),
+                        since: Instant::now(),
+                    });
+
+                    return Ok((index, client));
+                }
+                Err(e) => warn!("RPC endpoint {} is unavailable: {:#}", endpoint.url, e),
+            }
+        }
+
+        *self.active.lock() = None;
+        anyhow::bail!("No RPC endpoint is available")
+    }
+
+    /// Client of the active endpoint. Preferred endpoints are checked again once in a while
+    async fn client(&self) -> anyhow::Result<(usize, Arc<Client>)> {
+        let active = self
+            .active
+            .lock()
+            .as_ref()
+            .map(|x| (x.index, x.client.clone(), x.since.elapsed()));
+
+        match active {
+            Some((index, client, elapsed))
+                if index == 0 || elapsed < PREFERRED_RECHECK_INTERVAL =>
+            {
+                Ok((index, client))
+            }
+            Some((index, ..)) => self.reconnect(Some(index)).await,
+            None => self.reconnect(None).await,
+        }
+    }
+
+    fn backoff(&self, attempt: u32) -> Duration {
+        self.backoff_initial
+            .saturating_mul(2u32.saturating_pow(attempt))
+            .min(self.backoff_max)
+    }
+
+    /// Runs `call` on the active endpoint until it succeeds, failing over and backing off in between.
+    /// Errors replied by the node are returned at once, other endpoints would reply the same
+    async fn retrying<T, F, Fut>(&self, method: &str, call: F) -> anyhow::Result<T>
+    where
+        F: Fn(Arc<Client>) -> Fut,
+        Fut: Future<Output = anyhow::Result<T>>,
+    {
+        let mut attempt = 0;
+
         loop {
             if self.token.is_cancelled() {
                 anyhow::bail!("Cancelled");
             }
 
-            match self.client.call::<T>(method, &params.clone()).await {
-                Ok(res) => return Ok(res),
-                Err(e) => {
-                    tokio::time::sleep(Duration::from_secs(3)).await;
-                    error!("Node is not replying, retrying: {}", e);
-                    continue;
-                }
+            let (failed, e) = match self.client().await {
+                Ok((index, client)) => match call(client).await {
+                    Ok(res) => return Ok(res),
+                    Err(e) if is_rpc_error(&e) => return Err(e),
+                    Err(e) => (Some(index), e),
+                },
+                Err(e) => (None, e),
             };
+
+            crate::metrics::RPC_RETRIES
+                .with_label_values(&[method])
+                .inc();
+            error!("Node is not replying to {}: {:#}", method, e);
+
+            if failed.is_some() {
+                // Retried at once on another endpoint
+                if let Ok((index, _)) = self.reconnect(failed).await {
+                    if Some(index) != failed {
+                        continue;
+                    }
+                }
+            }
+
+            let delay = self.backoff(attempt);
+            attempt += 1;
+            tokio::select! {
+                _ = tokio::time::sleep(delay) => {}
+                _ = self.token.cancelled() => anyhow::bail!("Cancelled"),
+            }
         }
     }
 
+    async fn request<T: DeserializeOwned>(
+        &self,
+        method: &str,
+        params: &[Value],
+    ) -> anyhow::Result<T> {
+        let params = &to_params(params)?;
+
+        self.retrying(method, |client| async move {
+            Ok(client.call::<T>(method, params).await?)
+        })
+        .await
+    }
+
+    /// Calls `method` with each of `params` in batches of `rpc.batch_size` requests,
+    /// results are in the order of `params`
+    async fn request_batch<T: DeserializeOwned>(
+        &self,
+        method: &str,
+        params: &[Vec<Value>],
+    ) -> anyhow::Result<Vec<T>> {
+        let mut results = Vec::with_capacity(params.len());
+
+        for chunk in params.chunks(self.batch_size) {
+            let chunk = chunk
+                .iter()
+                .map(|x| to_params(x))
+                .collect::<anyhow::Result<Vec<_>>>()?;
+
+            let batch = self
+                .retrying(method, |client| {
+                    let chunk = &chunk;
+                    async move {
+                        let requests = chunk
+                            .iter()
+                            .map(|x| client.build_request(method, x))
+                            .collect::<Vec<_>>();
+
+                        client
+                            .send_batch(&requests)
+                            .await?
+                            .into_iter()
+                            .map(|x| {
+                                Ok(x.anyhow_with("No response to a batched request")?
+                                    .result::<T>()?)
+                            })
+                            .collect::<anyhow::Result<Vec<_>>>()
+                    }
+                })
+                .await?;
+
+            results.extend(batch);
+        }
+
+        Ok(results)
+    }
+
+    /// Single attempt without retries, used to check that the node is reachable
+    pub async fn get_block_count(&self) -> anyhow::Result<u64> {
+        let (_, client) = self.client().await?;
+        client
+            .call::<u64>("getblockcount", &[])
+            .await
+            .anyhow_with("Node is not reachable")
+    }
+
+    /// Single attempt, checks that the node finds the confirmed transaction. Transactions out of
+    /// the mempool are found only with `txindex`
+    pub async fn check_txindex(&self, txid: bellscoin::Txid) -> anyhow::Result<()> {
+        let (_, client) = self.client().await?;
+        client
+            .call::<String>(
+                "getrawtransaction",
+                &to_params(&[serde_json::to_value(txid)?])?,
+            )
+            .await
+            .anyhow_with(format!(
+                "Node doesn't find transaction {txid}, it has to run with txindex=1"
+            ))?;
+        Ok(())
+    }
+
     pub async fn get_block_hash(&self, height: u32) -> anyhow::Result<bellscoin::BlockHash> {
         self.request("getblockhash", &[height.into()]).await
     }
@@ -61,12 +349,42 @@ impl AsyncClient {
         self.request("getbestblockhash", &[]).await
     }
 
-    pub async fn get_block_info(
+    /// Hashes of the blocks at `heights` of the active chain, fetched in batches
+    pub async fn get_block_hashes(
         &self,
-        hash: &bellscoin::BlockHash,
-    ) -> anyhow::Result<bellscoincore_rpc::json::GetBlockResult> {
-        self.request("getblock", &[serde_json::to_value(hash)?, 1.into()])
-            .await
+        heights: RangeInclusive<u32>,
+    ) -> anyhow::Result<Vec<bellscoin::BlockHash>> {
+        let params = heights.map(|x| vec![x.into()]).collect::<Vec<_>>();
+        self.request_batch("getblockhash", &params).await
+    }
+
+    /// Headers of the blocks, fetched in batches
+    pub async fn get_block_headers(
+        &self,
+        hashes: &[bellscoin::BlockHash],
+    ) -> anyhow::Result<Vec<bellscoincore_rpc::json::GetBlockHeaderResult>> {
+        let params = hashes
+            .iter()
+            .map(|x| Ok(vec![serde_json::to_value(x)?, true.into()]))
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        self.request_batch("getblockheader", &params).await
+    }
+
+    /// Transactions fetched in batches. The node needs `txindex` for transactions out of the mempool
+    pub async fn get_transactions(
+        &self,
+        txids: &[bellscoin::Txid],
+    ) -> anyhow::Result<Vec<bellscoin::Transaction>> {
+        let params = txids
+            .iter()
+            .map(|x| Ok(vec![serde_json::to_value(x)?]))
+            .collect::<anyhow::Result<Vec<_>>>()?;
+
+        self.request_batch::<String>("getrawtransaction", &params)
+            .await?
+            .iter()
+            .map(|x| deserialize_hex(x))
+            .collect()
     }
 
     pub async fn get_block(&self, hash: &bellscoin::BlockHash) -> anyhow::Result<bellscoin::Block> {
@@ -86,3 +404,129 @@ fn deserialize_hex<T: Decodable>(hex: &str) -> anyhow::Result<T> {
         Ok(object)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+    use bellscoin::hashes::Hash;
+    use serde_json::json;
+
+    use super::*;
+    use crate::{config::RpcEndpointConfig, test_utils};
+
+    enum Reply {
+        Result(Value),
+        Error(i32, &'static str),
+        Status(u16),
+    }
+
+    /// Node answering each request with `reply` of its method, returns its URL
+    async fn node(reply: impl Fn(&str) -> Reply + Send + Sync + 'static) -> String {
+        test_utils::http_server(move |request| {
+            let request: Value = serde_json::from_slice(&request.body).unwrap();
+            let requests = match &request {
+                Value::Array(x) => x.clone(),
+                x => vec![x.clone()],
+            };
+
+            let mut status = 200;
+            let mut responses = vec![];
+            for x in requests {
+                let mut response = json!({ "id": x["id"], "jsonrpc": x["jsonrpc"] });
+                match reply(x["method"].as_str().unwrap()) {
+                    Reply::Result(v) => response["result"] = v,
+                    Reply::Error(code, message) => {
+                        response["error"] = json!({ "code": code, "message": message })
+                    }
+                    Reply::Status(x) => status = x,
+                }
+                responses.push(response);
+            }
+
+            let body = match (status, request.is_array()) {
+                (200, true) => Value::Array(responses).to_string(),
+                (200, false) => responses[0].to_string(),
+                _ => String::new(),
+            };
+            (status, body)
+        })
+        .await
+    }
+
+    fn client(url: String, fallbacks: Vec<String>) -> AsyncClient {
+        let config = RpcConfig {
+            url,
+            fallbacks: fallbacks
+                .into_iter()
+                .map(|url| RpcEndpointConfig {
+                    url,
+                    ..Default::default()
+                })
+                .collect(),
+            timeout_ms: 1000,
+            backoff_initial_ms: 10,
+            backoff_max_ms: 10,
+            ..Default::default()
+        };
+        AsyncClient::new(&config, WaitToken::default())
+    }
+
+    #[tokio::test]
+    async fn rpc_errors_are_returned_at_once() {
+        let calls = Arc::new(AtomicUsize::new(0));
+        let url = node({
+            let calls = calls.clone();
+            move |method| match method {
+                "getblockcount" => Reply::Result(json!(1)),
+                _ => {
+                    calls.fetch_add(1, Ordering::SeqCst);
+                    Reply::Error(-5, "No such mempool or blockchain transaction")
+                }
+            }
+        })
+        .await;
+        let client = client(url, vec![]);
+
+        let result = tokio::time::timeout(
+            Duration::from_secs(5),
+            client.get_transactions(&[bellscoin::Txid::all_zeros()]),
+        )
+        .await
+        .expect("RPC error is retried");
+
+        assert!(result.is_err());
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn http_errors_fail_over() {
+        let hash = BlockHash::all_zeros();
+        let down = Arc::new(AtomicBool::new(false));
+        let primary = node({
+            let down = down.clone();
+            move |method| match method {
+                _ if down.load(Ordering::SeqCst) => Reply::Status(503),
+                "getblockcount" => Reply::Result(json!(1)),
+                _ => {
+                    // Goes down while replying
+                    down.store(true, Ordering::SeqCst);
+                    Reply::Status(503)
+                }
+            }
+        })
+        .await;
+        let fallback = node(move |method| match method {
+            "getblockcount" => Reply::Result(json!(1)),
+            _ => Reply::Result(json!(hash)),
+        })
+        .await;
+        let client = client(primary, vec![fallback]);
+
+        let result = tokio::time::timeout(Duration::from_secs(5), client.best_block_hash())
+            .await
+            .unwrap();
+
+        assert_eq!(result.unwrap(), hash);
+    }
+}
@@ -2,18 +2,29 @@ use tracing_subscriber::layer::SubscriberExt;
 use tracing_subscriber::util::SubscriberInitExt;
 use tracing_subscriber::*;
 
-pub fn init_logger() {
-    let logging_mode = "debug";
+use crate::config::{LogFormat, LoggingConfig};
+
+pub fn init_logger(config: &LoggingConfig) {
+    let logging_mode = &config.level;
 
     let indicatif_layer = tracing_indicatif::IndicatifLayer::new();
 
     let fmt_layer = fmt::layer()
-        .pretty()
         .with_writer(indicatif_layer.get_stderr_writer())
         .with_thread_names(true)
-        .with_ansi(true)
-        .without_time()
-        .with_filter(EnvFilter::new(logging_mode));
+        .with_ansi(config.ansi)
+        .without_time();
+
+    let fmt_layer = match config.format {
+        LogFormat::Pretty => fmt_layer
+            .pretty()
+            .with_filter(EnvFilter::new(logging_mode))
+            .boxed(),
+        LogFormat::Compact => fmt_layer
+            .compact()
+            .with_filter(EnvFilter::new(logging_mode))
+            .boxed(),
+    };
 
     let filter_layer = EnvFilter::try_from_default_env()
         .or_else(|_| {
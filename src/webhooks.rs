@@ -0,0 +1,445 @@
+use std::ops::RangeInclusive;
+
+use dutils::async_thread::Handler;
+use hmac::{Hmac, Mac};
+use rest::events::{event_to_json, EventFilter, EventType};
+use sha2::Sha256;
+
+use super::*;
+
+/// Count of events read from the event log at once by a webhook
+const WEBHOOK_BATCH: usize = 100;
+/// Delivery is moved to the dead letters after this many failed attempts,
+/// about a minute after the first one
+const MAX_ATTEMPTS: u32 = 7;
+const FIRST_RETRY_DELAY: Duration = Duration::from_secs(1);
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(30);
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Registered webhook. Receives events logged after `created_seq`.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Webhook {
+    pub id: u64,
+    pub url: String,
+    /// Key of the `X-Signature` HMAC. Requests are not signed if `None`
+    pub secret: Option<String>,
+    pub addresses: HashSet<String>,
+    pub tokens: HashSet<String>,
+    pub events: HashSet<EventType>,
+    pub created_seq: u64,
+}
+
+impl Webhook {
+    /// Reorg notices are sent to every webhook so receivers can roll back delivered events.
+    /// Addresses and ticks are not filtered if none were registered.
+    fn filter(&self) -> EventFilter {
+        EventFilter {
+            addresses: (!self.addresses.is_empty()).then(|| self.addresses.clone()),
+            tokens: (!self.tokens.is_empty())
+                .then(|| self.tokens.iter().map(LowerCaseTick::from).collect()),
+            event_types: Some(
+                self.events
+                    .iter()
+                    .copied()
+                    .chain([EventType::Reorg])
+                    .collect(),
+            ),
+        }
+    }
+}
+
+/// Event that couldn't be delivered to a webhook
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct DeadLetter {
+    pub seq: u64,
+    pub payload: String,
+    pub attempts: u32,
+    pub error: String,
+    /// Unix timestamp of the last attempt
+    pub failed_at: u64,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct WebhookEvent {
+    pub webhook: u64,
+    pub seq: u64,
+}
+
+impl WebhookEvent {
+    pub fn search(webhook: u64) -> RangeInclusive<WebhookEvent> {
+        WebhookEvent { webhook, seq: 0 }..=WebhookEvent {
+            webhook,
+            seq: u64::MAX,
+        }
+    }
+}
+
+impl db::Pebble for WebhookEvent {
+    type Inner = Self;
+
+    fn get_bytes(v: &Self::Inner) -> Cow<[u8]> {
+        let mut result = Vec::with_capacity(8 + 8);
+        result.extend(v.webhook.to_be_bytes());
+        result.extend(v.seq.to_be_bytes());
+
+        Cow::Owned(result)
+    }
+
+    fn from_bytes(v: Cow<[u8]>) -> anyhow::Result<Self::Inner> {
+        let webhook = u64::from_be_bytes(v[..8].try_into().anyhow()?);
+        let seq = u64::from_be_bytes(v[8..].try_into().anyhow()?);
+
+        Ok(Self { webhook, seq })
+    }
+}
+
+/// Hex encoded HMAC-SHA256 of the payload
+pub fn sign(secret: &str, payload: &str) -> String {
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes()).unwrap();
+    mac.update(payload.as_bytes());
+    hex::encode(mac.finalize().into_bytes())
+}
+
+/// Runs a delivery task per registered webhook and restarts them when the registry changes
+#[derive(Clone)]
+pub struct WebhookSender {
+    pub server: Arc<Server>,
+    pub token: WaitToken,
+}
+
+impl Handler for WebhookSender {
+    async fn run(&mut self) -> anyhow::Result<()> {
+        let client = reqwest::Client::builder()
+            .timeout(REQUEST_TIMEOUT)
+            .build()?;
+
+        let mut tasks = HashMap::<u64, tokio::task::JoinHandle<()>>::new();
+
+        loop {
+            let changed = self.server.webhooks_changed.notified();
+
+            let webhooks = self
+                .server
+                .db
+                .webhooks
+                .iter()
+                .map_ok(|(id, _)| id)
+                .try_collect::<_, HashSet<_>, _>()?;
+
+            tasks.retain(|id, task| {
+                let keep = webhooks.contains(id) && !task.is_finished();
+                if !keep {
+                    task.abort();
+                }
+                keep
+            });
+
+            for id in webhooks {
+                tasks.entry(id).or_insert_with(|| {
+                    let delivery = WebhookDelivery {
+                        server: self.server.clone(),
+                        client: client.clone(),
+                        id,
+                        failing: false,
+                    };
+                    tokio::spawn(delivery.run())
+                });
+            }
+
+            tokio::select! {
+                _ = changed => {}
+                _ = self.token.cancelled() => break,
+            }
+        }
+
+        tasks.values().for_each(|x| x.abort());
+
+        Ok(())
+    }
+}
+
+/// Delivers events of the event log to a webhook in order. The cursor is saved after every event,
+/// so events are delivered at least once. Events of reorged blocks are delivered again
+/// after the reorg notice when the new chain is indexed.
+struct WebhookDelivery {
+    server: Arc<Server>,
+    client: reqwest::Client,
+    id: u64,
+    /// Set when the last event was moved to the dead letters. Events are tried once
+    /// until one is delivered, so a dead endpoint doesn't hold every event for the whole backoff
+    failing: bool,
+}
+
+impl WebhookDelivery {
+    async fn run(mut self) {
+        let (_, mut new_events) = self.server.subscribe_events();
+
+        loop {
+            match self.deliver_batch(&mut new_events).await.track() {
+                Ok(true) => {}
+                Ok(false) => break,
+                // The batch is delivered again from the saved cursor
+                Err(_) => tokio::time::sleep(MAX_RETRY_DELAY).await,
+            }
+        }
+    }
+
+    /// Returns `false` if the webhook was removed or the server is shutting down
+    async fn deliver_batch(
+        &mut self,
+        new_events: &mut tokio::sync::broadcast::Receiver<SequencedEvent>,
+    ) -> anyhow::Result<bool> {
+        let Some(webhook) = self.server.db.webhooks.get(self.id)? else {
+            return Ok(false);
+        };
+
+        let cursor = self
+            .server
+            .db
+            .webhook_cursors
+            .get(self.id)?
+            .unwrap_or(webhook.created_seq);
+
+        let events = match self.server.read_event_log(cursor + 1, WEBHOOK_BATCH) {
+            Ok(events) => events,
+            Err(e) => {
+                let gap = e.downcast::<EventLogGap>()?;
+                error!("Webhook {} skips events: {}", self.id, gap);
+                self.server.db.webhook_cursors.set(self.id, gap.first - 1)?;
+                return Ok(true);
+            }
+        };
+
+        if events.is_empty() {
+            // Events are read from the event log, lagging is fine
+            let closed = matches!(
+                new_events.recv().await,
+                Err(tokio::sync::broadcast::error::RecvError::Closed)
+            );
+            return Ok(!closed);
+        }
+
+        let filter = webhook.filter();
+
+        for (seq, event) in events {
+            if filter.matches(&event) {
+                let payload = event_to_json(event)?;
+                self.deliver(&webhook, seq, payload).await?;
+            }
+
+            self.server.db.webhook_cursors.set(self.id, seq)?;
+        }
+
+        Ok(true)
+    }
+
+    async fn deliver(
+        &mut self,
+        webhook: &Webhook,
+        seq: u64,
+        payload: String,
+    ) -> anyhow::Result<()> {
+        let mut delay = FIRST_RETRY_DELAY;
+        let mut attempts = 0;
+
+        loop {
+            attempts += 1;
+
+            let error = match self.post(webhook, seq, &payload).await {
+                Ok(()) => {
+                    self.failing = false;
+                    return Ok(());
+                }
+                Err(e) => e.to_string(),
+            };
+
+            if attempts == MAX_ATTEMPTS || self.failing {
+                self.failing = true;
+                warn!(
+                    "Webhook {} failed to deliver event {}: {}",
+                    webhook.id, seq, error
+                );
+
+                self.server.db.webhook_dead_letters.set(
+                    WebhookEvent {
+                        webhook: webhook.id,
+                        seq,
+                    },
+                    DeadLetter {
+                        seq,
+                        payload,
+                        attempts,
+                        error,
+                        failed_at: std::time::SystemTime::now()
+                            .duration_since(std::time::UNIX_EPOCH)
+                            .unwrap_or_default()
+                            .as_secs(),
+                    },
+                )?;
+                return Ok(());
+            }
+
+            tokio::time::sleep(delay).await;
+            delay = (delay * 2).min(MAX_RETRY_DELAY);
+        }
+    }
+
+    async fn post(&self, webhook: &Webhook, seq: u64, payload: &str) -> anyhow::Result<()> {
+        let mut request = self
+            .client
+            .post(&webhook.url)
+            .header("Content-Type", "application/json")
+            .header("X-Webhook-Id", webhook.id)
+            .header("X-Event-Seq", seq);
+
+        if let Some(secret) = &webhook.secret {
+            request = request.header("X-Signature", format!("sha256={}", sign(secret, payload)));
+        }
+
+        request
+            .body(payload.to_string())
+            .send()
+            .await?
+            .error_for_status()?;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::AtomicUsize;
+
+    use super::*;
+    use crate::test_utils::{self, TestChain};
+
+    fn webhook(url: String, events: &[EventType]) -> Webhook {
+        Webhook {
+            id: 1,
+            url,
+            secret: Some("secret".to_string()),
+            addresses: HashSet::new(),
+            tokens: HashSet::new(),
+            events: events.iter().copied().collect(),
+            created_seq: 0,
+        }
+    }
+
+    fn delivery(server: &Arc<Server>, failing: bool) -> WebhookDelivery {
+        WebhookDelivery {
+            server: server.clone(),
+            client: reqwest::Client::new(),
+            id: 1,
+            failing,
+        }
+    }
+
+    /// Receiver failing the first `failures` requests, returns its URL and the count of signed requests
+    async fn receiver(failures: usize) -> (String, Arc<AtomicUsize>) {
+        let requests = Arc::new(AtomicUsize::new(0));
+        let url = test_utils::http_server({
+            let requests = requests.clone();
+            move |request| {
+                let payload = String::from_utf8(request.body).unwrap();
+                let signature = format!("sha256={}", sign("secret", &payload));
+                if request.headers.get("x-signature") != Some(&signature) {
+                    return (401, String::new());
+                }
+
+                let n = requests.fetch_add(1, Ordering::SeqCst);
+                if n < failures {
+                    (500, String::new())
+                } else {
+                    (200, String::new())
+                }
+            }
+        })
+        .await;
+        (url, requests)
+    }
+
+    #[tokio::test]
+    async fn failed_delivery_is_retried() {
+        let server = test_utils::server().await;
+        let (url, requests) = receiver(1).await;
+        let webhook = webhook(url, &[EventType::NewBlock]);
+
+        let mut delivery = delivery(&server, false);
+        delivery
+            .deliver(&webhook, 1, "{}".to_string())
+            .await
+            .unwrap();
+
+        assert_eq!(requests.load(Ordering::SeqCst), 2);
+        assert!(!delivery.failing);
+        assert!(server.db.webhook_dead_letters.iter().next().is_none());
+    }
+
+    #[tokio::test]
+    async fn failing_webhook_moves_events_to_dead_letters() {
+        let server = test_utils::server().await;
+        let (url, requests) = receiver(usize::MAX).await;
+        let webhook = webhook(url, &[EventType::NewBlock]);
+
+        // Tried once while the last event failed too
+        let mut delivery = delivery(&server, true);
+        delivery
+            .deliver(&webhook, 3, "{}".to_string())
+            .await
+            .unwrap();
+
+        assert_eq!(requests.load(Ordering::SeqCst), 1);
+        assert!(delivery.failing);
+        let dead_letter = server
+            .db
+            .webhook_dead_letters
+            .get(WebhookEvent { webhook: 1, seq: 3 })
+            .unwrap()
+            .unwrap();
+        assert_eq!(dead_letter.seq, 3);
+        assert_eq!(dead_letter.payload, "{}");
+        assert_eq!(dead_letter.attempts, 1);
+        assert!(dead_letter.error.contains("500"));
+    }
+
+    #[tokio::test]
+    async fn batch_delivers_matching_events_and_saves_cursor() {
+        let alice = test_utils::address(1);
+        let mut chain = TestChain::new();
+        let deploy = chain.inscribe(&alice, &test_utils::deploy("abcd", "1000", "100"));
+        chain.mine(vec![deploy]);
+
+        let server = test_utils::server().await;
+        test_utils::index(&server, &chain, chain.height()).await;
+
+        let payloads = Arc::new(parking_lot::Mutex::new(vec![]));
+        let url = test_utils::http_server({
+            let payloads = payloads.clone();
+            move |request| {
+                let payload: serde_json::Value = serde_json::from_slice(&request.body).unwrap();
+                payloads.lock().push(payload);
+                (200, String::new())
+            }
+        })
+        .await;
+        server
+            .db
+            .webhooks
+            .set(1, webhook(url, &[EventType::NewBlock]))
+            .unwrap();
+
+        let (_, mut new_events) = server.subscribe_events();
+        assert!(delivery(&server, false)
+            .deliver_batch(&mut new_events)
+            .await
+            .unwrap());
+
+        let payloads = payloads.lock();
+        assert_eq!(payloads.len(), 2);
+        assert!(payloads.iter().all(|x| x["event_type"] == "new_block"));
+        assert_eq!(
+            server.db.webhook_cursors.get(1).unwrap(),
+            Some(server.last_event_seq())
+        );
+    }
+}